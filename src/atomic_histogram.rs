@@ -0,0 +1,245 @@
+//! The `atomic_histogram` module provides a lock-free histogram for the distributions tracked
+//! by [`crate::quic::StreamStats`], and a histogram of event rates built on it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+// Every power of two is split into this many equally sized buckets, which bounds the error of
+// the reported percentiles to 1/8th of the value.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const NUM_BUCKETS: usize = SUB_BUCKETS * (u64::BITS - SUB_BUCKET_BITS + 1) as usize;
+
+// Weight of the latest second in the moving average of a rate, which mostly reflects the last
+// ten seconds.
+const RATE_EMA_ALPHA: f64 = 0.1;
+
+/// Percentiles of the values recorded since the last report.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+/// Log-linear histogram that can be recorded into concurrently.
+pub(crate) struct AtomicHistogram {
+    buckets: [AtomicU64; NUM_BUCKETS],
+    max: AtomicU64,
+}
+
+impl Default for AtomicHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::default()),
+            max: AtomicU64::default(),
+        }
+    }
+}
+
+impl AtomicHistogram {
+    pub(crate) fn record(&self, value: u64) {
+        self.record_many(value, 1);
+    }
+
+    fn record_many(&self, value: u64, count: u64) {
+        if count == 0 {
+            return;
+        }
+        self.buckets[bucket_index(value)].fetch_add(count, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    /// Returns the percentiles, resetting the histogram if `reset` is set. Values recorded
+    /// while resetting may be counted toward either report.
+    pub(crate) fn collect(&self, reset: bool) -> HistogramSnapshot {
+        let read = |value: &AtomicU64| {
+            if reset {
+                value.swap(0, Ordering::Relaxed)
+            } else {
+                value.load(Ordering::Relaxed)
+            }
+        };
+        let counts: Vec<u64> = self.buckets.iter().map(read).collect();
+        let max = read(&self.max);
+        let count: u64 = counts.iter().sum();
+        let percentile = |percentile: u64| {
+            if count == 0 {
+                return 0;
+            }
+            let rank = (count * percentile).div_ceil(100);
+            let mut seen = 0;
+            let index = counts
+                .iter()
+                .position(|&bucket_count| {
+                    seen += bucket_count;
+                    seen >= rank
+                })
+                .unwrap_or(NUM_BUCKETS - 1);
+            bucket_upper_bound(index).min(max)
+        };
+        HistogramSnapshot {
+            count,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max,
+        }
+    }
+}
+
+struct RateWindow {
+    start: Instant,
+    events: u64,
+    ema: f64,
+}
+
+/// Histogram of the number of events per second, along with their moving average.
+///
+/// Only completed seconds are recorded, the ones without any event as zero, so that bursts
+/// stand out from a steady rate adding up to the same total.
+pub(crate) struct RateHistogram {
+    histogram: AtomicHistogram,
+    window: Mutex<RateWindow>,
+}
+
+impl Default for RateHistogram {
+    fn default() -> Self {
+        Self {
+            histogram: AtomicHistogram::default(),
+            window: Mutex::new(RateWindow {
+                start: Instant::now(),
+                events: 0,
+                ema: 0.0,
+            }),
+        }
+    }
+}
+
+impl RateHistogram {
+    pub(crate) fn record(&self) {
+        self.record_at(Instant::now());
+    }
+
+    fn record_at(&self, now: Instant) {
+        let mut window = self.window.lock().unwrap();
+        self.complete_seconds(&mut window, now);
+        window.events += 1;
+    }
+
+    /// Returns the percentiles of the seconds completed since the last reset, resetting them
+    /// if `reset` is set, and the moving average, which is never reset.
+    pub(crate) fn collect(&self, reset: bool) -> (HistogramSnapshot, u64) {
+        self.collect_at(reset, Instant::now())
+    }
+
+    fn collect_at(&self, reset: bool, now: Instant) -> (HistogramSnapshot, u64) {
+        let mut window = self.window.lock().unwrap();
+        self.complete_seconds(&mut window, now);
+        (self.histogram.collect(reset), window.ema.round() as u64)
+    }
+
+    fn complete_seconds(&self, window: &mut RateWindow, now: Instant) {
+        let seconds = now.saturating_duration_since(window.start).as_secs();
+        if seconds == 0 {
+            return;
+        }
+        let idle_seconds = seconds - 1;
+        self.histogram.record(window.events);
+        self.histogram.record_many(0, idle_seconds);
+        window.ema += RATE_EMA_ALPHA * (window.events as f64 - window.ema);
+        window.ema *= (1.0 - RATE_EMA_ALPHA).powf(idle_seconds as f64);
+        window.events = 0;
+        window.start += Duration::from_secs(seconds);
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = u64::BITS - 1 - value.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) as usize & (SUB_BUCKETS - 1);
+    SUB_BUCKETS * (shift as usize + 1) + sub_bucket
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub_bucket = (index % SUB_BUCKETS) as u64;
+    let lower_bound = (SUB_BUCKETS as u64 | sub_bucket) << shift;
+    lower_bound + ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_atomic_histogram() {
+        for value in [0, 7, 8, 9, 1232, 1 << 40, u64::MAX] {
+            let upper_bound = bucket_upper_bound(bucket_index(value));
+            assert!(upper_bound >= value);
+            assert!(upper_bound - value <= value / SUB_BUCKETS as u64);
+        }
+
+        let histogram = AtomicHistogram::default();
+        assert_eq!(histogram.collect(false), HistogramSnapshot::default());
+        for value in 1..=100 {
+            histogram.record(value);
+        }
+        histogram.record(1232);
+        let snapshot = histogram.collect(true);
+        assert_eq!(snapshot.count, 101);
+        assert_eq!(snapshot.max, 1232);
+        assert!((51..=57).contains(&snapshot.p50), "{snapshot:?}");
+        assert!((91..=103).contains(&snapshot.p90), "{snapshot:?}");
+        assert!((100..=111).contains(&snapshot.p99), "{snapshot:?}");
+        assert_eq!(histogram.collect(false), HistogramSnapshot::default());
+    }
+
+    #[test]
+    fn test_rate_histogram() {
+        let rate = RateHistogram::default();
+        let start = rate.window.lock().unwrap().start;
+        let at = |millis| start + Duration::from_millis(millis);
+        // A burst of 20 in the first second, then a second with 2 and two idle ones.
+        for _ in 0..20 {
+            rate.record_at(at(500));
+        }
+        rate.record_at(at(1_100));
+        rate.record_at(at(1_900));
+        let (snapshot, ema) = rate.collect_at(false, at(4_000));
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.p50, 0);
+        assert_eq!(snapshot.max, 20);
+        // 20 * 0.1 after the burst, kept by the next second and decayed to 1.62 by the idle ones.
+        assert_eq!(ema, 2);
+        assert_eq!(rate.collect_at(true, at(4_500)).0.count, 4);
+        assert_eq!(rate.collect_at(false, at(4_900)).0.count, 0);
+
+        // The running second isn't recorded until it completes.
+        let (snapshot, _) = rate.collect_at(false, at(5_000));
+        assert_eq!(snapshot.count, 1);
+        assert_eq!(snapshot.max, 0);
+
+        let rate = RateHistogram::default();
+        let start = rate.window.lock().unwrap().start;
+        for second in 0..30 {
+            for _ in 0..10 {
+                rate.record_at(start + Duration::from_secs(second));
+            }
+        }
+        let (snapshot, ema) = rate.collect_at(true, start + Duration::from_secs(30));
+        assert_eq!((snapshot.count, snapshot.p50, snapshot.max), (30, 10, 10));
+        assert_eq!(ema, 10);
+    }
+}
@@ -1,15 +1,33 @@
 #![allow(clippy::arithmetic_side_effects)]
+// The `datapoint_info!` of the stream stats recurses once per field.
+#![recursion_limit = "256"]
+
+// Stands in for the `solana_metrics` macro without the `metrics` feature. The fields are still
+// evaluated, so the stats they're read from are reset as with the real one.
+#[cfg(not(feature = "metrics"))]
+macro_rules! datapoint_info {
+    ($name:expr $(, ($field:expr, $value:expr, $type:ident))* $(,)?) => {{
+        let _ = $name;
+        $(let _ = ($field, $value);)*
+    }};
+}
+
+pub mod atomic_histogram;
 pub mod nonblocking;
 pub mod packet;
+pub mod packet_sink;
 pub mod quic;
 pub mod recvmmsg;
 pub mod sendmmsg;
 pub mod socket;
 pub mod streamer;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 pub mod tls_certificates;
 
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "metrics")]
 #[macro_use]
 extern crate solana_metrics;
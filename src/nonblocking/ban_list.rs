@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use solana_sdk::pubkey::Pubkey;
+
+/// A peer excluded from connecting, see [`crate::nonblocking::quic::ConnectionControl::ban`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BannedPeer {
+    /// The peer presenting this pubkey in its certificate.
+    Pubkey(Pubkey),
+    /// Every connection from this IP address.
+    Ip(IpAddr),
+}
+
+impl BannedPeer {
+    fn canonical(self) -> Self {
+        match self {
+            BannedPeer::Pubkey(_) => self,
+            BannedPeer::Ip(ip) => BannedPeer::Ip(ip.to_canonical()),
+        }
+    }
+}
+
+/// Bans with an expiry. Expired bans are purged by the next check.
+#[derive(Debug, Default)]
+pub(crate) struct BanList {
+    bans: Mutex<HashMap<BannedPeer, Instant>>,
+}
+
+impl BanList {
+    /// Bans `peer` for `duration` from now, replacing an earlier ban of it.
+    pub(crate) fn ban(&self, peer: BannedPeer, duration: Duration) {
+        let now = Instant::now();
+        let expiry = now
+            .checked_add(duration)
+            .unwrap_or(now + Duration::from_secs(u32::MAX.into()));
+        self.bans.lock().unwrap().insert(peer.canonical(), expiry);
+    }
+
+    /// Lifts the ban of `peer`, returns whether it was banned.
+    pub(crate) fn unban(&self, peer: BannedPeer) -> bool {
+        self.bans
+            .lock()
+            .unwrap()
+            .remove(&peer.canonical())
+            .is_some()
+    }
+
+    pub(crate) fn is_banned(&self, peer: BannedPeer) -> bool {
+        self.is_banned_at(peer, Instant::now())
+    }
+
+    fn is_banned_at(&self, peer: BannedPeer, now: Instant) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        if bans.is_empty() {
+            return false;
+        }
+        bans.retain(|_, expiry| *expiry > now);
+        bans.contains_key(&peer.canonical())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn test_ban_list() {
+        let ban_list = BanList::default();
+        let pubkey = Pubkey::new_unique();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        ban_list.ban(BannedPeer::Pubkey(pubkey), Duration::from_secs(60));
+        ban_list.ban(BannedPeer::Ip(ip), Duration::from_secs(1));
+
+        let now = Instant::now();
+        assert!(ban_list.is_banned_at(BannedPeer::Pubkey(pubkey), now));
+        assert!(!ban_list.is_banned_at(BannedPeer::Pubkey(Pubkey::new_unique()), now));
+        // IPv4-mapped IPv6 addresses match the IPv4 ban.
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped());
+        assert!(ban_list.is_banned_at(BannedPeer::Ip(mapped), now));
+        assert!(!ban_list.is_banned_at(BannedPeer::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST)), now));
+
+        // The IP ban lapses and is purged, the pubkey ban stays until lifted.
+        let later = now + Duration::from_secs(2);
+        assert!(!ban_list.is_banned_at(BannedPeer::Ip(ip), later));
+        assert_eq!(ban_list.bans.lock().unwrap().len(), 1);
+        assert!(ban_list.unban(BannedPeer::Pubkey(pubkey)));
+        assert!(!ban_list.unban(BannedPeer::Pubkey(pubkey)));
+        assert!(!ban_list.is_banned_at(BannedPeer::Pubkey(pubkey), later));
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::nonblocking::quic::ConnectionPeerType;
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    // Bytes the readers keeping the reserve took beyond it, paid off by the refill once the
+    // bucket is back at the reserve, before it rises above it
+    reserve_debt: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket capping the rate at which the streams of a connection are read.
+///
+/// The bucket holds up to a second's worth of bytes. A chunk that has already been read is
+/// never held back, instead its bytes are taken on credit and the stream waits until the
+/// bucket is out of debt before reading the next one.
+#[derive(Debug)]
+pub(crate) struct BandwidthThrottle {
+    bytes_per_second: u64,
+    // Bytes the readers calling `consume_keeping_reserve` leave in the bucket
+    reserve: u64,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl BandwidthThrottle {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        Self::with_reserve(bytes_per_second, 0)
+    }
+
+    pub(crate) fn with_reserve(bytes_per_second: u64, reserve: u64) -> Self {
+        Self {
+            bytes_per_second,
+            reserve: reserve.min(bytes_per_second),
+            bucket: Mutex::new(TokenBucket {
+                tokens: bytes_per_second as f64,
+                reserve_debt: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` from the bucket, returns how long to wait before reading more.
+    pub(crate) fn consume(&self, bytes: usize) -> Duration {
+        self.consume_at(bytes, false, Instant::now())
+    }
+
+    /// Like [`Self::consume`], but never takes the bucket below the reserve, which leaves it to
+    /// the readers calling [`Self::consume`]. The bytes it can't take are owed separately, and
+    /// the wait covers them along with those owed by the other readers keeping the reserve, so
+    /// that they share the rate without cutting into the reserve.
+    pub(crate) fn consume_keeping_reserve(&self, bytes: usize) -> Duration {
+        self.consume_at(bytes, true, Instant::now())
+    }
+
+    fn consume_at(&self, bytes: usize, keep_reserve: bool, now: Instant) -> Duration {
+        let rate = self.bytes_per_second as f64;
+        let reserve = self.reserve as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.last_refill = now;
+        // The refill goes to the reserve first, then to the debt taken beyond it.
+        let mut refill = elapsed.as_secs_f64() * rate;
+        let to_reserve = (reserve - bucket.tokens).clamp(0.0, refill);
+        bucket.tokens += to_reserve;
+        refill -= to_reserve;
+        let paid = bucket.reserve_debt.min(refill);
+        bucket.reserve_debt -= paid;
+        refill -= paid;
+        bucket.tokens = (bucket.tokens + refill).min(rate);
+
+        let bytes = bytes as f64;
+        let shortfall = if keep_reserve {
+            let taken = bytes.min((bucket.tokens - reserve).max(0.0));
+            bucket.tokens -= taken;
+            bucket.reserve_debt += bytes - taken;
+            (reserve - bucket.tokens).max(0.0) + bucket.reserve_debt
+        } else {
+            bucket.tokens -= bytes;
+            -bucket.tokens
+        };
+        if shortfall <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(shortfall / rate)
+        }
+    }
+}
+
+/// Endpoint-wide [`BandwidthThrottle`] that keeps part of its burst to staked peers, so that
+/// their reads resume first when the bandwidth runs short, however many bytes unstaked peers
+/// read.
+#[derive(Debug)]
+pub(crate) struct GlobalBandwidthThrottle {
+    throttle: BandwidthThrottle,
+}
+
+impl GlobalBandwidthThrottle {
+    pub(crate) fn new(bytes_per_second: u64, staked_reserve_percent: u8) -> Self {
+        Self {
+            throttle: BandwidthThrottle::with_reserve(
+                bytes_per_second,
+                bytes_per_second.saturating_mul(staked_reserve_percent.into()) / 100,
+            ),
+        }
+    }
+
+    pub(crate) fn consume(&self, bytes: usize, peer_type: ConnectionPeerType) -> Duration {
+        match peer_type {
+            ConnectionPeerType::Staked(_) => self.throttle.consume(bytes),
+            ConnectionPeerType::Unstaked => self.throttle.consume_keeping_reserve(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_throttle() {
+        let throttle = BandwidthThrottle::new(1_000);
+        let now = Instant::now();
+        // A second's worth of bytes passes unthrottled.
+        assert_eq!(throttle.consume_at(600, false, now), Duration::ZERO);
+        assert_eq!(throttle.consume_at(400, false, now), Duration::ZERO);
+        assert_eq!(
+            throttle.consume_at(500, false, now),
+            Duration::from_millis(500)
+        );
+
+        // Waiting out the debt clears it, and idling doesn't bank more than the burst.
+        let later = now + Duration::from_millis(500);
+        assert_eq!(throttle.consume_at(0, false, later), Duration::ZERO);
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(
+            throttle.consume_at(1_100, false, much_later),
+            Duration::from_millis(100)
+        );
+
+        // Readers keeping the reserve wait for the bucket to refill up to it.
+        let throttle = BandwidthThrottle::with_reserve(1_000, 500);
+        assert_eq!(throttle.consume_at(400, true, now), Duration::ZERO);
+        assert_eq!(
+            throttle.consume_at(400, true, now),
+            Duration::from_millis(300)
+        );
+        assert_eq!(throttle.consume_at(100, false, now), Duration::ZERO);
+
+        let throttle = GlobalBandwidthThrottle::new(1_000, 50);
+        assert_eq!(
+            throttle.consume(400, ConnectionPeerType::Unstaked),
+            Duration::ZERO
+        );
+        assert!(!throttle
+            .consume(200, ConnectionPeerType::Unstaked)
+            .is_zero());
+        assert_eq!(
+            throttle.consume(200, ConnectionPeerType::Staked(1)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_throttle_reserve_under_contention() {
+        let throttle = BandwidthThrottle::with_reserve(1_000, 500);
+        let now = Instant::now();
+        // Unstaked readers flooding the bucket owe ten seconds' worth of bytes between them.
+        let mut unstaked_wait = Duration::ZERO;
+        for _ in 0..10 {
+            unstaked_wait = throttle.consume_at(1_050, true, now);
+        }
+        assert_eq!(unstaked_wait, Duration::from_secs(10));
+
+        // The reserve is left untouched, a staked reader only waits for what it read itself.
+        assert_eq!(throttle.consume_at(500, false, now), Duration::ZERO);
+        assert_eq!(
+            throttle.consume_at(200, false, now),
+            Duration::from_millis(200)
+        );
+
+        // The refill restores the reserve first, the unstaked readers wait for the rest.
+        let later = now + Duration::from_millis(700);
+        assert_eq!(throttle.consume_at(500, false, later), Duration::ZERO);
+        assert_eq!(
+            throttle.consume_at(0, true, later),
+            Duration::from_millis(10_500)
+        );
+        let much_later = later + Duration::from_millis(10_500);
+        assert_eq!(throttle.consume_at(0, true, much_later), Duration::ZERO);
+    }
+}
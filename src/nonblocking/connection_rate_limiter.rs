@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Once the table holds this many addresses, buckets that have refilled completely are dropped
+// since they carry no state a fresh bucket wouldn't, at most once per interval.
+const MAX_TRACKED_ADDRESSES_BEFORE_PRUNE: usize = 16_384;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+// Addresses beyond it share a single bucket until a prune makes room.
+const MAX_TRACKED_ADDRESSES: usize = 65_536;
+
+/// Rate and burst of a per-source-IP token bucket for new connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionRateLimit {
+    /// Sustained number of new connections allowed per second. Must be positive and finite.
+    pub connections_per_second: f64,
+    /// Number of connections that may be opened back to back before the rate applies. Must not
+    /// be zero.
+    pub burst: u64,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    // Of the addresses that didn't fit in the table
+    overflow: TokenBucket,
+    last_prune: Instant,
+}
+
+/// Token bucket limiter keyed by the source IP of new connections.
+#[derive(Debug)]
+pub(crate) struct ConnectionRateLimiter {
+    limit: ConnectionRateLimit,
+    state: Mutex<LimiterState>,
+}
+
+impl ConnectionRateLimiter {
+    pub(crate) fn new(limit: ConnectionRateLimit) -> Self {
+        let now = Instant::now();
+        Self {
+            limit,
+            state: Mutex::new(LimiterState {
+                buckets: HashMap::default(),
+                overflow: TokenBucket {
+                    tokens: limit.burst as f64,
+                    last_refill: now,
+                },
+                last_prune: now,
+            }),
+        }
+    }
+
+    /// Takes a token from the bucket of `ip`, returns false if it's empty.
+    pub(crate) fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let burst = self.limit.burst as f64;
+        let mut state = self.state.lock().unwrap();
+        let LimiterState {
+            buckets,
+            overflow,
+            last_prune,
+        } = &mut *state;
+        if buckets.len() >= MAX_TRACKED_ADDRESSES_BEFORE_PRUNE
+            && now.saturating_duration_since(*last_prune) >= PRUNE_INTERVAL
+        {
+            buckets.retain(|_, bucket| self.refilled_tokens(bucket, now) < burst);
+            *last_prune = now;
+        }
+        let bucket = if buckets.len() < MAX_TRACKED_ADDRESSES || buckets.contains_key(&ip) {
+            buckets.entry(ip).or_insert(TokenBucket {
+                tokens: burst,
+                last_refill: now,
+            })
+        } else {
+            overflow
+        };
+        bucket.tokens = self.refilled_tokens(bucket, now);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refilled_tokens(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        let refill = elapsed.as_secs_f64() * self.limit.connections_per_second;
+        (bucket.tokens + refill).min(self.limit.burst as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_connection_rate_limiter() {
+        let limiter = ConnectionRateLimiter::new(ConnectionRateLimit {
+            connections_per_second: 8.0,
+            burst: 8,
+        });
+        let flooder = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let well_behaved = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let start = Instant::now();
+
+        assert_eq!(
+            (0..20).filter(|_| limiter.check_at(flooder, start)).count(),
+            8
+        );
+        assert!(limiter.check_at(well_behaved, start));
+
+        // 125ms refills a single token.
+        let later = start + Duration::from_millis(125);
+        assert!(limiter.check_at(flooder, later));
+        assert!(!limiter.check_at(flooder, later));
+
+        // The bucket never holds more than the burst.
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(
+            (0..20)
+                .filter(|_| limiter.check_at(flooder, much_later))
+                .count(),
+            8
+        );
+    }
+
+    #[test]
+    fn test_connection_rate_limiter_table_cap() {
+        let limiter = ConnectionRateLimiter::new(ConnectionRateLimit {
+            connections_per_second: 1.0,
+            burst: 1,
+        });
+        let ip = |i: usize| IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i as u32));
+        let start = Instant::now();
+        for i in 0..MAX_TRACKED_ADDRESSES {
+            assert!(limiter.check_at(ip(i), start));
+        }
+        assert_eq!(
+            limiter.state.lock().unwrap().buckets.len(),
+            MAX_TRACKED_ADDRESSES
+        );
+
+        // The addresses that don't fit share a bucket, while the tracked ones keep theirs.
+        assert!(limiter.check_at(ip(MAX_TRACKED_ADDRESSES), start));
+        assert!(!limiter.check_at(ip(MAX_TRACKED_ADDRESSES + 1), start));
+        assert!(!limiter.check_at(ip(0), start));
+        assert_eq!(
+            limiter.state.lock().unwrap().buckets.len(),
+            MAX_TRACKED_ADDRESSES
+        );
+
+        // Buckets that refilled are pruned once the interval passed, making room again.
+        let later = start + PRUNE_INTERVAL;
+        assert!(limiter.check_at(ip(MAX_TRACKED_ADDRESSES + 1), later));
+        assert_eq!(limiter.state.lock().unwrap().buckets.len(), 1);
+    }
+}
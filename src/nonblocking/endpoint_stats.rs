@@ -0,0 +1,220 @@
+use std::fmt;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::AsyncUdpSocket;
+
+use crate::quic::StreamStats;
+
+const LONG_HEADER_FORM: u8 = 0x80;
+const QUIC_V2: u32 = 0x6b33_43cf;
+// Datagrams carrying an ack-eliciting Initial packet are padded to at least this size
+const MIN_INITIAL_DATAGRAM_SIZE: usize = 1200;
+
+/// What the first packet of a datagram tells about the handshakes quinn handles on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PacketKind {
+    /// An Initial packet. `lone` when it's the only packet of the datagram.
+    Initial {
+        lone: bool,
+    },
+    Retry,
+    VersionNegotiation,
+    Other,
+}
+
+// Reads a variable-length integer, returns it along with the rest of `data`.
+fn read_varint(data: &[u8]) -> Option<(u64, &[u8])> {
+    let first = *data.first()?;
+    let len = 1 << (first >> 6);
+    let bytes = data.get(..len)?;
+    let value = bytes[1..]
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, &byte| {
+            value << 8 | u64::from(byte)
+        });
+    Some((value, &data[len..]))
+}
+
+// Skips a connection ID prefixed by its length.
+fn skip_cid(data: &[u8]) -> Option<&[u8]> {
+    let (&len, data) = data.split_first()?;
+    data.get(usize::from(len)..)
+}
+
+/// Classifies the first packet of `datagram` by its long header, which isn't protected.
+pub(crate) fn classify(datagram: &[u8]) -> PacketKind {
+    let Some((&first, rest)) = datagram.split_first() else {
+        return PacketKind::Other;
+    };
+    if first & LONG_HEADER_FORM == 0 {
+        return PacketKind::Other;
+    }
+    let Some(version) = rest.get(..4) else {
+        return PacketKind::Other;
+    };
+    let version = u32::from_be_bytes(version.try_into().unwrap());
+    if version == 0 {
+        return PacketKind::VersionNegotiation;
+    }
+    // QUIC v2 shuffled the packet types of v1 and the drafts before it.
+    let (initial, retry) = if version == QUIC_V2 { (1, 0) } else { (0, 3) };
+    let packet_type = (first >> 4) & 0x3;
+    if packet_type == retry {
+        return PacketKind::Retry;
+    }
+    if packet_type != initial {
+        return PacketKind::Other;
+    }
+    let lone = (|| {
+        let rest = skip_cid(skip_cid(&rest[4..])?)?;
+        let (token_len, rest) = read_varint(rest)?;
+        let rest = rest.get(usize::try_from(token_len).ok()?..)?;
+        let (len, rest) = read_varint(rest)?;
+        Some(len == rest.len() as u64)
+    })();
+    match lone {
+        Some(lone) => PacketKind::Initial { lone },
+        None => PacketKind::Other,
+    }
+}
+
+/// Socket counting the packets of the handshakes quinn answers, or refuses, before the accept
+/// loop sees a connection, see the `endpoint_*` counters of [`StreamStats`].
+pub(crate) struct EndpointStatsSocket {
+    inner: Box<dyn AsyncUdpSocket>,
+    stats: Arc<StreamStats>,
+}
+
+impl EndpointStatsSocket {
+    pub(crate) fn new(inner: Box<dyn AsyncUdpSocket>, stats: Arc<StreamStats>) -> Self {
+        Self { inner, stats }
+    }
+
+    fn record_received(&self, datagram: &[u8]) {
+        self.stats.endpoint_datagrams_received.add(1);
+        if let PacketKind::Initial { .. } = classify(datagram) {
+            self.stats.endpoint_initial_packets_received.add(1);
+        }
+    }
+
+    // The server only sends a short Initial packet on its own to close the connection right
+    // away, a handshake that goes ahead is padded or has its Handshake packets coalesced.
+    fn record_sent(&self, transmit: &Transmit) {
+        let datagram = match transmit.segment_size {
+            Some(segment_size) => &transmit.contents[..segment_size.min(transmit.contents.len())],
+            None => &transmit.contents[..],
+        };
+        match classify(datagram) {
+            PacketKind::Initial { lone: true } if datagram.len() < MIN_INITIAL_DATAGRAM_SIZE => {
+                self.stats.endpoint_connections_refused.add(1);
+            }
+            PacketKind::Retry => self.stats.endpoint_retry_packets_sent.add(1),
+            PacketKind::VersionNegotiation => {
+                self.stats.endpoint_version_negotiation_sent.add(1);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl fmt::Debug for EndpointStatsSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EndpointStatsSocket")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncUdpSocket for EndpointStatsSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let sent = ready!(self.inner.poll_send(state, cx, transmits))?;
+        transmits[..sent]
+            .iter()
+            .for_each(|transmit| self.record_sent(transmit));
+        Poll::Ready(Ok(sent))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let count = ready!(self.inner.poll_recv(cx, bufs, meta))?;
+        for (buf, meta) in bufs.iter().zip(meta.iter()).take(count) {
+            // Segments coalesced by GRO are a stride apart.
+            for segment in buf[..meta.len].chunks(meta.stride.max(1)) {
+                self.record_received(segment);
+            }
+        }
+        Poll::Ready(Ok(count))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Long header of an Initial packet whose length field claims `len` more bytes.
+    fn initial_header(version: u32, token: &[u8], len: u16) -> Vec<u8> {
+        let packet_type = if version == QUIC_V2 { 1 } else { 0 };
+        let mut header = vec![0xc0 | packet_type << 4];
+        header.extend_from_slice(&version.to_be_bytes());
+        header.extend_from_slice(&[8, 1, 2, 3, 4, 5, 6, 7, 8]);
+        header.extend_from_slice(&[0]);
+        header.push(token.len() as u8);
+        header.extend_from_slice(token);
+        header.extend_from_slice(&(0x4000 | len).to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn test_classify() {
+        let mut lone = initial_header(1, &[], 100);
+        lone.resize(lone.len() + 100, 0);
+        assert_eq!(classify(&lone), PacketKind::Initial { lone: true });
+
+        // Coalesced with another packet, with a token.
+        let mut coalesced = initial_header(1, &[7; 20], 100);
+        coalesced.resize(coalesced.len() + 150, 0);
+        assert_eq!(classify(&coalesced), PacketKind::Initial { lone: false });
+
+        let mut v2 = initial_header(QUIC_V2, &[], 10);
+        v2.resize(v2.len() + 10, 0);
+        assert_eq!(classify(&v2), PacketKind::Initial { lone: true });
+        v2[0] = 0xc0;
+        assert_eq!(classify(&v2), PacketKind::Retry);
+
+        assert_eq!(classify(&[0xf0, 0, 0, 0, 1, 0, 0]), PacketKind::Retry);
+        assert_eq!(
+            classify(&[0xc0, 0, 0, 0, 0, 0, 0]),
+            PacketKind::VersionNegotiation
+        );
+        // Handshake and short header packets, and truncated ones.
+        assert_eq!(classify(&[0xe0, 0, 0, 0, 1, 0, 0]), PacketKind::Other);
+        assert_eq!(classify(&[0x40, 1, 2, 3]), PacketKind::Other);
+        assert_eq!(classify(&lone[..12]), PacketKind::Other);
+        assert_eq!(classify(&[]), PacketKind::Other);
+
+        assert_eq!(read_varint(&[0x25, 1]), Some((0x25, &[1][..])));
+        assert_eq!(read_varint(&[0x7b, 0xbd]), Some((15_293, &[][..])));
+        assert_eq!(read_varint(&[0x80, 0, 1]), None);
+    }
+}
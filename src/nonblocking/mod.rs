@@ -1,4 +1,13 @@
+pub mod ban_list;
+mod bandwidth_throttle;
+pub mod connection_rate_limiter;
+mod endpoint_stats;
+pub mod peer_stats;
+mod proxy_protocol;
 pub mod quic;
+mod read_scheduler;
 pub mod recvmmsg;
 pub mod sendmmsg;
+pub mod socket_rebind;
+pub mod stake_tiers;
 mod stream_throttle;
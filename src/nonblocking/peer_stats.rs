@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use solana_sdk::pubkey::Pubkey;
+
+/// Counters for the connections of a single peer identity.
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    pub(crate) streams: AtomicUsize,
+    pub(crate) chunks: AtomicUsize,
+    pub(crate) bytes: AtomicUsize,
+    pub(crate) throttled_streams: AtomicUsize,
+}
+
+impl PeerStats {
+    pub fn snapshot(&self) -> PeerStatsSnapshot {
+        PeerStatsSnapshot {
+            streams: self.streams.load(Ordering::Relaxed) as u64,
+            chunks: self.chunks.load(Ordering::Relaxed) as u64,
+            bytes: self.bytes.load(Ordering::Relaxed) as u64,
+            throttled_streams: self.throttled_streams.load(Ordering::Relaxed) as u64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PeerStatsSnapshot {
+    pub streams: u64,
+    pub chunks: u64,
+    pub bytes: u64,
+    pub throttled_streams: u64,
+}
+
+/// How long the entry of a staked peer is kept after its last connection is closed, see
+/// [`PeerStatsTable`].
+pub const DEFAULT_STAKED_PEER_STATS_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+struct PeerStatsEntry {
+    stats: Arc<PeerStats>,
+    num_connections: usize,
+    staked: bool,
+    // When the last connection was closed, `None` while the peer is connected.
+    disconnected_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Peers {
+    entries: HashMap<Pubkey, PeerStatsEntry>,
+    last_sweep: Instant,
+}
+
+/// Per-peer counters keyed by the pubkey from the peer's certificate.
+///
+/// Entries of unstaked peers are dropped once their last connection is closed to bound the
+/// memory used under high connection churn. Entries of staked peers outlive their connections
+/// so that the counters of a peer add up across reconnects, until the peer stays away for
+/// longer than the TTL. A peer counts as staked as of its latest connection, so a peer which
+/// lost its stake is dropped like an unstaked one.
+#[derive(Debug)]
+pub struct PeerStatsTable {
+    peers: RwLock<Peers>,
+    staked_peer_ttl: Duration,
+}
+
+impl Default for PeerStatsTable {
+    fn default() -> Self {
+        Self::new(DEFAULT_STAKED_PEER_STATS_TTL)
+    }
+}
+
+impl PeerStatsTable {
+    pub fn new(staked_peer_ttl: Duration) -> Self {
+        Self {
+            peers: RwLock::new(Peers {
+                entries: HashMap::default(),
+                last_sweep: Instant::now(),
+            }),
+            staked_peer_ttl,
+        }
+    }
+
+    pub fn get(&self, pubkey: &Pubkey) -> Option<PeerStatsSnapshot> {
+        self.peers
+            .read()
+            .unwrap()
+            .entries
+            .get(pubkey)
+            .map(|entry| entry.stats.snapshot())
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.read().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Registers a new connection from `pubkey` and returns the counters to update for it.
+    pub(crate) fn add_connection(&self, pubkey: Pubkey, staked: bool) -> Arc<PeerStats> {
+        let mut peers = self.peers.write().unwrap();
+        let entry = peers
+            .entries
+            .entry(pubkey)
+            .or_insert_with(|| PeerStatsEntry {
+                stats: Arc::default(),
+                num_connections: 0,
+                staked,
+                disconnected_at: None,
+            });
+        entry.num_connections += 1;
+        entry.staked = staked;
+        entry.disconnected_at = None;
+        entry.stats.clone()
+    }
+
+    pub(crate) fn remove_connection(&self, pubkey: &Pubkey) {
+        let mut peers = self.peers.write().unwrap();
+        let now = Instant::now();
+        if let Some(entry) = peers.entries.get_mut(pubkey) {
+            entry.num_connections = entry.num_connections.saturating_sub(1);
+            if entry.num_connections == 0 {
+                if entry.staked {
+                    entry.disconnected_at = Some(now);
+                } else {
+                    peers.entries.remove(pubkey);
+                }
+            }
+        }
+        // At most once per TTL, so that disconnects don't walk the whole table each time.
+        if now.duration_since(peers.last_sweep) >= self.staked_peer_ttl {
+            peers.last_sweep = now;
+            let ttl = self.staked_peer_ttl;
+            peers
+                .entries
+                .retain(|_, entry| match entry.disconnected_at {
+                    Some(disconnected_at) => now.duration_since(disconnected_at) < ttl,
+                    None => true,
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unstaked_peer_evicted_on_last_disconnect() {
+        let table = PeerStatsTable::default();
+        let pubkey = Pubkey::new_unique();
+
+        let stats = table.add_connection(pubkey, false);
+        stats.streams.fetch_add(2, Ordering::Relaxed);
+        table.add_connection(pubkey, false);
+        assert_eq!(table.get(&pubkey).unwrap().streams, 2);
+
+        table.remove_connection(&pubkey);
+        assert_eq!(table.get(&pubkey).unwrap().streams, 2);
+        table.remove_connection(&pubkey);
+        assert!(table.get(&pubkey).is_none());
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_staked_peer_retained_after_disconnect() {
+        let table = PeerStatsTable::default();
+        let pubkey = Pubkey::new_unique();
+
+        let stats = table.add_connection(pubkey, true);
+        stats.bytes.fetch_add(100, Ordering::Relaxed);
+        table.remove_connection(&pubkey);
+        assert_eq!(table.get(&pubkey).unwrap().bytes, 100);
+
+        table.add_connection(pubkey, true);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_staked_peer_evicted_after_ttl() {
+        let table = PeerStatsTable::new(Duration::from_millis(50));
+        let (gone, connected) = (Pubkey::new_unique(), Pubkey::new_unique());
+        table.add_connection(gone, true);
+        table.add_connection(connected, true);
+        table.add_connection(connected, true);
+        table.remove_connection(&gone);
+        assert!(table.get(&gone).is_some());
+
+        std::thread::sleep(Duration::from_millis(100));
+        // Swept on the next disconnect, which leaves the peer with a connection open.
+        table.remove_connection(&connected);
+        assert!(table.get(&gone).is_none());
+        assert!(table.get(&connected).is_some());
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_peer_evicted_after_losing_stake() {
+        let table = PeerStatsTable::default();
+        let pubkey = Pubkey::new_unique();
+        table.add_connection(pubkey, true);
+        table.remove_connection(&pubkey);
+        assert!(table.get(&pubkey).is_some());
+
+        // Reconnects without stake, and is dropped on disconnect like an unstaked peer.
+        table.add_connection(pubkey, false);
+        table.remove_connection(&pubkey);
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::AsyncUdpSocket;
+
+use crate::quic::StreamStats;
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// Signature, version and command, address family and transport, length of the addresses
+const HEADER_LEN: usize = 16;
+const VERSION: u8 = 2;
+const COMMAND_LOCAL: u8 = 0;
+const COMMAND_PROXY: u8 = 1;
+const FAMILY_INET: u8 = 1;
+const FAMILY_INET6: u8 = 2;
+
+// Clients are looked up when their first packet is accepted, so an address only has to be
+// kept for that long. The table is bounded for when a balancer relays many short flows.
+const PROXY_ADDRESS_TTL: Duration = Duration::from_secs(60);
+const MAX_PROXY_ADDRESSES: usize = 65_536;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ProxyHeader {
+    pub(crate) len: usize,
+    /// The address of the client, unset for the health checks of the balancer itself and
+    /// for address families that can't identify a client.
+    pub(crate) source: Option<SocketAddr>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct InvalidProxyHeader;
+
+/// Parses the PROXY protocol v2 header at the start of `data`, `None` when there's none.
+///
+/// The signature can't start a QUIC packet, which always has its fixed bit set, so a
+/// datagram without header is never taken for a malformed one.
+pub(crate) fn parse_header(data: &[u8]) -> Result<Option<ProxyHeader>, InvalidProxyHeader> {
+    if !data.starts_with(SIGNATURE) {
+        return Ok(None);
+    }
+    let Some(&[version_command, family, len_hi, len_lo]) = data.get(SIGNATURE.len()..HEADER_LEN)
+    else {
+        return Err(InvalidProxyHeader);
+    };
+    if version_command >> 4 != VERSION {
+        return Err(InvalidProxyHeader);
+    }
+    let len = HEADER_LEN + usize::from(u16::from_be_bytes([len_hi, len_lo]));
+    let addresses = data.get(HEADER_LEN..len).ok_or(InvalidProxyHeader)?;
+    let source = match version_command & 0x0f {
+        COMMAND_LOCAL => None,
+        COMMAND_PROXY => match family >> 4 {
+            FAMILY_INET => {
+                let addresses = addresses.get(..12).ok_or(InvalidProxyHeader)?;
+                let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+                let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+                Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+            }
+            FAMILY_INET6 => {
+                let addresses = addresses.get(..36).ok_or(InvalidProxyHeader)?;
+                let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+                let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+            }
+            // Unspecified and unix socket addresses don't identify a client.
+            _ => None,
+        },
+        _ => return Err(InvalidProxyHeader),
+    };
+    Ok(Some(ProxyHeader { len, source }))
+}
+
+/// Client addresses recovered from PROXY protocol headers, by the address of the balancer
+/// they were relayed from.
+#[derive(Debug, Default)]
+pub(crate) struct ProxyAddressTable {
+    addresses: Mutex<HashMap<SocketAddr, (SocketAddr, Instant)>>,
+}
+
+impl ProxyAddressTable {
+    // Returns false when the table is full of addresses seen within the TTL.
+    fn insert(&self, proxy: SocketAddr, client: SocketAddr) -> bool {
+        self.insert_at(proxy, client, Instant::now())
+    }
+
+    fn insert_at(&self, proxy: SocketAddr, client: SocketAddr, now: Instant) -> bool {
+        let mut addresses = self.addresses.lock().unwrap();
+        if addresses.len() >= MAX_PROXY_ADDRESSES && !addresses.contains_key(&proxy) {
+            addresses
+                .retain(|_, (_, last_seen)| now.duration_since(*last_seen) < PROXY_ADDRESS_TTL);
+            if addresses.len() >= MAX_PROXY_ADDRESSES {
+                return false;
+            }
+        }
+        addresses.insert(proxy, (client, now));
+        true
+    }
+
+    /// Returns the client behind `addr`, or `addr` itself when it didn't relay one within the
+    /// TTL.
+    pub(crate) fn resolve(&self, addr: SocketAddr) -> SocketAddr {
+        self.resolve_at(addr, Instant::now())
+    }
+
+    fn resolve_at(&self, addr: SocketAddr, now: Instant) -> SocketAddr {
+        self.addresses
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .filter(|(_, last_seen)| now.duration_since(*last_seen) < PROXY_ADDRESS_TTL)
+            .map_or(addr, |(client, _)| *client)
+    }
+}
+
+/// Socket stripping the PROXY protocol header off the datagrams of trusted balancers before
+/// quinn sees them, and recording the client addresses they carry.
+pub(crate) struct ProxyProtocolSocket {
+    inner: Box<dyn AsyncUdpSocket>,
+    trusted_proxies: Vec<IpNet>,
+    addresses: Arc<ProxyAddressTable>,
+    stats: Arc<StreamStats>,
+}
+
+impl ProxyProtocolSocket {
+    pub(crate) fn new(
+        inner: Box<dyn AsyncUdpSocket>,
+        trusted_proxies: Vec<IpNet>,
+        addresses: Arc<ProxyAddressTable>,
+        stats: Arc<StreamStats>,
+    ) -> Self {
+        Self {
+            inner,
+            trusted_proxies,
+            addresses,
+            stats,
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    // Segments coalesced by GRO carry a header each. They're compacted in place, which keeps
+    // them a common stride apart as long as their headers are of the same length. Datagrams
+    // with a malformed header are dropped by truncating them.
+    fn strip_headers(&self, buf: &mut [u8], meta: &mut RecvMeta) {
+        let mut header_len = None;
+        let mut read = 0;
+        let mut written = 0;
+        while read < meta.len {
+            let end = read.saturating_add(meta.stride).min(meta.len);
+            let Ok(header) = parse_header(&buf[read..end]) else {
+                self.stats.proxy_protocol_invalid_headers.add(1);
+                meta.len = 0;
+                return;
+            };
+            let len = header.as_ref().map_or(0, |header| header.len);
+            if *header_len.get_or_insert(len) != len {
+                self.stats.proxy_protocol_invalid_headers.add(1);
+                meta.len = 0;
+                return;
+            }
+            if let Some(source) = header.and_then(|header| header.source) {
+                if !self.addresses.insert(meta.addr, source) {
+                    self.stats.proxy_protocol_dropped_addresses.add(1);
+                }
+            }
+            buf.copy_within(read + len..end, written);
+            written += end - read - len;
+            read = end;
+        }
+        meta.len = written;
+        meta.stride -= header_len.unwrap_or(0);
+    }
+}
+
+impl fmt::Debug for ProxyProtocolSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyProtocolSocket")
+            .field("inner", &self.inner)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncUdpSocket for ProxyProtocolSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.poll_send(state, cx, transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let count = ready!(self.inner.poll_recv(cx, bufs, meta))?;
+        for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()).take(count) {
+            if self.is_trusted(meta.addr.ip()) {
+                self.strip_headers(buf, meta);
+            }
+        }
+        Poll::Ready(Ok(count))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
+
+/// Builds the PROXY protocol v2 header a balancer relaying the datagrams of `source` prepends.
+#[cfg(test)]
+pub(crate) fn encode_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION << 4 | COMMAND_PROXY);
+    // The transport is UDP in either family.
+    match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            header.extend_from_slice(&[FAMILY_INET << 4 | 2, 0, 12]);
+            header.extend_from_slice(&source.ip().octets());
+            header.extend_from_slice(&destination.ip().octets());
+        }
+        (source, destination) => {
+            let to_v6 = |addr: SocketAddr| match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.extend_from_slice(&[FAMILY_INET6 << 4 | 2, 0, 36]);
+            header.extend_from_slice(&to_v6(source).octets());
+            header.extend_from_slice(&to_v6(destination).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod test {
+    use quinn::Runtime;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let source: SocketAddr = "10.1.2.3:4567".parse().unwrap();
+        let destination: SocketAddr = "192.168.0.1:8009".parse().unwrap();
+        let mut datagram = encode_header(source, destination);
+        let len = datagram.len();
+        datagram.extend_from_slice(b"quic packet");
+        assert_eq!(
+            parse_header(&datagram),
+            Ok(Some(ProxyHeader {
+                len,
+                source: Some(source)
+            }))
+        );
+
+        let source: SocketAddr = "[2001:db8::1]:4567".parse().unwrap();
+        let destination: SocketAddr = "[2001:db8::2]:8009".parse().unwrap();
+        let header = encode_header(source, destination);
+        assert_eq!(
+            parse_header(&header),
+            Ok(Some(ProxyHeader {
+                len: header.len(),
+                source: Some(source)
+            }))
+        );
+
+        // Health checks of the balancer carry no client.
+        let mut local = header.clone();
+        local[12] = VERSION << 4 | COMMAND_LOCAL;
+        assert_eq!(
+            parse_header(&local),
+            Ok(Some(ProxyHeader {
+                len: header.len(),
+                source: None
+            }))
+        );
+
+        assert_eq!(parse_header(&[0xc0, 0, 0, 0, 1]), Ok(None));
+        assert_eq!(parse_header(&header[..20]), Err(InvalidProxyHeader));
+        let mut version_1 = header.clone();
+        version_1[12] = 1 << 4 | COMMAND_PROXY;
+        assert_eq!(parse_header(&version_1), Err(InvalidProxyHeader));
+    }
+
+    #[tokio::test]
+    async fn test_strip_headers() {
+        let proxy: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let client: SocketAddr = "10.1.2.3:4567".parse().unwrap();
+        let header = encode_header(client, "127.0.0.1:8009".parse().unwrap());
+        let socket = ProxyProtocolSocket::new(
+            quinn::TokioRuntime
+                .wrap_udp_socket(std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+                .unwrap(),
+            vec!["127.0.0.0/8".parse().unwrap()],
+            Arc::default(),
+            Arc::default(),
+        );
+        assert!(socket.is_trusted(proxy.ip()));
+        assert!(!socket.is_trusted(client.ip()));
+
+        // Two segments coalesced by GRO, the second one shorter.
+        let mut buf = [&header[..], &[1; 10], &header[..], &[2; 4]].concat();
+        let mut meta = RecvMeta {
+            addr: proxy,
+            len: buf.len(),
+            stride: header.len() + 10,
+            ..RecvMeta::default()
+        };
+        socket.strip_headers(&mut buf, &mut meta);
+        assert_eq!((meta.len, meta.stride), (14, 10));
+        assert_eq!(&buf[..14], &[&[1; 10][..], &[2; 4]].concat()[..]);
+        assert_eq!(socket.addresses.resolve(proxy), client);
+
+        // A datagram without header is left alone.
+        let other: SocketAddr = "127.0.0.2:9000".parse().unwrap();
+        let mut buf = [3; 10];
+        let mut meta = RecvMeta {
+            addr: other,
+            len: 10,
+            stride: 10,
+            ..RecvMeta::default()
+        };
+        socket.strip_headers(&mut buf, &mut meta);
+        assert_eq!((meta.len, meta.stride), (10, 10));
+        assert_eq!(socket.addresses.resolve(other), other);
+
+        let mut buf = header[..20].to_vec();
+        let mut meta = RecvMeta {
+            addr: other,
+            len: buf.len(),
+            stride: buf.len(),
+            ..RecvMeta::default()
+        };
+        socket.strip_headers(&mut buf, &mut meta);
+        assert_eq!(meta.len, 0);
+        assert_eq!(socket.stats.proxy_protocol_invalid_headers.get(), 1);
+    }
+
+    #[test]
+    fn test_proxy_address_table() {
+        let table = ProxyAddressTable::default();
+        let now = Instant::now();
+        let client: SocketAddr = "10.1.2.3:4567".parse().unwrap();
+        let proxy = |i: usize| SocketAddr::new(Ipv4Addr::from(0x7f00_0000 + i as u32).into(), 9000);
+        assert!(table.insert_at(proxy(0), client, now));
+        assert_eq!(table.resolve_at(proxy(0), now), client);
+        // An address past its TTL isn't used, even before it's pruned.
+        assert_eq!(
+            table.resolve_at(proxy(0), now + PROXY_ADDRESS_TTL),
+            proxy(0)
+        );
+
+        // A full table makes room by pruning the addresses past their TTL only.
+        for i in 1..MAX_PROXY_ADDRESSES {
+            assert!(table.insert_at(proxy(i), client, now + PROXY_ADDRESS_TTL / 2));
+        }
+        let later = now + PROXY_ADDRESS_TTL;
+        assert!(table.insert_at(proxy(MAX_PROXY_ADDRESSES), client, later));
+        assert!(!table.insert_at(proxy(MAX_PROXY_ADDRESSES + 1), client, later));
+        // Known balancers are still refreshed.
+        assert!(table.insert_at(proxy(1), client, later));
+    }
+}
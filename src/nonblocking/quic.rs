@@ -1,21 +1,45 @@
 use {
     crate::{
-        nonblocking::stream_throttle::{
-            ConnectionStreamCounter, StakedStreamLoadEMA, STREAM_THROTTLING_INTERVAL,
-            STREAM_THROTTLING_INTERVAL_MS,
+        nonblocking::{
+            ban_list::{BanList, BannedPeer},
+            bandwidth_throttle::{BandwidthThrottle, GlobalBandwidthThrottle},
+            connection_rate_limiter::ConnectionRateLimiter,
+            endpoint_stats::EndpointStatsSocket,
+            peer_stats::{PeerStats, PeerStatsTable},
+            proxy_protocol::{ProxyAddressTable, ProxyProtocolSocket},
+            read_scheduler::{read_weight, FairReadScheduler},
+            socket_rebind::RebindingSocket,
+            stake_tiers::{StakeTierSlot, StakeTiers},
+            stream_throttle::{
+                ConnectionStreamCounter, StakedStreamLoadEMA, STREAM_THROTTLING_INTERVAL,
+                STREAM_THROTTLING_INTERVAL_MS,
+            },
+        },
+        packet_sink::{PacketSink, SinkError},
+        quic::{
+            configure_server, configure_server_with_tls, default_metrics_sink, AdmissionController,
+            AdmissionDecision, BidiStreamHandler, ConnectionContext, ConnectionEventHandler,
+            ConnectionSummary, EvictionPolicy, FullChannelPolicy, MetricsSink, PoolOccupancy,
+            QuicServerConfig, QuicServerError, StakedOverflow, StreamStats,
         },
-        quic::{configure_server, QuicServerError, StreamStats},
+        socket::ServerSocketOptions,
         streamer::StakedNodes,
         tls_certificates::get_pubkey_from_tls_certificate,
     },
+    arc_swap::ArcSwap,
     async_channel::{
         unbounded as async_unbounded, Receiver as AsyncReceiver, Sender as AsyncSender,
     },
     bytes::Bytes,
     crossbeam_channel::Sender,
+    futures_util::future::select_all,
     indexmap::map::{Entry, IndexMap},
+    log::Level,
     percentage::Percentage,
-    quinn::{Connecting, Connection, Endpoint, EndpointConfig, TokioRuntime, VarInt},
+    quinn::{
+        crypto::rustls::HandshakeData, Connecting, Connection, Endpoint, EndpointConfig, Runtime,
+        ServerConfig, TokioRuntime, VarInt,
+    },
     quinn_proto::VarIntBoundsExceeded,
     rand::{thread_rng, Rng},
     smallvec::SmallVec,
@@ -24,20 +48,23 @@ use {
         packet::{Meta, PACKET_DATA_SIZE},
         pubkey::Pubkey,
         quic::{
-            QUIC_CONNECTION_HANDSHAKE_TIMEOUT, QUIC_MAX_STAKED_CONCURRENT_STREAMS,
-            QUIC_MAX_STAKED_RECEIVE_WINDOW_RATIO, QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS,
-            QUIC_MIN_STAKED_CONCURRENT_STREAMS, QUIC_MIN_STAKED_RECEIVE_WINDOW_RATIO,
-            QUIC_TOTAL_STAKED_CONCURRENT_STREAMS, QUIC_UNSTAKED_RECEIVE_WINDOW_RATIO,
+            QUIC_MAX_STAKED_CONCURRENT_STREAMS, QUIC_MAX_STAKED_RECEIVE_WINDOW_RATIO,
+            QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS, QUIC_MIN_STAKED_CONCURRENT_STREAMS,
+            QUIC_MIN_STAKED_RECEIVE_WINDOW_RATIO, QUIC_TOTAL_STAKED_CONCURRENT_STREAMS,
+            QUIC_UNSTAKED_RECEIVE_WINDOW_RATIO,
         },
         signature::Keypair,
         timing,
     },
     std::{
+        collections::HashSet,
+        fmt,
         iter::repeat_with,
         net::{IpAddr, SocketAddr, UdpSocket},
+        ops::{Deref, DerefMut},
         // CAUTION: be careful not to introduce any awaits while holding an RwLock.
         sync::{
-            atomic::{AtomicBool, AtomicU64, Ordering},
+            atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
             Arc, RwLock,
         },
         time::{Duration, Instant},
@@ -52,15 +79,27 @@ use {
         // (i.e. lock order is always async Mutex -> RwLock). Also, be careful not to
         // introduce any other awaits while holding the RwLock.
         select,
-        sync::{Mutex, MutexGuard},
+        sync::{mpsc::Sender as TokioSender, Mutex, MutexGuard},
         task::JoinHandle,
         time::{sleep, timeout},
     },
     tokio_util::sync::CancellationToken,
 };
 
+// Emits a `tracing` event in the span of the connection, or stream, being handled. Expands to
+// nothing without the `tracing` feature.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::$level, $($arg)+);
+    };
+}
+
 pub const DEFAULT_WAIT_FOR_CHUNK_TIMEOUT: Duration = Duration::from_secs(2);
 
+// How often the quinn stats of the live connections are sampled when no report interval is set
+const DEFAULT_CONNECTION_STATS_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
 pub const ALPN_TPU_PROTOCOL_ID: &[u8] = b"solana-tpu";
 
 const CONNECTION_CLOSE_CODE_DROPPED_ENTRY: u32 = 1;
@@ -75,9 +114,32 @@ const CONNECTION_CLOSE_REASON_EXCEED_MAX_STREAM_COUNT: &[u8] = b"exceed_max_stre
 const CONNECTION_CLOSE_CODE_TOO_MANY: u32 = 4;
 const CONNECTION_CLOSE_REASON_TOO_MANY: &[u8] = b"too_many";
 
+const CONNECTION_CLOSE_CODE_RATE_LIMITED: u32 = 5;
+const CONNECTION_CLOSE_REASON_RATE_LIMITED: &[u8] = b"rate_limited";
+
+const CONNECTION_CLOSE_CODE_CLOSED_BY_OPERATOR: u32 = 6;
+const CONNECTION_CLOSE_REASON_CLOSED_BY_OPERATOR: &[u8] = b"closed_by_operator";
+
+const CONNECTION_CLOSE_CODE_BANNED: u32 = 7;
+const CONNECTION_CLOSE_REASON_BANNED: &[u8] = b"banned";
+
+const CONNECTION_CLOSE_CODE_STREAM_TIMEOUTS: u32 = 8;
+const CONNECTION_CLOSE_REASON_STREAM_TIMEOUTS: &[u8] = b"stream_timeouts";
+
+// Sent along with the reason given by the `AdmissionController`
+const CONNECTION_CLOSE_CODE_REJECTED: u32 = 9;
+
+// How often packet_batch_sender checks for the exit while waiting for packets when there's no
+// coalesce window to keep track of
+const UNCOALESCED_EXIT_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
 /// Limit to 250K PPS
 pub const DEFAULT_MAX_STREAMS_PER_MS: u64 = 250;
 
+/// Highest `max_streams_per_ms` the throttling takes, 1B PPS, which keeps the stream limits
+/// derived from it from overflowing.
+pub const MAX_STREAMS_PER_MS_LIMIT: u64 = 1_000_000;
+
 // A sequence of bytes that is part of a packet
 // along with where in the packet it is
 struct PacketChunk {
@@ -100,6 +162,12 @@ struct PacketChunk {
 struct PacketAccumulator {
     pub meta: Meta,
     pub chunks: SmallVec<[PacketChunk; 2]>,
+    // The pubkey from the sender's certificate, if it presented a valid one
+    pub identity: Option<Pubkey>,
+    // The stake the sender was admitted with, 0 if unstaked
+    pub stake: u64,
+    // When the first chunk was read off the stream
+    pub received_at: Instant,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -112,22 +180,259 @@ impl ConnectionPeerType {
     pub(crate) fn is_staked(&self) -> bool {
         matches!(self, ConnectionPeerType::Staked(_))
     }
+
+    fn stake(&self) -> u64 {
+        match self {
+            ConnectionPeerType::Unstaked => 0,
+            ConnectionPeerType::Staked(stake) => *stake,
+        }
+    }
 }
 
 pub struct SpawnNonBlockingServerResult {
-    pub endpoint: Endpoint,
+    pub endpoints: Vec<Endpoint>,
     pub stats: Arc<StreamStats>,
     pub thread: JoinHandle<()>,
     pub max_concurrent_connections: usize,
+    /// Set when [`QuicServerConfig::enable_peer_stats`] is on.
+    pub peer_stats_table: Option<Arc<PeerStatsTable>>,
+    /// Limit on new streams per millisecond the throttling is derived from. It's read for every
+    /// stream, so a new value applies from the next throttle window on. Zero is treated as one,
+    /// and values above [`MAX_STREAMS_PER_MS_LIMIT`] as the limit.
+    pub max_streams_per_ms: Arc<AtomicU64>,
+    pub connection_limits: Arc<ConnectionLimits>,
+    pub connection_control: Arc<ConnectionControl>,
+    /// PEM encoded certificate chain the server presents, e.g. to pin it in clients. `None` for
+    /// an externally constructed TLS configuration, whose chain the caller already has.
+    pub cert_chain_pem: Option<String>,
+}
+
+impl SpawnNonBlockingServerResult {
+    /// Whether the server is still accepting connections: its task is running and at least one
+    /// endpoint is open.
+    pub fn is_healthy(&self) -> bool {
+        !self.thread.is_finished() && self.stats.active_endpoints.get() > 0
+    }
+
+    /// When the last packet was received in full from a peer, `None` until the first one.
+    pub fn last_packet_received(&self) -> Option<Instant> {
+        self.stats.last_packet_received.get()
+    }
+}
+
+/// Identifies the connections to close with [`ConnectionControl::close_connection`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionId {
+    /// All connections of the peer presenting this pubkey in its certificate.
+    Pubkey(Pubkey),
+    /// The connection from this remote address.
+    Address(SocketAddr),
+}
+
+/// Gives access to the live connections of a running server.
+pub struct ConnectionControl {
+    staked_connection_table: Arc<ConnectionTableLock>,
+    unstaked_connection_table: Arc<ConnectionTableLock>,
+    ban_list: Arc<BanList>,
+    protected_peers: HashSet<Pubkey>,
+    staked_overflow: StakedOverflow,
+    admission_controller: Option<Arc<dyn AdmissionController>>,
+    // Set when `QuicServerConfig::proxy_protocol_trusted_proxies` are configured
+    proxy_addresses: Option<Arc<ProxyAddressTable>>,
+}
+
+impl ConnectionControl {
+    fn new(quic_server_config: &QuicServerConfig, stats: &StreamStats) -> Self {
+        let event_handler = quic_server_config.connection_event_handler.clone();
+        let stake_tiers = (!quic_server_config.stake_tiers.is_empty())
+            .then(|| Arc::new(StakeTiers::new(quic_server_config.stake_tiers.clone())));
+        if let Some(stake_tiers) = &stake_tiers {
+            let _ = stats.stake_tiers.set(stake_tiers.clone());
+        }
+        Self {
+            staked_connection_table: Arc::new(ConnectionTableLock::new(
+                ConnectionTable::new(event_handler.clone())
+                    .with_stake_tiers(stake_tiers)
+                    .with_eviction_policy(quic_server_config.eviction_policy),
+            )),
+            unstaked_connection_table: Arc::new(ConnectionTableLock::new(ConnectionTable::new(
+                event_handler,
+            ))),
+            ban_list: Arc::default(),
+            protected_peers: quic_server_config.protected_peers.clone(),
+            staked_overflow: quic_server_config.staked_overflow,
+            admission_controller: quic_server_config.admission_controller.clone(),
+            proxy_addresses: quic_server_config
+                .proxy_protocol_trusted_proxies
+                .is_some()
+                .then(Arc::default),
+        }
+    }
+
+    // The address of the client behind a connection from `remote_address`, which differs
+    // when a trusted proxy relayed it.
+    fn peer_address(&self, remote_address: SocketAddr) -> SocketAddr {
+        self.proxy_addresses
+            .as_ref()
+            .map_or(remote_address, |addresses| {
+                addresses.resolve(remote_address)
+            })
+    }
+
+    /// Closes the connections identified by `peer`, returns whether any was found. The
+    /// connections leave the tables, and the disconnect is reported, once their handlers
+    /// notice the close.
+    pub async fn close_connection(&self, peer: ConnectionId) -> bool {
+        let matches = |connection: &Connection, remote_addr| peer.matches(connection, remote_addr);
+        let closed_staked = self.staked_connection_table.lock().await.close(matches);
+        let closed_unstaked = self.unstaked_connection_table.lock().await.close(matches);
+        closed_staked || closed_unstaked
+    }
+
+    /// Same as [`Self::close_connection`] for callers outside of an async context, panics when
+    /// called from within one.
+    pub fn blocking_close_connection(&self, peer: ConnectionId) -> bool {
+        let matches = |connection: &Connection, remote_addr| peer.matches(connection, remote_addr);
+        let closed_staked = self.staked_connection_table.blocking_lock().close(matches);
+        let closed_unstaked = self
+            .unstaked_connection_table
+            .blocking_lock()
+            .close(matches);
+        closed_staked || closed_unstaked
+    }
+
+    /// Closes the connections of `peer` and rejects its new ones for `duration`, counted in
+    /// `connection_rejected_banned`. Banning a peer again replaces its expiry.
+    pub async fn ban(&self, peer: BannedPeer, duration: Duration) {
+        self.ban_list.ban(peer, duration);
+        let matches = |connection: &Connection, remote_addr| peer.matches(connection, remote_addr);
+        self.staked_connection_table.lock().await.close(matches);
+        self.unstaked_connection_table.lock().await.close(matches);
+    }
+
+    /// Same as [`Self::ban`] for callers outside of an async context, panics when called from
+    /// within one.
+    pub fn blocking_ban(&self, peer: BannedPeer, duration: Duration) {
+        self.ban_list.ban(peer, duration);
+        let matches = |connection: &Connection, remote_addr| peer.matches(connection, remote_addr);
+        self.staked_connection_table.blocking_lock().close(matches);
+        self.unstaked_connection_table
+            .blocking_lock()
+            .close(matches);
+    }
+
+    /// Lifts the ban of `peer`, returns whether it was banned.
+    pub fn unban(&self, peer: BannedPeer) -> bool {
+        self.ban_list.unban(peer)
+    }
+}
+
+impl ConnectionId {
+    fn matches(&self, connection: &Connection, remote_addr: SocketAddr) -> bool {
+        match *self {
+            ConnectionId::Pubkey(pubkey) => get_remote_pubkey(connection) == Some(pubkey),
+            ConnectionId::Address(address) => {
+                remote_addr.ip().to_canonical() == address.ip().to_canonical()
+                    && remote_addr.port() == address.port()
+            }
+        }
+    }
+}
+
+impl BannedPeer {
+    fn matches(&self, connection: &Connection, remote_addr: SocketAddr) -> bool {
+        match *self {
+            BannedPeer::Pubkey(pubkey) => get_remote_pubkey(connection) == Some(pubkey),
+            BannedPeer::Ip(ip) => remote_addr.ip().to_canonical() == ip.to_canonical(),
+        }
+    }
+}
+
+/// Connection caps of a running server.
+///
+/// Raising a cap admits new connections right away. Lowering it below the current number of
+/// connections evicts the oldest connections of that kind down to the new cap within a second.
+/// The number of connections quinn accepts concurrently is derived from the caps at spawn time,
+/// see [`SpawnNonBlockingServerResult::max_concurrent_connections`], and isn't raised with them.
+///
+/// A zero unstaked cap refuses unstaked peers outright, and leaves staked peers that don't fit
+/// in the staked table nowhere to fall back to. The stake of a peer is only known from the
+/// certificate it presents, so an unstaked peer is refused once its handshake completed: it
+/// still costs the server a key exchange and a signature, but never a connection slot. Only
+/// when no peer has stake and there are no protected peers is every connection refused before
+/// its handshake.
+#[derive(Debug)]
+pub struct ConnectionLimits {
+    max_staked_connections: AtomicUsize,
+    max_unstaked_connections: AtomicUsize,
+    // Set when a cap was changed so the server prunes its tables to the new caps.
+    updated: AtomicBool,
+}
+
+impl ConnectionLimits {
+    pub fn new(max_staked_connections: usize, max_unstaked_connections: usize) -> Self {
+        Self {
+            max_staked_connections: AtomicUsize::new(max_staked_connections),
+            max_unstaked_connections: AtomicUsize::new(max_unstaked_connections),
+            updated: AtomicBool::default(),
+        }
+    }
+
+    pub fn max_staked_connections(&self) -> usize {
+        self.max_staked_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn max_unstaked_connections(&self) -> usize {
+        self.max_unstaked_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_staked_connections(&self, max_staked_connections: usize) {
+        self.max_staked_connections
+            .store(max_staked_connections, Ordering::Relaxed);
+        self.updated.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_max_unstaked_connections(&self, max_unstaked_connections: usize) {
+        self.max_unstaked_connections
+            .store(max_unstaked_connections, Ordering::Relaxed);
+        self.updated.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Where the server looks up the stake of new connections.
+///
+/// Publishing a new snapshot into [`StakedNodesSource::Swappable`] never blocks the connection
+/// handlers, while updating [`StakedNodesSource::Locked`] makes them wait for the writer.
+#[derive(Clone)]
+pub enum StakedNodesSource {
+    Locked(Arc<RwLock<StakedNodes>>),
+    Swappable(Arc<ArcSwap<StakedNodes>>),
+}
+
+impl From<Arc<RwLock<StakedNodes>>> for StakedNodesSource {
+    fn from(staked_nodes: Arc<RwLock<StakedNodes>>) -> Self {
+        Self::Locked(staked_nodes)
+    }
+}
+
+impl From<Arc<ArcSwap<StakedNodes>>> for StakedNodesSource {
+    fn from(staked_nodes: Arc<ArcSwap<StakedNodes>>) -> Self {
+        Self::Swappable(staked_nodes)
+    }
 }
 
+/// Spawns a server on `sock`, which may be bound to an IPv4 or IPv6 address. Dual-stack IPv6
+/// sockets, e.g. bound to `[::]` without `IPV6_V6ONLY`, are supported: their IPv4 peers show
+/// up as IPv4-mapped IPv6 addresses and are treated as the IPv4 address in the packet metadata,
+/// the connection limits and the IP filters. `gossip_host`, of either family, is the subject
+/// alternative name of the self-signed certificate.
 #[allow(clippy::too_many_arguments)]
-pub fn spawn_server(
+pub fn spawn_server<S: PacketSink>(
     name: &'static str,
     sock: UdpSocket,
     keypair: &Keypair,
     gossip_host: IpAddr,
-    packet_sender: Sender<PacketBatch>,
+    packet_sender: S,
     exit: Arc<AtomicBool>,
     max_connections_per_peer: usize,
     staked_nodes: Arc<RwLock<StakedNodes>>,
@@ -136,24 +441,13 @@ pub fn spawn_server(
     max_streams_per_ms: u64,
     wait_for_chunk_timeout: Duration,
     coalesce: Duration,
+    quic_server_config: QuicServerConfig,
 ) -> Result<SpawnNonBlockingServerResult, QuicServerError> {
-    info!("Start {name} quic server on {sock:?}");
-    let concurrent_connections = max_staked_connections + max_unstaked_connections;
-    let max_concurrent_connections = concurrent_connections + concurrent_connections / 4;
-    let (config, _cert) = configure_server(keypair, gossip_host, max_concurrent_connections)?;
-
-    let endpoint = Endpoint::new(
-        EndpointConfig::default(),
-        Some(config),
-        sock,
-        Arc::new(TokioRuntime),
-    )
-    .map_err(QuicServerError::EndpointFailed)?;
-
-    let stats = Arc::<StreamStats>::default();
-    let handle = tokio::spawn(run_server(
+    spawn_server_multi(
         name,
-        endpoint.clone(),
+        vec![sock],
+        keypair,
+        gossip_host,
         packet_sender,
         exit,
         max_connections_per_peer,
@@ -161,127 +455,809 @@ pub fn spawn_server(
         max_staked_connections,
         max_unstaked_connections,
         max_streams_per_ms,
-        stats.clone(),
         wait_for_chunk_timeout,
         coalesce,
-    ));
-    Ok(SpawnNonBlockingServerResult {
-        endpoint,
-        stats,
-        thread: handle,
-        max_concurrent_connections,
-    })
+        quic_server_config,
+    )
 }
 
+/// Spawns a server with one endpoint per socket. The sockets are expected to be bound to the
+/// same address with SO_REUSEPORT so that the kernel spreads incoming packets across them. All
+/// endpoints share the same connection tables, stats and packet sender.
 #[allow(clippy::too_many_arguments)]
-async fn run_server(
+pub fn spawn_server_multi<S: PacketSink>(
     name: &'static str,
-    incoming: Endpoint,
-    packet_sender: Sender<PacketBatch>,
+    sockets: Vec<UdpSocket>,
+    keypair: &Keypair,
+    gossip_host: IpAddr,
+    packet_sender: S,
     exit: Arc<AtomicBool>,
     max_connections_per_peer: usize,
     staked_nodes: Arc<RwLock<StakedNodes>>,
     max_staked_connections: usize,
     max_unstaked_connections: usize,
     max_streams_per_ms: u64,
-    stats: Arc<StreamStats>,
     wait_for_chunk_timeout: Duration,
     coalesce: Duration,
-) {
-    const WAIT_FOR_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
-    debug!("spawn quic server");
-    let mut last_datapoint = Instant::now();
-    let unstaked_connection_table: Arc<Mutex<ConnectionTable>> =
-        Arc::new(Mutex::new(ConnectionTable::new()));
-    let stream_load_ema = Arc::new(StakedStreamLoadEMA::new(
-        stats.clone(),
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnNonBlockingServerResult, QuicServerError> {
+    spawn_server_with_keypair(
+        name,
+        sockets,
+        keypair,
+        gossip_host,
+        PacketBatchConsumer::Sink(Arc::new(packet_sender)),
+        exit,
+        max_connections_per_peer,
+        staked_nodes.into(),
+        max_staked_connections,
         max_unstaked_connections,
         max_streams_per_ms,
-    ));
-    let staked_connection_table: Arc<Mutex<ConnectionTable>> =
-        Arc::new(Mutex::new(ConnectionTable::new()));
-    let (sender, receiver) = async_unbounded();
-    tokio::spawn(packet_batch_sender(
-        packet_sender,
-        receiver,
-        exit.clone(),
-        stats.clone(),
+        wait_for_chunk_timeout,
         coalesce,
-    ));
-    while !exit.load(Ordering::Relaxed) {
-        let timeout_connection = timeout(WAIT_FOR_CONNECTION_TIMEOUT, incoming.accept()).await;
-
-        if last_datapoint.elapsed().as_secs() >= 5 {
-            stats.report(name);
-            last_datapoint = Instant::now();
-        }
-
-        if let Ok(Some(connection)) = timeout_connection {
-            info!("Got a connection {:?}", connection.remote_address());
-            tokio::spawn(setup_connection(
-                connection,
-                unstaked_connection_table.clone(),
-                staked_connection_table.clone(),
-                sender.clone(),
-                max_connections_per_peer,
-                staked_nodes.clone(),
-                max_staked_connections,
-                max_unstaked_connections,
-                max_streams_per_ms,
-                stats.clone(),
-                wait_for_chunk_timeout,
-                stream_load_ema.clone(),
-            ));
-        } else {
-            debug!("accept(): Timed out waiting for connection");
-        }
-    }
+        quic_server_config,
+    )
 }
 
-fn prune_unstaked_connection_table(
-    unstaked_connection_table: &mut ConnectionTable,
+/// Same as [`spawn_server_multi`], but delivers the packet batches to a tokio channel. When the
+/// channel is full the batcher awaits capacity instead of blocking a runtime worker.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_server_with_async_sender(
+    name: &'static str,
+    sockets: Vec<UdpSocket>,
+    keypair: &Keypair,
+    gossip_host: IpAddr,
+    packet_sender: TokioSender<PacketBatch>,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: Arc<RwLock<StakedNodes>>,
+    max_staked_connections: usize,
     max_unstaked_connections: usize,
-    stats: Arc<StreamStats>,
-) {
-    if unstaked_connection_table.total_size >= max_unstaked_connections {
-        const PRUNE_TABLE_TO_PERCENTAGE: u8 = 90;
-        let max_percentage_full = Percentage::from(PRUNE_TABLE_TO_PERCENTAGE);
+    max_streams_per_ms: u64,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnNonBlockingServerResult, QuicServerError> {
+    spawn_server_with_keypair(
+        name,
+        sockets,
+        keypair,
+        gossip_host,
+        PacketBatchConsumer::Tokio(packet_sender),
+        exit,
+        max_connections_per_peer,
+        staked_nodes.into(),
+        max_staked_connections,
+        max_unstaked_connections,
+        max_streams_per_ms,
+        wait_for_chunk_timeout,
+        coalesce,
+        quic_server_config,
+    )
+}
 
-        let max_connections = max_percentage_full.apply_to(max_unstaked_connections);
-        let num_pruned = unstaked_connection_table.prune_oldest(max_connections);
-        stats.num_evictions.fetch_add(num_pruned, Ordering::Relaxed);
-    }
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spawn_server_with_keypair(
+    name: &'static str,
+    sockets: Vec<UdpSocket>,
+    keypair: &Keypair,
+    gossip_host: IpAddr,
+    packet_sender: PacketBatchConsumer,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: StakedNodesSource,
+    max_staked_connections: usize,
+    max_unstaked_connections: usize,
+    max_streams_per_ms: u64,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnNonBlockingServerResult, QuicServerError> {
+    info!("Start {name} quic server on {sockets:?}");
+    let max_concurrent_connections = validate_server_args(
+        max_connections_per_peer,
+        max_staked_connections,
+        max_unstaked_connections,
+        max_streams_per_ms,
+    )?;
+    let stats = Arc::<StreamStats>::default();
+    let (config, cert_chain_pem) = configure_server(
+        keypair,
+        gossip_host,
+        max_concurrent_connections,
+        &quic_server_config,
+        &stats,
+    )?;
+    let mut result = spawn_server_with_config(
+        name,
+        sockets,
+        config,
+        stats,
+        max_concurrent_connections,
+        packet_sender,
+        exit,
+        max_connections_per_peer,
+        staked_nodes,
+        max_staked_connections,
+        max_unstaked_connections,
+        max_streams_per_ms,
+        wait_for_chunk_timeout,
+        coalesce,
+        quic_server_config,
+    )?;
+    result.cert_chain_pem = Some(cert_chain_pem);
+    Ok(result)
 }
 
-pub fn get_remote_pubkey(connection: &Connection) -> Option<Pubkey> {
-    // Use the client cert only if it is self signed and the chain length is 1.
-    connection
-        .peer_identity()?
-        .downcast::<Vec<rustls::Certificate>>()
-        .ok()
-        .filter(|certs| certs.len() == 1)?
-        .first()
-        .and_then(get_pubkey_from_tls_certificate)
+/// Spawns a server presenting `tls_config` instead of a self-signed certificate derived from a
+/// keypair, see [`spawn_server_multi`].
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_server_with_tls<S: PacketSink>(
+    name: &'static str,
+    sockets: Vec<UdpSocket>,
+    tls_config: Arc<rustls::ServerConfig>,
+    packet_sender: S,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: Arc<RwLock<StakedNodes>>,
+    max_staked_connections: usize,
+    max_unstaked_connections: usize,
+    max_streams_per_ms: u64,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnNonBlockingServerResult, QuicServerError> {
+    spawn_server_with_tls_config(
+        name,
+        sockets,
+        tls_config,
+        PacketBatchConsumer::Sink(Arc::new(packet_sender)),
+        exit,
+        max_connections_per_peer,
+        staked_nodes.into(),
+        max_staked_connections,
+        max_unstaked_connections,
+        max_streams_per_ms,
+        wait_for_chunk_timeout,
+        coalesce,
+        quic_server_config,
+    )
 }
 
-fn get_connection_stake(
-    connection: &Connection,
-    staked_nodes: &RwLock<StakedNodes>,
-) -> Option<(Pubkey, u64, u64, u64, u64)> {
-    let pubkey = get_remote_pubkey(connection)?;
-    debug!("Peer public key is {pubkey:?}");
-    let staked_nodes = staked_nodes.read().unwrap();
-    Some((
-        pubkey,
-        staked_nodes.get_node_stake(&pubkey)?,
-        staked_nodes.total_stake(),
-        staked_nodes.max_stake(),
-        staked_nodes.min_stake(),
-    ))
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spawn_server_with_tls_config(
+    name: &'static str,
+    sockets: Vec<UdpSocket>,
+    tls_config: Arc<rustls::ServerConfig>,
+    packet_sender: PacketBatchConsumer,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: StakedNodesSource,
+    max_staked_connections: usize,
+    max_unstaked_connections: usize,
+    max_streams_per_ms: u64,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnNonBlockingServerResult, QuicServerError> {
+    info!("Start {name} quic server with external TLS config on {sockets:?}");
+    let max_concurrent_connections = validate_server_args(
+        max_connections_per_peer,
+        max_staked_connections,
+        max_unstaked_connections,
+        max_streams_per_ms,
+    )?;
+    let stats = Arc::<StreamStats>::default();
+    let config = configure_server_with_tls(
+        tls_config,
+        max_concurrent_connections,
+        &quic_server_config,
+        &stats,
+    )?;
+    spawn_server_with_config(
+        name,
+        sockets,
+        config,
+        stats,
+        max_concurrent_connections,
+        packet_sender,
+        exit,
+        max_connections_per_peer,
+        staked_nodes,
+        max_staked_connections,
+        max_unstaked_connections,
+        max_streams_per_ms,
+        wait_for_chunk_timeout,
+        coalesce,
+        quic_server_config,
+    )
 }
 
-pub fn compute_max_allowed_uni_streams(peer_type: ConnectionPeerType, total_stake: u64) -> usize {
-    match peer_type {
+// Validates the arguments the spawn functions take besides the QuicServerConfig, returns the
+// limit on concurrent connections of the endpoints, which leaves room for the handshakes of
+// connections that end up pruned or rejected.
+fn validate_server_args(
+    max_connections_per_peer: usize,
+    max_staked_connections: usize,
+    max_unstaked_connections: usize,
+    max_streams_per_ms: u64,
+) -> Result<usize, QuicServerError> {
+    if max_connections_per_peer == 0 {
+        return Err(QuicServerError::InvalidConfig(
+            "max_connections_per_peer must not be zero".to_string(),
+        ));
+    }
+    if max_streams_per_ms == 0 || max_streams_per_ms > MAX_STREAMS_PER_MS_LIMIT {
+        return Err(QuicServerError::InvalidConfig(format!(
+            "max_streams_per_ms ({max_streams_per_ms}) must be non-zero and at most \
+             {MAX_STREAMS_PER_MS_LIMIT}"
+        )));
+    }
+    max_staked_connections
+        .checked_add(max_unstaked_connections)
+        .and_then(|connections| connections.checked_add(connections / 4))
+        .filter(|&connections| u32::try_from(connections).is_ok())
+        .ok_or_else(|| {
+            QuicServerError::InvalidConfig(format!(
+                "max_staked_connections ({max_staked_connections}) + max_unstaked_connections \
+                 ({max_unstaked_connections}) exceeds the concurrent connections an endpoint \
+                 supports"
+            ))
+        })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_server_with_config(
+    name: &'static str,
+    sockets: Vec<UdpSocket>,
+    config: ServerConfig,
+    stats: Arc<StreamStats>,
+    max_concurrent_connections: usize,
+    packet_sender: PacketBatchConsumer,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: StakedNodesSource,
+    max_staked_connections: usize,
+    max_unstaked_connections: usize,
+    max_streams_per_ms: u64,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnNonBlockingServerResult, QuicServerError> {
+    if sockets.is_empty() {
+        return Err(QuicServerError::InvalidConfig(
+            "at least one socket is required".to_string(),
+        ));
+    }
+    let connection_control = Arc::new(ConnectionControl::new(&quic_server_config, &stats));
+    let endpoints = sockets
+        .into_iter()
+        .map(|sock| {
+            let addr = sock.local_addr().ok();
+            let options = ServerSocketOptions::of(&sock);
+            let runtime = Arc::new(TokioRuntime);
+            runtime
+                .wrap_udp_socket(sock)
+                .and_then(|sock| {
+                    let sock = match quic_server_config.socket_rebind {
+                        Some(socket_rebind) => Box::new(RebindingSocket::new(
+                            sock,
+                            options?,
+                            socket_rebind,
+                            stats.clone(),
+                        )?),
+                        None => sock,
+                    };
+                    let sock = match (
+                        &quic_server_config.proxy_protocol_trusted_proxies,
+                        &connection_control.proxy_addresses,
+                    ) {
+                        (Some(trusted_proxies), Some(proxy_addresses)) => {
+                            Box::new(ProxyProtocolSocket::new(
+                                sock,
+                                trusted_proxies.clone(),
+                                proxy_addresses.clone(),
+                                stats.clone(),
+                            ))
+                        }
+                        _ => sock,
+                    };
+                    Endpoint::new_with_abstract_socket(
+                        EndpointConfig::default(),
+                        Some(config.clone()),
+                        EndpointStatsSocket::new(sock, stats.clone()),
+                        runtime,
+                    )
+                })
+                .map_err(|err| QuicServerError::endpoint_failed(addr, err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    stats.active_endpoints.set(endpoints.len());
+
+    let peer_stats_table = quic_server_config
+        .enable_peer_stats
+        .then(Arc::<PeerStatsTable>::default);
+    let max_streams_per_ms = Arc::new(AtomicU64::new(max_streams_per_ms));
+    let connection_limits = Arc::new(ConnectionLimits::new(
+        max_staked_connections,
+        max_unstaked_connections,
+    ));
+    let handle = tokio::spawn(run_server(
+        name,
+        endpoints.clone(),
+        packet_sender,
+        exit,
+        max_connections_per_peer,
+        staked_nodes,
+        connection_limits.clone(),
+        connection_control.clone(),
+        max_streams_per_ms.clone(),
+        stats.clone(),
+        peer_stats_table.clone(),
+        wait_for_chunk_timeout,
+        coalesce,
+        quic_server_config,
+    ));
+    Ok(SpawnNonBlockingServerResult {
+        endpoints,
+        stats,
+        thread: handle,
+        max_concurrent_connections,
+        peer_stats_table,
+        max_streams_per_ms,
+        connection_limits,
+        connection_control,
+        cert_chain_pem: None,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_server(
+    name: &'static str,
+    mut incoming: Vec<Endpoint>,
+    packet_sender: PacketBatchConsumer,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: StakedNodesSource,
+    connection_limits: Arc<ConnectionLimits>,
+    connection_control: Arc<ConnectionControl>,
+    max_streams_per_ms: Arc<AtomicU64>,
+    stats: Arc<StreamStats>,
+    peer_stats_table: Option<Arc<PeerStatsTable>>,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) {
+    const WAIT_FOR_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+    debug!("spawn quic server");
+    let mut last_datapoint = Instant::now();
+    let mut last_connection_stats_sample = Instant::now();
+    let connection_stats_sample_interval = quic_server_config
+        .report_interval
+        .unwrap_or(DEFAULT_CONNECTION_STATS_SAMPLE_INTERVAL);
+    let metrics_sink = quic_server_config
+        .metrics_sink
+        .clone()
+        .unwrap_or_else(default_metrics_sink);
+    if let Some(report_interval) = quic_server_config.report_interval {
+        spawn_stats_reporter(
+            name,
+            stats.clone(),
+            metrics_sink.clone(),
+            exit.clone(),
+            report_interval,
+        );
+    }
+    let event_handler = quic_server_config.connection_event_handler.clone();
+    let connection_rate_limiter = quic_server_config
+        .unstaked_connection_rate_limit
+        .map(|limit| Arc::new(ConnectionRateLimiter::new(limit)));
+    let unstaked_connection_table = connection_control.unstaked_connection_table.clone();
+    let staked_connection_table = connection_control.staked_connection_table.clone();
+    let global_bandwidth_throttle = quic_server_config.max_bandwidth.map(|bytes_per_second| {
+        Arc::new(GlobalBandwidthThrottle::new(
+            bytes_per_second,
+            quic_server_config.staked_bandwidth_reserve_percent,
+        ))
+    });
+    let read_scheduler = quic_server_config
+        .max_concurrent_reads
+        .map(|max_concurrent_reads| Arc::new(FairReadScheduler::new(max_concurrent_reads)));
+    let stream_load_ema = Arc::new(StakedStreamLoadEMA::new(
+        stats.clone(),
+        connection_limits.clone(),
+        max_streams_per_ms,
+        &quic_server_config.stream_load_ema,
+    ));
+    let batch_limits = BatchLimits {
+        max_packets: quic_server_config
+            .coalesce_max_packets
+            .map_or(PACKETS_PER_BATCH, |max_packets| {
+                max_packets.min(PACKETS_PER_BATCH)
+            }),
+        max_bytes: quic_server_config.coalesce_max_bytes,
+    };
+    let (sender, receiver) = async_unbounded();
+    let mut batchers = vec![tokio::spawn(packet_batch_sender(
+        packet_sender,
+        SideChannels {
+            identity_sender: quic_server_config.identity_sender.clone(),
+            stake_sender: quic_server_config.stake_sender.clone(),
+            receive_time_sender: quic_server_config.receive_time_sender.clone(),
+        },
+        receiver,
+        exit.clone(),
+        stats.clone(),
+        coalesce,
+        batch_limits,
+        quic_server_config.full_channel_policy,
+        true,
+        Some(stream_load_ema.clone()),
+    ))];
+    let staked_sender = quic_server_config
+        .staked_packet_sink
+        .clone()
+        .map(|staked_packet_sink| {
+            let (staked_sender, staked_receiver) = async_unbounded();
+            batchers.push(tokio::spawn(packet_batch_sender(
+                PacketBatchConsumer::Sink(staked_packet_sink),
+                SideChannels::default(),
+                staked_receiver,
+                exit.clone(),
+                stats.clone(),
+                coalesce,
+                batch_limits,
+                quic_server_config.full_channel_policy,
+                false,
+                None,
+            )));
+            staked_sender
+        });
+    let context = Arc::new(ServerContext {
+        unstaked_connection_table: unstaked_connection_table.clone(),
+        staked_connection_table: staked_connection_table.clone(),
+        packet_sender: sender,
+        staked_packet_sender: staked_sender,
+        max_connections_per_peer,
+        connections_per_peer_limits: PeerTypeLimits::connections_per_peer(&quic_server_config),
+        uni_stream_limits: PeerTypeLimits::uni_streams(&quic_server_config),
+        bandwidth_limits: PeerTypeLimits::bandwidth(&quic_server_config),
+        global_bandwidth_throttle,
+        read_scheduler,
+        max_stream_timeouts_before_close: quic_server_config.max_stream_timeouts_before_close,
+        stream_idle_timeout: quic_server_config.stream_idle_timeout,
+        chunk_limits: PeerTypeLimits::chunks_per_stream(&quic_server_config),
+        receive_window_base: quic_server_config.receive_window_base().into(),
+        staked_nodes,
+        connection_limits: connection_limits.clone(),
+        stats: stats.clone(),
+        peer_stats_table,
+        event_handler,
+        bidi_stream_handler: quic_server_config.bidi_stream_handler.clone(),
+        datagram_sender: quic_server_config.datagram_sender.clone(),
+        connection_rate_limiter,
+        connection_control: connection_control.clone(),
+        connection_setup_timeout: quic_server_config.connection_setup_timeout,
+        wait_for_chunk_timeout,
+        stream_load_ema,
+    });
+    while !exit.load(Ordering::Relaxed) {
+        let timeout_connection = if incoming.is_empty() {
+            sleep(WAIT_FOR_CONNECTION_TIMEOUT).await;
+            None
+        } else {
+            let accepts = incoming.iter().map(|endpoint| Box::pin(endpoint.accept()));
+            timeout(WAIT_FOR_CONNECTION_TIMEOUT, select_all(accepts))
+                .await
+                .ok()
+                .map(|(connecting, index, _)| (connecting, index))
+        };
+
+        if last_connection_stats_sample.elapsed() >= connection_stats_sample_interval {
+            sample_connection_stats(
+                &connection_control,
+                &stats,
+                last_connection_stats_sample.elapsed(),
+            )
+            .await;
+            last_connection_stats_sample = Instant::now();
+        }
+        if quic_server_config.report_interval.is_none() && last_datapoint.elapsed().as_secs() >= 5 {
+            stats.report_to(name, metrics_sink.as_ref());
+            last_datapoint = Instant::now();
+        }
+
+        if let Some((None, index)) = timeout_connection {
+            // The endpoint stopped, e.g. its socket failed and couldn't be rebound, and would
+            // keep resolving accept() right away.
+            if !exit.load(Ordering::Relaxed) {
+                let endpoint = incoming.swap_remove(index);
+                stats.active_endpoints.sub(1);
+                warn!(
+                    "Endpoint {:?} stopped accepting connections",
+                    endpoint.local_addr()
+                );
+            }
+        } else if let Some((Some(connection), _)) = timeout_connection {
+            let remote_addr = connection_control.peer_address(connection.remote_address());
+            if !quic_server_config.is_ip_allowed(remote_addr.ip()) {
+                // Dropping the connection before the handshake completes closes it.
+                debug!("Rejected connection from {remote_addr:?} by ip filter");
+                stats.connection_rejected_by_filter.add(1);
+                continue;
+            }
+            if connection_control
+                .ban_list
+                .is_banned(BannedPeer::Ip(remote_addr.ip()))
+            {
+                debug!("Rejected connection from banned {remote_addr:?}");
+                stats.connection_rejected_banned.add(1);
+                continue;
+            }
+            // Every peer would be unstaked, so there's no point in waiting for its certificate.
+            if connection_limits.max_unstaked_connections() == 0
+                && connection_control.protected_peers.is_empty()
+                && !has_stake(&context.staked_nodes)
+            {
+                debug!("Rejected unstaked connection from {remote_addr:?}");
+                stats.connection_rejected_unstaked_disabled.add(1);
+                continue;
+            }
+            info!("Got a connection {:?}", remote_addr);
+            tokio::spawn(setup_connection(connection, context.clone()));
+        } else {
+            debug!("accept(): Timed out waiting for connection");
+        }
+
+        if connection_limits.updated.swap(false, Ordering::Relaxed) {
+            prune_connection_tables_to_limits(
+                &staked_connection_table,
+                &unstaked_connection_table,
+                &connection_limits,
+                &stats,
+            )
+            .await;
+        }
+    }
+    // Wait for the batchers to flush the packets they have already received.
+    for batcher in batchers {
+        if let Err(e) = batcher.await {
+            warn!("packet batch sender failed: {e:?}");
+        }
+    }
+}
+
+fn spawn_stats_reporter(
+    name: &'static str,
+    stats: Arc<StreamStats>,
+    metrics_sink: Arc<dyn MetricsSink>,
+    exit: Arc<AtomicBool>,
+    report_interval: Duration,
+) {
+    // Sleep in short steps so that the thread notices the exit soon after it's set.
+    const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+    std::thread::Builder::new()
+        .name("solQuicReport".into())
+        .spawn(move || {
+            let mut last_report = Instant::now();
+            while !exit.load(Ordering::Relaxed) {
+                std::thread::sleep(EXIT_POLL_INTERVAL.min(report_interval));
+                if last_report.elapsed() >= report_interval {
+                    stats.report_to(name, metrics_sink.as_ref());
+                    last_report = Instant::now();
+                }
+            }
+        })
+        .unwrap();
+}
+
+fn prune_unstaked_connection_table(
+    unstaked_connection_table: &mut ConnectionTable,
+    max_unstaked_connections: usize,
+    stats: Arc<StreamStats>,
+    evicted_for: EvictedFor,
+) {
+    if unstaked_connection_table.total_size >= max_unstaked_connections {
+        const PRUNE_TABLE_TO_PERCENTAGE: u8 = 90;
+        let max_percentage_full = Percentage::from(PRUNE_TABLE_TO_PERCENTAGE);
+
+        let max_connections = max_percentage_full.apply_to(max_unstaked_connections);
+        let num_pruned = unstaked_connection_table.prune_oldest(max_connections, evicted_for);
+        stats.num_evictions.add(num_pruned);
+    }
+}
+
+/// Folds the quinn path stats of the live connections into the `sampled_connections`,
+/// `connection_rtt_*_us` and `connection_lost_packets` gauges, and the packets they had sent for
+/// batching over the `elapsed` since the last sample into the `connection_packets_per_second_*`
+/// gauges.
+async fn sample_connection_stats(
+    connection_control: &ConnectionControl,
+    stats: &StreamStats,
+    elapsed: Duration,
+) {
+    let mut num_connections = 0;
+    let mut min_rtt = Duration::MAX;
+    let mut total_rtt = Duration::ZERO;
+    let mut max_rtt = Duration::ZERO;
+    let mut lost_packets = 0;
+    let mut max_packets = 0;
+    let mut min_packets = u64::MAX;
+    for (connection_table, min_limit_gauge, max_limit_gauge) in [
+        (
+            &connection_control.staked_connection_table,
+            &stats.staked_uni_streams_limit_min,
+            &stats.staked_uni_streams_limit_max,
+        ),
+        (
+            &connection_control.unstaked_connection_table,
+            &stats.unstaked_uni_streams_limit_min,
+            &stats.unstaked_uni_streams_limit_max,
+        ),
+    ] {
+        // Sampled once the table is unlocked, the quinn stats lock every connection in turn.
+        let (connections, connection_counters): (Vec<_>, Vec<_>) = {
+            let connection_table = connection_table.lock().await;
+            (
+                connection_table
+                    .connections()
+                    .map(|(connection, _)| connection.clone())
+                    .collect(),
+                connection_table.connection_counters().cloned().collect(),
+            )
+        };
+        for connection in connections {
+            let path_stats = connection.stats().path;
+            num_connections += 1;
+            min_rtt = min_rtt.min(path_stats.rtt);
+            total_rtt = total_rtt.saturating_add(path_stats.rtt);
+            max_rtt = max_rtt.max(path_stats.rtt);
+            lost_packets += path_stats.lost_packets as usize;
+        }
+        let mut min_limit = u64::MAX;
+        let mut max_limit = 0;
+        for counters in connection_counters {
+            let limit = counters.uni_streams_limit.load(Ordering::Relaxed);
+            min_limit = min_limit.min(limit);
+            max_limit = max_limit.max(limit);
+            let packets = counters.packets.load(Ordering::Relaxed);
+            let sampled_packets = counters.sampled_packets.swap(packets, Ordering::Relaxed);
+            let packets = packets.saturating_sub(sampled_packets);
+            if packets > 0 {
+                max_packets = max_packets.max(packets);
+                min_packets = min_packets.min(packets);
+            }
+        }
+        min_limit_gauge.set(min_limit.min(max_limit) as usize);
+        max_limit_gauge.set(max_limit as usize);
+    }
+    let packets_per_second = |packets: u64| {
+        if elapsed.is_zero() {
+            0
+        } else {
+            (packets as f64 / elapsed.as_secs_f64()) as usize
+        }
+    };
+    let (min_packets, max_packets) = if max_packets == 0 {
+        (0, 0)
+    } else {
+        (
+            packets_per_second(min_packets),
+            packets_per_second(max_packets),
+        )
+    };
+    let (min_rtt, avg_rtt) = if num_connections == 0 {
+        (Duration::ZERO, Duration::ZERO)
+    } else {
+        (min_rtt, total_rtt / num_connections as u32)
+    };
+    let gauges = [
+        (&stats.sampled_connections, num_connections),
+        (&stats.connection_rtt_min_us, min_rtt.as_micros() as usize),
+        (&stats.connection_rtt_avg_us, avg_rtt.as_micros() as usize),
+        (&stats.connection_rtt_max_us, max_rtt.as_micros() as usize),
+        (&stats.connection_lost_packets, lost_packets),
+        (&stats.connection_packets_per_second_max, max_packets),
+        (&stats.connection_packets_per_second_min, min_packets),
+    ];
+    for (gauge, value) in gauges {
+        gauge.set(value);
+    }
+}
+
+/// Evicts the oldest connections from tables holding more connections than their cap.
+async fn prune_connection_tables_to_limits(
+    staked_connection_table: &ConnectionTableLock,
+    unstaked_connection_table: &ConnectionTableLock,
+    connection_limits: &ConnectionLimits,
+    stats: &StreamStats,
+) {
+    let tables = [
+        (
+            staked_connection_table,
+            connection_limits.max_staked_connections(),
+        ),
+        (
+            unstaked_connection_table,
+            connection_limits.max_unstaked_connections(),
+        ),
+    ];
+    for (connection_table, max_connections) in tables {
+        let num_pruned = connection_table
+            .lock()
+            .await
+            .prune_oldest(max_connections, EvictedFor::LoweredLimit);
+        stats.num_evictions.add(num_pruned);
+    }
+}
+
+pub fn get_remote_pubkey(connection: &Connection) -> Option<Pubkey> {
+    // Use the client cert only if it is self signed and the chain length is 1.
+    connection
+        .peer_identity()?
+        .downcast::<Vec<rustls::Certificate>>()
+        .ok()
+        .filter(|certs| certs.len() == 1)?
+        .first()
+        .and_then(get_pubkey_from_tls_certificate)
+}
+
+fn get_alpn_protocol(connection: &Connection) -> Option<Vec<u8>> {
+    connection
+        .handshake_data()?
+        .downcast::<HandshakeData>()
+        .ok()?
+        .protocol
+}
+
+fn get_connection_stake(
+    pubkey: Pubkey,
+    staked_nodes: &StakedNodesSource,
+) -> Option<(Pubkey, u64, u64, u64, u64)> {
+    debug!("Peer public key is {pubkey:?}");
+    let stake = |staked_nodes: &StakedNodes| {
+        Some((
+            pubkey,
+            staked_nodes.get_node_stake(&pubkey)?,
+            staked_nodes.total_stake(),
+            staked_nodes.max_stake(),
+            staked_nodes.min_stake(),
+        ))
+    };
+    match staked_nodes {
+        StakedNodesSource::Locked(staked_nodes) => stake(&staked_nodes.read().unwrap()),
+        StakedNodesSource::Swappable(staked_nodes) => stake(&staked_nodes.load()),
+    }
+}
+
+fn has_stake(staked_nodes: &StakedNodesSource) -> bool {
+    match staked_nodes {
+        StakedNodesSource::Locked(staked_nodes) => staked_nodes.read().unwrap().total_stake() > 0,
+        StakedNodesSource::Swappable(staked_nodes) => staked_nodes.load().total_stake() > 0,
+    }
+}
+
+/// Classifies a peer holding `stake` out of `total_stake` while the server admits
+/// `max_streams_per_ms` streams.
+pub(crate) fn classify_peer(
+    stake: u64,
+    total_stake: u64,
+    max_streams_per_ms: u64,
+) -> ConnectionPeerType {
+    // The heuristic is that the stake should be large engouh to have 1 stream pass throuh within one throttle
+    // interval during which we allow max (MAX_STREAMS_PER_MS * STREAM_THROTTLING_INTERVAL_MS) streams.
+    let min_stake_ratio = 1_f64 / (max_streams_per_ms * STREAM_THROTTLING_INTERVAL_MS) as f64;
+    let stake_ratio = stake as f64 / total_stake as f64;
+    if stake_ratio < min_stake_ratio {
+        // If it is a staked connection with ultra low stake ratio, treat it as unstaked.
+        ConnectionPeerType::Unstaked
+    } else {
+        ConnectionPeerType::Staked(stake)
+    }
+}
+
+pub fn compute_max_allowed_uni_streams(peer_type: ConnectionPeerType, total_stake: u64) -> usize {
+    match peer_type {
         ConnectionPeerType::Staked(peer_stake) => {
             // No checked math for f64 type. So let's explicitly check for 0 here
             if total_stake == 0 || peer_stake > total_stake {
@@ -307,11 +1283,102 @@ pub fn compute_max_allowed_uni_streams(peer_type: ConnectionPeerType, total_stak
     }
 }
 
+#[derive(Debug)]
 enum ConnectionHandlerError {
     ConnectionAddError,
     MaxStreamError,
 }
 
+// Per-connection limits configured separately for staked and unstaked peers, chosen once the
+// peer is classified
+#[derive(Clone, Copy, Debug)]
+struct PeerTypeLimits<T> {
+    staked: Option<T>,
+    unstaked: Option<T>,
+}
+
+impl PeerTypeLimits<u32> {
+    // Overrides of the stream limits derived from the stake
+    fn uni_streams(quic_server_config: &QuicServerConfig) -> Self {
+        Self {
+            staked: quic_server_config
+                .max_staked_concurrent_uni_streams
+                .or(quic_server_config.max_concurrent_uni_streams),
+            unstaked: quic_server_config
+                .max_unstaked_concurrent_uni_streams
+                .or(quic_server_config.max_concurrent_uni_streams),
+        }
+    }
+}
+
+impl PeerTypeLimits<u64> {
+    fn bandwidth(quic_server_config: &QuicServerConfig) -> Self {
+        Self {
+            staked: quic_server_config.max_staked_connection_bandwidth,
+            unstaked: quic_server_config.max_unstaked_connection_bandwidth,
+        }
+    }
+}
+
+impl PeerTypeLimits<usize> {
+    // Overrides of the `max_connections_per_peer` spawn argument
+    fn connections_per_peer(quic_server_config: &QuicServerConfig) -> Self {
+        Self {
+            staked: quic_server_config.max_connections_per_staked_peer,
+            unstaked: quic_server_config.max_connections_per_unstaked_peer,
+        }
+    }
+
+    fn chunks_per_stream(quic_server_config: &QuicServerConfig) -> Self {
+        Self {
+            staked: quic_server_config
+                .max_staked_chunks_per_stream
+                .or(quic_server_config.max_chunks_per_stream),
+            unstaked: quic_server_config.max_chunks_per_stream,
+        }
+    }
+}
+
+impl<T: Copy> PeerTypeLimits<T> {
+    fn get(&self, peer_type: ConnectionPeerType) -> Option<T> {
+        match peer_type {
+            ConnectionPeerType::Staked(_) => self.staked,
+            ConnectionPeerType::Unstaked => self.unstaked,
+        }
+    }
+}
+
+// Server-wide state the connections are set up with, built once by `run_server`
+struct ServerContext {
+    unstaked_connection_table: Arc<ConnectionTableLock>,
+    staked_connection_table: Arc<ConnectionTableLock>,
+    packet_sender: AsyncSender<PacketAccumulator>,
+    // Set along with `QuicServerConfig::staked_packet_sink`
+    staked_packet_sender: Option<AsyncSender<PacketAccumulator>>,
+    max_connections_per_peer: usize,
+    connections_per_peer_limits: PeerTypeLimits<usize>,
+    uni_stream_limits: PeerTypeLimits<u32>,
+    bandwidth_limits: PeerTypeLimits<u64>,
+    global_bandwidth_throttle: Option<Arc<GlobalBandwidthThrottle>>,
+    read_scheduler: Option<Arc<FairReadScheduler>>,
+    max_stream_timeouts_before_close: Option<usize>,
+    stream_idle_timeout: Option<Duration>,
+    chunk_limits: PeerTypeLimits<usize>,
+    receive_window_base: u64,
+    staked_nodes: StakedNodesSource,
+    connection_limits: Arc<ConnectionLimits>,
+    stats: Arc<StreamStats>,
+    peer_stats_table: Option<Arc<PeerStatsTable>>,
+    event_handler: Option<Arc<dyn ConnectionEventHandler>>,
+    bidi_stream_handler: Option<Arc<dyn BidiStreamHandler>>,
+    datagram_sender: Option<Sender<(SocketAddr, Bytes)>>,
+    connection_rate_limiter: Option<Arc<ConnectionRateLimiter>>,
+    connection_control: Arc<ConnectionControl>,
+    connection_setup_timeout: Duration,
+    wait_for_chunk_timeout: Duration,
+    stream_load_ema: Arc<StakedStreamLoadEMA>,
+}
+
 #[derive(Clone)]
 struct NewConnectionHandlerParams {
     // In principle, the code can be made to work with a crossbeam channel
@@ -321,49 +1388,98 @@ struct NewConnectionHandlerParams {
     // we're sticking with an async channel
     packet_sender: AsyncSender<PacketAccumulator>,
     remote_pubkey: Option<Pubkey>,
+    // Unlike remote_pubkey, which is only set for staked peers and keys the connection table,
+    // this is the pubkey from the peer's certificate regardless of its stake.
+    identity: Option<Pubkey>,
     peer_type: ConnectionPeerType,
     total_stake: u64,
     max_connections_per_peer: usize,
+    // Overrides the stream limit derived from the stake
+    max_concurrent_uni_streams: Option<u32>,
+    // Bytes per second at which the streams are read, `None` if uncapped
+    max_bandwidth: Option<u64>,
+    // Shared by the connections of the endpoint, see `QuicServerConfig::max_bandwidth`
+    global_bandwidth_throttle: Option<Arc<GlobalBandwidthThrottle>>,
+    // Shared by the connections of the endpoint, see `QuicServerConfig::max_concurrent_reads`
+    read_scheduler: Option<Arc<FairReadScheduler>>,
+    max_stream_timeouts_before_close: Option<usize>,
+    stream_idle_timeout: Option<Duration>,
+    // Chunks read from a stream before it's dropped, `None` if unbounded
+    max_chunks_per_stream: Option<usize>,
+    // Bytes the receive window ratios are applied to, see `QuicServerConfig::receive_window_base`
+    receive_window_base: u64,
+    // Bypasses the connection caps, see `QuicServerConfig::protected_peers`
+    protected: bool,
     stats: Arc<StreamStats>,
+    peer_stats_table: Option<Arc<PeerStatsTable>>,
+    event_handler: Option<Arc<dyn ConnectionEventHandler>>,
+    bidi_stream_handler: Option<Arc<dyn BidiStreamHandler>>,
+    datagram_sender: Option<Sender<(SocketAddr, Bytes)>>,
     max_stake: u64,
     min_stake: u64,
 }
 
 impl NewConnectionHandlerParams {
-    fn new_unstaked(
-        packet_sender: AsyncSender<PacketAccumulator>,
-        max_connections_per_peer: usize,
-        stats: Arc<StreamStats>,
-    ) -> NewConnectionHandlerParams {
+    fn new_unstaked(context: &ServerContext, identity: Option<Pubkey>, protected: bool) -> Self {
         NewConnectionHandlerParams {
-            packet_sender,
+            packet_sender: context.packet_sender.clone(),
             remote_pubkey: None,
+            identity,
             peer_type: ConnectionPeerType::Unstaked,
             total_stake: 0,
-            max_connections_per_peer,
-            stats,
+            max_connections_per_peer: context
+                .connections_per_peer_limits
+                .unstaked
+                .unwrap_or(context.max_connections_per_peer),
+            max_concurrent_uni_streams: context.uni_stream_limits.unstaked,
+            max_bandwidth: context.bandwidth_limits.unstaked,
+            global_bandwidth_throttle: context.global_bandwidth_throttle.clone(),
+            read_scheduler: context.read_scheduler.clone(),
+            max_stream_timeouts_before_close: context.max_stream_timeouts_before_close,
+            stream_idle_timeout: context.stream_idle_timeout,
+            max_chunks_per_stream: context.chunk_limits.unstaked,
+            receive_window_base: context.receive_window_base,
+            protected,
+            stats: context.stats.clone(),
+            peer_stats_table: context.peer_stats_table.clone(),
+            event_handler: context.event_handler.clone(),
+            bidi_stream_handler: context.bidi_stream_handler.clone(),
+            datagram_sender: context.datagram_sender.clone(),
             max_stake: 0,
             min_stake: 0,
         }
     }
+
+    fn notify_connect(&self, remote_addr: SocketAddr, alpn_protocol: Option<&[u8]>) {
+        if let Some(handler) = &self.event_handler {
+            if let Some(protocol) = alpn_protocol {
+                handler.on_protocol_negotiated(remote_addr, self.identity, protocol);
+            }
+            handler.on_connect(remote_addr, self.identity, self.peer_type.stake());
+        }
+    }
 }
 
 fn handle_and_cache_new_connection(
     connection: Connection,
-    mut connection_table_l: MutexGuard<ConnectionTable>,
-    connection_table: Arc<Mutex<ConnectionTable>>,
+    remote_addr: SocketAddr,
+    mut connection_table_l: ConnectionTableGuard,
+    connection_table: Arc<ConnectionTableLock>,
     params: &NewConnectionHandlerParams,
     wait_for_chunk_timeout: Duration,
     stream_load_ema: Arc<StakedStreamLoadEMA>,
 ) -> Result<(), ConnectionHandlerError> {
-    if let Ok(max_uni_streams) = VarInt::from_u64(compute_max_allowed_uni_streams(
-        params.peer_type,
-        params.total_stake,
-    ) as u64)
-    {
-        let remote_addr = connection.remote_address();
-        let receive_window =
-            compute_recieve_window(params.max_stake, params.min_stake, params.peer_type);
+    let max_uni_streams = params.max_concurrent_uni_streams.map_or_else(
+        || compute_max_allowed_uni_streams(params.peer_type, params.total_stake) as u64,
+        u64::from,
+    );
+    if let Ok(max_uni_streams) = VarInt::from_u64(max_uni_streams) {
+        let receive_window = compute_recieve_window(
+            params.max_stake,
+            params.min_stake,
+            params.peer_type,
+            params.receive_window_base,
+        );
 
         debug!(
             "Peer type {:?}, total stake {}, max streams {} receive_window {:?} from peer {}",
@@ -374,16 +1490,21 @@ fn handle_and_cache_new_connection(
             remote_addr,
         );
 
-        if let Some((last_update, cancel_connection, stream_counter)) = connection_table_l
-            .try_add_connection(
+        if let Some((last_update, cancel_connection, stream_counter, connection_counters)) =
+            connection_table_l.try_add_connection(
                 ConnectionTableKey::new(remote_addr.ip(), params.remote_pubkey),
-                remote_addr.port(),
+                remote_addr,
                 Some(connection.clone()),
                 params.peer_type,
                 timing::timestamp(),
                 params.max_connections_per_peer,
+                params.protected,
             )
         {
+            // Recorded while the table is locked so that it's there once it's sampled.
+            connection_counters
+                .uni_streams_limit
+                .store(max_uni_streams.into_inner(), Ordering::Relaxed);
             drop(connection_table_l);
 
             if let Ok(receive_window) = receive_window {
@@ -391,7 +1512,16 @@ fn handle_and_cache_new_connection(
             }
             connection.set_max_concurrent_uni_streams(max_uni_streams);
 
-            tokio::spawn(handle_connection(
+            #[cfg(feature = "tracing")]
+            let span = tracing::info_span!(
+                "quic_connection",
+                %remote_addr,
+                peer = params.identity.map(tracing::field::display),
+                stake = params.peer_type.stake(),
+                connection_id = connection.stable_id(),
+            );
+            let read_weight = read_weight(params.peer_type, max_uni_streams.into_inner());
+            let handler = handle_connection(
                 connection,
                 remote_addr,
                 last_update,
@@ -401,13 +1531,23 @@ fn handle_and_cache_new_connection(
                 wait_for_chunk_timeout,
                 stream_load_ema,
                 stream_counter,
-            ));
+                connection_counters,
+                read_weight,
+            );
+            #[cfg(feature = "tracing")]
+            let handler = tracing::Instrument::instrument(handler, span);
+            tokio::spawn(handler);
             Ok(())
         } else {
-            params
-                .stats
-                .connection_add_failed
-                .fetch_add(1, Ordering::Relaxed);
+            params.stats.connection_add_failed.add(1);
+            if params.peer_type.is_staked() {
+                params.stats.connection_add_failed_staked_peer_limit.add(1);
+            } else {
+                params
+                    .stats
+                    .connection_add_failed_unstaked_peer_limit
+                    .add(1);
+            }
             Err(ConnectionHandlerError::ConnectionAddError)
         }
     } else {
@@ -418,14 +1558,15 @@ fn handle_and_cache_new_connection(
         params
             .stats
             .connection_add_failed_invalid_stream_count
-            .fetch_add(1, Ordering::Relaxed);
+            .add(1);
         Err(ConnectionHandlerError::MaxStreamError)
     }
 }
 
 async fn prune_unstaked_connections_and_add_new_connection(
     connection: Connection,
-    connection_table: Arc<Mutex<ConnectionTable>>,
+    remote_addr: SocketAddr,
+    connection_table: Arc<ConnectionTableLock>,
     max_connections: usize,
     params: &NewConnectionHandlerParams,
     wait_for_chunk_timeout: Duration,
@@ -435,9 +1576,18 @@ async fn prune_unstaked_connections_and_add_new_connection(
     if max_connections > 0 {
         let connection_table_clone = connection_table.clone();
         let mut connection_table = connection_table.lock().await;
-        prune_unstaked_connection_table(&mut connection_table, max_connections, stats);
+        prune_unstaked_connection_table(
+            &mut connection_table,
+            max_connections,
+            stats,
+            EvictedFor::NewConnection {
+                remote_addr,
+                stake: params.peer_type.stake(),
+            },
+        );
         handle_and_cache_new_connection(
             connection,
+            remote_addr,
             connection_table,
             connection_table_clone,
             params,
@@ -453,6 +1603,43 @@ async fn prune_unstaked_connections_and_add_new_connection(
     }
 }
 
+// Puts the connection of a staked peer the staked table has no room for in the unstaked table,
+// evicting the least recently used unstaked peer if it's full.
+#[allow(clippy::too_many_arguments)]
+async fn evict_unstaked_and_add_staked_connection(
+    connection: Connection,
+    remote_addr: SocketAddr,
+    connection_table: Arc<ConnectionTableLock>,
+    max_connections: usize,
+    params: &NewConnectionHandlerParams,
+    wait_for_chunk_timeout: Duration,
+    stream_load_ema: Arc<StakedStreamLoadEMA>,
+    evicted_for: EvictedFor,
+) -> Result<(), ConnectionHandlerError> {
+    let mut connection_table_l = connection_table.lock().await;
+    if connection_table_l.total_size >= max_connections {
+        let num_pruned = connection_table_l.prune_least_recently_used_unstaked(evicted_for);
+        params.stats.num_evictions.add(num_pruned);
+        params.stats.evicted_unstaked_for_staked.add(num_pruned);
+    }
+    if connection_table_l.total_size >= max_connections {
+        connection.close(
+            CONNECTION_CLOSE_CODE_DISALLOWED.into(),
+            CONNECTION_CLOSE_REASON_DISALLOWED,
+        );
+        return Err(ConnectionHandlerError::ConnectionAddError);
+    }
+    handle_and_cache_new_connection(
+        connection,
+        remote_addr,
+        connection_table_l,
+        connection_table.clone(),
+        params,
+        wait_for_chunk_timeout,
+        stream_load_ema,
+    )
+}
+
 /// Calculate the ratio for per connection receive window from a staked peer
 fn compute_receive_window_ratio_for_staked_node(max_stake: u64, min_stake: u64, stake: u64) -> u64 {
     // Testing shows the maximum througput from a connection is achieved at receive_window =
@@ -483,125 +1670,289 @@ fn compute_recieve_window(
     max_stake: u64,
     min_stake: u64,
     peer_type: ConnectionPeerType,
+    receive_window_base: u64,
 ) -> Result<VarInt, VarIntBoundsExceeded> {
     match peer_type {
         ConnectionPeerType::Unstaked => {
-            VarInt::from_u64(PACKET_DATA_SIZE as u64 * QUIC_UNSTAKED_RECEIVE_WINDOW_RATIO)
+            VarInt::from_u64(receive_window_base * QUIC_UNSTAKED_RECEIVE_WINDOW_RATIO)
         }
         ConnectionPeerType::Staked(peer_stake) => {
             let ratio =
                 compute_receive_window_ratio_for_staked_node(max_stake, min_stake, peer_stake);
-            VarInt::from_u64(PACKET_DATA_SIZE as u64 * ratio)
+            VarInt::from_u64(receive_window_base * ratio)
         }
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn setup_connection(
-    connecting: Connecting,
-    unstaked_connection_table: Arc<Mutex<ConnectionTable>>,
-    staked_connection_table: Arc<Mutex<ConnectionTable>>,
-    packet_sender: AsyncSender<PacketAccumulator>,
-    max_connections_per_peer: usize,
-    staked_nodes: Arc<RwLock<StakedNodes>>,
-    max_staked_connections: usize,
-    max_unstaked_connections: usize,
-    max_streams_per_ms: u64,
-    stats: Arc<StreamStats>,
-    wait_for_chunk_timeout: Duration,
-    stream_load_ema: Arc<StakedStreamLoadEMA>,
-) {
-    const PRUNE_RANDOM_SAMPLE_SIZE: usize = 2;
-    let from = connecting.remote_address();
-    if let Ok(connecting_result) = timeout(QUIC_CONNECTION_HANDSHAKE_TIMEOUT, connecting).await {
+async fn setup_connection(connecting: Connecting, context: Arc<ServerContext>) {
+    let ServerContext {
+        unstaked_connection_table,
+        staked_connection_table,
+        packet_sender,
+        staked_packet_sender,
+        max_connections_per_peer,
+        connections_per_peer_limits,
+        uni_stream_limits,
+        bandwidth_limits,
+        staked_nodes,
+        connection_limits,
+        stats,
+        connection_rate_limiter,
+        connection_control,
+        connection_setup_timeout,
+        wait_for_chunk_timeout,
+        stream_load_ema,
+        chunk_limits,
+        ..
+    } = &*context;
+    let wait_for_chunk_timeout = *wait_for_chunk_timeout;
+    let from = connection_control.peer_address(connecting.remote_address());
+    let accepted_at = Instant::now();
+    if let Ok(connecting_result) = timeout(*connection_setup_timeout, connecting).await {
         match connecting_result {
             Ok(new_connection) => {
-                stats.total_new_connections.fetch_add(1, Ordering::Relaxed);
-
-                let params = get_connection_stake(&new_connection, &staked_nodes).map_or(
-                    NewConnectionHandlerParams::new_unstaked(
-                        packet_sender.clone(),
-                        max_connections_per_peer,
-                        stats.clone(),
-                    ),
+                stats
+                    .connection_setup_latency_us
+                    .record(accepted_at.elapsed().as_micros() as u64);
+                stats.total_new_connections.add(1);
+                stats.new_connections_per_second.record();
+
+                // Peers whose certificate can't be parsed are treated as anonymous and unstaked.
+                let identity = get_remote_pubkey(&new_connection);
+                let alpn_protocol = get_alpn_protocol(&new_connection);
+                if identity.is_some_and(|pubkey| {
+                    connection_control
+                        .ban_list
+                        .is_banned(BannedPeer::Pubkey(pubkey))
+                }) {
+                    debug!("Rejected connection from banned {identity:?} at {from:?}");
+                    stats.connection_rejected_banned.add(1);
+                    new_connection.close(
+                        CONNECTION_CLOSE_CODE_BANNED.into(),
+                        CONNECTION_CLOSE_REASON_BANNED,
+                    );
+                    return;
+                }
+                let protected = identity
+                    .is_some_and(|pubkey| connection_control.protected_peers.contains(&pubkey));
+                let connection_stake =
+                    identity.and_then(|pubkey| get_connection_stake(pubkey, staked_nodes));
+                let stake = connection_stake.map_or(0, |(_, stake, ..)| stake);
+                let unstaked_params =
+                    NewConnectionHandlerParams::new_unstaked(&context, identity, protected);
+                let mut params = connection_stake.map_or_else(
+                    || unstaked_params.clone(),
                     |(pubkey, stake, total_stake, max_stake, min_stake)| {
-                        // The heuristic is that the stake should be large engouh to have 1 stream pass throuh within one throttle
-                        // interval during which we allow max (MAX_STREAMS_PER_MS * STREAM_THROTTLING_INTERVAL_MS) streams.
-                        let min_stake_ratio =
-                            1_f64 / (max_streams_per_ms * STREAM_THROTTLING_INTERVAL_MS) as f64;
-                        let stake_ratio = stake as f64 / total_stake as f64;
-                        let peer_type = if stake_ratio < min_stake_ratio {
-                            // If it is a staked connection with ultra low stake ratio, treat it as unstaked.
-                            ConnectionPeerType::Unstaked
-                        } else {
-                            ConnectionPeerType::Staked(stake)
+                        let peer_type =
+                            classify_peer(stake, total_stake, stream_load_ema.max_streams_per_ms());
+                        let packet_sender = match peer_type {
+                            ConnectionPeerType::Staked(_) => {
+                                staked_packet_sender.as_ref().unwrap_or(packet_sender)
+                            }
+                            ConnectionPeerType::Unstaked => packet_sender,
                         };
                         NewConnectionHandlerParams {
-                            packet_sender,
+                            packet_sender: packet_sender.clone(),
                             remote_pubkey: Some(pubkey),
                             peer_type,
                             total_stake,
-                            max_connections_per_peer,
-                            stats: stats.clone(),
+                            max_connections_per_peer: connections_per_peer_limits
+                                .get(peer_type)
+                                .unwrap_or(*max_connections_per_peer),
+                            max_concurrent_uni_streams: uni_stream_limits.get(peer_type),
+                            max_bandwidth: bandwidth_limits.get(peer_type),
+                            max_chunks_per_stream: chunk_limits.get(peer_type),
                             max_stake,
                             min_stake,
+                            ..unstaked_params.clone()
                         }
                     },
                 );
 
+                if let Some(controller) = &connection_control.admission_controller {
+                    // One table lock at a time, neither is held while the other is waited on.
+                    let staked_connections = staked_connection_table.lock().await.total_size;
+                    let unstaked_connections = unstaked_connection_table.lock().await.total_size;
+                    let occupancy = PoolOccupancy {
+                        staked_connections,
+                        max_staked_connections: connection_limits.max_staked_connections(),
+                        unstaked_connections,
+                        max_unstaked_connections: connection_limits.max_unstaked_connections(),
+                    };
+                    let context = ConnectionContext {
+                        remote_addr: from,
+                        pubkey: identity,
+                        stake,
+                        staked: params.peer_type.is_staked(),
+                        protected,
+                        alpn_protocol: alpn_protocol.as_deref(),
+                        occupancy,
+                    };
+                    match controller.admit(&context) {
+                        AdmissionDecision::Accept => {}
+                        // A demoted peer loses the exemptions of protected peers too.
+                        AdmissionDecision::AcceptAsUnstaked => {
+                            params = NewConnectionHandlerParams {
+                                protected: false,
+                                ..unstaked_params
+                            }
+                        }
+                        AdmissionDecision::Reject(reason) => {
+                            debug!("Admission controller rejected {from:?}: {reason}");
+                            stats.connection_rejected_by_controller.add(1);
+                            new_connection
+                                .close(CONNECTION_CLOSE_CODE_REJECTED.into(), reason.as_bytes());
+                            return;
+                        }
+                    }
+                }
+
+                // After the handshake, which the TLS stack finishes without knowing the stakes,
+                // but before any stream work, see `ConnectionLimits`.
+                if !params.peer_type.is_staked()
+                    && !params.protected
+                    && connection_limits.max_unstaked_connections() == 0
+                {
+                    debug!("Rejected unstaked connection from {from:?}");
+                    stats.connection_rejected_unstaked_disabled.add(1);
+                    new_connection.close(
+                        CONNECTION_CLOSE_CODE_DISALLOWED.into(),
+                        CONNECTION_CLOSE_REASON_DISALLOWED,
+                    );
+                    return;
+                }
+
+                if let Some(limiter) = connection_rate_limiter
+                    .as_ref()
+                    .filter(|_| !params.peer_type.is_staked() && !params.protected)
+                {
+                    if !limiter.check(from.ip().to_canonical()) {
+                        debug!("Rate limited new connection from {from:?}");
+                        stats.connection_rate_limited.add(1);
+                        new_connection.close(
+                            CONNECTION_CLOSE_CODE_RATE_LIMITED.into(),
+                            CONNECTION_CLOSE_REASON_RATE_LIMITED,
+                        );
+                        return;
+                    }
+                }
+
+                if params.protected {
+                    let connection_table = if params.peer_type.is_staked() {
+                        staked_connection_table
+                    } else {
+                        unstaked_connection_table
+                    };
+                    let connection_table_l = connection_table.lock().await;
+                    if let Ok(()) = handle_and_cache_new_connection(
+                        new_connection,
+                        from,
+                        connection_table_l,
+                        connection_table.clone(),
+                        &params,
+                        wait_for_chunk_timeout,
+                        stream_load_ema.clone(),
+                    ) {
+                        stats.connection_added_protected.add(1);
+                        params.notify_connect(from, alpn_protocol.as_deref());
+                    }
+                    return;
+                }
+
+                let max_staked_connections = connection_limits.max_staked_connections();
+                let max_unstaked_connections = connection_limits.max_unstaked_connections();
                 match params.peer_type {
                     ConnectionPeerType::Staked(stake) => {
                         let mut connection_table_l = staked_connection_table.lock().await;
+                        let evicted_for = EvictedFor::NewConnection {
+                            remote_addr: from,
+                            stake,
+                        };
 
-                        if connection_table_l.total_size >= max_staked_connections {
-                            let num_pruned =
-                                connection_table_l.prune_random(PRUNE_RANDOM_SAMPLE_SIZE, stake);
-                            stats.num_evictions.fetch_add(num_pruned, Ordering::Relaxed);
-                        }
+                        let has_capacity = if let Some(stake_tiers) =
+                            connection_table_l.stake_tiers.clone()
+                        {
+                            let num_pruned = connection_table_l.prune_for_stake_tier(
+                                &stake_tiers,
+                                stake,
+                                max_staked_connections,
+                                evicted_for,
+                            );
+                            stats.num_evictions.add(num_pruned);
+                            !stake_tiers.is_full(stake_tiers.tier(stake), max_staked_connections)
+                                && connection_table_l.total_size < max_staked_connections
+                        } else {
+                            if connection_table_l.total_size >= max_staked_connections {
+                                let num_pruned =
+                                    connection_table_l.prune_for_stake(stake, evicted_for);
+                                stats.num_evictions.add(num_pruned);
+                            }
+                            connection_table_l.total_size < max_staked_connections
+                        };
 
-                        if connection_table_l.total_size < max_staked_connections {
+                        if has_capacity {
                             if let Ok(()) = handle_and_cache_new_connection(
                                 new_connection,
+                                from,
                                 connection_table_l,
                                 staked_connection_table.clone(),
                                 &params,
                                 wait_for_chunk_timeout,
                                 stream_load_ema.clone(),
                             ) {
-                                stats
-                                    .connection_added_from_staked_peer
-                                    .fetch_add(1, Ordering::Relaxed);
+                                stats.connection_added_from_staked_peer.add(1);
+                                params.notify_connect(from, alpn_protocol.as_deref());
                             }
                         } else {
                             // If we couldn't prune a connection in the staked connection table, let's
-                            // put this connection in the unstaked connection table. If needed, prune a
-                            // connection from the unstaked connection table.
-                            if let Ok(()) = prune_unstaked_connections_and_add_new_connection(
-                                new_connection,
-                                unstaked_connection_table.clone(),
-                                max_unstaked_connections,
-                                &params,
-                                wait_for_chunk_timeout,
-                                stream_load_ema.clone(),
-                            )
-                            .await
-                            {
-                                stats
-                                    .connection_added_from_staked_peer
-                                    .fetch_add(1, Ordering::Relaxed);
+                            // put this connection in the unstaked connection table. If needed, evict
+                            // an unstaked connection from the unstaked connection table.
+                            let result = match connection_control.staked_overflow {
+                                StakedOverflow::EvictUnstaked => {
+                                    evict_unstaked_and_add_staked_connection(
+                                        new_connection,
+                                        from,
+                                        unstaked_connection_table.clone(),
+                                        max_unstaked_connections,
+                                        &params,
+                                        wait_for_chunk_timeout,
+                                        stream_load_ema.clone(),
+                                        evicted_for,
+                                    )
+                                    .await
+                                }
+                                StakedOverflow::Strict => {
+                                    new_connection.close(
+                                        CONNECTION_CLOSE_CODE_DISALLOWED.into(),
+                                        CONNECTION_CLOSE_REASON_DISALLOWED,
+                                    );
+                                    Err(ConnectionHandlerError::ConnectionAddError)
+                                }
+                            };
+                            if let Err(err) = result {
+                                // The staked table is still locked, so it's as it was when it
+                                // refused the connection.
+                                if log_enabled!(Level::Debug) {
+                                    let staked_table_full = connection_table_l
+                                        .full_reason(stake, max_staked_connections);
+                                    debug!(
+                                        "Couldn't add the connection from staked peer {from} \
+                                         with stake {stake}: {staked_table_full}, and the \
+                                         unstaked table refused it ({err:?})"
+                                    );
+                                }
+                                stats.connection_add_failed_on_pruning.add(1);
+                                stats.connection_add_failed_staked_node.add(1);
                             } else {
-                                stats
-                                    .connection_add_failed_on_pruning
-                                    .fetch_add(1, Ordering::Relaxed);
-                                stats
-                                    .connection_add_failed_staked_node
-                                    .fetch_add(1, Ordering::Relaxed);
+                                stats.connection_added_from_staked_peer.add(1);
+                                params.notify_connect(from, alpn_protocol.as_deref());
                             }
                         }
                     }
                     ConnectionPeerType::Unstaked => {
                         if let Ok(()) = prune_unstaked_connections_and_add_new_connection(
                             new_connection,
+                            from,
                             unstaked_connection_table.clone(),
                             max_unstaked_connections,
                             &params,
@@ -610,168 +1961,433 @@ async fn setup_connection(
                         )
                         .await
                         {
-                            stats
-                                .connection_added_from_unstaked_peer
-                                .fetch_add(1, Ordering::Relaxed);
+                            stats.connection_added_from_unstaked_peer.add(1);
+                            params.notify_connect(from, alpn_protocol.as_deref());
                         } else {
-                            stats
-                                .connection_add_failed_unstaked_node
-                                .fetch_add(1, Ordering::Relaxed);
+                            stats.connection_add_failed_unstaked_node.add(1);
                         }
                     }
                 }
             }
             Err(e) => {
-                handle_connection_error(e, &stats, from);
+                handle_connection_error(e, stats, from);
             }
         }
     } else {
-        stats
-            .connection_setup_timeout
-            .fetch_add(1, Ordering::Relaxed);
+        stats.connection_setup_timeout.add(1);
     }
 }
 
 fn handle_connection_error(e: quinn::ConnectionError, stats: &StreamStats, from: SocketAddr) {
     debug!("error: {:?} from: {:?}", e, from);
-    stats.connection_setup_error.fetch_add(1, Ordering::Relaxed);
+    stats.connection_setup_error.add(1);
     match e {
         quinn::ConnectionError::TimedOut => {
-            stats
-                .connection_setup_error_timed_out
-                .fetch_add(1, Ordering::Relaxed);
+            stats.connection_setup_error_timed_out.add(1);
         }
         quinn::ConnectionError::ConnectionClosed(_) => {
-            stats
-                .connection_setup_error_closed
-                .fetch_add(1, Ordering::Relaxed);
+            stats.connection_setup_error_closed.add(1);
         }
         quinn::ConnectionError::TransportError(_) => {
-            stats
-                .connection_setup_error_transport
-                .fetch_add(1, Ordering::Relaxed);
+            stats.connection_setup_error_transport.add(1);
         }
         quinn::ConnectionError::ApplicationClosed(_) => {
-            stats
-                .connection_setup_error_app_closed
-                .fetch_add(1, Ordering::Relaxed);
+            stats.connection_setup_error_app_closed.add(1);
         }
         quinn::ConnectionError::Reset => {
-            stats
-                .connection_setup_error_reset
-                .fetch_add(1, Ordering::Relaxed);
+            stats.connection_setup_error_reset.add(1);
         }
         quinn::ConnectionError::LocallyClosed => {
-            stats
-                .connection_setup_error_locally_closed
-                .fetch_add(1, Ordering::Relaxed);
+            stats.connection_setup_error_locally_closed.add(1);
+        }
+        quinn::ConnectionError::VersionMismatch => {
+            stats.connection_setup_error_version_mismatch.add(1);
         }
-        _ => {}
     }
 }
 
+// Per-packet metadata delivered along with each batch, in the same order as its packets
+#[derive(Clone, Default)]
+struct SideChannels {
+    identity_sender: Option<Sender<Vec<Option<Pubkey>>>>,
+    stake_sender: Option<Sender<Vec<u64>>>,
+    receive_time_sender: Option<Sender<Vec<Instant>>>,
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn packet_batch_sender(
-    packet_sender: Sender<PacketBatch>,
+    packet_sender: PacketBatchConsumer,
+    side_channels: SideChannels,
     packet_receiver: AsyncReceiver<PacketAccumulator>,
     exit: Arc<AtomicBool>,
     stats: Arc<StreamStats>,
     coalesce: Duration,
+    batch_limits: BatchLimits,
+    full_channel_policy: FullChannelPolicy,
+    report_channel_depth: bool,
+    // Throttled as the consumer falls behind
+    stream_load_ema: Option<Arc<StakedStreamLoadEMA>>,
 ) {
     trace!("enter packet_batch_sender");
+    // Without a coalesce window, the receive timeout is only there to check for the exit.
+    let recv_timeout = if coalesce.is_zero() {
+        UNCOALESCED_EXIT_CHECK_INTERVAL
+    } else {
+        Duration::from_micros(250)
+    };
     let mut batch_start_time = Instant::now();
     loop {
-        let mut packet_batch = PacketBatch::with_capacity(PACKETS_PER_BATCH);
-        let mut total_bytes: usize = 0;
-
-        stats
-            .total_packet_batches_allocated
-            .fetch_add(1, Ordering::Relaxed);
-        stats
-            .total_packets_allocated
-            .fetch_add(PACKETS_PER_BATCH, Ordering::Relaxed);
+        let mut batch = PendingPacketBatch::new(&side_channels, &stats);
 
         loop {
             if exit.load(Ordering::Relaxed) {
+                // Flush what was received before the exit so that a graceful shutdown doesn't
+                // lose packets. This is bounded by the queue length at this point since the
+                // stream tasks may keep on sending until the runtime is dropped.
+                let pending = packet_receiver.len();
+                for packet_accumulator in
+                    (0..pending).map_while(|_| packet_receiver.try_recv().ok())
+                {
+                    if batch.is_full(&batch_limits) {
+                        batch
+                            .send(
+                                &packet_sender,
+                                full_channel_policy,
+                                &side_channels,
+                                &stats,
+                                &exit,
+                            )
+                            .await;
+                        batch = PendingPacketBatch::new(&side_channels, &stats);
+                    }
+                    batch.push(packet_accumulator, &stats);
+                }
+                if !batch.packet_batch.is_empty() {
+                    batch
+                        .send(
+                            &packet_sender,
+                            full_channel_policy,
+                            &side_channels,
+                            &stats,
+                            &exit,
+                        )
+                        .await;
+                }
                 return;
             }
             let elapsed = batch_start_time.elapsed();
-            if packet_batch.len() >= PACKETS_PER_BATCH
-                || (!packet_batch.is_empty() && elapsed >= coalesce)
+            if batch.is_full(&batch_limits)
+                || (!batch.packet_batch.is_empty() && elapsed >= coalesce)
             {
-                let len = packet_batch.len();
-                if let Err(e) = packet_sender.send(packet_batch) {
-                    stats
-                        .total_packet_batch_send_err
-                        .fetch_add(1, Ordering::Relaxed);
-                    trace!("Send error: {}", e);
-                } else {
-                    stats
-                        .total_packet_batches_sent
-                        .fetch_add(1, Ordering::Relaxed);
-
-                    stats
-                        .total_packets_sent_to_consumer
-                        .fetch_add(len, Ordering::Relaxed);
-
-                    stats
-                        .total_bytes_sent_to_consumer
-                        .fetch_add(total_bytes, Ordering::Relaxed);
-
-                    trace!("Sent {} packet batch", len);
+                batch
+                    .send(
+                        &packet_sender,
+                        full_channel_policy,
+                        &side_channels,
+                        &stats,
+                        &exit,
+                    )
+                    .await;
+                if let Some(depth) = packet_sender.depth().filter(|_| report_channel_depth) {
+                    stats.packet_channel_depth.set(depth);
+                    if let Some((stream_load_ema, capacity)) =
+                        stream_load_ema.as_ref().zip(packet_sender.capacity())
+                    {
+                        stream_load_ema.update_backpressure(depth, capacity);
+                    }
                 }
                 break;
             }
 
-            let timeout_res = timeout(Duration::from_micros(250), packet_receiver.recv()).await;
+            let timeout_res = timeout(recv_timeout, packet_receiver.recv()).await;
 
             if let Ok(Ok(packet_accumulator)) = timeout_res {
+                if coalesce.is_zero() {
+                    // Send right away, along with the packets that queued up in the meantime.
+                    batch.push(packet_accumulator, &stats);
+                    while !batch.is_full(&batch_limits) {
+                        let Ok(packet_accumulator) = packet_receiver.try_recv() else {
+                            break;
+                        };
+                        batch.push(packet_accumulator, &stats);
+                    }
+                    continue;
+                }
                 // Start the timeout from when the packet batch first becomes non-empty
-                if packet_batch.is_empty() {
+                if batch.packet_batch.is_empty() {
                     batch_start_time = Instant::now();
                 }
+                batch.push(packet_accumulator, &stats);
+            }
+        }
+    }
+}
 
-                unsafe {
-                    packet_batch.set_len(packet_batch.len() + 1);
-                }
+// Where packet_batch_sender delivers the packet batches
+#[derive(Clone)]
+pub(crate) enum PacketBatchConsumer {
+    Sink(Arc<dyn PacketSink>),
+    Tokio(TokioSender<PacketBatch>),
+}
 
-                let i = packet_batch.len() - 1;
-                *packet_batch[i].meta_mut() = packet_accumulator.meta;
-                let num_chunks = packet_accumulator.chunks.len();
-                for chunk in packet_accumulator.chunks {
-                    packet_batch[i].buffer_mut()[chunk.offset..chunk.end_of_chunk]
-                        .copy_from_slice(&chunk.bytes);
+impl PacketBatchConsumer {
+    // Waits for room no longer than the server runs, so that a stalled consumer doesn't hold
+    // up the shutdown.
+    async fn send(
+        &self,
+        packet_batch: PacketBatch,
+        full_channel_policy: FullChannelPolicy,
+        exit: &AtomicBool,
+    ) -> Result<(), SinkError> {
+        const FULL_CHANNEL_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+        const EXIT_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+        match self {
+            Self::Sink(sink) => match full_channel_policy {
+                FullChannelPolicy::Drop => {
+                    sink.try_send_batch(packet_batch).map_err(|(err, _)| err)
+                }
+                FullChannelPolicy::Block(max_wait) => {
+                    let deadline = Instant::now() + max_wait;
+                    let mut packet_batch = packet_batch;
+                    loop {
+                        match sink.try_send_batch(packet_batch) {
+                            Err((SinkError::Full, Some(returned)))
+                                if Instant::now() < deadline && !exit.load(Ordering::Relaxed) =>
+                            {
+                                packet_batch = returned;
+                                sleep(FULL_CHANNEL_RETRY_INTERVAL).await;
+                            }
+                            result => return result.map_err(|(err, _)| err),
+                        }
+                    }
+                }
+            },
+            Self::Tokio(sender) => loop {
+                match timeout(EXIT_CHECK_INTERVAL, sender.reserve()).await {
+                    Ok(Ok(permit)) => {
+                        permit.send(packet_batch);
+                        return Ok(());
+                    }
+                    Ok(Err(_)) => return Err(SinkError::Disconnected),
+                    Err(_) if exit.load(Ordering::Relaxed) => return Err(SinkError::Full),
+                    Err(_) => {}
                 }
+            },
+        }
+    }
 
-                total_bytes += packet_batch[i].meta().size;
+    fn depth(&self) -> Option<usize> {
+        match self {
+            Self::Sink(sink) => sink.depth(),
+            Self::Tokio(sender) => Some(sender.max_capacity() - sender.capacity()),
+        }
+    }
 
-                stats
-                    .total_chunks_processed_by_batcher
-                    .fetch_add(num_chunks, Ordering::Relaxed);
+    fn capacity(&self) -> Option<usize> {
+        match self {
+            Self::Sink(sink) => sink.capacity(),
+            Self::Tokio(sender) => Some(sender.max_capacity()),
+        }
+    }
+}
+
+// Sizes at which packet_batch_sender flushes a batch before the coalesce window elapses
+#[derive(Clone, Copy, Debug)]
+struct BatchLimits {
+    max_packets: usize,
+    max_bytes: Option<usize>,
+}
+
+// A packet batch being filled by packet_batch_sender
+struct PendingPacketBatch {
+    packet_batch: PacketBatch,
+    identities: Option<Vec<Option<Pubkey>>>,
+    stakes: Option<Vec<u64>>,
+    receive_times: Option<Vec<Instant>>,
+    total_bytes: usize,
+}
+
+impl PendingPacketBatch {
+    fn new(side_channels: &SideChannels, stats: &StreamStats) -> Self {
+        stats.total_packet_batches_allocated.add(1);
+        stats.total_packets_allocated.add(PACKETS_PER_BATCH);
+        Self {
+            packet_batch: PacketBatch::with_capacity(PACKETS_PER_BATCH),
+            identities: side_channels
+                .identity_sender
+                .is_some()
+                .then(|| Vec::with_capacity(PACKETS_PER_BATCH)),
+            stakes: side_channels
+                .stake_sender
+                .is_some()
+                .then(|| Vec::with_capacity(PACKETS_PER_BATCH)),
+            receive_times: side_channels
+                .receive_time_sender
+                .is_some()
+                .then(|| Vec::with_capacity(PACKETS_PER_BATCH)),
+            total_bytes: 0,
+        }
+    }
+
+    fn is_full(&self, limits: &BatchLimits) -> bool {
+        self.packet_batch.len() >= limits.max_packets
+            || limits
+                .max_bytes
+                .is_some_and(|max_bytes| self.total_bytes >= max_bytes)
+    }
+
+    fn push(&mut self, packet_accumulator: PacketAccumulator, stats: &StreamStats) {
+        let packet_batch = &mut self.packet_batch;
+        unsafe {
+            packet_batch.set_len(packet_batch.len() + 1);
+        }
+
+        let i = packet_batch.len() - 1;
+        *packet_batch[i].meta_mut() = packet_accumulator.meta;
+        let num_chunks = packet_accumulator.chunks.len();
+        for chunk in packet_accumulator.chunks {
+            packet_batch[i].buffer_mut()[chunk.offset..chunk.end_of_chunk]
+                .copy_from_slice(&chunk.bytes);
+        }
+
+        self.total_bytes += packet_batch[i].meta().size;
+        if let Some(identities) = self.identities.as_mut() {
+            identities.push(packet_accumulator.identity);
+        }
+        if let Some(stakes) = self.stakes.as_mut() {
+            stakes.push(packet_accumulator.stake);
+        }
+        if let Some(receive_times) = self.receive_times.as_mut() {
+            receive_times.push(packet_accumulator.received_at);
+        }
+
+        stats.total_chunks_processed_by_batcher.add(num_chunks);
+    }
+
+    async fn send(
+        self,
+        packet_sender: &PacketBatchConsumer,
+        full_channel_policy: FullChannelPolicy,
+        side_channels: &SideChannels,
+        stats: &StreamStats,
+        exit: &AtomicBool,
+    ) {
+        let len = self.packet_batch.len();
+        if let Err(e) = packet_sender
+            .send(self.packet_batch, full_channel_policy, exit)
+            .await
+        {
+            stats.total_packet_batch_send_err.add(1);
+            trace!("Send error: {}", e);
+        } else {
+            stats.total_packet_batches_sent.add(1);
+
+            stats.total_packets_sent_to_consumer.add(len);
+
+            stats.total_bytes_sent_to_consumer.add(self.total_bytes);
+            stats.batch_packets.record(len as u64);
+            stats.batch_bytes.record(self.total_bytes as u64);
+
+            trace!("Sent {} packet batch", len);
+
+            if let (Some(identity_sender), Some(identities)) =
+                (&side_channels.identity_sender, self.identities)
+            {
+                if let Err(e) = identity_sender.send(identities) {
+                    trace!("Identity send error: {}", e);
+                }
+            }
+            if let (Some(stake_sender), Some(stakes)) = (&side_channels.stake_sender, self.stakes) {
+                if let Err(e) = stake_sender.send(stakes) {
+                    trace!("Stake send error: {}", e);
+                }
             }
+            if let (Some(receive_time_sender), Some(receive_times)) =
+                (&side_channels.receive_time_sender, self.receive_times)
+            {
+                if let Err(e) = receive_time_sender.send(receive_times) {
+                    trace!("Receive time send error: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Totals of a single connection, reported to `ConnectionEventHandler::on_disconnect`
+#[derive(Debug, Default)]
+struct ConnectionCounters {
+    streams: AtomicU64,
+    chunks: AtomicU64,
+    bytes: AtomicU64,
+    packets: AtomicU64,
+    throttled_streams: AtomicU64,
+    // `packets` at the last `sample_connection_stats`
+    sampled_packets: AtomicU64,
+    // Stream limit chosen for the peer type
+    uni_streams_limit: AtomicU64,
+}
+
+impl ConnectionCounters {
+    fn summary(&self, duration: Duration) -> ConnectionSummary {
+        ConnectionSummary {
+            streams: self.streams.load(Ordering::Relaxed),
+            chunks: self.chunks.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            packets: self.packets.load(Ordering::Relaxed),
+            throttled_streams: self.throttled_streams.load(Ordering::Relaxed),
+            duration,
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection(
     connection: Connection,
     remote_addr: SocketAddr,
     last_update: Arc<AtomicU64>,
-    connection_table: Arc<Mutex<ConnectionTable>>,
+    connection_table: Arc<ConnectionTableLock>,
     cancel: CancellationToken,
     params: NewConnectionHandlerParams,
     wait_for_chunk_timeout: Duration,
     stream_load_ema: Arc<StakedStreamLoadEMA>,
     stream_counter: Arc<ConnectionStreamCounter>,
+    connection_counters: Arc<ConnectionCounters>,
+    // Turns taken per round, see `FairReadScheduler`
+    read_weight: u32,
 ) {
     let stats = params.stats;
     debug!(
         "quic new connection {} streams: {} connections: {}",
         remote_addr,
-        stats.total_streams.load(Ordering::Relaxed),
-        stats.total_connections.load(Ordering::Relaxed),
+        stats.total_streams.get(),
+        stats.total_connections.get(),
     );
+    trace_event!(DEBUG, "connection opened");
+    let connected_at = Instant::now();
     let stable_id = connection.stable_id();
-    stats.total_connections.fetch_add(1, Ordering::Relaxed);
+    stats.total_connections.add(1);
+    let active_connections = if params.peer_type.is_staked() {
+        &stats.active_staked_connections
+    } else {
+        &stats.active_unstaked_connections
+    };
+    active_connections.add(1);
+    // Anonymous peers can't be told apart across connections, so they aren't tracked per peer.
+    let peer_stats = params
+        .peer_stats_table
+        .as_ref()
+        .zip(params.identity)
+        .map(|(table, pubkey)| table.add_connection(pubkey, params.peer_type.is_staked()));
+    // Shared by the streams of the connection
+    let bandwidth_throttle = params
+        .max_bandwidth
+        .map(|bytes_per_second| Arc::new(BandwidthThrottle::new(bytes_per_second)));
+    let stream_timeouts = Arc::new(AtomicUsize::default());
+    let read_timeout = params
+        .stream_idle_timeout
+        .map_or(wait_for_chunk_timeout, |stream_idle_timeout| {
+            stream_idle_timeout.min(wait_for_chunk_timeout)
+        });
     loop {
         // Wait for new streams. If the peer is disconnected we get a cancellation signal and stop
         // the connection task.
@@ -783,6 +2399,44 @@ async fn handle_connection(
                     break;
                 }
             },
+            stream = connection.accept_bi(), if params.bidi_stream_handler.is_some() => {
+                match stream {
+                    Ok((send_stream, recv_stream)) => {
+                        stats.total_bidi_streams.add(1);
+                        if let Some(handler) = &params.bidi_stream_handler {
+                            handler.on_stream(
+                                remote_addr,
+                                params.identity,
+                                params.peer_type.stake(),
+                                send_stream,
+                                recv_stream,
+                            );
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        debug!("bidi stream error: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            datagram = connection.read_datagram(), if params.datagram_sender.is_some() => {
+                match datagram {
+                    Ok(datagram) => {
+                        stats.total_datagrams.add(1);
+                        if let Some(sender) = &params.datagram_sender {
+                            if sender.try_send((remote_addr, datagram)).is_err() {
+                                stats.datagrams_dropped.add(1);
+                            }
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        debug!("datagram error: {:?}", e);
+                        break;
+                    }
+                }
+            }
             _ = cancel.cancelled() => break,
         };
 
@@ -802,17 +2456,19 @@ async fn handle_connection(
                                     max_streams_per_interval: {max_streams_per_throttling_interval}, read_interval_streams: {streams_read_in_throttle_interval} \
                                     throttle_duration: {throttle_duration:?}",
                                     params.peer_type, params.total_stake);
-                stats.throttled_streams.fetch_add(1, Ordering::Relaxed);
+                stats.throttled_streams.add(1);
+                if let Some(peer_stats) = &peer_stats {
+                    peer_stats.throttled_streams.fetch_add(1, Ordering::Relaxed);
+                }
+                connection_counters
+                    .throttled_streams
+                    .fetch_add(1, Ordering::Relaxed);
                 match params.peer_type {
                     ConnectionPeerType::Unstaked => {
-                        stats
-                            .throttled_unstaked_streams
-                            .fetch_add(1, Ordering::Relaxed);
+                        stats.throttled_unstaked_streams.add(1);
                     }
                     ConnectionPeerType::Staked(_) => {
-                        stats
-                            .throttled_staked_streams
-                            .fetch_add(1, Ordering::Relaxed);
+                        stats.throttled_staked_streams.add(1);
                     }
                 }
                 sleep(throttle_duration).await;
@@ -820,23 +2476,40 @@ async fn handle_connection(
         }
         stream_load_ema.increment_load(params.peer_type);
         stream_counter.stream_count.fetch_add(1, Ordering::Relaxed);
-        stats.total_streams.fetch_add(1, Ordering::Relaxed);
-        stats.total_new_streams.fetch_add(1, Ordering::Relaxed);
+        stats.total_streams.add(1);
+        stats.total_new_streams.add(1);
+        if let Some(peer_stats) = &peer_stats {
+            peer_stats.streams.fetch_add(1, Ordering::Relaxed);
+        }
+        connection_counters.streams.fetch_add(1, Ordering::Relaxed);
         let cancel = cancel.clone();
         let stats = stats.clone();
+        let peer_stats = peer_stats.clone();
+        let connection_counters = connection_counters.clone();
         let packet_sender = params.packet_sender.clone();
         let last_update = last_update.clone();
         let stream_load_ema = stream_load_ema.clone();
-        tokio::spawn(async move {
+        let bandwidth_throttle = bandwidth_throttle.clone();
+        let global_bandwidth_throttle = params.global_bandwidth_throttle.clone();
+        let read_scheduler = params.read_scheduler.clone();
+        let stream_timeouts = stream_timeouts.clone();
+        let connection = connection.clone();
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("quic_stream", stream_id = %stream.id());
+        let stream_task = async move {
+            trace_event!(DEBUG, "stream opened");
             let mut maybe_batch = None;
+            let mut bandwidth_throttled = false;
+            let mut chunks_read = 0;
             loop {
-                // Read the next chunk, waiting up to `wait_for_chunk_timeout`. If we don't get a
+                // Read the next chunk, waiting up to `wait_for_chunk_timeout`, or the shorter
+                // `stream_idle_timeout` if configured. If we don't get a
                 // chunk before then, we assume the stream is dead and stop the stream task. This
                 // can only happen if there's severe packet loss or the peer stop sending for
                 // whatever reason.
                 let chunk = match tokio::select! {
                     chunk = tokio::time::timeout(
-                        wait_for_chunk_timeout,
+                        read_timeout,
                         stream.read_chunk(PACKET_DATA_SIZE, true)) => chunk,
 
                     // If the peer gets disconnected stop the task right away.
@@ -847,39 +2520,116 @@ async fn handle_connection(
                     // read_chunk returned error
                     Ok(Err(e)) => {
                         debug!("Received stream error: {:?}", e);
-                        stats
-                            .total_stream_read_errors
-                            .fetch_add(1, Ordering::Relaxed);
+                        stats.total_stream_read_errors.add(1);
                         break;
                     }
                     // timeout elapsed
                     Err(_) => {
                         debug!("Timeout in receiving on stream");
-                        stats
-                            .total_stream_read_timeouts
-                            .fetch_add(1, Ordering::Relaxed);
+                        stats.total_stream_read_timeouts.add(1);
+                        if read_timeout < wait_for_chunk_timeout {
+                            stats.streams_reaped_idle.add(1);
+                        }
+                        let timeouts = stream_timeouts.fetch_add(1, Ordering::Relaxed) + 1;
+                        if params.max_stream_timeouts_before_close == Some(timeouts) {
+                            debug!(
+                                "Closing connection from {remote_addr:?} after {timeouts} \
+                                 stream timeouts"
+                            );
+                            stats.connection_closed_stream_timeouts.add(1);
+                            connection.close(
+                                CONNECTION_CLOSE_CODE_STREAM_TIMEOUTS.into(),
+                                CONNECTION_CLOSE_REASON_STREAM_TIMEOUTS,
+                            );
+                        }
                         break;
                     }
                 };
+                let chunk_len = chunk.as_ref().map_or(0, |chunk| chunk.bytes.len());
+                trace_event!(TRACE, bytes = chunk_len, "stream chunk");
+                stats.total_bytes_received.add(chunk_len);
+                if chunk.is_some() {
+                    chunks_read += 1;
+                    if params
+                        .max_chunks_per_stream
+                        .is_some_and(|max_chunks| chunks_read > max_chunks)
+                    {
+                        debug!("Dropping stream from {remote_addr:?} after {chunks_read} chunks");
+                        stats.streams_reset_chunk_limit.add(1);
+                        break;
+                    }
+                }
 
-                if handle_chunk(
+                // Held while the chunk is handled, the turns go round-robin across the
+                // connections whose streams are waiting for one.
+                let read_turn = match &read_scheduler {
+                    Some(read_scheduler) => {
+                        let (read_turn, waited) =
+                            read_scheduler.acquire(stable_id, read_weight).await;
+                        if waited {
+                            stats.read_turns_waited.add(1);
+                        }
+                        Some(read_turn)
+                    }
+                    None => None,
+                };
+                let done = handle_chunk(
                     chunk,
                     &mut maybe_batch,
                     &remote_addr,
                     &packet_sender,
                     stats.clone(),
                     params.peer_type,
+                    params.identity,
+                    peer_stats.as_deref(),
+                    Some(&connection_counters),
                 )
-                .await
-                {
+                .await;
+                drop(read_turn);
+                if done {
                     last_update.store(timing::timestamp(), Ordering::Relaxed);
                     break;
                 }
+
+                // The connection, or the endpoint as a whole, is reading faster than its
+                // bandwidth cap, wait until it's back under the cap before reading the next
+                // chunk.
+                let mut throttle_duration = Duration::ZERO;
+                if let Some(bandwidth_throttle) = &bandwidth_throttle {
+                    throttle_duration = bandwidth_throttle.consume(chunk_len);
+                    if !throttle_duration.is_zero() && !bandwidth_throttled {
+                        bandwidth_throttled = true;
+                        stats.bandwidth_throttled_streams.add(1);
+                    }
+                }
+                if let Some(global_bandwidth_throttle) = &global_bandwidth_throttle {
+                    let global_throttle_duration =
+                        global_bandwidth_throttle.consume(chunk_len, params.peer_type);
+                    if !global_throttle_duration.is_zero() {
+                        stats.global_bandwidth_throttled.add(1);
+                        if global_throttle_duration > wait_for_chunk_timeout {
+                            debug!("Timeout in waiting for the global bandwidth cap");
+                            stats.total_stream_read_timeouts.add(1);
+                            break;
+                        }
+                        throttle_duration = throttle_duration.max(global_throttle_duration);
+                    }
+                }
+                if !throttle_duration.is_zero() {
+                    select! {
+                        _ = sleep(throttle_duration) => {}
+                        _ = cancel.cancelled() => break,
+                    }
+                }
             }
 
-            stats.total_streams.fetch_sub(1, Ordering::Relaxed);
+            stats.total_streams.sub(1);
             stream_load_ema.update_ema_if_needed();
-        });
+            trace_event!(DEBUG, "stream closed");
+        };
+        #[cfg(feature = "tracing")]
+        let stream_task = tracing::Instrument::instrument(stream_task, span);
+        tokio::spawn(stream_task);
     }
 
     let removed_connection_count = connection_table.lock().await.remove_connection(
@@ -888,18 +2638,29 @@ async fn handle_connection(
         stable_id,
     );
     if removed_connection_count > 0 {
-        stats
-            .connection_removed
-            .fetch_add(removed_connection_count, Ordering::Relaxed);
+        stats.connection_removed.add(removed_connection_count);
+        if let Some(handler) = &params.event_handler {
+            let summary = connection_counters.summary(connected_at.elapsed());
+            handler.on_disconnect(
+                remote_addr,
+                params.identity,
+                params.peer_type.stake(),
+                &summary,
+            );
+        }
     } else {
-        stats
-            .connection_remove_failed
-            .fetch_add(1, Ordering::Relaxed);
+        stats.connection_remove_failed.add(1);
     }
-    stats.total_connections.fetch_sub(1, Ordering::Relaxed);
+    if let (Some(table), Some(pubkey)) = (&params.peer_stats_table, &params.identity) {
+        table.remove_connection(pubkey);
+    }
+    stats.total_connections.sub(1);
+    active_connections.sub(1);
+    trace_event!(DEBUG, "connection closed");
 }
 
 // Return true if the server should drop the stream
+#[allow(clippy::too_many_arguments)]
 async fn handle_chunk(
     maybe_chunk: Option<quinn::Chunk>,
     packet_accum: &mut Option<PacketAccumulator>,
@@ -907,34 +2668,64 @@ async fn handle_chunk(
     packet_sender: &AsyncSender<PacketAccumulator>,
     stats: Arc<StreamStats>,
     peer_type: ConnectionPeerType,
+    identity: Option<Pubkey>,
+    peer_stats: Option<&PeerStats>,
+    connection_counters: Option<&ConnectionCounters>,
 ) -> bool {
     if let Some(chunk) = maybe_chunk {
         trace!("got chunk: {:?}", chunk);
         let chunk_len = chunk.bytes.len() as u64;
 
-        // shouldn't happen, but sanity check the size and offsets
+        // shouldn't happen, but sanity check the size and offsets. The packet is dropped along
+        // with the chunks accumulated so far and never reaches the batcher, so it doesn't take a
+        // slot in a batch.
         if chunk.offset > PACKET_DATA_SIZE as u64 || chunk_len > PACKET_DATA_SIZE as u64 {
-            stats.total_invalid_chunks.fetch_add(1, Ordering::Relaxed);
+            stats.total_invalid_chunks.add(1);
+            stats.invalid_chunk_oversize.add(1);
+            stats.packets_rejected_oversize.add(1);
             return true;
         }
         let Some(end_of_chunk) = chunk.offset.checked_add(chunk_len) else {
+            stats.total_invalid_chunks.add(1);
+            stats.invalid_chunk_oversize.add(1);
+            stats.packets_rejected_oversize.add(1);
             return true;
         };
         if end_of_chunk > PACKET_DATA_SIZE as u64 {
-            stats
-                .total_invalid_chunk_size
-                .fetch_add(1, Ordering::Relaxed);
+            stats.total_invalid_chunks.add(1);
+            stats.total_invalid_chunk_size.add(1);
+            stats.invalid_chunk_oversize.add(1);
+            stats.packets_rejected_oversize.add(1);
+            return true;
+        }
+        // Skipped rather than recorded as a chunk of the packet, the stream carries on.
+        if chunk_len == 0 {
+            stats.invalid_chunk_empty.add(1);
+            return false;
+        }
+        // The stream is read in order, so each chunk starts where the previous one ended. A gap
+        // would leave zeroes in the packet the batcher assembles from the chunks.
+        let expected_offset = packet_accum.as_ref().map_or(0, |accum| accum.meta.size);
+        if chunk.offset != expected_offset as u64 {
+            stats.invalid_chunk_count.add(1);
             return true;
         }
 
         // chunk looks valid
         if packet_accum.is_none() {
             let mut meta = Meta::default();
-            meta.set_socket_addr(remote_addr);
+            // Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses.
+            meta.set_socket_addr(&SocketAddr::new(
+                remote_addr.ip().to_canonical(),
+                remote_addr.port(),
+            ));
             meta.set_from_staked_node(matches!(peer_type, ConnectionPeerType::Staked(_)));
             *packet_accum = Some(PacketAccumulator {
                 meta,
                 chunks: SmallVec::new(),
+                identity,
+                stake: peer_type.stake(),
+                received_at: Instant::now(),
             });
         }
 
@@ -952,89 +2743,110 @@ async fn handle_chunk(
             accum.meta.size = std::cmp::max(accum.meta.size, end_of_chunk);
         }
 
+        if let Some(peer_stats) = peer_stats {
+            peer_stats.chunks.fetch_add(1, Ordering::Relaxed);
+            peer_stats
+                .bytes
+                .fetch_add(chunk_len as usize, Ordering::Relaxed);
+        }
+        if let Some(counters) = connection_counters {
+            counters.chunks.fetch_add(1, Ordering::Relaxed);
+            counters.bytes.fetch_add(chunk_len, Ordering::Relaxed);
+        }
+
         if peer_type.is_staked() {
-            stats
-                .total_staked_chunks_received
-                .fetch_add(1, Ordering::Relaxed);
+            stats.total_staked_chunks_received.add(1);
         } else {
-            stats
-                .total_unstaked_chunks_received
-                .fetch_add(1, Ordering::Relaxed);
+            stats.total_unstaked_chunks_received.add(1);
         }
     } else {
         // done receiving chunks
         trace!("chunk is none");
         if let Some(accum) = packet_accum.take() {
             let bytes_sent = accum.meta.size;
+            stats.stream_bytes.record(bytes_sent as u64);
             let chunks_sent = accum.chunks.len();
 
+            stats.last_packet_received.set_now();
             if let Err(err) = packet_sender.send(accum).await {
-                stats
-                    .total_handle_chunk_to_packet_batcher_send_err
-                    .fetch_add(1, Ordering::Relaxed);
+                stats.total_handle_chunk_to_packet_batcher_send_err.add(1);
                 trace!("packet batch send error {:?}", err);
             } else {
-                stats
-                    .total_packets_sent_for_batching
-                    .fetch_add(1, Ordering::Relaxed);
-                stats
-                    .total_bytes_sent_for_batching
-                    .fetch_add(bytes_sent, Ordering::Relaxed);
-                stats
-                    .total_chunks_sent_for_batching
-                    .fetch_add(chunks_sent, Ordering::Relaxed);
+                stats.total_packets_sent_for_batching.add(1);
+                stats.total_bytes_sent_for_batching.add(bytes_sent);
+                stats.total_chunks_sent_for_batching.add(chunks_sent);
+                if let Some(counters) = connection_counters {
+                    counters.packets.fetch_add(1, Ordering::Relaxed);
+                }
 
                 match peer_type {
                     ConnectionPeerType::Unstaked => {
-                        stats
-                            .total_unstaked_packets_sent_for_batching
-                            .fetch_add(1, Ordering::Relaxed);
+                        stats.total_unstaked_packets_sent_for_batching.add(1);
                     }
                     ConnectionPeerType::Staked(_) => {
-                        stats
-                            .total_staked_packets_sent_for_batching
-                            .fetch_add(1, Ordering::Relaxed);
+                        stats.total_staked_packets_sent_for_batching.add(1);
                     }
                 }
 
                 trace!("sent {} byte packet for batching", bytes_sent);
             }
         } else {
-            stats
-                .total_packet_batches_none
-                .fetch_add(1, Ordering::Relaxed);
+            stats.total_packet_batches_none.add(1);
         }
         return true;
     }
     false
 }
 
+// What the handler of a connection shares with its entry in the `ConnectionTable`
+type AddedConnection = (
+    Arc<AtomicU64>,
+    CancellationToken,
+    Arc<ConnectionStreamCounter>,
+    Arc<ConnectionCounters>,
+);
+
 #[derive(Debug)]
 struct ConnectionEntry {
     cancel: CancellationToken,
     peer_type: ConnectionPeerType,
     last_update: Arc<AtomicU64>,
-    port: u16,
+    // The address of the client, behind the proxy if the connection is relayed by one
+    remote_addr: SocketAddr,
     connection: Option<Connection>,
     stream_counter: Arc<ConnectionStreamCounter>,
+    counters: Arc<ConnectionCounters>,
+    connected_at: u64,
+    // Set for the peers in `QuicServerConfig::protected_peers`, which are never evicted
+    protected: bool,
+    // Counts the connection against its stake tier for as long as it's in the staked table
+    _stake_tier_slot: Option<StakeTierSlot>,
 }
 
 impl ConnectionEntry {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         cancel: CancellationToken,
         peer_type: ConnectionPeerType,
         last_update: Arc<AtomicU64>,
-        port: u16,
+        remote_addr: SocketAddr,
         connection: Option<Connection>,
         stream_counter: Arc<ConnectionStreamCounter>,
+        counters: Arc<ConnectionCounters>,
+        protected: bool,
+        stake_tier_slot: Option<StakeTierSlot>,
     ) -> Self {
         Self {
             cancel,
             peer_type,
+            connected_at: last_update.load(Ordering::Relaxed),
             last_update,
-            port,
+            remote_addr,
             connection,
             stream_counter,
+            counters,
+            protected,
+            _stake_tier_slot: stake_tier_slot,
         }
     }
 
@@ -1042,14 +2854,21 @@ impl ConnectionEntry {
         self.last_update.load(Ordering::Relaxed)
     }
 
+    fn connected_at(&self) -> u64 {
+        self.connected_at
+    }
+
     fn stake(&self) -> u64 {
-        match self.peer_type {
-            ConnectionPeerType::Unstaked => 0,
-            ConnectionPeerType::Staked(stake) => stake,
-        }
+        self.peer_type.stake()
     }
 }
 
+// Protected peers are never evicted. Unstaked peers share a key by IP, so a key holding any
+// protected connection is skipped as a whole.
+fn is_protected(connections: &[ConnectionEntry]) -> bool {
+    connections.iter().any(|entry| entry.protected)
+}
+
 impl Drop for ConnectionEntry {
     fn drop(&mut self) {
         if let Some(conn) = self.connection.take() {
@@ -1070,39 +2889,193 @@ enum ConnectionTableKey {
 
 impl ConnectionTableKey {
     fn new(ip: IpAddr, maybe_pubkey: Option<Pubkey>) -> Self {
-        maybe_pubkey.map_or(ConnectionTableKey::IP(ip), |pubkey| {
+        // A peer reaching a dual-stack socket over IPv4 shares the limit of its IPv4 address.
+        maybe_pubkey.map_or(ConnectionTableKey::IP(ip.to_canonical()), |pubkey| {
             ConnectionTableKey::Pubkey(pubkey)
         })
     }
 }
 
+// What connections are evicted for, logged along with each evicted connection
+#[derive(Clone, Copy, Debug)]
+enum EvictedFor {
+    // A new connection from this peer, which needed the slot
+    NewConnection { remote_addr: SocketAddr, stake: u64 },
+    // The cap on the table, lowered below the number of connections it holds
+    LoweredLimit,
+}
+
+impl fmt::Display for EvictedFor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NewConnection { remote_addr, stake } => {
+                write!(f, "a new connection from {remote_addr} with stake {stake}")
+            }
+            Self::LoweredLimit => write!(f, "a lowered connection limit"),
+        }
+    }
+}
+
+// Lock of a connection table that notifies the event handler of the connections evicted while
+// it was held once it's released, so that the handler may take its time, or call back into the
+// server, without holding up the other connections.
+struct ConnectionTableLock(Mutex<ConnectionTable>);
+
+impl ConnectionTableLock {
+    fn new(table: ConnectionTable) -> Self {
+        Self(Mutex::new(table))
+    }
+
+    async fn lock(&self) -> ConnectionTableGuard<'_> {
+        ConnectionTableGuard(Some(self.0.lock().await))
+    }
+
+    fn blocking_lock(&self) -> ConnectionTableGuard<'_> {
+        ConnectionTableGuard(Some(self.0.blocking_lock()))
+    }
+}
+
+struct ConnectionTableGuard<'a>(Option<MutexGuard<'a, ConnectionTable>>);
+
+impl Deref for ConnectionTableGuard<'_> {
+    type Target = ConnectionTable;
+
+    fn deref(&self) -> &ConnectionTable {
+        self.0.as_ref().expect("table is locked")
+    }
+}
+
+impl DerefMut for ConnectionTableGuard<'_> {
+    fn deref_mut(&mut self) -> &mut ConnectionTable {
+        self.0.as_mut().expect("table is locked")
+    }
+}
+
+impl Drop for ConnectionTableGuard<'_> {
+    fn drop(&mut self) {
+        let Some(mut table) = self.0.take() else {
+            return;
+        };
+        let evicted = std::mem::take(&mut table.evicted);
+        let event_handler = table.event_handler.clone();
+        drop(table);
+        if let Some(handler) = event_handler {
+            for (remote_addr, pubkey, stake) in evicted {
+                handler.on_evict(remote_addr, pubkey, stake);
+            }
+        }
+    }
+}
+
 // Map of IP to list of connection entries
 struct ConnectionTable {
     table: IndexMap<ConnectionTableKey, Vec<ConnectionEntry>>,
     total_size: usize,
+    event_handler: Option<Arc<dyn ConnectionEventHandler>>,
+    // Evicted connections the event handler is notified of once the table is unlocked
+    evicted: Vec<(SocketAddr, Option<Pubkey>, u64)>,
+    // Only set on the staked table when stake tiers are configured
+    stake_tiers: Option<Arc<StakeTiers>>,
+    eviction_policy: EvictionPolicy,
 }
 
 // Prune the connection which has the oldest update
 // Return number pruned
 impl ConnectionTable {
-    fn new() -> Self {
+    fn new(event_handler: Option<Arc<dyn ConnectionEventHandler>>) -> Self {
         Self {
             table: IndexMap::default(),
             total_size: 0,
+            event_handler,
+            evicted: Vec::new(),
+            stake_tiers: None,
+            eviction_policy: EvictionPolicy::default(),
+        }
+    }
+
+    fn with_eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.eviction_policy = eviction_policy;
+        self
+    }
+
+    // Evicts a connection for a new one with `stake` according to the eviction policy and
+    // returns the number of pruned connections.
+    fn prune_for_stake(&mut self, stake: u64, evicted_for: EvictedFor) -> usize {
+        const PRUNE_RANDOM_SAMPLE_SIZE: usize = 2;
+        let key = match self.eviction_policy {
+            EvictionPolicy::LowestStake => {
+                return self.prune_random(PRUNE_RANDOM_SAMPLE_SIZE, stake, evicted_for);
+            }
+            EvictionPolicy::LeastRecentlyUsed => ConnectionEntry::last_update,
+            EvictionPolicy::OldestConnection => ConnectionEntry::connected_at,
+        };
+        let num_pruned = self
+            .table
+            .values()
+            .enumerate()
+            .filter(|(_, connections)| {
+                !is_protected(connections)
+                    && connections
+                        .first()
+                        .is_some_and(|connection| connection.stake() <= stake)
+            })
+            .min_by_key(|(_, connections)| connections.iter().map(key).min())
+            .map(|(index, _)| index)
+            .and_then(|index| self.table.swap_remove_index(index))
+            .map(|(_, connections)| {
+                self.notify_evicted(&connections, evicted_for);
+                connections.len()
+            })
+            .unwrap_or_default();
+        self.total_size = self.total_size.saturating_sub(num_pruned);
+        num_pruned
+    }
+
+    fn with_stake_tiers(mut self, stake_tiers: Option<Arc<StakeTiers>>) -> Self {
+        self.stake_tiers = stake_tiers;
+        self
+    }
+
+    fn notify_evicted(&mut self, connections: &[ConnectionEntry], evicted_for: EvictedFor) {
+        let now = timing::timestamp();
+        for entry in connections {
+            let Some(connection) = &entry.connection else {
+                continue;
+            };
+            let pubkey = get_remote_pubkey(connection);
+            debug!(
+                "Evicted the connection from {} (pubkey {pubkey:?}, stake {}, connected {}ms \
+                 ago) for {evicted_for}",
+                entry.remote_addr,
+                entry.stake(),
+                now.saturating_sub(entry.connected_at),
+            );
+            if self.event_handler.is_some() {
+                self.evicted
+                    .push((entry.remote_addr, pubkey, entry.stake()));
+            }
         }
     }
 
-    fn prune_oldest(&mut self, max_size: usize) -> usize {
+    fn prune_oldest(&mut self, max_size: usize, evicted_for: EvictedFor) -> usize {
         let mut num_pruned = 0;
         let key = |(_, connections): &(_, &Vec<_>)| {
             connections.iter().map(ConnectionEntry::last_update).min()
         };
         while self.total_size.saturating_sub(num_pruned) > max_size {
-            match self.table.values().enumerate().min_by_key(key) {
+            match self
+                .table
+                .values()
+                .enumerate()
+                .filter(|(_, connections)| !is_protected(connections))
+                .min_by_key(key)
+            {
                 None => break,
                 Some((index, connections)) => {
                     num_pruned += connections.len();
-                    self.table.swap_remove_index(index);
+                    if let Some((_, connections)) = self.table.swap_remove_index(index) {
+                        self.notify_evicted(&connections, evicted_for);
+                    }
                 }
             }
         }
@@ -1110,14 +3083,46 @@ impl ConnectionTable {
         num_pruned
     }
 
-    // Randomly selects sample_size many connections, evicts the one with the
-    // lowest stake, and returns the number of pruned connections.
-    // If the stakes of all the sampled connections are higher than the
-    // threshold_stake, rejects the pruning attempt, and returns 0.
-    fn prune_random(&mut self, sample_size: usize, threshold_stake: u64) -> usize {
-        let num_pruned = std::iter::once(self.table.len())
-            .filter(|&size| size > 0)
-            .flat_map(|size| {
+    // Evicts the unstaked peer that least recently opened a stream, leaving alone the staked
+    // peers put in the unstaked table. Returns the number of pruned connections.
+    fn prune_least_recently_used_unstaked(&mut self, evicted_for: EvictedFor) -> usize {
+        let num_pruned = self
+            .table
+            .values()
+            .enumerate()
+            .filter(|(_, connections)| {
+                !is_protected(connections)
+                    && connections
+                        .first()
+                        .is_some_and(|connection| !connection.peer_type.is_staked())
+            })
+            .min_by_key(|(_, connections)| {
+                connections.iter().map(ConnectionEntry::last_update).max()
+            })
+            .map(|(index, _)| index)
+            .and_then(|index| self.table.swap_remove_index(index))
+            .map(|(_, connections)| {
+                self.notify_evicted(&connections, evicted_for);
+                connections.len()
+            })
+            .unwrap_or_default();
+        self.total_size = self.total_size.saturating_sub(num_pruned);
+        num_pruned
+    }
+
+    // Randomly selects sample_size many connections, evicts the one with the
+    // lowest stake, and returns the number of pruned connections.
+    // If the stakes of all the sampled connections are higher than the
+    // threshold_stake, rejects the pruning attempt, and returns 0.
+    fn prune_random(
+        &mut self,
+        sample_size: usize,
+        threshold_stake: u64,
+        evicted_for: EvictedFor,
+    ) -> usize {
+        let num_pruned = std::iter::once(self.table.len())
+            .filter(|&size| size > 0)
+            .flat_map(|size| {
                 let mut rng = thread_rng();
                 repeat_with(move || rng.gen_range(0..size))
             })
@@ -1127,28 +3132,124 @@ impl ConnectionTable {
                 (index, stake)
             })
             .take(sample_size)
+            .filter(|&(index, _)| !is_protected(&self.table[index]))
             .min_by_key(|&(_, stake)| stake)
             .filter(|&(_, stake)| stake < Some(threshold_stake))
             .and_then(|(index, _)| self.table.swap_remove_index(index))
-            .map(|(_, connections)| connections.len())
+            .map(|(_, connections)| {
+                self.notify_evicted(&connections, evicted_for);
+                connections.len()
+            })
+            .unwrap_or_default();
+        self.total_size = self.total_size.saturating_sub(num_pruned);
+        num_pruned
+    }
+
+    // Makes room for a connection with `stake` according to the stake tiers: a full tier evicts
+    // its lowest-stake connection below `stake`, a full table evicts from the tier picked by
+    // `StakeTiers::tier_to_evict`. Returns the number of pruned connections.
+    fn prune_for_stake_tier(
+        &mut self,
+        stake_tiers: &StakeTiers,
+        stake: u64,
+        max_staked_connections: usize,
+        evicted_for: EvictedFor,
+    ) -> usize {
+        let tier = stake_tiers.tier(stake);
+        let (evicted_tier, threshold_stake) = if stake_tiers.is_full(tier, max_staked_connections) {
+            (tier, stake)
+        } else if self.total_size >= max_staked_connections {
+            match stake_tiers.tier_to_evict(tier, max_staked_connections) {
+                Some(evicted_tier) => (evicted_tier, u64::MAX),
+                None => return 0,
+            }
+        } else {
+            return 0;
+        };
+        let num_pruned = self
+            .table
+            .values()
+            .enumerate()
+            .filter(|(_, connections)| !is_protected(connections))
+            .filter_map(|(index, connections)| {
+                let stake = connections.first()?.stake();
+                (stake_tiers.tier(stake) == evicted_tier && stake < threshold_stake)
+                    .then_some((index, stake))
+            })
+            .min_by_key(|&(_, stake)| stake)
+            .and_then(|(index, _)| self.table.swap_remove_index(index))
+            .map(|(_, connections)| {
+                self.notify_evicted(&connections, evicted_for);
+                connections.len()
+            })
             .unwrap_or_default();
         self.total_size = self.total_size.saturating_sub(num_pruned);
         num_pruned
     }
 
+    // Why the staked table couldn't make room for a connection with `stake`, for the logs.
+    fn full_reason(&self, stake: u64, max_staked_connections: usize) -> String {
+        let total_size = self.total_size;
+        match &self.stake_tiers {
+            Some(stake_tiers)
+                if stake_tiers.is_full(stake_tiers.tier(stake), max_staked_connections) =>
+            {
+                format!(
+                    "stake tier {} is full without a connection of lower stake to evict",
+                    stake_tiers.tier(stake)
+                )
+            }
+            Some(_) => format!(
+                "the table is full ({total_size}/{max_staked_connections}) without a stake tier \
+                 over its share to evict from"
+            ),
+            None => format!(
+                "the table is full ({total_size}/{max_staked_connections}) without a connection \
+                 the {:?} eviction policy gives way",
+                self.eviction_policy
+            ),
+        }
+    }
+
+    fn connections(&self) -> impl Iterator<Item = (&Connection, SocketAddr)> {
+        self.table
+            .values()
+            .flatten()
+            .filter_map(|entry| Some((entry.connection.as_ref()?, entry.remote_addr)))
+    }
+
+    fn connection_counters(&self) -> impl Iterator<Item = &Arc<ConnectionCounters>> {
+        self.table.values().flatten().map(|entry| &entry.counters)
+    }
+
+    // Closes the connections `matches` picks, returns whether there were any. Unstaked peers
+    // are keyed by IP, so every connection has to be checked.
+    fn close(&self, matches: impl Fn(&Connection, SocketAddr) -> bool) -> bool {
+        let mut closed = false;
+        for (connection, _) in self
+            .connections()
+            .filter(|(connection, remote_addr)| matches(connection, *remote_addr))
+        {
+            connection.close(
+                CONNECTION_CLOSE_CODE_CLOSED_BY_OPERATOR.into(),
+                CONNECTION_CLOSE_REASON_CLOSED_BY_OPERATOR,
+            );
+            closed = true;
+        }
+        closed
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn try_add_connection(
         &mut self,
         key: ConnectionTableKey,
-        port: u16,
+        remote_addr: SocketAddr,
         connection: Option<Connection>,
         peer_type: ConnectionPeerType,
         last_update: u64,
         max_connections_per_peer: usize,
-    ) -> Option<(
-        Arc<AtomicU64>,
-        CancellationToken,
-        Arc<ConnectionStreamCounter>,
-    )> {
+        protected: bool,
+    ) -> Option<AddedConnection> {
         let connection_entry = self.table.entry(key).or_default();
         let has_connection_capacity = connection_entry
             .len()
@@ -1162,16 +3263,25 @@ impl ConnectionTable {
                 .first()
                 .map(|entry| entry.stream_counter.clone())
                 .unwrap_or(Arc::new(ConnectionStreamCounter::new()));
+            let counters = Arc::<ConnectionCounters>::default();
+            let stake_tier_slot = self
+                .stake_tiers
+                .as_ref()
+                .filter(|_| peer_type.is_staked())
+                .map(|stake_tiers| stake_tiers.register(peer_type.stake()));
             connection_entry.push(ConnectionEntry::new(
                 cancel.clone(),
                 peer_type,
                 last_update.clone(),
-                port,
+                remote_addr,
                 connection,
                 stream_counter.clone(),
+                counters.clone(),
+                protected,
+                stake_tier_slot,
             ));
             self.total_size += 1;
-            Some((last_update, cancel, stream_counter))
+            Some((last_update, cancel, stream_counter, counters))
         } else {
             if let Some(connection) = connection {
                 connection.close(
@@ -1195,7 +3305,7 @@ impl ConnectionTable {
                 // (Some unit tests do not fill in a valid connection in the table. To support that,
                 // if the connection is none, the stable_id check is ignored. i.e. if the port matches,
                 // the connection gets removed)
-                connection_entry.port != port
+                connection_entry.remote_addr.port() != port
                     || connection_entry
                         .connection
                         .as_ref()
@@ -1214,3 +3324,3312 @@ impl ConnectionTable {
         }
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::SystemTime;
+
+    use assert_matches::assert_matches;
+    use crossbeam_channel::{unbounded, Receiver};
+    use quinn::{congestion, ClientConfig, RecvStream, SendStream};
+    use rustls::server::{ClientCertVerified, ClientCertVerifier};
+    use rustls::{Certificate, DistinguishedName};
+    use solana_sdk::signer::Signer;
+
+    use super::*;
+    use crate::nonblocking::ban_list::BannedPeer;
+    use crate::nonblocking::stake_tiers::StakeTier;
+    use crate::packet_sink::bounded_packet_channel;
+    use crate::quic::{
+        CongestionController, SkipClientVerification, SniFallback, DEFAULT_TPU_COALESCE,
+        MAX_STAKED_CONNECTIONS, MAX_UNSTAKED_CONNECTIONS,
+    };
+    use crate::test_utils::SkipServerVerification;
+    pub use crate::test_utils::{get_client_config, get_client_config_with_alpn};
+    use crate::tls_certificates::new_self_signed_tls_certificate;
+
+    pub fn make_client_endpoint(client_keypair: Option<&Keypair>) -> Endpoint {
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            client_socket,
+            Arc::new(TokioRuntime),
+        )
+        .unwrap();
+        let default_keypair = Keypair::new();
+        endpoint.set_default_client_config(get_client_config(
+            client_keypair.unwrap_or(&default_keypair),
+        ));
+        endpoint
+    }
+
+    pub async fn make_client_connection(
+        addr: &SocketAddr,
+        client_keypair: Option<&Keypair>,
+    ) -> Connection {
+        make_client_endpoint(client_keypair)
+            .connect(*addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap()
+    }
+
+    pub fn setup_quic_server_with_config(
+        quic_server_config: QuicServerConfig,
+    ) -> (
+        JoinHandle<()>,
+        Arc<AtomicBool>,
+        Receiver<PacketBatch>,
+        SocketAddr,
+        Arc<StreamStats>,
+    ) {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let keypair = Keypair::new();
+        let ip = "127.0.0.1".parse().unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let SpawnNonBlockingServerResult {
+            endpoints: _,
+            stats,
+            thread: handle,
+            max_concurrent_connections: _,
+            peer_stats_table: _,
+            max_streams_per_ms: _,
+            connection_limits: _,
+            connection_control: _,
+            cert_chain_pem: _,
+        } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &keypair,
+            ip,
+            sender,
+            exit.clone(),
+            1,
+            Arc::<RwLock<StakedNodes>>::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_secs(1),
+            quic_server_config,
+        )
+        .unwrap();
+        (handle, exit, receiver, server_address, stats)
+    }
+
+    pub fn setup_quic_server() -> (
+        JoinHandle<()>,
+        Arc<AtomicBool>,
+        Receiver<PacketBatch>,
+        SocketAddr,
+        Arc<StreamStats>,
+    ) {
+        setup_quic_server_with_config(QuicServerConfig::default())
+    }
+
+    /// Polls `receiver` without blocking the runtime the server runs on.
+    pub async fn recv_with_timeout<T>(receiver: &Receiver<T>, timeout: Duration) -> Option<T> {
+        let start = Instant::now();
+        loop {
+            if let Ok(item) = receiver.try_recv() {
+                return Some(item);
+            }
+            if start.elapsed() >= timeout {
+                return None;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    struct RejectClientVerification;
+
+    impl ClientCertVerifier for RejectClientVerification {
+        fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+            &[]
+        }
+
+        fn verify_client_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _now: SystemTime,
+        ) -> Result<ClientCertVerified, rustls::Error> {
+            Err(rustls::Error::General("rejected by test".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_custom_client_cert_verifier() {
+        solana_logger::setup();
+        let (t, exit, _receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                client_cert_verifier: Some(Arc::new(RejectClientVerification)),
+                ..QuicServerConfig::default()
+            });
+
+        let connecting = make_client_endpoint(None)
+            .connect(server_address, "localhost")
+            .unwrap();
+        // With TLS 1.3 the client may consider the handshake complete before the server has
+        // looked at its certificate, in which case the server closes the connection right after.
+        if let Ok(connection) = connecting.await {
+            let reason = timeout(Duration::from_secs(2), connection.closed())
+                .await
+                .unwrap();
+            assert!(!matches!(reason, quinn::ConnectionError::TimedOut));
+        }
+
+        for _ in 0..20 {
+            if stats.connection_setup_error.get() > 0 {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(stats.connection_setup_error.get(), 1);
+        assert_eq!(stats.total_new_connections.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_default_client_cert_verifier() {
+        solana_logger::setup();
+        let (t, exit, _receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                client_cert_verifier: Some(SkipClientVerification::new()),
+                ..QuicServerConfig::default()
+            });
+
+        let _connection = make_client_connection(&server_address, None).await;
+        for _ in 0..20 {
+            if stats.total_new_connections.get() > 0 {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(stats.total_new_connections.get(), 1);
+        assert_eq!(stats.connection_setup_error.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_packet_identity() {
+        solana_logger::setup();
+        let (identity_sender, identity_receiver) = unbounded();
+        let (receive_time_sender, receive_time_receiver) = unbounded();
+        let (t, exit, receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                identity_sender: Some(identity_sender),
+                receive_time_sender: Some(receive_time_sender),
+                ..QuicServerConfig::default()
+            });
+
+        let client_keypair = Keypair::new();
+        let connection = make_client_connection(&server_address, Some(&client_keypair)).await;
+        let sent_at = Instant::now();
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+
+        let packet_batch = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let identities = recv_with_timeout(&identity_receiver, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(packet_batch.len(), 1);
+        assert_eq!(identities, vec![Some(client_keypair.pubkey())]);
+        let receive_times = recv_with_timeout(&receive_time_receiver, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(receive_times.len(), 1);
+        // Stamped on arrival, the batch was only flushed once the 1s coalesce window elapsed.
+        assert!(receive_times[0] > sent_at);
+        assert!(receive_times[0].elapsed() >= Duration::from_secs(1));
+        let setup_latency = stats.connection_setup_latency_us.collect(false);
+        assert_eq!(setup_latency.count, 1);
+        assert!(setup_latency.max < sent_at.elapsed().as_micros() as u64);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_ipv6() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("[::1]:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let SpawnNonBlockingServerResult { thread, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        let mut endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            UdpSocket::bind("[::1]:0").unwrap(),
+            Arc::new(TokioRuntime),
+        )
+        .unwrap();
+        endpoint.set_default_client_config(get_client_config(&Keypair::new()));
+        let client_address = endpoint.local_addr().unwrap();
+        let connection = endpoint
+            .connect(server_address, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+        let packet_batch = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(packet_batch.len(), 1);
+        assert_eq!(packet_batch[0].meta().socket_addr(), client_address);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    // Client socket prepending the PROXY protocol header a balancer would to every datagram.
+    #[derive(Debug)]
+    struct ProxiedSocket {
+        inner: Box<dyn quinn::AsyncUdpSocket>,
+        header: Vec<u8>,
+    }
+
+    impl quinn::AsyncUdpSocket for ProxiedSocket {
+        fn poll_send(
+            &self,
+            state: &quinn::udp::UdpState,
+            cx: &mut std::task::Context,
+            transmits: &[quinn::udp::Transmit],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let transmits: Vec<_> = transmits
+                .iter()
+                .map(|transmit| {
+                    let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+                    let contents = transmit
+                        .contents
+                        .chunks(segment_size)
+                        .flat_map(|segment| [&self.header[..], segment].concat())
+                        .collect::<Vec<_>>();
+                    quinn::udp::Transmit {
+                        contents: contents.into(),
+                        segment_size: transmit
+                            .segment_size
+                            .map(|segment_size| segment_size + self.header.len()),
+                        ..transmit.clone()
+                    }
+                })
+                .collect();
+            self.inner.poll_send(state, cx, &transmits)
+        }
+
+        fn poll_recv(
+            &self,
+            cx: &mut std::task::Context,
+            bufs: &mut [std::io::IoSliceMut<'_>],
+            meta: &mut [quinn::udp::RecvMeta],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.inner.poll_recv(cx, bufs, meta)
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+    }
+
+    fn make_proxied_client_endpoint(
+        client_address: SocketAddr,
+        server_address: SocketAddr,
+    ) -> Endpoint {
+        let runtime = Arc::new(TokioRuntime);
+        let socket = runtime
+            .wrap_udp_socket(UdpSocket::bind("127.0.0.1:0").unwrap())
+            .unwrap();
+        let mut endpoint = Endpoint::new_with_abstract_socket(
+            EndpointConfig::default(),
+            None,
+            ProxiedSocket {
+                inner: socket,
+                header: crate::nonblocking::proxy_protocol::encode_header(
+                    client_address,
+                    server_address,
+                ),
+            },
+            runtime,
+        )
+        .unwrap();
+        endpoint.set_default_client_config(get_client_config(&Keypair::new()));
+        endpoint
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_proxy_protocol() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let SpawnNonBlockingServerResult {
+            thread,
+            stats,
+            connection_control,
+            ..
+        } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig {
+                proxy_protocol_trusted_proxies: Some(vec!["127.0.0.0/8".parse().unwrap()]),
+                denied_ips: vec!["10.9.0.0/16".parse().unwrap()],
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+
+        // The packets carry the address of the client behind the balancer.
+        let client_address: SocketAddr = "10.1.2.3:4567".parse().unwrap();
+        let connection = make_proxied_client_endpoint(client_address, server_address)
+            .connect(server_address, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+        let packet_batch = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(packet_batch.len(), 1);
+        assert_eq!(packet_batch[0].meta().socket_addr(), client_address);
+        assert!(
+            connection_control
+                .close_connection(ConnectionId::Address(client_address))
+                .await
+        );
+
+        // So do the ip filters.
+        let connecting =
+            make_proxied_client_endpoint("10.9.0.1:4567".parse().unwrap(), server_address)
+                .connect(server_address, "localhost")
+                .unwrap();
+        if let Ok(Ok(connection)) = timeout(Duration::from_secs(5), connecting).await {
+            assert!(timeout(Duration::from_secs(5), connection.closed())
+                .await
+                .is_ok());
+        }
+        assert_eq!(stats.connection_rejected_by_filter.get(), 1);
+        assert_eq!(stats.proxy_protocol_invalid_headers.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_packet_source_address() {
+        solana_logger::setup();
+        // A dual-stack socket sees the IPv4 client as an IPv4-mapped IPv6 address.
+        let sock = UdpSocket::bind("[::]:0").unwrap();
+        let server_address = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sock.local_addr().unwrap().port(),
+        );
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let SpawnNonBlockingServerResult { thread, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        let endpoint = make_client_endpoint(None);
+        let client_address = endpoint.local_addr().unwrap();
+        let connection = endpoint
+            .connect(server_address, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+
+        let packet_batch = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(packet_batch[0].meta().socket_addr(), client_address);
+        assert!(client_address.is_ipv4());
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_denied_ip() {
+        solana_logger::setup();
+        let (t, exit, _receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                denied_ips: vec!["127.0.0.0/8".parse().unwrap()],
+                ..QuicServerConfig::default()
+            });
+
+        let connecting = make_client_endpoint(None)
+            .connect(server_address, "localhost")
+            .unwrap();
+        // The client may finish its side of the handshake before the server's close arrives.
+        if let Ok(Ok(connection)) = timeout(Duration::from_secs(5), connecting).await {
+            assert!(timeout(Duration::from_secs(5), connection.closed())
+                .await
+                .is_ok());
+        }
+        assert_eq!(stats.connection_rejected_by_filter.get(), 1);
+        assert_eq!(stats.total_new_connections.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_full_channel_policy_block() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = bounded_packet_channel(1);
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig {
+                full_channel_policy: FullChannelPolicy::Block(Duration::from_secs(10)),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+
+        let connection = make_client_connection(&server_address, None).await;
+        let num_packets = 3;
+        for _ in 0..num_packets {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        // Nothing is drained until every packet reached the server, the batches beyond the
+        // channel's capacity wait for room instead of being dropped. The streams are read all the
+        // same, their packets wait in front of the blocked batcher.
+        assert_eq!(stats.packet_channel_depth.get(), 1);
+        assert_eq!(stats.total_packets_sent_for_batching.get(), num_packets);
+        assert_eq!(stats.total_streams.get(), 0);
+        let mut total_packets = 0;
+        while total_packets < num_packets {
+            let packets = recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap();
+            total_packets += packets.len();
+        }
+        assert_eq!(total_packets, num_packets);
+        assert_eq!(stats.total_packet_batch_send_err.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_async_sender() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let SpawnNonBlockingServerResult { thread, .. } = spawn_server_with_async_sender(
+            "quic_streamer_test",
+            vec![sock],
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        let connection = make_client_connection(&server_address, None).await;
+        for _ in 0..2 {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        }
+        let mut num_packets = 0;
+        while num_packets < 2 {
+            let packet_batch = timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            num_packets += packet_batch.len();
+        }
+        assert_eq!(num_packets, 2);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_multiple_sockets() {
+        solana_logger::setup();
+        let sockets: Vec<_> = (0..3)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let SpawnNonBlockingServerResult {
+            endpoints,
+            stats,
+            thread,
+            ..
+        } = spawn_server_multi(
+            "quic_streamer_test",
+            sockets,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            3,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(endpoints.len(), 3);
+        assert_eq!(stats.active_endpoints.get(), 3);
+
+        // Every endpoint takes connections and delivers their packets to the shared sender.
+        let mut connections = Vec::new();
+        for (i, endpoint) in endpoints.iter().enumerate() {
+            let connection = make_client_connection(&endpoint.local_addr().unwrap(), None).await;
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[i as u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+            let packets = recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].data(..), Some(&[i as u8; 10][..]));
+            connections.push(connection);
+        }
+        assert_eq!(stats.connection_added_from_unstaked_peer.get(), 3);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_async_sender_stalled_consumer() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server_with_async_sender(
+            "quic_streamer_test",
+            vec![sock],
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        // The receiver is never drained, the second batch waits for room that never comes.
+        let connection = make_client_connection(&server_address, None).await;
+        for _ in 0..3 {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(stats.total_packet_batches_sent.get(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        timeout(Duration::from_secs(5), thread)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stats.total_packet_batch_send_err.get() > 0);
+    }
+
+    fn new_connection(stake: u64) -> EvictedFor {
+        EvictedFor::NewConnection {
+            remote_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8000),
+            stake,
+        }
+    }
+
+    #[test]
+    fn test_prune_for_stake_tier() {
+        let stake_tiers = Arc::new(StakeTiers::new(vec![StakeTier {
+            min_stake: 100,
+            max_connections: 2,
+        }]));
+        let mut table = ConnectionTable::new(None).with_stake_tiers(Some(stake_tiers.clone()));
+        let max_staked_connections = 4;
+        let add = |table: &mut ConnectionTable, stake: u64| {
+            assert_eq!(
+                table.prune_for_stake_tier(
+                    &stake_tiers,
+                    stake,
+                    max_staked_connections,
+                    new_connection(stake)
+                ),
+                0
+            );
+            table
+                .try_add_connection(
+                    ConnectionTableKey::Pubkey(Pubkey::new_unique()),
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+                    None,
+                    ConnectionPeerType::Staked(stake),
+                    0,
+                    1,
+                    false,
+                )
+                .unwrap();
+        };
+        for stake in [10, 20, 100, 200] {
+            add(&mut table, stake);
+        }
+        assert_eq!(stake_tiers.snapshot(), vec![2, 2]);
+
+        // A full tier only gives way to a higher stake of the same tier.
+        assert_eq!(
+            table.prune_for_stake_tier(&stake_tiers, 5, max_staked_connections, new_connection(5)),
+            0
+        );
+        assert_eq!(
+            table.prune_for_stake_tier(
+                &stake_tiers,
+                300,
+                max_staked_connections,
+                new_connection(300)
+            ),
+            1
+        );
+        assert_eq!(stake_tiers.snapshot(), vec![2, 1]);
+        assert_eq!(table.total_size, 3);
+
+        // With the table capped at 3, the bottom tier is over its single slot and gives way to
+        // the top tier, which still has room.
+        assert_eq!(
+            table.prune_for_stake_tier(&stake_tiers, 150, 3, new_connection(150)),
+            1
+        );
+        assert_eq!(stake_tiers.snapshot(), vec![1, 1]);
+        assert_eq!(table.total_size, 2);
+
+        assert_eq!(
+            table.full_reason(10, 2),
+            "stake tier 0 is full without a connection of lower stake to evict"
+        );
+        assert_eq!(
+            table.full_reason(150, 2),
+            "the table is full (2/2) without a stake tier over its share to evict from"
+        );
+        assert_eq!(
+            ConnectionTable::new(None).full_reason(10, 0),
+            "the table is full (0/0) without a connection the LowestStake eviction policy gives \
+             way"
+        );
+        assert_eq!(
+            new_connection(150).to_string(),
+            "a new connection from 127.0.0.1:8000 with stake 150"
+        );
+    }
+
+    #[test]
+    fn test_prune_least_recently_used_unstaked() {
+        let mut table = ConnectionTable::new(None);
+        let mut add = |key, peer_type, last_update| {
+            table
+                .try_add_connection(
+                    key,
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+                    None,
+                    peer_type,
+                    last_update,
+                    2,
+                    false,
+                )
+                .unwrap();
+        };
+        let ip = |octet| ConnectionTableKey::IP(IpAddr::V4(Ipv4Addr::new(10, 0, 0, octet)));
+        // A staked peer that overflowed into the unstaked table, used least recently of all.
+        add(
+            ConnectionTableKey::Pubkey(Pubkey::new_unique()),
+            ConnectionPeerType::Staked(10),
+            1,
+        );
+        // An address is as recently used as its most recently used connection.
+        add(ip(1), ConnectionPeerType::Unstaked, 2);
+        add(ip(1), ConnectionPeerType::Unstaked, 5);
+        add(ip(2), ConnectionPeerType::Unstaked, 3);
+
+        assert_eq!(
+            table.prune_least_recently_used_unstaked(new_connection(10)),
+            1
+        );
+        assert!(!table.table.contains_key(&ip(2)));
+        assert_eq!(
+            table.prune_least_recently_used_unstaked(new_connection(10)),
+            2
+        );
+        assert_eq!(
+            table.prune_least_recently_used_unstaked(new_connection(10)),
+            0
+        );
+        assert_eq!(table.total_size, 1);
+    }
+
+    #[test]
+    fn test_prune_for_stake_eviction_policy() {
+        let make_table = |eviction_policy| {
+            let mut table = ConnectionTable::new(None).with_eviction_policy(eviction_policy);
+            let keys: Vec<_> = (1..=3)
+                .map(|connected_at| {
+                    let key = ConnectionTableKey::Pubkey(Pubkey::new_unique());
+                    let (last_update, _, _, _) = table
+                        .try_add_connection(
+                            key,
+                            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+                            None,
+                            ConnectionPeerType::Staked(10),
+                            connected_at,
+                            1,
+                            false,
+                        )
+                        .unwrap();
+                    (key, last_update)
+                })
+                .collect();
+            // The first connection is also the most recently used one.
+            keys[0].1.store(10, Ordering::Relaxed);
+            (table, keys)
+        };
+
+        let (mut table, keys) = make_table(EvictionPolicy::LeastRecentlyUsed);
+        assert_eq!(table.prune_for_stake(5, new_connection(5)), 0);
+        assert_eq!(table.prune_for_stake(10, new_connection(10)), 1);
+        assert!(!table.table.contains_key(&keys[1].0));
+
+        let (mut table, keys) = make_table(EvictionPolicy::OldestConnection);
+        assert_eq!(table.prune_for_stake(10, new_connection(10)), 1);
+        assert!(!table.table.contains_key(&keys[0].0));
+        assert_eq!(table.total_size, 2);
+
+        // Equal stakes never evict each other with the default policy.
+        let (mut table, _) = make_table(EvictionPolicy::LowestStake);
+        assert_eq!(table.prune_for_stake(10, new_connection(10)), 0);
+    }
+
+    #[tokio::test]
+    async fn test_handle_chunk_invalid_chunks() {
+        let stats = Arc::new(StreamStats::default());
+        let (packet_sender, packet_receiver) = async_unbounded();
+        let remote_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8000);
+        let chunk = |offset: usize, len: usize| {
+            Some(quinn::Chunk {
+                offset: offset as u64,
+                bytes: Bytes::from(vec![1u8; len]),
+            })
+        };
+        // Feeds the chunks of a single stream, returns whether each one ended the stream.
+        let handle_stream = |chunks: Vec<Option<quinn::Chunk>>| {
+            let stats = stats.clone();
+            let packet_sender = packet_sender.clone();
+            async move {
+                let mut packet_accum = None;
+                let mut done = vec![];
+                for maybe_chunk in chunks {
+                    done.push(
+                        handle_chunk(
+                            maybe_chunk,
+                            &mut packet_accum,
+                            &remote_addr,
+                            &packet_sender,
+                            stats.clone(),
+                            ConnectionPeerType::Unstaked,
+                            None,
+                            None,
+                            None,
+                        )
+                        .await,
+                    );
+                }
+                done
+            }
+        };
+
+        assert_eq!(
+            handle_stream(vec![chunk(0, 10), chunk(10, 0), chunk(10, 10), None]).await,
+            vec![false, false, false, true]
+        );
+        assert_eq!(packet_receiver.try_recv().unwrap().meta.size, 20);
+        assert_eq!(stats.stream_bytes.collect(false).max, 20);
+
+        assert_eq!(
+            handle_stream(vec![chunk(0, PACKET_DATA_SIZE + 1)]).await,
+            vec![true]
+        );
+        assert_eq!(
+            handle_stream(vec![chunk(PACKET_DATA_SIZE - 1, 2)]).await,
+            vec![true]
+        );
+        // A gap in the stream.
+        assert_eq!(
+            handle_stream(vec![chunk(0, 10), chunk(20, 10)]).await,
+            vec![false, true]
+        );
+        // A packet growing past the limit is dropped along with the chunks read so far.
+        assert_eq!(
+            handle_stream(vec![chunk(0, 10), chunk(10, PACKET_DATA_SIZE - 9)]).await,
+            vec![false, true]
+        );
+        assert!(packet_receiver.is_empty());
+
+        assert_eq!(stats.packets_rejected_oversize.get(), 3);
+        assert_eq!(stats.invalid_chunk_oversize.get(), 3);
+        assert_eq!(stats.total_invalid_chunks.get(), 3);
+        assert_eq!(stats.total_invalid_chunk_size.get(), 2);
+        assert_eq!(stats.invalid_chunk_empty.get(), 1);
+        assert_eq!(stats.invalid_chunk_count.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_oversize_packet() {
+        solana_logger::setup();
+        // Keeps the stats from being reset while they're checked.
+        let (t, exit, receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                report_interval: Some(Duration::from_secs(3600)),
+                ..QuicServerConfig::default()
+            });
+        let connection = make_client_connection(&server_address, None).await;
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream
+            .write_all(&[1u8; PACKET_DATA_SIZE + 1])
+            .await
+            .unwrap();
+        // The server may stop the stream before it's acknowledged.
+        let _ = send_stream.finish().await;
+        let start = Instant::now();
+        while stats.packets_rejected_oversize.get() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(10)).await;
+        }
+        sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(stats.packets_rejected_oversize.get(), 1);
+        assert_eq!(stats.total_packets_sent_for_batching.get(), 0);
+        // Only the batch the batcher starts with is allocated.
+        assert_eq!(stats.total_packet_batches_allocated.get(), 1);
+        assert_eq!(stats.total_packets_allocated.get(), PACKETS_PER_BATCH);
+        assert!(receiver.is_empty());
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[test]
+    fn test_prune_skips_protected_connections() {
+        let mut table =
+            ConnectionTable::new(None).with_eviction_policy(EvictionPolicy::OldestConnection);
+        let protected_key = ConnectionTableKey::Pubkey(Pubkey::new_unique());
+        for (key, stake, protected) in [
+            (protected_key, 1, true),
+            (ConnectionTableKey::Pubkey(Pubkey::new_unique()), 5, false),
+        ] {
+            table
+                .try_add_connection(
+                    key,
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+                    None,
+                    ConnectionPeerType::Staked(stake),
+                    stake,
+                    1,
+                    protected,
+                )
+                .unwrap();
+        }
+
+        // Only the unprotected connection is evicted, though it has the higher stake.
+        assert_eq!(table.prune_for_stake(10, new_connection(10)), 1);
+        assert_eq!(table.prune_for_stake(10, new_connection(10)), 0);
+        assert_eq!(table.prune_random(4, 10, new_connection(10)), 0);
+        assert_eq!(table.prune_oldest(0, EvictedFor::LoweredLimit), 0);
+        assert!(table.table.contains_key(&protected_key));
+        assert_eq!(table.total_size, 1);
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_protected_peers() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let protected_keypair = Keypair::new();
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            // No unstaked connections are admitted, except for the protected peer.
+            0,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig {
+                protected_peers: HashSet::from([protected_keypair.pubkey()]),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+
+        let connection = make_client_connection(&server_address, None).await;
+        assert!(timeout(Duration::from_secs(5), connection.closed())
+            .await
+            .is_ok());
+
+        let connection = make_client_connection(&server_address, Some(&protected_keypair)).await;
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+        let packets = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(stats.connection_added_protected.get(), 1);
+        assert_eq!(stats.connection_added_from_unstaked_peer.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sample_connection_stats() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let SpawnNonBlockingServerResult {
+            thread,
+            stats,
+            connection_control,
+            ..
+        } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            2,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        let connection = make_client_connection(&server_address, None).await;
+        let _idle_connection = make_client_connection(&server_address, None).await;
+        for _ in 0..2 {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        }
+        let mut packets = 0;
+        while packets < 2 {
+            packets += recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap()
+                .len();
+        }
+        sleep(Duration::from_millis(200)).await;
+        sample_connection_stats(&connection_control, &stats, Duration::from_secs(2)).await;
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.sampled_connections, 2);
+        // Over the two seconds passed as the interval, the idle connection isn't counted.
+        assert_eq!(snapshot.connection_packets_per_second_max, 1);
+        assert_eq!(snapshot.connection_packets_per_second_min, 1);
+        assert!(snapshot.connection_rtt_min_us <= snapshot.connection_rtt_avg_us);
+        assert!(snapshot.connection_rtt_avg_us <= snapshot.connection_rtt_max_us);
+        assert!(snapshot.connection_rtt_max_us < 1_000_000);
+
+        // Only the packets sent since the last sample are counted.
+        sample_connection_stats(&connection_control, &stats, Duration::from_secs(2)).await;
+        assert_eq!(stats.connection_packets_per_second_max.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_update_connection_limits() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let SpawnNonBlockingServerResult {
+            thread,
+            stats,
+            connection_limits,
+            ..
+        } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            0,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        // Unstaked connections are turned away while their cap is zero.
+        let connection = make_client_connection(&server_address, None).await;
+        assert!(timeout(Duration::from_secs(5), connection.closed())
+            .await
+            .is_ok());
+        assert_eq!(stats.connection_rejected_unstaked_disabled.get(), 1);
+
+        connection_limits.set_max_unstaked_connections(2);
+        let connection = make_client_connection(&server_address, None).await;
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+        let packet_batch = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(packet_batch.len(), 1);
+
+        // Lowering the cap again evicts the connection.
+        connection_limits.set_max_unstaked_connections(0);
+        assert!(timeout(Duration::from_secs(5), connection.closed())
+            .await
+            .is_ok());
+        assert_eq!(stats.num_evictions.get(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_close_connection() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let SpawnNonBlockingServerResult {
+            thread,
+            stats,
+            connection_control,
+            ..
+        } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            2,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        let client_keypair = Keypair::new();
+        let by_pubkey = make_client_connection(&server_address, Some(&client_keypair)).await;
+        let endpoint = make_client_endpoint(None);
+        let client_address = endpoint.local_addr().unwrap();
+        let by_address = endpoint
+            .connect(server_address, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if stats.connection_added_from_unstaked_peer.get() == 2 {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        assert!(
+            !connection_control
+                .close_connection(ConnectionId::Pubkey(Pubkey::new_unique()))
+                .await
+        );
+        assert!(
+            connection_control
+                .close_connection(ConnectionId::Pubkey(client_keypair.pubkey()))
+                .await
+        );
+        assert!(timeout(Duration::from_secs(5), by_pubkey.closed())
+            .await
+            .is_ok());
+        assert!(
+            connection_control
+                .close_connection(ConnectionId::Address(client_address))
+                .await
+        );
+        assert!(timeout(Duration::from_secs(5), by_address.closed())
+            .await
+            .is_ok());
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_ban() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let SpawnNonBlockingServerResult {
+            thread,
+            stats,
+            connection_control,
+            ..
+        } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+        // The client may finish its side of the handshake before the server's close arrives.
+        let assert_rejected = |client_keypair: Option<&Keypair>| {
+            let connecting = make_client_endpoint(client_keypair)
+                .connect(server_address, "localhost")
+                .unwrap();
+            async move {
+                if let Ok(Ok(connection)) = timeout(Duration::from_secs(5), connecting).await {
+                    assert!(timeout(Duration::from_secs(5), connection.closed())
+                        .await
+                        .is_ok());
+                }
+            }
+        };
+
+        let client_keypair = Keypair::new();
+        let peer = BannedPeer::Pubkey(client_keypair.pubkey());
+        let connection = make_client_connection(&server_address, Some(&client_keypair)).await;
+        sleep(Duration::from_millis(200)).await;
+        connection_control.ban(peer, Duration::from_secs(60)).await;
+        assert!(timeout(Duration::from_secs(5), connection.closed())
+            .await
+            .is_ok());
+        assert_rejected(Some(&client_keypair)).await;
+        assert_eq!(stats.connection_rejected_banned.get(), 1);
+        assert!(connection_control.unban(peer));
+        drop(make_client_connection(&server_address, Some(&client_keypair)).await);
+
+        // IP bans lapse on their own.
+        connection_control
+            .ban(
+                BannedPeer::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                Duration::from_millis(500),
+            )
+            .await;
+        assert_rejected(None).await;
+        assert_eq!(stats.connection_rejected_banned.get(), 2);
+        sleep(Duration::from_millis(500)).await;
+        let connection = make_client_connection(&server_address, None).await;
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_packet_stake() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let (stake_sender, stake_receiver) = unbounded();
+        let staked_keypair = Keypair::new();
+        let stakes = HashMap::from([(staked_keypair.pubkey(), 1_000_000)]);
+        let staked_nodes = Arc::new(RwLock::new(StakedNodes::new(
+            Arc::new(stakes),
+            HashMap::default(),
+        )));
+        let SpawnNonBlockingServerResult { thread, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            staked_nodes,
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig {
+                stake_sender: Some(stake_sender),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+
+        for (keypair, stake) in [(&staked_keypair, 1_000_000), (&Keypair::new(), 0)] {
+            let connection = make_client_connection(&server_address, Some(keypair)).await;
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+
+            let packet_batch = recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap();
+            let stakes = recv_with_timeout(&stake_receiver, Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(packet_batch.len(), 1);
+            assert_eq!(packet_batch[0].meta().is_from_staked_node(), stake > 0);
+            assert_eq!(stakes, vec![stake]);
+        }
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_staked_packet_sink() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let (staked_sender, staked_receiver) = unbounded();
+        let staked_keypair = Keypair::new();
+        let stakes = HashMap::from([(staked_keypair.pubkey(), 1_000_000)]);
+        let staked_nodes = Arc::new(RwLock::new(StakedNodes::new(
+            Arc::new(stakes),
+            HashMap::default(),
+        )));
+        let SpawnNonBlockingServerResult { thread, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            staked_nodes,
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig {
+                staked_packet_sink: Some(Arc::new(staked_sender)),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+
+        for keypair in [&staked_keypair, &Keypair::new()] {
+            let connection = make_client_connection(&server_address, Some(keypair)).await;
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        }
+        let staked_batch = recv_with_timeout(&staked_receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let unstaked_batch = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(staked_batch.len(), 1);
+        assert!(staked_batch[0].meta().is_from_staked_node());
+        assert_eq!(unstaked_batch.len(), 1);
+        assert!(!unstaked_batch[0].meta().is_from_staked_node());
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_max_concurrent_uni_streams() {
+        solana_logger::setup();
+        let (t, exit, receiver, server_address, _stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                max_concurrent_uni_streams: Some(1),
+                ..QuicServerConfig::default()
+            });
+
+        let connection = make_client_connection(&server_address, None).await;
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        // The limit still applies once the connection is admitted.
+        sleep(Duration::from_millis(200)).await;
+        assert!(timeout(Duration::from_millis(200), connection.open_uni())
+            .await
+            .is_err());
+
+        send_stream.finish().await.unwrap();
+        let mut send_stream = timeout(Duration::from_secs(5), connection.open_uni())
+            .await
+            .unwrap()
+            .unwrap();
+        send_stream.write_all(&[2u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+        let mut total_packets = 0;
+        while total_packets < 2 {
+            total_packets += recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap()
+                .len();
+        }
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_max_idle_timeout() {
+        solana_logger::setup();
+        let (t, exit, _receiver, server_address, _stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                max_idle_timeout: Some(Duration::from_millis(500)),
+                ..QuicServerConfig::default()
+            });
+
+        // Without the keep-alives of the test client, only the server's timeout applies.
+        let mut client_config = get_client_config(&Keypair::new());
+        client_config.transport_config(Arc::new(quinn::TransportConfig::default()));
+        let mut endpoint = make_client_endpoint(None);
+        endpoint.set_default_client_config(client_config);
+        let connection = endpoint
+            .connect(server_address, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let reason = timeout(Duration::from_secs(5), connection.closed())
+            .await
+            .unwrap();
+        assert_matches!(reason, quinn::ConnectionError::TimedOut);
+        // Well before the default of QUIC_MAX_TIMEOUT.
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert!(start.elapsed() < Duration::from_millis(1500));
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_retry_token_lifetime() {
+        solana_logger::setup();
+        for (retry_token_lifetime, accepted) in [
+            (Duration::from_secs(60), true),
+            // Expired by the time the client presents it.
+            (Duration::from_nanos(1), false),
+        ] {
+            let (t, exit, _receiver, server_address, _stats) =
+                setup_quic_server_with_config(QuicServerConfig {
+                    use_retry: true,
+                    retry_token_lifetime: Some(retry_token_lifetime),
+                    ..QuicServerConfig::default()
+                });
+            let result = make_client_endpoint(None)
+                .connect(server_address, "localhost")
+                .unwrap()
+                .await;
+            if accepted {
+                assert!(result.is_ok());
+            } else {
+                assert_matches!(
+                    result,
+                    Err(quinn::ConnectionError::ConnectionClosed(close))
+                        if close.error_code == quinn_proto::TransportErrorCode::INVALID_TOKEN
+                );
+            }
+
+            exit.store(true, Ordering::Relaxed);
+            t.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_connection_bandwidth() {
+        solana_logger::setup();
+        let (t, exit, receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                max_unstaked_connection_bandwidth: Some(4_000),
+                ..QuicServerConfig::default()
+            });
+
+        // 12 packets of 1000 bytes go out in a burst of 4000 bytes and 2 seconds worth of
+        // 4000 bytes per second.
+        let start = Instant::now();
+        let connection = make_client_connection(&server_address, None).await;
+        for _ in 0..12 {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 1000]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        }
+        let mut total_packets = 0;
+        while total_packets < 12 {
+            total_packets += recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap()
+                .len();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(1_500));
+        assert!(stats.bandwidth_throttled_streams.get() > 0);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_global_bandwidth() {
+        solana_logger::setup();
+        let (t, exit, receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                max_bandwidth: Some(8_000),
+                ..QuicServerConfig::default()
+            });
+
+        // The cap lets 4000 bytes of an unstaked burst through, leaving the staked reserve of
+        // 4000 bytes, and then 8000 bytes per second once the reserve has refilled.
+        let start = Instant::now();
+        let connection = make_client_connection(&server_address, None).await;
+        for _ in 0..12 {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 1000]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        }
+        let mut total_packets = 0;
+        while total_packets < 12 {
+            total_packets += recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap()
+                .len();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(750));
+        assert!(stats.global_bandwidth_throttled.get() > 0);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_max_stream_timeouts_before_close() {
+        solana_logger::setup();
+        let (t, exit, _receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                max_stream_timeouts_before_close: Some(2),
+                ..QuicServerConfig::default()
+            });
+
+        // Two streams stall past the chunk timeout without finishing.
+        let connection = make_client_connection(&server_address, None).await;
+        let mut send_streams = Vec::new();
+        for _ in 0..2 {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_streams.push(send_stream);
+        }
+        let err = timeout(Duration::from_secs(5), connection.closed())
+            .await
+            .unwrap();
+        assert_matches!(
+            err,
+            quinn::ConnectionError::ApplicationClosed(close)
+                if close.reason.as_ref() == CONNECTION_CLOSE_REASON_STREAM_TIMEOUTS
+        );
+        assert_eq!(stats.total_stream_read_timeouts.get(), 2);
+        assert_eq!(stats.connection_closed_stream_timeouts.get(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[test]
+    fn test_compute_recieve_window() {
+        let window = |peer_type, receive_window_base| {
+            compute_recieve_window(1_000, 10, peer_type, receive_window_base)
+                .unwrap()
+                .into_inner()
+        };
+        assert_eq!(
+            window(ConnectionPeerType::Unstaked, PACKET_DATA_SIZE as u64),
+            PACKET_DATA_SIZE as u64 * QUIC_UNSTAKED_RECEIVE_WINDOW_RATIO
+        );
+        assert_eq!(
+            window(ConnectionPeerType::Staked(1_000), PACKET_DATA_SIZE as u64),
+            PACKET_DATA_SIZE as u64 * QUIC_MAX_STAKED_RECEIVE_WINDOW_RATIO
+        );
+        // A larger base scales the windows of every peer type.
+        assert_eq!(
+            window(ConnectionPeerType::Unstaked, 4_096),
+            4_096 * QUIC_UNSTAKED_RECEIVE_WINDOW_RATIO
+        );
+        assert_eq!(
+            window(ConnectionPeerType::Staked(10), 4_096),
+            4_096 * QUIC_MIN_STAKED_RECEIVE_WINDOW_RATIO
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_max_chunks_per_stream() {
+        solana_logger::setup();
+        let (t, exit, receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                max_chunks_per_stream: Some(2),
+                ..QuicServerConfig::default()
+            });
+
+        let connection = make_client_connection(&server_address, None).await;
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+        let packets = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(packets.len(), 1);
+
+        // A stream dribbled one byte at a time is dropped after its second chunk.
+        let mut send_stream = connection.open_uni().await.unwrap();
+        let mut stopped = false;
+        for _ in 0..20 {
+            if send_stream.write_all(&[1u8]).await.is_err() {
+                stopped = true;
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        assert!(stopped);
+        assert_eq!(stats.streams_reset_chunk_limit.get(), 1);
+        assert_eq!(stats.total_streams.get(), 0);
+        assert!(receiver.try_recv().is_err());
+        // The chunks of the dropped stream still count as received, at least a byte each.
+        assert!(stats.total_bytes_received.get() >= 13);
+        assert_eq!(stats.total_bytes_sent_for_batching.get(), 10);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_max_concurrent_reads() {
+        solana_logger::setup();
+        let (t, exit, receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                max_concurrent_reads: Some(1),
+                max_connections_per_unstaked_peer: Some(2),
+                ..QuicServerConfig::default()
+            });
+
+        // The streams of both connections take turns, and every turn is given back.
+        let connections = [
+            make_client_connection(&server_address, None).await,
+            make_client_connection(&server_address, None).await,
+        ];
+        let mut packets = 0;
+        for round in 0..2 {
+            let senders = connections.iter().map(|connection| async move {
+                for _ in 0..10 {
+                    let mut send_stream = connection.open_uni().await.unwrap();
+                    send_stream.write_all(&[1u8; 10]).await.unwrap();
+                    send_stream.finish().await.unwrap();
+                }
+            });
+            futures_util::future::join_all(senders).await;
+            while packets < 20 * (round + 1) {
+                packets += recv_with_timeout(&receiver, Duration::from_secs(5))
+                    .await
+                    .unwrap()
+                    .len();
+            }
+        }
+        assert_eq!(stats.total_packets_sent_for_batching.get(), 40);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_stream_idle_timeout() {
+        solana_logger::setup();
+        let (t, exit, _receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                max_concurrent_uni_streams: Some(1),
+                stream_idle_timeout: Some(Duration::from_millis(200)),
+                ..QuicServerConfig::default()
+            });
+
+        // The stalled stream is reaped well before the chunk timeout.
+        let connection = make_client_connection(&server_address, None).await;
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(stats.streams_reaped_idle.get(), 1);
+        assert_eq!(stats.total_stream_read_timeouts.get(), 1);
+        assert_eq!(stats.total_streams.get(), 0);
+
+        // Its slot frees up once the peer resets the stream the server stopped.
+        assert_matches!(
+            send_stream.write_all(&[1u8; 10]).await,
+            Err(quinn::WriteError::Stopped(_))
+        );
+        send_stream.reset(0u32.into()).unwrap();
+        let _send_stream = timeout(Duration::from_secs(1), connection.open_uni())
+            .await
+            .unwrap()
+            .unwrap();
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_uni_stream_limits_per_peer_type() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let staked_keypair = Keypair::new();
+        let stakes = HashMap::from([(staked_keypair.pubkey(), 1_000_000)]);
+        let staked_nodes = Arc::new(RwLock::new(StakedNodes::new(
+            Arc::new(stakes),
+            HashMap::default(),
+        )));
+        let SpawnNonBlockingServerResult {
+            thread,
+            stats,
+            connection_control,
+            ..
+        } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            staked_nodes,
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig {
+                // The handshake limit, which the staked limit raises.
+                max_concurrent_uni_streams: Some(1),
+                max_staked_concurrent_uni_streams: Some(3),
+                max_unstaked_concurrent_uni_streams: Some(1),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+
+        let mut connections = vec![];
+        for (keypair, limit) in [(&staked_keypair, 3), (&Keypair::new(), 1)] {
+            let connection = make_client_connection(&server_address, Some(keypair)).await;
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            // Keep the streams open so that they count against the limit.
+            let mut open_streams = vec![send_stream];
+            sleep(Duration::from_millis(200)).await;
+            for _ in 1..limit {
+                open_streams.push(
+                    timeout(Duration::from_secs(1), connection.open_uni())
+                        .await
+                        .unwrap()
+                        .unwrap(),
+                );
+            }
+            assert!(timeout(Duration::from_millis(200), connection.open_uni())
+                .await
+                .is_err());
+
+            for mut send_stream in open_streams {
+                send_stream.finish().await.unwrap();
+            }
+            recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap();
+            connections.push(connection);
+        }
+        sample_connection_stats(&connection_control, &stats, Duration::from_secs(1)).await;
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.staked_uni_streams_limit_min, 3);
+        assert_eq!(snapshot.staked_uni_streams_limit_max, 3);
+        assert_eq!(snapshot.unstaked_uni_streams_limit_min, 1);
+        assert_eq!(snapshot.unstaked_uni_streams_limit_max, 1);
+
+        // The limits of closed connections no longer count.
+        drop(connections);
+        sleep(Duration::from_millis(200)).await;
+        sample_connection_stats(&connection_control, &stats, Duration::from_secs(1)).await;
+        assert_eq!(stats.staked_uni_streams_limit_max.get(), 0);
+        assert_eq!(stats.unstaked_uni_streams_limit_max.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_is_healthy() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let server = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            DEFAULT_TPU_COALESCE,
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+        assert!(server.is_healthy());
+        assert_eq!(server.last_packet_received(), None);
+
+        // An endpoint that stops on its own is no longer accepting connections, while the
+        // server keeps running until the exit.
+        server.endpoints[0].close(0u32.into(), b"");
+        timeout(Duration::from_secs(5), async {
+            while server.is_healthy() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(server.stats.active_endpoints.get(), 0);
+        assert!(!server.thread.is_finished());
+
+        exit.store(true, Ordering::Relaxed);
+        server.thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_staked_overflow() {
+        solana_logger::setup();
+        for staked_overflow in [StakedOverflow::EvictUnstaked, StakedOverflow::Strict] {
+            let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let server_address = sock.local_addr().unwrap();
+            let exit = Arc::new(AtomicBool::new(false));
+            let (sender, _receiver) = unbounded();
+            let whales: Vec<_> = repeat_with(Keypair::new).take(3).collect();
+            let minnow = Keypair::new();
+            let stakes: Vec<_> = whales
+                .iter()
+                .map(|whale| (whale.pubkey(), 1_000_000))
+                .chain([(minnow.pubkey(), 500_000)])
+                .collect();
+            let staked_nodes = Arc::new(RwLock::new(StakedNodes::new_for_test(&stakes)));
+            let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+                "quic_streamer_test",
+                sock,
+                &Keypair::new(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                sender,
+                exit.clone(),
+                1,
+                staked_nodes,
+                whales.len(),
+                1,
+                DEFAULT_MAX_STREAMS_PER_MS,
+                DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+                Duration::from_millis(1),
+                QuicServerConfig {
+                    staked_overflow,
+                    ..QuicServerConfig::default()
+                },
+            )
+            .unwrap();
+
+            let unstaked = make_client_connection(&server_address, None).await;
+            let mut whale_connections = Vec::new();
+            for whale in &whales {
+                whale_connections.push(make_client_connection(&server_address, Some(whale)).await);
+            }
+            sleep(Duration::from_millis(200)).await;
+            assert_eq!(stats.connection_added_from_unstaked_peer.get(), 1);
+            assert_eq!(stats.connection_added_from_staked_peer.get(), 3);
+
+            // The minnow can't evict the whales out of the full staked table.
+            let minnow_connection = make_client_connection(&server_address, Some(&minnow)).await;
+            let (evicted, rejected) = match staked_overflow {
+                StakedOverflow::EvictUnstaked => (&unstaked, &minnow_connection),
+                StakedOverflow::Strict => (&minnow_connection, &unstaked),
+            };
+            timeout(Duration::from_secs(5), evicted.closed())
+                .await
+                .unwrap();
+            sleep(Duration::from_millis(200)).await;
+            assert!(rejected.close_reason().is_none());
+            for whale_connection in &whale_connections {
+                assert!(whale_connection.close_reason().is_none());
+            }
+            let evictions = match staked_overflow {
+                StakedOverflow::EvictUnstaked => 1,
+                StakedOverflow::Strict => 0,
+            };
+            assert_eq!(stats.num_evictions.get(), evictions);
+            assert_eq!(stats.evicted_unstaked_for_staked.get(), evictions);
+            assert_eq!(stats.connection_add_failed_on_pruning.get(), 1 - evictions);
+
+            exit.store(true, Ordering::Relaxed);
+            thread.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_unstaked_disabled() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let staked_keypair = Keypair::new();
+        let staked_nodes = Arc::new(RwLock::new(StakedNodes::new_for_test(&[(
+            staked_keypair.pubkey(),
+            1_000_000,
+        )])));
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            staked_nodes,
+            MAX_STAKED_CONNECTIONS,
+            0,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        let unstaked = make_client_connection(&server_address, None).await;
+        let err = timeout(Duration::from_secs(5), unstaked.closed())
+            .await
+            .unwrap();
+        assert_matches!(
+            err,
+            quinn::ConnectionError::ApplicationClosed(close)
+                if close.reason.as_ref() == CONNECTION_CLOSE_REASON_DISALLOWED
+        );
+        assert_eq!(stats.connection_rejected_unstaked_disabled.get(), 1);
+        assert_eq!(stats.connection_add_failed_unstaked_node.get(), 0);
+
+        let staked = make_client_connection(&server_address, Some(&staked_keypair)).await;
+        let mut send_stream = staked.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+        let packets = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(stats.connection_added_from_staked_peer.get(), 1);
+        assert_eq!(stats.connection_rejected_unstaked_disabled.get(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    // Rejects `rejected`, demotes `demoted` and accepts the rest, recording what it was told.
+    struct TestAdmissionController {
+        rejected: Pubkey,
+        demoted: Pubkey,
+        contexts: Sender<(Option<Pubkey>, u64, bool, PoolOccupancy)>,
+    }
+
+    impl AdmissionController for TestAdmissionController {
+        fn admit(&self, context: &ConnectionContext) -> AdmissionDecision {
+            assert!(context.remote_addr.ip().is_loopback());
+            assert_eq!(context.alpn_protocol, Some(ALPN_TPU_PROTOCOL_ID));
+            self.contexts
+                .send((
+                    context.pubkey,
+                    context.stake,
+                    context.staked,
+                    context.occupancy,
+                ))
+                .unwrap();
+            match context.pubkey {
+                Some(pubkey) if pubkey == self.rejected => {
+                    AdmissionDecision::Reject("not_welcome".to_string())
+                }
+                Some(pubkey) if pubkey == self.demoted => AdmissionDecision::AcceptAsUnstaked,
+                _ => AdmissionDecision::Accept,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_admission_controller() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let (rejected, demoted, accepted) = (Keypair::new(), Keypair::new(), Keypair::new());
+        let staked_nodes = Arc::new(RwLock::new(StakedNodes::new_for_test(&[
+            (rejected.pubkey(), 1_000_000),
+            (demoted.pubkey(), 1_000_000),
+            (accepted.pubkey(), 1_000_000),
+        ])));
+        let (contexts_sender, contexts) = unbounded();
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            staked_nodes,
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig {
+                admission_controller: Some(Arc::new(TestAdmissionController {
+                    rejected: rejected.pubkey(),
+                    demoted: demoted.pubkey(),
+                    contexts: contexts_sender,
+                })),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+        let receiver = &receiver;
+        let send = |connection: Connection| async move {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+            let packets = recv_with_timeout(receiver, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(packets.len(), 1);
+            connection
+        };
+
+        let connection = make_client_connection(&server_address, Some(&rejected)).await;
+        let err = timeout(Duration::from_secs(5), connection.closed())
+            .await
+            .unwrap();
+        assert_matches!(
+            err,
+            quinn::ConnectionError::ApplicationClosed(close)
+                if close.reason.as_ref() == b"not_welcome"
+        );
+        assert_eq!(stats.connection_rejected_by_controller.get(), 1);
+        let occupancy = PoolOccupancy {
+            staked_connections: 0,
+            max_staked_connections: MAX_STAKED_CONNECTIONS,
+            unstaked_connections: 0,
+            max_unstaked_connections: MAX_UNSTAKED_CONNECTIONS,
+        };
+        assert_eq!(
+            contexts.try_recv().unwrap(),
+            (Some(rejected.pubkey()), 1_000_000, true, occupancy)
+        );
+
+        // Admitted to the unstaked table, though the peer is staked.
+        let _demoted = send(make_client_connection(&server_address, Some(&demoted)).await).await;
+        assert_eq!(stats.connection_added_from_unstaked_peer.get(), 1);
+        assert_eq!(stats.connection_added_from_staked_peer.get(), 0);
+        assert_eq!(contexts.try_recv().unwrap().3, occupancy);
+
+        let _accepted = send(make_client_connection(&server_address, Some(&accepted)).await).await;
+        assert_eq!(stats.connection_added_from_staked_peer.get(), 1);
+        assert_eq!(
+            contexts.try_recv().unwrap().3,
+            PoolOccupancy {
+                unstaked_connections: 1,
+                ..occupancy
+            }
+        );
+        assert_eq!(stats.connection_rejected_by_controller.get(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_unstaked_disabled_without_stake() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            0,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        // Refused before the server finished the handshake, whether or not the client did.
+        let connecting = make_client_endpoint(Some(&Keypair::new()))
+            .connect(server_address, "localhost")
+            .unwrap();
+        if let Ok(Ok(connection)) = timeout(Duration::from_secs(5), connecting).await {
+            assert!(timeout(Duration::from_secs(5), connection.closed())
+                .await
+                .is_ok());
+        }
+        assert_eq!(stats.connection_rejected_unstaked_disabled.get(), 1);
+        assert_eq!(stats.total_new_connections.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    struct DemotingAdmissionController;
+
+    impl AdmissionController for DemotingAdmissionController {
+        fn admit(&self, _context: &ConnectionContext) -> AdmissionDecision {
+            AdmissionDecision::AcceptAsUnstaked
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_admission_controller_demotes_protected_peer() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let protected_keypair = Keypair::new();
+        let staked_nodes = Arc::new(RwLock::new(StakedNodes::new_for_test(&[(
+            protected_keypair.pubkey(),
+            1_000_000,
+        )])));
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            staked_nodes,
+            MAX_STAKED_CONNECTIONS,
+            // Would be bypassed by the protected peer, were it not demoted.
+            0,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig {
+                protected_peers: HashSet::from([protected_keypair.pubkey()]),
+                admission_controller: Some(Arc::new(DemotingAdmissionController)),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+
+        let connection = make_client_connection(&server_address, Some(&protected_keypair)).await;
+        assert!(timeout(Duration::from_secs(5), connection.closed())
+            .await
+            .is_ok());
+        assert_eq!(stats.connection_rejected_unstaked_disabled.get(), 1);
+        assert_eq!(stats.connection_added_protected.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_connections_per_peer_type() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let staked_keypair = Keypair::new();
+        let staked_nodes = Arc::new(RwLock::new(StakedNodes::new_for_test(&[(
+            staked_keypair.pubkey(),
+            1_000_000,
+        )])));
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            staked_nodes,
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig {
+                max_connections_per_staked_peer: Some(3),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+
+        for (keypair, limit) in [(Some(&staked_keypair), 3), (None, 1)] {
+            let mut connections = Vec::new();
+            for _ in 0..limit {
+                connections.push(make_client_connection(&server_address, keypair).await);
+            }
+            let rejected = make_client_connection(&server_address, keypair).await;
+            let err = timeout(Duration::from_secs(5), rejected.closed())
+                .await
+                .unwrap();
+            assert_matches!(
+                err,
+                quinn::ConnectionError::ApplicationClosed(close)
+                    if close.reason.as_ref() == CONNECTION_CLOSE_REASON_TOO_MANY
+            );
+            for connection in &connections {
+                assert!(connection.close_reason().is_none());
+            }
+        }
+        assert_eq!(stats.connection_add_failed_staked_peer_limit.get(), 1);
+        assert_eq!(stats.connection_add_failed_unstaked_peer_limit.get(), 1);
+        assert_eq!(stats.connection_add_failed.get(), 2);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    // Records the spans and events this crate emits on the test thread, along with the span
+    // each event was emitted in.
+    #[cfg(feature = "tracing")]
+    type Recorded<T> = Arc<std::sync::Mutex<Vec<T>>>;
+
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct RecordingSubscriber {
+        spans: Recorded<(&'static str, String)>,
+        events: Recorded<(String, Option<&'static str>)>,
+        entered: Recorded<u64>,
+    }
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct FieldRecorder(String);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for FieldRecorder {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 += &format!("{value:?}");
+            } else {
+                self.0 += &format!("{}={value:?} ", field.name());
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for RecordingSubscriber {
+        fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+            metadata.target().starts_with("solana_streamer")
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = FieldRecorder::default();
+            span.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata().name(), fields.0));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut message = FieldRecorder::default();
+            event.record(&mut message);
+            let span = self.entered.lock().unwrap().last().map(|&id| {
+                let spans = self.spans.lock().unwrap();
+                spans[id as usize - 1].0
+            });
+            self.events.lock().unwrap().push((message.0, span));
+        }
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &tracing::span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_quic_server_tracing_spans() {
+        let subscriber = RecordingSubscriber::default();
+        let _guard = tracing::subscriber::set_default(subscriber.clone());
+        let (t, exit, receiver, server_address, _stats) = setup_quic_server();
+
+        let client_keypair = Keypair::new();
+        let endpoint = make_client_endpoint(Some(&client_keypair));
+        let client_address = endpoint.local_addr().unwrap();
+        let connection = endpoint
+            .connect(server_address, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+        recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        connection.close(0u32.into(), b"done");
+        sleep(Duration::from_millis(500)).await;
+
+        let spans = subscriber.spans.lock().unwrap().clone();
+        let (_, fields) = spans
+            .iter()
+            .find(|(name, _)| *name == "quic_connection")
+            .unwrap();
+        assert!(
+            fields.contains(&format!("remote_addr={client_address}")),
+            "{fields}"
+        );
+        assert!(
+            fields.contains(&format!("peer={}", client_keypair.pubkey())),
+            "{fields}"
+        );
+        assert!(fields.contains("stake=0"), "{fields}");
+        assert!(fields.contains("connection_id="), "{fields}");
+        assert!(spans.iter().any(|(name, _)| *name == "quic_stream"));
+
+        let events = subscriber.events.lock().unwrap().clone();
+        for (message, span) in [
+            ("connection opened", "quic_connection"),
+            ("stream opened", "quic_stream"),
+            ("stream chunk", "quic_stream"),
+            ("stream closed", "quic_stream"),
+            ("connection closed", "quic_connection"),
+        ] {
+            assert!(
+                events
+                    .iter()
+                    .any(|event| event.0.starts_with(message) && event.1 == Some(span)),
+                "{message} in {span}: {events:?}"
+            );
+        }
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_active_connections_per_peer_type() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let staked_keypair = Keypair::new();
+        let stakes = HashMap::from([(staked_keypair.pubkey(), 1_000_000)]);
+        let staked_nodes = Arc::new(RwLock::new(StakedNodes::new(
+            Arc::new(stakes),
+            HashMap::default(),
+        )));
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            staked_nodes,
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_millis(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        let staked_connection =
+            make_client_connection(&server_address, Some(&staked_keypair)).await;
+        let _unstaked_connection = make_client_connection(&server_address, None).await;
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(stats.active_staked_connections.get(), 1);
+        assert_eq!(stats.active_unstaked_connections.get(), 1);
+
+        staked_connection.close(0u32.into(), b"done");
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(stats.active_staked_connections.get(), 0);
+        assert_eq!(stats.active_unstaked_connections.get(), 1);
+        assert_eq!(stats.total_connections.get(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    struct EchoBidiStreamHandler;
+
+    impl BidiStreamHandler for EchoBidiStreamHandler {
+        fn on_stream(
+            &self,
+            _remote_addr: SocketAddr,
+            _pubkey: Option<Pubkey>,
+            _stake: u64,
+            mut send_stream: SendStream,
+            mut recv_stream: RecvStream,
+        ) {
+            tokio::spawn(async move {
+                let request = recv_stream.read_to_end(PACKET_DATA_SIZE).await.unwrap();
+                send_stream.write_all(&request).await.unwrap();
+                send_stream.finish().await.unwrap();
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_bidi_streams() {
+        solana_logger::setup();
+        let (t, exit, receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                max_concurrent_bidi_streams: 2,
+                bidi_stream_handler: Some(Arc::new(EchoBidiStreamHandler)),
+                ..QuicServerConfig::default()
+            });
+
+        let connection = make_client_connection(&server_address, None).await;
+        for request in [b"ping".as_slice(), b"pong".as_slice()] {
+            let (mut send_stream, mut recv_stream) = connection.open_bi().await.unwrap();
+            send_stream.write_all(request).await.unwrap();
+            send_stream.finish().await.unwrap();
+            let response = timeout(
+                Duration::from_secs(5),
+                recv_stream.read_to_end(PACKET_DATA_SIZE),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(response, request);
+        }
+        assert_eq!(stats.total_bidi_streams.get(), 2);
+
+        // The uni streams still feed the packet path.
+        let mut send_stream = connection.open_uni().await.unwrap();
+        send_stream.write_all(&[1u8; 10]).await.unwrap();
+        send_stream.finish().await.unwrap();
+        let packet_batch = recv_with_timeout(&receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(packet_batch.len(), 1);
+        assert_eq!(packet_batch[0].meta().size, 10);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_datagrams() {
+        solana_logger::setup();
+        let (datagram_sender, datagram_receiver) = unbounded();
+        let (t, exit, _receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                datagram_receive_buffer_size: Some(64 * 1024),
+                datagram_sender: Some(datagram_sender),
+                ..QuicServerConfig::default()
+            });
+
+        let connection = make_client_connection(&server_address, None).await;
+        assert!(connection.max_datagram_size().is_some());
+        connection
+            .send_datagram(Bytes::from_static(b"probe"))
+            .unwrap();
+        let (remote_addr, datagram) = recv_with_timeout(&datagram_receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(remote_addr.ip().is_loopback());
+        assert_eq!(datagram, Bytes::from_static(b"probe"));
+        assert_eq!(stats.total_datagrams.get(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    struct RecordingProtocolHandler(Sender<Vec<u8>>);
+
+    impl ConnectionEventHandler for RecordingProtocolHandler {
+        fn on_protocol_negotiated(
+            &self,
+            _remote_addr: SocketAddr,
+            _pubkey: Option<Pubkey>,
+            protocol: &[u8],
+        ) {
+            self.0.send(protocol.to_vec()).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_alpn_protocols() {
+        solana_logger::setup();
+        const ALPN_V2: &[u8] = b"solana-tpu-v2";
+        let connect = |server_address: SocketAddr, alpn: &[u8]| {
+            let mut endpoint = make_client_endpoint(None);
+            endpoint.set_default_client_config(get_client_config_with_alpn(
+                &Keypair::new(),
+                vec![alpn.to_vec()],
+            ));
+            endpoint.connect(server_address, "localhost").unwrap()
+        };
+
+        let (t, exit, _receiver, server_address, stats) = setup_quic_server();
+        assert!(connect(server_address, ALPN_V2).await.is_err());
+        let start = Instant::now();
+        while stats.connection_rejected_alpn.get() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(10)).await;
+        }
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+
+        let (protocol_sender, protocol_receiver) = unbounded();
+        let (t, exit, _receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                alpn_protocols: vec![ALPN_V2.to_vec(), ALPN_TPU_PROTOCOL_ID.to_vec()],
+                connection_event_handler: Some(Arc::new(RecordingProtocolHandler(protocol_sender))),
+                ..QuicServerConfig::default()
+            });
+        for alpn in [ALPN_V2, ALPN_TPU_PROTOCOL_ID] {
+            let _connection = connect(server_address, alpn).await.unwrap();
+            let protocol = recv_with_timeout(&protocol_receiver, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(protocol, alpn);
+        }
+        assert_eq!(stats.connection_rejected_alpn.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_with_tls_appends_alpn() {
+        solana_logger::setup();
+        const ALPN_CUSTOM: &[u8] = b"custom";
+        let keypair = Keypair::new();
+        let (cert, key) =
+            new_self_signed_tls_certificate(&keypair, IpAddr::V4(Ipv4Addr::LOCALHOST)).unwrap();
+        let mut tls_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(SkipClientVerification::new())
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        tls_config.alpn_protocols = vec![ALPN_CUSTOM.to_vec()];
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let (protocol_sender, protocol_receiver) = unbounded();
+        let SpawnNonBlockingServerResult {
+            endpoints,
+            stats,
+            thread,
+            ..
+        } = spawn_server_with_tls(
+            "quic_streamer_test",
+            vec![UdpSocket::bind("127.0.0.1:0").unwrap()],
+            Arc::new(tls_config),
+            sender,
+            exit.clone(),
+            1,
+            Arc::<RwLock<StakedNodes>>::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            DEFAULT_TPU_COALESCE,
+            QuicServerConfig {
+                connection_event_handler: Some(Arc::new(RecordingProtocolHandler(protocol_sender))),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+        let server_address = endpoints[0].local_addr().unwrap();
+        let connect = |alpn: &[u8]| {
+            let mut endpoint = make_client_endpoint(None);
+            endpoint.set_default_client_config(get_client_config_with_alpn(
+                &Keypair::new(),
+                vec![alpn.to_vec()],
+            ));
+            endpoint.connect(server_address, "localhost").unwrap()
+        };
+
+        // The protocol of the supplied config is kept, the TPU one is appended to it.
+        for alpn in [ALPN_CUSTOM, ALPN_TPU_PROTOCOL_ID] {
+            let connection = connect(alpn).await.unwrap();
+            assert_eq!(get_alpn_protocol(&connection).as_deref(), Some(alpn));
+            let protocol = recv_with_timeout(&protocol_receiver, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(protocol, alpn);
+        }
+        assert!(connect(b"solana-tpu-v2").await.is_err());
+        let start = Instant::now();
+        while stats.connection_rejected_alpn.get() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_endpoint_stats() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        // Room for a single connection at the quinn layer.
+        let SpawnNonBlockingServerResult { thread, stats, .. } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+            1,
+            Arc::<RwLock<StakedNodes>>::default(),
+            0,
+            1,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_secs(1),
+            QuicServerConfig::default(),
+        )
+        .unwrap();
+
+        let _connection = make_client_connection(&server_address, None).await;
+        // The first Initial packet is answered with a retry, the second one starts the
+        // handshake.
+        assert!(stats.endpoint_initial_packets_received.get() >= 2);
+        assert!(
+            stats.endpoint_datagrams_received.get()
+                >= stats.endpoint_initial_packets_received.get()
+        );
+        assert_eq!(stats.endpoint_retry_packets_sent.get(), 1);
+        assert_eq!(stats.endpoint_connections_refused.get(), 0);
+
+        // quinn refuses the second connection before the server sees it, and before it would
+        // ask for a retry.
+        let endpoint = make_client_endpoint(None);
+        assert!(endpoint
+            .connect(server_address, "localhost")
+            .unwrap()
+            .await
+            .is_err());
+        assert_eq!(stats.endpoint_connections_refused.get(), 1);
+        assert_eq!(stats.endpoint_retry_packets_sent.get(), 1);
+        assert_eq!(stats.total_new_connections.get(), 1);
+        assert_eq!(stats.endpoint_version_negotiation_sent.get(), 0);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_sni_identities() {
+        solana_logger::setup();
+        let server_keypair = Keypair::new();
+        let sni_keypair = Arc::new(Keypair::new());
+        for sni_fallback in [SniFallback::DefaultIdentity, SniFallback::Reject] {
+            let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let server_address = sock.local_addr().unwrap();
+            let exit = Arc::new(AtomicBool::new(false));
+            let (sender, _receiver) = unbounded();
+            let SpawnNonBlockingServerResult { thread, .. } = spawn_server(
+                "quic_streamer_test",
+                sock,
+                &server_keypair,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                sender,
+                exit.clone(),
+                1,
+                Arc::<RwLock<StakedNodes>>::default(),
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+                DEFAULT_MAX_STREAMS_PER_MS,
+                DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+                Duration::from_millis(1),
+                QuicServerConfig {
+                    sni_identities: HashMap::from([(
+                        "Validator.example".to_string(),
+                        sni_keypair.clone(),
+                    )]),
+                    sni_fallback,
+                    ..QuicServerConfig::default()
+                },
+            )
+            .unwrap();
+
+            let connection = make_client_endpoint(None)
+                .connect(server_address, "validator.example")
+                .unwrap()
+                .await
+                .unwrap();
+            assert_eq!(get_remote_pubkey(&connection), Some(sni_keypair.pubkey()));
+
+            let connection = make_client_endpoint(None)
+                .connect(server_address, "localhost")
+                .unwrap()
+                .await;
+            match sni_fallback {
+                SniFallback::DefaultIdentity => assert_eq!(
+                    get_remote_pubkey(&connection.unwrap()),
+                    Some(server_keypair.pubkey())
+                ),
+                SniFallback::Reject => assert!(connection.is_err()),
+            }
+
+            exit.store(true, Ordering::Relaxed);
+            thread.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_tls_cipher_suites() {
+        solana_logger::setup();
+        let (t, exit, _receiver, server_address, _stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                tls_cipher_suites: Some(vec![rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256]),
+                tls_kx_groups: Some(vec![&rustls::kx_group::X25519]),
+                ..QuicServerConfig::default()
+            });
+        let connect = |cipher_suite| {
+            let mut crypto = rustls::ClientConfig::builder()
+                .with_cipher_suites(&[cipher_suite])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .unwrap()
+                .with_custom_certificate_verifier(SkipServerVerification::new())
+                .with_no_client_auth();
+            crypto.alpn_protocols = vec![ALPN_TPU_PROTOCOL_ID.to_vec()];
+            let mut endpoint = make_client_endpoint(None);
+            endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+            endpoint.connect(server_address, "localhost").unwrap()
+        };
+
+        assert!(connect(rustls::cipher_suite::TLS13_AES_128_GCM_SHA256)
+            .await
+            .is_err());
+        assert!(
+            connect(rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256)
+                .await
+                .is_ok()
+        );
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_alpn_rejected_after_retry() {
+        solana_logger::setup();
+        let (t, exit, _receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                tls_kx_groups: Some(vec![&rustls::kx_group::X25519]),
+                ..QuicServerConfig::default()
+            });
+        // The client only sends a key share for its preferred group, which the server answers
+        // with a HelloRetryRequest.
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_kx_groups(&[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519])
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_custom_certificate_verifier(SkipServerVerification::new())
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"solana-tpu-v2".to_vec()];
+        let mut endpoint = make_client_endpoint(None);
+        endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+        assert!(endpoint
+            .connect(server_address, "localhost")
+            .unwrap()
+            .await
+            .is_err());
+
+        let start = Instant::now();
+        while stats.connection_rejected_alpn.get() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(10)).await;
+        }
+        sleep(Duration::from_millis(100)).await;
+        // Counted once for both ClientHellos.
+        assert_eq!(stats.connection_rejected_alpn.get(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[test]
+    fn test_handle_connection_error() {
+        use quinn::ConnectionError;
+        let stats = StreamStats::default();
+        let from = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8009);
+        let errors = [
+            ConnectionError::VersionMismatch,
+            ConnectionError::TransportError(quinn_proto::TransportError {
+                code: quinn_proto::TransportErrorCode::PROTOCOL_VIOLATION,
+                frame: None,
+                reason: String::default(),
+            }),
+            ConnectionError::ConnectionClosed(quinn_proto::ConnectionClose {
+                error_code: quinn_proto::TransportErrorCode::NO_ERROR,
+                frame_type: None,
+                reason: Bytes::default(),
+            }),
+            ConnectionError::ApplicationClosed(quinn_proto::ApplicationClose {
+                error_code: VarInt::from_u32(0),
+                reason: Bytes::default(),
+            }),
+            ConnectionError::Reset,
+            ConnectionError::TimedOut,
+            ConnectionError::LocallyClosed,
+        ];
+        for error in errors {
+            handle_connection_error(error, &stats, from);
+        }
+
+        let snapshot = stats.snapshot();
+        let counters = [
+            snapshot.connection_setup_error_version_mismatch,
+            snapshot.connection_setup_error_transport,
+            snapshot.connection_setup_error_closed,
+            snapshot.connection_setup_error_app_closed,
+            snapshot.connection_setup_error_reset,
+            snapshot.connection_setup_error_timed_out,
+            snapshot.connection_setup_error_locally_closed,
+        ];
+        assert_eq!(counters, [1; 7]);
+        assert_eq!(
+            snapshot.connection_setup_error,
+            counters.iter().sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn test_validate_server_args() {
+        assert_matches!(validate_server_args(1, 2000, 500, 1), Ok(3125));
+        assert_matches!(
+            validate_server_args(1, 2000, 500, MAX_STREAMS_PER_MS_LIMIT),
+            Ok(3125)
+        );
+        for (max_connections_per_peer, max_staked_connections, max_streams_per_ms) in [
+            (0, 2000, DEFAULT_MAX_STREAMS_PER_MS),
+            (1, 2000, 0),
+            (1, 2000, MAX_STREAMS_PER_MS_LIMIT + 1),
+            (1, usize::MAX, DEFAULT_MAX_STREAMS_PER_MS),
+            (1, u32::MAX as usize, DEFAULT_MAX_STREAMS_PER_MS),
+        ] {
+            assert_matches!(
+                validate_server_args(
+                    max_connections_per_peer,
+                    max_staked_connections,
+                    500,
+                    max_streams_per_ms
+                ),
+                Err(QuicServerError::InvalidConfig(_))
+            );
+        }
+
+        let result = spawn_server(
+            "quic_streamer_test",
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            unbounded().0,
+            Arc::default(),
+            0,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            MAX_UNSTAKED_CONNECTIONS,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            DEFAULT_TPU_COALESCE,
+            QuicServerConfig::default(),
+        );
+        assert_matches!(result.err(), Some(QuicServerError::InvalidConfig(_)));
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_congestion_controller() {
+        solana_logger::setup();
+        use std::any::{Any, TypeId};
+        for (congestion_controller, controller_type) in [
+            (
+                CongestionController::Cubic,
+                TypeId::of::<congestion::Cubic>(),
+            ),
+            (
+                CongestionController::NewReno,
+                TypeId::of::<congestion::NewReno>(),
+            ),
+            (CongestionController::Bbr, TypeId::of::<congestion::Bbr>()),
+        ] {
+            let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let server_address = sock.local_addr().unwrap();
+            let exit = Arc::new(AtomicBool::new(false));
+            let (sender, receiver) = unbounded();
+            let SpawnNonBlockingServerResult {
+                thread,
+                connection_control,
+                ..
+            } = spawn_server(
+                "quic_streamer_test",
+                sock,
+                &Keypair::new(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                sender,
+                exit.clone(),
+                1,
+                Arc::default(),
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+                DEFAULT_MAX_STREAMS_PER_MS,
+                DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+                Duration::from_millis(1),
+                QuicServerConfig {
+                    congestion_controller,
+                    ..QuicServerConfig::default()
+                },
+            )
+            .unwrap();
+
+            let connection = make_client_connection(&server_address, None).await;
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+            let packet_batch = recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(packet_batch.len(), 1);
+
+            // The server's side of the connection runs the configured controller.
+            let controllers: Vec<_> = connection_control
+                .unstaked_connection_table
+                .lock()
+                .await
+                .connections()
+                .map(|(connection, _)| {
+                    let controller: Box<dyn Any> = connection.congestion_state().into_any();
+                    (*controller).type_id()
+                })
+                .collect();
+            assert_eq!(controllers, vec![controller_type]);
+
+            exit.store(true, Ordering::Relaxed);
+            thread.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_coalesce_max_packets() {
+        solana_logger::setup();
+        // The coalesce window of the test server is 1s, the batches are expected way before.
+        let (t, exit, receiver, server_address, stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                coalesce_max_packets: Some(2),
+                ..QuicServerConfig::default()
+            });
+
+        let connection = make_client_connection(&server_address, None).await;
+        for _ in 0..4 {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        }
+        for _ in 0..2 {
+            let packet_batch = recv_with_timeout(&receiver, Duration::from_millis(500))
+                .await
+                .unwrap();
+            assert_eq!(packet_batch.len(), 2);
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.batch_packets.count, 2);
+        assert_eq!(snapshot.batch_packets.max, 2);
+        assert_eq!(snapshot.avg_packets_per_batch(), 2);
+        assert_eq!(snapshot.avg_batch_bytes(), 20);
+        assert_eq!(snapshot.batch_bytes.max, 20);
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_zero_coalesce() {
+        solana_logger::setup();
+        // A single packet waits out the whole window, unless there's none.
+        for (coalesce, expected_delay) in [
+            (Duration::ZERO, Duration::ZERO..Duration::from_millis(100)),
+            (
+                Duration::from_secs(1),
+                Duration::from_millis(500)..Duration::from_secs(5),
+            ),
+        ] {
+            let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let server_address = sock.local_addr().unwrap();
+            let exit = Arc::new(AtomicBool::new(false));
+            let (sender, receiver) = unbounded();
+            let SpawnNonBlockingServerResult { thread, .. } = spawn_server(
+                "quic_streamer_test",
+                sock,
+                &Keypair::new(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                sender,
+                exit.clone(),
+                1,
+                Arc::<RwLock<StakedNodes>>::default(),
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+                DEFAULT_MAX_STREAMS_PER_MS,
+                DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+                coalesce,
+                QuicServerConfig::default(),
+            )
+            .unwrap();
+
+            let connection = make_client_connection(&server_address, None).await;
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+            let start = Instant::now();
+            let packet_batch = recv_with_timeout(&receiver, expected_delay.end)
+                .await
+                .unwrap();
+            assert_eq!(packet_batch.len(), 1);
+            assert!(expected_delay.contains(&start.elapsed()));
+
+            exit.store(true, Ordering::Relaxed);
+            thread.await.unwrap();
+        }
+    }
+
+    struct RecordingEventHandler(Sender<(&'static str, Option<Pubkey>, u64)>);
+
+    impl ConnectionEventHandler for RecordingEventHandler {
+        fn on_connect(&self, _remote_addr: SocketAddr, pubkey: Option<Pubkey>, stake: u64) {
+            self.0.send(("connect", pubkey, stake)).unwrap();
+        }
+
+        fn on_evict(&self, _remote_addr: SocketAddr, pubkey: Option<Pubkey>, stake: u64) {
+            self.0.send(("evict", pubkey, stake)).unwrap();
+        }
+
+        fn on_disconnect(
+            &self,
+            _remote_addr: SocketAddr,
+            pubkey: Option<Pubkey>,
+            stake: u64,
+            _summary: &ConnectionSummary,
+        ) {
+            self.0.send(("disconnect", pubkey, stake)).unwrap();
+        }
+    }
+
+    struct RecordingSummaryHandler(Sender<ConnectionSummary>);
+
+    impl ConnectionEventHandler for RecordingSummaryHandler {
+        fn on_disconnect(
+            &self,
+            _remote_addr: SocketAddr,
+            _pubkey: Option<Pubkey>,
+            _stake: u64,
+            summary: &ConnectionSummary,
+        ) {
+            self.0.send(summary.clone()).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_connection_events() {
+        solana_logger::setup();
+        let (event_sender, event_receiver) = unbounded();
+        let (t, exit, _receiver, server_address, _stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                connection_event_handler: Some(Arc::new(RecordingEventHandler(event_sender))),
+                ..QuicServerConfig::default()
+            });
+
+        let client_keypair = Keypair::new();
+        let connection = make_client_connection(&server_address, Some(&client_keypair)).await;
+        let event = recv_with_timeout(&event_receiver, Duration::from_secs(5)).await;
+        assert_eq!(event, Some(("connect", Some(client_keypair.pubkey()), 0)));
+
+        connection.close(0u32.into(), b"done");
+        let event = recv_with_timeout(&event_receiver, Duration::from_secs(5)).await;
+        assert_eq!(
+            event,
+            Some(("disconnect", Some(client_keypair.pubkey()), 0))
+        );
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+
+    // Reports whether the unstaked table is unlocked when notified of an eviction.
+    struct UnlockedEvictionHandler {
+        connection_control: std::sync::OnceLock<Arc<ConnectionControl>>,
+        sender: Sender<bool>,
+    }
+
+    impl ConnectionEventHandler for UnlockedEvictionHandler {
+        fn on_evict(&self, _remote_addr: SocketAddr, _pubkey: Option<Pubkey>, _stake: u64) {
+            let connection_control = self.connection_control.get().unwrap();
+            let unlocked = connection_control
+                .unstaked_connection_table
+                .0
+                .try_lock()
+                .is_ok();
+            self.sender.send(unlocked).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_evict_event_after_unlock() {
+        solana_logger::setup();
+        let (sender, receiver) = unbounded();
+        let handler = Arc::new(UnlockedEvictionHandler {
+            connection_control: std::sync::OnceLock::new(),
+            sender,
+        });
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = sock.local_addr().unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let SpawnNonBlockingServerResult {
+            thread,
+            stats,
+            connection_control,
+            ..
+        } = spawn_server(
+            "quic_streamer_test",
+            sock,
+            &Keypair::new(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            unbounded().0,
+            exit.clone(),
+            1,
+            Arc::default(),
+            MAX_STAKED_CONNECTIONS,
+            1,
+            DEFAULT_MAX_STREAMS_PER_MS,
+            DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            Duration::from_secs(1),
+            QuicServerConfig {
+                connection_event_handler: Some(handler.clone()),
+                ..QuicServerConfig::default()
+            },
+        )
+        .unwrap();
+        assert!(handler.connection_control.set(connection_control).is_ok());
+
+        let _first = make_client_connection(&server_address, None).await;
+        let _second = make_client_connection(&server_address, None).await;
+        assert_eq!(
+            recv_with_timeout(&receiver, Duration::from_secs(5)).await,
+            Some(true)
+        );
+        assert_eq!(stats.num_evictions.get(), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_server_connection_summary() {
+        solana_logger::setup();
+        let (summary_sender, summary_receiver) = unbounded();
+        let (t, exit, receiver, server_address, _stats) =
+            setup_quic_server_with_config(QuicServerConfig {
+                connection_event_handler: Some(Arc::new(RecordingSummaryHandler(summary_sender))),
+                ..QuicServerConfig::default()
+            });
+
+        let connection = make_client_connection(&server_address, None).await;
+        for len in [10, 20, 30] {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&vec![1u8; len]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        }
+        let mut packets = 0;
+        while packets < 3 {
+            packets += recv_with_timeout(&receiver, Duration::from_secs(5))
+                .await
+                .unwrap()
+                .len();
+        }
+
+        connection.close(0u32.into(), b"done");
+        let summary = recv_with_timeout(&summary_receiver, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(summary.streams, 3);
+        assert_eq!(summary.packets, 3);
+        assert_eq!(summary.bytes, 60);
+        assert!(summary.chunks >= 3);
+        assert_eq!(summary.throttled_streams, 0);
+        assert!(!summary.duration.is_zero());
+
+        exit.store(true, Ordering::Relaxed);
+        t.await.unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use solana_sdk::quic::QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS;
+use tokio::sync::oneshot;
+
+use crate::nonblocking::quic::ConnectionPeerType;
+
+// Turns a connection can take in a row, however large its stake
+const MAX_READ_WEIGHT: u32 = 4;
+
+/// Turns a connection takes per round, one for every unstaked connection's worth of streams
+/// its stake allows, see [`compute_max_allowed_uni_streams`].
+///
+/// [`compute_max_allowed_uni_streams`]: crate::nonblocking::quic::compute_max_allowed_uni_streams
+pub(crate) fn read_weight(peer_type: ConnectionPeerType, max_uni_streams: u64) -> u32 {
+    match peer_type {
+        ConnectionPeerType::Staked(_) => (max_uni_streams
+            / QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS as u64)
+            .clamp(1, MAX_READ_WEIGHT.into()) as u32,
+        ConnectionPeerType::Unstaked => 1,
+    }
+}
+
+#[derive(Debug)]
+struct Waiting {
+    weight: u32,
+    // Turns left in the current round
+    credits: u32,
+    queue: VecDeque<oneshot::Sender<()>>,
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    available: usize,
+    // Connections with streams waiting for a turn, the one at the front is served next
+    ring: VecDeque<usize>,
+    waiting: HashMap<usize, Waiting>,
+}
+
+/// Caps how many chunks are handled at once, handing the turns that free up to the waiting
+/// connections in weighted round-robin order rather than to the streams that asked first.
+///
+/// Without it, a connection with many busy streams gets as many turns as it has streams, and
+/// under saturation starves the connections with few. With it, a connection waiting for turns
+/// leaves its chunks unread, so its receive window fills and quinn's flow control throttles
+/// the peer.
+#[derive(Debug)]
+pub(crate) struct FairReadScheduler {
+    state: Mutex<SchedulerState>,
+}
+
+impl FairReadScheduler {
+    pub(crate) fn new(max_concurrent_reads: usize) -> Self {
+        Self {
+            state: Mutex::new(SchedulerState {
+                available: max_concurrent_reads,
+                ..SchedulerState::default()
+            }),
+        }
+    }
+
+    /// Waits for a turn of `connection_id`, taking `weight` turns in a row once its round comes.
+    /// Returns whether it had to wait, along with the turn, which is held until dropped.
+    pub(crate) async fn acquire(
+        self: &Arc<Self>,
+        connection_id: usize,
+        weight: u32,
+    ) -> (ReadTurn, bool) {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 && state.ring.is_empty() {
+                state.available -= 1;
+                return (self.turn(), false);
+            }
+            let (sender, receiver) = oneshot::channel();
+            let SchedulerState { ring, waiting, .. } = &mut *state;
+            waiting
+                .entry(connection_id)
+                .or_insert_with(|| {
+                    ring.push_back(connection_id);
+                    Waiting {
+                        weight,
+                        credits: weight,
+                        queue: VecDeque::new(),
+                    }
+                })
+                .queue
+                .push_back(sender);
+            receiver
+        };
+        let mut pending = PendingTurn {
+            scheduler: self,
+            receiver: Some(receiver),
+        };
+        // The sender is only dropped along with the scheduler, which this holds on to.
+        let _ = pending.receiver.as_mut().unwrap().await;
+        pending.receiver = None;
+        (self.turn(), true)
+    }
+
+    fn turn(self: &Arc<Self>) -> ReadTurn {
+        ReadTurn {
+            scheduler: self.clone(),
+        }
+    }
+
+    // Hands the turn to the next waiting connection, back to the pool if there's none.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let SchedulerState {
+                available,
+                ring,
+                waiting,
+            } = &mut *state;
+            let Some(&connection_id) = ring.front() else {
+                *available += 1;
+                return;
+            };
+            let entry = waiting
+                .get_mut(&connection_id)
+                .expect("connection is waiting");
+            let sender = entry.queue.pop_front();
+            entry.credits -= 1;
+            if entry.queue.is_empty() {
+                waiting.remove(&connection_id);
+                ring.pop_front();
+            } else if entry.credits == 0 {
+                entry.credits = entry.weight;
+                ring.rotate_left(1);
+            }
+            // Skips the streams that stopped waiting.
+            if sender.is_some_and(|sender| sender.send(()).is_ok()) {
+                return;
+            }
+        }
+    }
+}
+
+/// The right to handle a chunk, passed on to the next waiting connection when dropped.
+#[derive(Debug)]
+pub(crate) struct ReadTurn {
+    scheduler: Arc<FairReadScheduler>,
+}
+
+impl Drop for ReadTurn {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+// Gives back a turn that was handed over after the waiting stream was cancelled.
+struct PendingTurn<'a> {
+    scheduler: &'a FairReadScheduler,
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for PendingTurn<'_> {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            receiver.close();
+            if receiver.try_recv().is_ok() {
+                self.scheduler.release();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+
+    #[test]
+    fn test_read_weight() {
+        let unstaked = QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS as u64;
+        assert_eq!(read_weight(ConnectionPeerType::Unstaked, unstaked * 10), 1);
+        assert_eq!(read_weight(ConnectionPeerType::Staked(1), unstaked / 2), 1);
+        assert_eq!(read_weight(ConnectionPeerType::Staked(1), unstaked * 3), 3);
+        assert_eq!(
+            read_weight(ConnectionPeerType::Staked(1), unstaked * 100),
+            MAX_READ_WEIGHT
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fair_read_scheduler() {
+        let scheduler = Arc::new(FairReadScheduler::new(1));
+        let (first, waited) = scheduler.acquire(0, 1).await;
+        assert!(!waited);
+
+        // A hog with many streams waiting and two connections with one each. The staked one
+        // takes two turns per round.
+        let (served_sender, mut served) = mpsc::unbounded_channel();
+        let mut tasks = Vec::new();
+        let mut wait = |connection_id, weight| {
+            let scheduler = scheduler.clone();
+            let served_sender = served_sender.clone();
+            tasks.push(tokio::spawn(async move {
+                let (_turn, waited) = scheduler.acquire(connection_id, weight).await;
+                assert!(waited);
+                served_sender.send(connection_id).unwrap();
+                sleep(Duration::from_millis(1)).await;
+            }));
+        };
+        for _ in 0..4 {
+            wait(1, 1);
+        }
+        wait(2, 1);
+        wait(3, 2);
+        wait(3, 2);
+        wait(3, 2);
+        // Waits for the tasks to queue up.
+        sleep(Duration::from_millis(50)).await;
+
+        // A stream that stops waiting gives up its place.
+        let cancelled = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire(4, 1).await }
+        });
+        sleep(Duration::from_millis(50)).await;
+        cancelled.abort();
+        drop(first);
+
+        let mut order = Vec::new();
+        for _ in 0..8 {
+            order.push(
+                timeout(Duration::from_secs(5), served.recv())
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+        assert_eq!(order, vec![1, 2, 3, 3, 1, 3, 1, 1]);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // Every turn went back to the pool.
+        let (_turn, waited) = scheduler.acquire(5, 1).await;
+        assert!(!waited);
+        assert_eq!(scheduler.state.lock().unwrap().available, 0);
+    }
+}
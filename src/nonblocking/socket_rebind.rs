@@ -0,0 +1,357 @@
+use std::fmt;
+use std::future::Future;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use log::{info, warn};
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::{AsyncUdpSocket, Runtime, TokioRuntime};
+use tokio::time::{sleep, Sleep};
+
+use crate::quic::StreamStats;
+use crate::socket::{bind_server_socket_with, ServerSocketOptions};
+
+/// How an endpoint recovers from a fatal error of its socket, e.g. after the network interface
+/// went down, by binding a fresh socket to the same address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketRebindConfig {
+    /// Number of rebinds attempted in a row before giving up, which stops the endpoint. Reset
+    /// once the new socket sends or receives a datagram.
+    pub max_attempts: u32,
+    /// Wait before the first attempt, doubled after every failed one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for SocketRebindConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl SocketRebindConfig {
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Default)]
+struct RebindState {
+    // None from the error until the rebind succeeds, so that the old socket releases the
+    // address first.
+    socket: Option<Box<dyn AsyncUdpSocket>>,
+    // Of the socket last bound, to check that the rebound one got the options it asked for
+    #[cfg_attr(not(test), allow(dead_code))]
+    options: Option<ServerSocketOptions>,
+    attempts: u32,
+    backoff: Option<Pin<Box<Sleep>>>,
+}
+
+/// Socket binding a fresh UDP socket to the same address when the one it wraps fails, instead
+/// of returning the error, which would stop the endpoint along with all its connections.
+///
+/// The new socket is bound like [`bind_server_socket`], with the buffer sizes and dual-stack
+/// setting of the original socket. Other options set by the caller on the original socket
+/// aren't carried over.
+///
+/// [`bind_server_socket`]: crate::socket::bind_server_socket
+pub(crate) struct RebindingSocket {
+    addr: SocketAddr,
+    options: ServerSocketOptions,
+    may_fragment: bool,
+    config: SocketRebindConfig,
+    stats: Arc<StreamStats>,
+    state: Mutex<RebindState>,
+}
+
+impl RebindingSocket {
+    /// Wraps `socket`, rebinding it with `options`, read from the original socket before it was
+    /// handed to the runtime.
+    pub(crate) fn new(
+        socket: Box<dyn AsyncUdpSocket>,
+        options: ServerSocketOptions,
+        config: SocketRebindConfig,
+        stats: Arc<StreamStats>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            addr: socket.local_addr()?,
+            options,
+            may_fragment: socket.may_fragment(),
+            config,
+            stats,
+            state: Mutex::new(RebindState {
+                socket: Some(socket),
+                ..RebindState::default()
+            }),
+        })
+    }
+
+    // Polls `op` on the current socket, rebinding it as long as it fails.
+    fn poll_io<T>(
+        &self,
+        cx: &mut Context,
+        mut op: impl FnMut(&dyn AsyncUdpSocket, &mut Context) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(backoff) = state.backoff.as_mut() {
+                ready!(backoff.as_mut().poll(cx));
+                state.backoff = None;
+                if let Err(err) = self.rebind(&mut state) {
+                    if state.attempts >= self.config.max_attempts {
+                        warn!("Giving up rebinding the socket of {}: {err}", self.addr);
+                        return Poll::Ready(Err(err));
+                    }
+                    warn!("Failed to rebind the socket of {}: {err}", self.addr);
+                    self.schedule_rebind(&mut state);
+                }
+                continue;
+            }
+            let socket = state.socket.as_deref().expect("socket is bound");
+            match ready!(op(socket, cx)) {
+                Ok(result) => {
+                    state.attempts = 0;
+                    return Poll::Ready(Ok(result));
+                }
+                // Not fatal for the endpoint either, see `EndpointDriver`.
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => {
+                    return Poll::Ready(Err(err));
+                }
+                Err(err) => {
+                    warn!("Socket of {} failed, rebinding it: {err}", self.addr);
+                    state.socket = None;
+                    self.schedule_rebind(&mut state);
+                }
+            }
+        }
+    }
+
+    fn schedule_rebind(&self, state: &mut RebindState) {
+        state.backoff = Some(Box::pin(sleep(self.config.backoff(state.attempts))));
+    }
+
+    fn rebind(&self, state: &mut RebindState) -> io::Result<()> {
+        state.attempts += 1;
+        let socket = bind_server_socket_with(self.addr, self.options)?;
+        let options = ServerSocketOptions::of(&socket)?;
+        let socket = TokioRuntime.wrap_udp_socket(socket)?;
+        info!("Rebound the socket of {} with {options:?}", self.addr);
+        self.stats.endpoint_rebinds.add(1);
+        state.socket = Some(socket);
+        state.options = Some(options);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RebindingSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RebindingSocket")
+            .field("addr", &self.addr)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncUdpSocket for RebindingSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_io(cx, |socket, cx| socket.poll_send(state, cx, transmits))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_io(cx, |socket, cx| socket.poll_recv(cx, bufs, meta))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.may_fragment
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::poll_fn;
+    use std::net::UdpSocket;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::socket::bind_server_socket;
+
+    // Fails its first receive, as if the network interface went down.
+    #[derive(Debug)]
+    struct FailingSocket {
+        inner: Box<dyn AsyncUdpSocket>,
+        failed: AtomicBool,
+    }
+
+    impl AsyncUdpSocket for FailingSocket {
+        fn poll_send(
+            &self,
+            state: &UdpState,
+            cx: &mut Context,
+            transmits: &[Transmit],
+        ) -> Poll<io::Result<usize>> {
+            self.inner.poll_send(state, cx, transmits)
+        }
+
+        fn poll_recv(
+            &self,
+            cx: &mut Context,
+            bufs: &mut [IoSliceMut<'_>],
+            meta: &mut [RecvMeta],
+        ) -> Poll<io::Result<usize>> {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                return Poll::Ready(Err(io::Error::other("network down")));
+            }
+            self.inner.poll_recv(cx, bufs, meta)
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+    }
+
+    fn failing_socket(config: SocketRebindConfig, stats: Arc<StreamStats>) -> RebindingSocket {
+        let socket = bind_server_socket("127.0.0.1:0".parse().unwrap(), 1 << 20, 1 << 19).unwrap();
+        failing_socket_from(socket, config, stats)
+    }
+
+    fn failing_socket_from(
+        socket: UdpSocket,
+        config: SocketRebindConfig,
+        stats: Arc<StreamStats>,
+    ) -> RebindingSocket {
+        let options = ServerSocketOptions::of(&socket).unwrap();
+        let socket = FailingSocket {
+            inner: TokioRuntime.wrap_udp_socket(socket).unwrap(),
+            failed: AtomicBool::new(false),
+        };
+        RebindingSocket::new(Box::new(socket), options, config, stats).unwrap()
+    }
+
+    async fn recv(socket: &RebindingSocket) -> io::Result<usize> {
+        let mut buf = [0u8; 64];
+        let mut meta = [RecvMeta::default()];
+        poll_fn(|cx| socket.poll_recv(cx, &mut [IoSliceMut::new(&mut buf)], &mut meta))
+            .await
+            .map(|_| meta[0].len)
+    }
+
+    #[tokio::test]
+    async fn test_rebinding_socket() {
+        let config = SocketRebindConfig {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+        };
+        assert_eq!(config.backoff(0), Duration::from_millis(10));
+        assert_eq!(config.backoff(5), Duration::from_millis(20));
+
+        let stats = Arc::<StreamStats>::default();
+        let socket = failing_socket(config, stats.clone());
+        let addr = socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let received = timeout(Duration::from_secs(5), async {
+            loop {
+                // Datagrams sent before the rebind are lost along with the old socket.
+                client.send_to(&[1u8; 10], addr).unwrap();
+                if let Ok(Ok(len)) = timeout(Duration::from_millis(50), recv(&socket)).await {
+                    break len;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, 10);
+        assert_eq!(socket.local_addr().unwrap(), addr);
+        assert_eq!(stats.endpoint_rebinds.get(), 1);
+        // The buffer sizes of the original socket are carried over.
+        assert_eq!(socket.state.lock().unwrap().options, Some(socket.options));
+
+        // The error is returned once the address can't be bound again.
+        let socket = failing_socket(config, stats.clone());
+        let addr = socket.local_addr().unwrap();
+        let err = timeout(Duration::from_secs(5), async {
+            // Takes the address over once the old socket released it.
+            let mut squatter = None;
+            let recv = recv(&socket);
+            tokio::pin!(recv);
+            loop {
+                tokio::select! {
+                    result = &mut recv => break result.unwrap_err(),
+                    _ = sleep(Duration::from_millis(1)), if squatter.is_none() => {
+                        squatter = UdpSocket::bind(addr).ok();
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(stats.endpoint_rebinds.get(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_rebinding_socket_reuse_port() {
+        let config = SocketRebindConfig {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+        };
+        let options = ServerSocketOptions {
+            recv_buf_size: 1 << 20,
+            send_buf_size: 1 << 19,
+            only_v6: false,
+            reuse_port: true,
+        };
+        // Two sockets of a multi-socket server, the other one keeps the port while the failing
+        // one is rebound.
+        let other = bind_server_socket_with("127.0.0.1:0".parse().unwrap(), options).unwrap();
+        let addr = other.local_addr().unwrap();
+        let socket = bind_server_socket_with(addr, options).unwrap();
+        let stats = Arc::<StreamStats>::default();
+        let socket = failing_socket_from(socket, config, stats.clone());
+
+        let received = timeout(Duration::from_secs(5), async {
+            loop {
+                // The kernel picks the socket by the client's address, so some clients reach
+                // the other socket.
+                let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+                client.send_to(&[1u8; 10], addr).unwrap();
+                if let Ok(Ok(len)) = timeout(Duration::from_millis(50), recv(&socket)).await {
+                    break len;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, 10);
+        assert_eq!(stats.endpoint_rebinds.get(), 1);
+        assert_eq!(socket.state.lock().unwrap().options, Some(options));
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Reserves `max_connections` of the staked connection slots for peers with at least
+/// `min_stake`, up to the `min_stake` of the next tier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StakeTier {
+    pub min_stake: u64,
+    pub max_connections: usize,
+}
+
+/// Connection counts of the configured stake tiers.
+///
+/// Tier 0 holds the staked peers below the lowest `min_stake` and gets the slots of
+/// `max_staked_connections` that aren't reserved by the other tiers.
+#[derive(Debug)]
+pub(crate) struct StakeTiers {
+    // Sorted by min_stake
+    tiers: Vec<StakeTier>,
+    connections: Vec<AtomicUsize>,
+}
+
+impl StakeTiers {
+    pub(crate) fn new(mut tiers: Vec<StakeTier>) -> Self {
+        tiers.sort_by_key(|tier| tier.min_stake);
+        let connections = (0..=tiers.len()).map(|_| AtomicUsize::default()).collect();
+        Self { tiers, connections }
+    }
+
+    pub(crate) fn tier(&self, stake: u64) -> usize {
+        self.tiers.partition_point(|tier| tier.min_stake <= stake)
+    }
+
+    pub(crate) fn max_connections(&self, tier: usize, max_staked_connections: usize) -> usize {
+        match tier.checked_sub(1) {
+            None => {
+                let reserved: usize = self.tiers.iter().map(|tier| tier.max_connections).sum();
+                max_staked_connections.saturating_sub(reserved)
+            }
+            Some(index) => self.tiers[index].max_connections,
+        }
+    }
+
+    pub(crate) fn connections(&self, tier: usize) -> usize {
+        self.connections[tier].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_full(&self, tier: usize, max_staked_connections: usize) -> bool {
+        self.connections(tier) >= self.max_connections(tier, max_staked_connections)
+    }
+
+    /// Picks the tier to evict a connection from to admit one into `tier` when the staked
+    /// connection table is full: the lowest tier holding more connections than its cap, then
+    /// the lowest non-empty tier below `tier`.
+    pub(crate) fn tier_to_evict(
+        &self,
+        tier: usize,
+        max_staked_connections: usize,
+    ) -> Option<usize> {
+        (0..self.connections.len())
+            .find(|&candidate| {
+                self.connections(candidate)
+                    > self.max_connections(candidate, max_staked_connections)
+            })
+            .or_else(|| (0..tier).find(|&candidate| self.connections(candidate) > 0))
+    }
+
+    pub(crate) fn snapshot(&self) -> Vec<u64> {
+        self.connections
+            .iter()
+            .map(|connections| connections.load(Ordering::Relaxed) as u64)
+            .collect()
+    }
+
+    /// Counts a connection with `stake` against its tier until the returned slot is dropped.
+    pub(crate) fn register(self: &Arc<Self>, stake: u64) -> StakeTierSlot {
+        let tier = self.tier(stake);
+        self.connections[tier].fetch_add(1, Ordering::Relaxed);
+        StakeTierSlot {
+            tiers: self.clone(),
+            tier,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct StakeTierSlot {
+    tiers: Arc<StakeTiers>,
+    tier: usize,
+}
+
+impl Drop for StakeTierSlot {
+    fn drop(&mut self) {
+        self.tiers.connections[self.tier].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stake_tiers() {
+        let tiers = Arc::new(StakeTiers::new(vec![
+            StakeTier {
+                min_stake: 1_000,
+                max_connections: 2,
+            },
+            StakeTier {
+                min_stake: 100,
+                max_connections: 3,
+            },
+        ]));
+        assert_eq!(tiers.tier(1), 0);
+        assert_eq!(tiers.tier(100), 1);
+        assert_eq!(tiers.tier(999), 1);
+        assert_eq!(tiers.tier(u64::MAX), 2);
+        assert_eq!(tiers.max_connections(0, 10), 5);
+        assert_eq!(tiers.max_connections(0, 4), 0);
+        assert_eq!(tiers.max_connections(2, 10), 2);
+
+        let slots: Vec<_> = [5_000, 2_000, 3_000]
+            .map(|stake| tiers.register(stake))
+            .into();
+        assert_eq!(tiers.snapshot(), vec![0, 0, 3]);
+        assert!(tiers.is_full(2, 10));
+        // The top tier is over its cap, so it's evicted from even to admit a lower tier.
+        assert_eq!(tiers.tier_to_evict(1, 10), Some(2));
+
+        drop(slots);
+        let _slot = tiers.register(10);
+        assert_eq!(tiers.snapshot(), vec![1, 0, 0]);
+        assert_eq!(tiers.tier_to_evict(2, 10), Some(0));
+        assert_eq!(tiers.tier_to_evict(0, 10), None);
+    }
+}
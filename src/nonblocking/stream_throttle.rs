@@ -5,15 +5,16 @@ use std::time::{Duration, Instant};
 
 use percentage::Percentage;
 
-use crate::nonblocking::quic::ConnectionPeerType;
-use crate::quic::StreamStats;
+use crate::nonblocking::quic::{ConnectionLimits, ConnectionPeerType, MAX_STREAMS_PER_MS_LIMIT};
+use crate::quic::{BackpressureThrottleConfig, StreamLoadEmaConfig, StreamStats};
 
-const MAX_UNSTAKED_STREAMS_PERCENT: u64 = 20;
 pub const STREAM_THROTTLING_INTERVAL_MS: u64 = 100;
 pub const STREAM_THROTTLING_INTERVAL: Duration =
     Duration::from_millis(STREAM_THROTTLING_INTERVAL_MS);
 const STREAM_LOAD_EMA_INTERVAL_MS: u64 = 5;
 const STREAM_LOAD_EMA_INTERVAL_COUNT: u64 = 10;
+// Using the EMA multiplier helps in avoiding the floating point math during EMA related calculations
+const STREAM_LOAD_EMA_MULTIPLIER: u128 = 1024;
 const EMA_WINDOW_MS: u64 = STREAM_LOAD_EMA_INTERVAL_MS * STREAM_LOAD_EMA_INTERVAL_COUNT;
 
 pub(crate) struct StakedStreamLoadEMA {
@@ -21,62 +22,115 @@ pub(crate) struct StakedStreamLoadEMA {
     load_in_recent_interval: AtomicU64,
     last_update: RwLock<Instant>,
     stats: Arc<StreamStats>,
-    // Maximum number of streams for a staked connection in EMA window
-    // Note: EMA window can be different than stream throttling window. EMA is being calculated
-    //       specifically for staked connections. Unstaked connections have fixed limit on
-    //       stream load, which is tracked by `max_unstaked_load_in_throttling_window` field.
-    max_staked_load_in_ema_window: u64,
-    // Maximum number of streams for an unstaked connection in stream throttling window
-    max_unstaked_load_in_throttling_window: u64,
+    // Shared with the server handle so the limit can be re-tuned while running. The load limits
+    // below are derived from it on every call.
+    max_streams_per_ms: Arc<AtomicU64>,
+    // Also shared with the server handle, unstaked peers split their share of the streams
+    // evenly between the maximum number of unstaked connections.
+    connection_limits: Arc<ConnectionLimits>,
+    max_unstaked_streams_percent: u64,
+    // EMA smoothing factor multiplied by STREAM_LOAD_EMA_MULTIPLIER
+    multiplied_smoothing_factor: u128,
+    // Floor of the load in the capacity calculation, in percent of the max staked load
+    min_load_percent: u64,
+    backpressure: Option<BackpressureThrottleConfig>,
+    // Scale of max_streams_per_ms in the load limits, in percent, driven by the fill of the
+    // packet channel
+    backpressure_scale_percent: AtomicU64,
 }
 
 impl StakedStreamLoadEMA {
     pub(crate) fn new(
         stats: Arc<StreamStats>,
-        max_unstaked_connections: usize,
-        max_streams_per_ms: u64,
+        connection_limits: Arc<ConnectionLimits>,
+        max_streams_per_ms: Arc<AtomicU64>,
+        config: &StreamLoadEmaConfig,
     ) -> Self {
-        let allow_unstaked_streams = max_unstaked_connections > 0;
-        let max_staked_load_in_ema_window = if allow_unstaked_streams {
+        stats.stream_throttle_scale_percent.set(100);
+        Self {
+            current_load_ema: AtomicU64::default(),
+            load_in_recent_interval: AtomicU64::default(),
+            last_update: RwLock::new(Instant::now()),
+            stats,
+            max_streams_per_ms,
+            connection_limits,
+            max_unstaked_streams_percent: config.max_unstaked_streams_percent,
+            multiplied_smoothing_factor: (config.alpha * STREAM_LOAD_EMA_MULTIPLIER as f64).round()
+                as u128,
+            min_load_percent: config.min_load_percent,
+            backpressure: config.backpressure,
+            backpressure_scale_percent: AtomicU64::new(100),
+        }
+    }
+
+    /// The current limit on new streams per millisecond, a stored zero is treated as one and
+    /// values above [`MAX_STREAMS_PER_MS_LIMIT`] as the limit, so that the loads derived from it
+    /// don't overflow.
+    pub(crate) fn max_streams_per_ms(&self) -> u64 {
+        self.max_streams_per_ms
+            .load(Ordering::Relaxed)
+            .clamp(1, MAX_STREAMS_PER_MS_LIMIT)
+    }
+
+    // The limit the load limits are derived from, scaled down under backpressure
+    fn scaled_max_streams_per_ms(&self) -> u64 {
+        (self.max_streams_per_ms() * self.backpressure_scale_percent.load(Ordering::Relaxed) / 100)
+            .max(1)
+    }
+
+    /// Rescales the stream limits to the fill of the packet channel, a no-op unless the
+    /// backpressure throttling is configured.
+    pub(crate) fn update_backpressure(&self, depth: usize, capacity: usize) {
+        if let Some(backpressure) = &self.backpressure {
+            let scale_percent = backpressure.scale_percent(depth, capacity);
+            self.backpressure_scale_percent
+                .store(scale_percent, Ordering::Relaxed);
+            self.stats
+                .stream_throttle_scale_percent
+                .set(scale_percent as usize);
+        }
+    }
+
+    fn max_unstaked_connections(&self) -> u64 {
+        let max_unstaked_connections = self.connection_limits.max_unstaked_connections();
+        u64::try_from(max_unstaked_connections).unwrap_or_else(|_| {
+            error!(
+                "Failed to convert maximum number of unstaked connections {} to u64.",
+                max_unstaked_connections
+            );
+            500
+        })
+    }
+
+    // Maximum number of streams for a staked connection in EMA window
+    // Note: EMA window can be different than stream throttling window. EMA is being calculated
+    //       specifically for staked connections. Unstaked connections have fixed limit on
+    //       stream load, which is tracked by `max_unstaked_load_in_throttling_window`.
+    fn max_staked_load_in_ema_window(&self) -> u64 {
+        let max_streams_per_ms = self.scaled_max_streams_per_ms();
+        if self.max_unstaked_connections() > 0 {
             (max_streams_per_ms
-                - Percentage::from(MAX_UNSTAKED_STREAMS_PERCENT).apply_to(max_streams_per_ms))
+                - Percentage::from(self.max_unstaked_streams_percent).apply_to(max_streams_per_ms))
                 * EMA_WINDOW_MS
         } else {
             max_streams_per_ms * EMA_WINDOW_MS
-        };
+        }
+    }
 
-        let max_num_unstaked_connections =
-            u64::try_from(max_unstaked_connections).unwrap_or_else(|_| {
-                error!(
-                    "Failed to convert maximum number of unstaked connections {} to u64.",
-                    max_unstaked_connections
-                );
-                500
-            });
-
-        let max_unstaked_load_in_throttling_window = if allow_unstaked_streams {
-            Percentage::from(MAX_UNSTAKED_STREAMS_PERCENT)
-                .apply_to(max_streams_per_ms * STREAM_THROTTLING_INTERVAL_MS)
-                .saturating_div(max_num_unstaked_connections)
+    // Maximum number of streams for an unstaked connection in stream throttling window
+    fn max_unstaked_load_in_throttling_window(&self) -> u64 {
+        let max_unstaked_connections = self.max_unstaked_connections();
+        if max_unstaked_connections > 0 {
+            Percentage::from(self.max_unstaked_streams_percent)
+                .apply_to(self.scaled_max_streams_per_ms() * STREAM_THROTTLING_INTERVAL_MS)
+                .saturating_div(max_unstaked_connections)
         } else {
             0
-        };
-
-        Self {
-            current_load_ema: AtomicU64::default(),
-            load_in_recent_interval: AtomicU64::default(),
-            last_update: RwLock::new(Instant::now()),
-            stats,
-            max_staked_load_in_ema_window,
-            max_unstaked_load_in_throttling_window,
         }
     }
 
-    fn ema_function(current_ema: u128, recent_load: u128) -> u128 {
-        // Using the EMA multiplier helps in avoiding the floating point math during EMA related calculations
-        const STREAM_LOAD_EMA_MULTIPLIER: u128 = 1024;
-        let multiplied_smoothing_factor: u128 =
-            2 * STREAM_LOAD_EMA_MULTIPLIER / (u128::from(STREAM_LOAD_EMA_INTERVAL_COUNT) + 1);
+    fn ema_function(&self, current_ema: u128, recent_load: u128) -> u128 {
+        let multiplied_smoothing_factor = self.multiplied_smoothing_factor;
 
         // The formula is
         //    updated_ema = recent_load * smoothing_factor + current_ema * (1 - smoothing_factor)
@@ -97,13 +151,13 @@ impl StakedStreamLoadEMA {
         let load_in_recent_interval =
             u128::from(self.load_in_recent_interval.swap(0, Ordering::Relaxed));
 
-        let mut updated_load_ema = Self::ema_function(
+        let mut updated_load_ema = self.ema_function(
             u128::from(self.current_load_ema.load(Ordering::Relaxed)),
             load_in_recent_interval,
         );
 
         for _ in 0..num_extra_updates {
-            updated_load_ema = Self::ema_function(updated_load_ema, load_in_recent_interval);
+            updated_load_ema = self.ema_function(updated_load_ema, load_in_recent_interval);
         }
 
         let Ok(updated_load_ema) = u64::try_from(updated_load_ema) else {
@@ -111,17 +165,13 @@ impl StakedStreamLoadEMA {
                 "Failed to convert EMA {} to a u64. Not updating the load EMA",
                 updated_load_ema
             );
-            self.stats
-                .stream_load_ema_overflow
-                .fetch_add(1, Ordering::Relaxed);
+            self.stats.stream_load_ema_overflow.add(1);
             return;
         };
 
         self.current_load_ema
             .store(updated_load_ema, Ordering::Relaxed);
-        self.stats
-            .stream_load_ema
-            .store(updated_load_ema as usize, Ordering::Relaxed);
+        self.stats.stream_load_ema.set(updated_load_ema as usize);
     }
 
     pub(crate) fn update_ema_if_needed(&self) {
@@ -150,18 +200,20 @@ impl StakedStreamLoadEMA {
         peer_type: ConnectionPeerType,
         total_stake: u64,
     ) -> u64 {
+        let max_unstaked_load_in_throttling_window = self.max_unstaked_load_in_throttling_window();
         match peer_type {
-            ConnectionPeerType::Unstaked => self.max_unstaked_load_in_throttling_window,
+            ConnectionPeerType::Unstaked => max_unstaked_load_in_throttling_window,
             ConnectionPeerType::Staked(stake) => {
-                // If the current load is low, cap it to 25% of max_load.
+                let max_staked_load_in_ema_window = self.max_staked_load_in_ema_window();
+                // If the current load is low, cap it to min_load_percent of max_load.
                 let current_load = u128::from(cmp::max(
                     self.current_load_ema.load(Ordering::Relaxed),
-                    self.max_staked_load_in_ema_window / 4,
+                    (max_staked_load_in_ema_window * self.min_load_percent / 100).max(1),
                 ));
 
                 // Formula is (max_load ^ 2 / current_load) * (stake / total_stake)
-                let capacity_in_ema_window = (u128::from(self.max_staked_load_in_ema_window)
-                    * u128::from(self.max_staked_load_in_ema_window)
+                let capacity_in_ema_window = (u128::from(max_staked_load_in_ema_window)
+                    * u128::from(max_staked_load_in_ema_window)
                     * u128::from(stake))
                     / (current_load * u128::from(total_stake));
 
@@ -173,19 +225,15 @@ impl StakedStreamLoadEMA {
                         "Failed to convert stream capacity {} to u64. Using minimum load capacity",
                         calculated_capacity
                     );
-                    self.stats
-                        .stream_load_capacity_overflow
-                        .fetch_add(1, Ordering::Relaxed);
-                    self.max_unstaked_load_in_throttling_window
-                        .saturating_add(1)
+                    self.stats.stream_load_capacity_overflow.add(1);
+                    max_unstaked_load_in_throttling_window.saturating_add(1)
                 });
 
                 // 1 is added to `max_unstaked_load_in_throttling_window` to guarantee that staked
                 // clients get at least 1 more number of streams than unstaked connections.
                 cmp::max(
                     calculated_capacity,
-                    self.max_unstaked_load_in_throttling_window
-                        .saturating_add(1),
+                    max_unstaked_load_in_throttling_window.saturating_add(1),
                 )
             }
         }
@@ -230,21 +278,25 @@ impl ConnectionStreamCounter {
 
 #[cfg(test)]
 pub mod test {
-    use std::sync::atomic::Ordering;
+    use std::sync::atomic::{AtomicU64, Ordering};
     use std::sync::Arc;
     use std::time::{Duration, Instant};
 
     use super::*;
     use crate::nonblocking::quic::DEFAULT_MAX_STREAMS_PER_MS;
     use crate::nonblocking::stream_throttle::STREAM_LOAD_EMA_INTERVAL_MS;
-    use crate::quic::{StreamStats, MAX_UNSTAKED_CONNECTIONS};
+    use crate::quic::{StreamStats, MAX_STAKED_CONNECTIONS, MAX_UNSTAKED_CONNECTIONS};
 
     #[test]
     fn test_max_streams_for_unstaked_connection() {
         let load_ema = Arc::new(StakedStreamLoadEMA::new(
             Arc::new(StreamStats::default()),
-            MAX_UNSTAKED_CONNECTIONS,
-            DEFAULT_MAX_STREAMS_PER_MS,
+            Arc::new(ConnectionLimits::new(
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+            )),
+            Arc::new(AtomicU64::new(DEFAULT_MAX_STREAMS_PER_MS)),
+            &StreamLoadEmaConfig::default(),
         ));
         // 25K packets per ms * 20% / 500 max unstaked connections
         assert_eq!(
@@ -260,8 +312,12 @@ pub mod test {
     fn test_max_streams_for_staked_connection() {
         let load_ema = Arc::new(StakedStreamLoadEMA::new(
             Arc::new(StreamStats::default()),
-            MAX_UNSTAKED_CONNECTIONS,
-            DEFAULT_MAX_STREAMS_PER_MS,
+            Arc::new(ConnectionLimits::new(
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+            )),
+            Arc::new(AtomicU64::new(DEFAULT_MAX_STREAMS_PER_MS)),
+            &StreamLoadEmaConfig::default(),
         ));
 
         // EMA load is used for staked connections to calculate max number of allowed streams.
@@ -343,7 +399,7 @@ pub mod test {
                 40000,
             ),
             load_ema
-                .max_unstaked_load_in_throttling_window
+                .max_unstaked_load_in_throttling_window()
                 .saturating_add(1)
         );
     }
@@ -352,8 +408,9 @@ pub mod test {
     fn test_max_streams_for_staked_connection_with_no_unstaked_connections() {
         let load_ema = Arc::new(StakedStreamLoadEMA::new(
             Arc::new(StreamStats::default()),
-            0,
-            DEFAULT_MAX_STREAMS_PER_MS,
+            Arc::new(ConnectionLimits::new(MAX_STAKED_CONNECTIONS, 0)),
+            Arc::new(AtomicU64::new(DEFAULT_MAX_STREAMS_PER_MS)),
+            &StreamLoadEmaConfig::default(),
         ));
 
         // EMA load is used for staked connections to calculate max number of allowed streams.
@@ -431,7 +488,7 @@ pub mod test {
                 400000
             ),
             load_ema
-                .max_unstaked_load_in_throttling_window
+                .max_unstaked_load_in_throttling_window()
                 .saturating_add(1)
         );
     }
@@ -440,8 +497,12 @@ pub mod test {
     fn test_update_ema() {
         let stream_load_ema = Arc::new(StakedStreamLoadEMA::new(
             Arc::new(StreamStats::default()),
-            MAX_UNSTAKED_CONNECTIONS,
-            DEFAULT_MAX_STREAMS_PER_MS,
+            Arc::new(ConnectionLimits::new(
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+            )),
+            Arc::new(AtomicU64::new(DEFAULT_MAX_STREAMS_PER_MS)),
+            &StreamLoadEmaConfig::default(),
         ));
         stream_load_ema
             .load_in_recent_interval
@@ -465,12 +526,160 @@ pub mod test {
         assert_eq!(updated_ema, 2164);
     }
 
+    #[test]
+    fn test_custom_stream_load_ema_config() {
+        let load_ema = StakedStreamLoadEMA::new(
+            Arc::new(StreamStats::default()),
+            Arc::new(ConnectionLimits::new(
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+            )),
+            Arc::new(AtomicU64::new(DEFAULT_MAX_STREAMS_PER_MS)),
+            &StreamLoadEmaConfig {
+                alpha: 0.5,
+                max_unstaked_streams_percent: 40,
+                min_load_percent: 50,
+                backpressure: None,
+            },
+        );
+        // 25K packets per ms * 40% / 500 max unstaked connections
+        assert_eq!(
+            load_ema.available_load_capacity_in_throttling_duration(
+                ConnectionPeerType::Unstaked,
+                10000,
+            ),
+            20
+        );
+
+        // max_load = 250 * 60% * 50ms = 7500, loads below 50% of it are raised to 3750.
+        // max_streams in 100ms = 2 * ((7500 * 7500) / 3750) * 1K / 10K = 3000
+        load_ema.current_load_ema.store(1000, Ordering::Relaxed);
+        assert_eq!(
+            load_ema.available_load_capacity_in_throttling_duration(
+                ConnectionPeerType::Staked(1000),
+                10000
+            ),
+            3000
+        );
+
+        load_ema.current_load_ema.store(2000, Ordering::Relaxed);
+        load_ema
+            .load_in_recent_interval
+            .store(2500, Ordering::Relaxed);
+        load_ema.update_ema(5);
+        assert_eq!(load_ema.current_load_ema.load(Ordering::Relaxed), 2250);
+    }
+
+    #[test]
+    fn test_max_streams_per_ms_update() {
+        let max_streams_per_ms = Arc::new(AtomicU64::new(DEFAULT_MAX_STREAMS_PER_MS));
+        let load_ema = StakedStreamLoadEMA::new(
+            Arc::new(StreamStats::default()),
+            Arc::new(ConnectionLimits::new(
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+            )),
+            max_streams_per_ms.clone(),
+            &StreamLoadEmaConfig::default(),
+        );
+        load_ema.current_load_ema.store(10000, Ordering::Relaxed);
+        let staked_capacity = load_ema.available_load_capacity_in_throttling_duration(
+            ConnectionPeerType::Staked(1000),
+            10000,
+        );
+        assert_eq!(
+            load_ema.available_load_capacity_in_throttling_duration(
+                ConnectionPeerType::Unstaked,
+                10000
+            ),
+            10
+        );
+
+        max_streams_per_ms.store(2 * DEFAULT_MAX_STREAMS_PER_MS, Ordering::Relaxed);
+        assert_eq!(
+            load_ema.available_load_capacity_in_throttling_duration(
+                ConnectionPeerType::Unstaked,
+                10000
+            ),
+            20
+        );
+        // The capacity grows with the square of the max load.
+        assert_eq!(
+            load_ema.available_load_capacity_in_throttling_duration(
+                ConnectionPeerType::Staked(1000),
+                10000
+            ),
+            4 * staked_capacity
+        );
+
+        max_streams_per_ms.store(0, Ordering::Relaxed);
+        assert_eq!(load_ema.max_streams_per_ms(), 1);
+
+        // Stored without going through the validation, the loads derived from it don't overflow.
+        max_streams_per_ms.store(u64::MAX, Ordering::Relaxed);
+        assert_eq!(load_ema.max_streams_per_ms(), MAX_STREAMS_PER_MS_LIMIT);
+        load_ema.current_load_ema.store(u64::MAX, Ordering::Relaxed);
+        assert!(
+            load_ema.available_load_capacity_in_throttling_duration(
+                ConnectionPeerType::Staked(u64::MAX),
+                u64::MAX
+            ) > 0
+        );
+        assert!(
+            load_ema.available_load_capacity_in_throttling_duration(
+                ConnectionPeerType::Unstaked,
+                u64::MAX
+            ) > 0
+        );
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let stats = Arc::new(StreamStats::default());
+        let load_ema = StakedStreamLoadEMA::new(
+            stats.clone(),
+            Arc::new(ConnectionLimits::new(
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+            )),
+            Arc::new(AtomicU64::new(DEFAULT_MAX_STREAMS_PER_MS)),
+            &StreamLoadEmaConfig {
+                backpressure: Some(BackpressureThrottleConfig::default()),
+                ..StreamLoadEmaConfig::default()
+            },
+        );
+        let unstaked_capacity = || {
+            load_ema.available_load_capacity_in_throttling_duration(ConnectionPeerType::Unstaked, 1)
+        };
+        assert_eq!(unstaked_capacity(), 10);
+
+        // Half full, the start of the scaling.
+        load_ema.update_backpressure(50, 100);
+        assert_eq!(unstaked_capacity(), 10);
+        load_ema.update_backpressure(75, 100);
+        assert_eq!(stats.stream_throttle_scale_percent.get(), 55);
+        assert_eq!(unstaked_capacity(), 5);
+        load_ema.update_backpressure(100, 100);
+        assert_eq!(stats.stream_throttle_scale_percent.get(), 10);
+        assert_eq!(unstaked_capacity(), 1);
+        // The classification of peers keeps the configured limit.
+        assert_eq!(load_ema.max_streams_per_ms(), DEFAULT_MAX_STREAMS_PER_MS);
+
+        load_ema.update_backpressure(0, 100);
+        assert_eq!(stats.stream_throttle_scale_percent.get(), 100);
+        assert_eq!(unstaked_capacity(), 10);
+    }
+
     #[test]
     fn test_update_ema_missing_interval() {
         let stream_load_ema = Arc::new(StakedStreamLoadEMA::new(
             Arc::new(StreamStats::default()),
-            MAX_UNSTAKED_CONNECTIONS,
-            DEFAULT_MAX_STREAMS_PER_MS,
+            Arc::new(ConnectionLimits::new(
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+            )),
+            Arc::new(AtomicU64::new(DEFAULT_MAX_STREAMS_PER_MS)),
+            &StreamLoadEmaConfig::default(),
         ));
         stream_load_ema
             .load_in_recent_interval
@@ -489,8 +698,12 @@ pub mod test {
     fn test_update_ema_if_needed() {
         let stream_load_ema = Arc::new(StakedStreamLoadEMA::new(
             Arc::new(StreamStats::default()),
-            MAX_UNSTAKED_CONNECTIONS,
-            DEFAULT_MAX_STREAMS_PER_MS,
+            Arc::new(ConnectionLimits::new(
+                MAX_STAKED_CONNECTIONS,
+                MAX_UNSTAKED_CONNECTIONS,
+            )),
+            Arc::new(AtomicU64::new(DEFAULT_MAX_STREAMS_PER_MS)),
+            &StreamLoadEmaConfig::default(),
         ));
         stream_load_ema
             .load_in_recent_interval
@@ -0,0 +1,374 @@
+//! The `packet_sink` module defines where the QUIC server delivers the packet batches it
+//! assembles.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use thiserror::Error;
+
+use crate::packet::{PacketBatch, PACKETS_PER_BATCH};
+
+/// How long the consumer may stall before a channel sized by
+/// [`recommended_packet_channel_capacity`] fills up.
+pub const PACKET_CHANNEL_STALL_TOLERANCE: Duration = Duration::from_millis(200);
+
+/// Smallest capacity [`recommended_packet_channel_capacity`] returns.
+pub const MIN_PACKET_CHANNEL_CAPACITY: usize = 64;
+
+/// Returns a channel capacity, in batches, that absorbs a consumer stall of
+/// [`PACKET_CHANNEL_STALL_TOLERANCE`].
+///
+/// The batcher flushes at least once per `coalesce` window, and when every staked peer gets a
+/// packet into the window, it flushes `max_staked_connections / PACKETS_PER_BATCH` full batches.
+pub fn recommended_packet_channel_capacity(
+    max_staked_connections: usize,
+    coalesce: Duration,
+) -> usize {
+    let windows = PACKET_CHANNEL_STALL_TOLERANCE
+        .as_nanos()
+        .div_ceil(coalesce.as_nanos().max(1)) as usize;
+    let batches_per_window = max_staked_connections.div_ceil(PACKETS_PER_BATCH).max(1);
+    windows
+        .saturating_mul(batches_per_window)
+        .max(MIN_PACKET_CHANNEL_CAPACITY)
+}
+
+/// Creates a bounded channel for the packet batches of the QUIC server, see
+/// [`recommended_packet_channel_capacity`] for sizing it. The server never blocks on a full
+/// channel, see [`crate::quic::FullChannelPolicy`] for what it does instead.
+pub fn bounded_packet_channel(capacity: usize) -> (Sender<PacketBatch>, Receiver<PacketBatch>) {
+    bounded(capacity)
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SinkError {
+    #[error("packet sink disconnected")]
+    Disconnected,
+
+    #[error("packet sink full")]
+    Full,
+}
+
+/// Consumer of the packet batches assembled by the QUIC server.
+///
+/// The server hands the batches over with `try_send_batch` from its runtime, by default through
+/// `send_batch`, so implementations should not block for long there. Failed sends are counted
+/// in `total_packet_batch_send_err` and the batch is dropped.
+pub trait PacketSink: Send + Sync + 'static {
+    fn send_batch(&self, batch: PacketBatch) -> Result<(), SinkError>;
+
+    /// Like [`PacketSink::send_batch`], but hands the batch back along with
+    /// [`SinkError::Full`] so that the send can be retried, see
+    /// [`crate::quic::FullChannelPolicy::Block`]. The default implementation never does, which
+    /// makes the batch count as dropped.
+    fn try_send_batch(&self, batch: PacketBatch) -> Result<(), (SinkError, Option<PacketBatch>)> {
+        self.send_batch(batch).map_err(|err| (err, None))
+    }
+
+    /// Number of batches waiting for the consumer, reported as `packet_channel_depth`. `None`,
+    /// the default, if the sink doesn't queue or can't tell.
+    fn depth(&self) -> Option<usize> {
+        None
+    }
+
+    /// Number of batches that may wait for the consumer, see
+    /// [`crate::quic::BackpressureThrottleConfig`]. `None`, the default, if unbounded or
+    /// unknown.
+    fn capacity(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// `send_batch` waits for room in a full bounded channel, while `try_send_batch`, which the
+/// server uses, fails the send with [`SinkError::Full`] instead.
+impl PacketSink for Sender<PacketBatch> {
+    fn send_batch(&self, batch: PacketBatch) -> Result<(), SinkError> {
+        self.send(batch).map_err(|_| SinkError::Disconnected)
+    }
+
+    fn try_send_batch(&self, batch: PacketBatch) -> Result<(), (SinkError, Option<PacketBatch>)> {
+        self.try_send(batch).map_err(|err| match err {
+            TrySendError::Full(batch) => (SinkError::Full, Some(batch)),
+            TrySendError::Disconnected(batch) => (SinkError::Disconnected, Some(batch)),
+        })
+    }
+
+    fn depth(&self) -> Option<usize> {
+        Some(self.len())
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        Sender::capacity(self)
+    }
+}
+
+impl<S: PacketSink + ?Sized> PacketSink for Arc<S> {
+    fn send_batch(&self, batch: PacketBatch) -> Result<(), SinkError> {
+        self.as_ref().send_batch(batch)
+    }
+
+    fn try_send_batch(&self, batch: PacketBatch) -> Result<(), (SinkError, Option<PacketBatch>)> {
+        self.as_ref().try_send_batch(batch)
+    }
+
+    fn depth(&self) -> Option<usize> {
+        self.as_ref().depth()
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        self.as_ref().capacity()
+    }
+}
+
+/// Distributes the batches round-robin across several sinks, e.g. to feed parallel
+/// processing pipelines.
+///
+/// A sink that reports [`SinkError::Disconnected`] is taken out of the rotation, and the batch
+/// goes on to the next sink if it was handed back along with the error. The fan-out only
+/// reports the error once every sink has.
+pub struct FanOutSink<S = Sender<PacketBatch>> {
+    sinks: Vec<S>,
+    next: AtomicUsize,
+    send_errors: Vec<AtomicUsize>,
+    disconnected: Vec<AtomicBool>,
+}
+
+impl<S: PacketSink> FanOutSink<S> {
+    /// Panics if `sinks` is empty.
+    pub fn new(sinks: Vec<S>) -> Self {
+        assert!(!sinks.is_empty(), "FanOutSink requires at least one sink");
+        let send_errors = sinks.iter().map(|_| AtomicUsize::default()).collect();
+        let disconnected = sinks.iter().map(|_| AtomicBool::default()).collect();
+        Self {
+            sinks,
+            next: AtomicUsize::default(),
+            send_errors,
+            disconnected,
+        }
+    }
+
+    // Never empty, see `new`.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    /// Number of batches the sink at `index` failed to accept.
+    pub fn send_errors(&self, index: usize) -> usize {
+        self.send_errors[index].load(Ordering::Relaxed)
+    }
+
+    /// Whether the sink at `index` was taken out of the rotation.
+    pub fn is_disconnected(&self, index: usize) -> bool {
+        self.disconnected[index].load(Ordering::Relaxed)
+    }
+
+    // The next sink in turn that's still connected, None once none is.
+    fn next_index(&self) -> Option<usize> {
+        (0..self.sinks.len())
+            .map(|_| self.next.fetch_add(1, Ordering::Relaxed) % self.sinks.len())
+            .find(|&index| !self.is_disconnected(index))
+    }
+
+    fn record_error(&self, index: usize, err: &SinkError) {
+        self.send_errors[index].fetch_add(1, Ordering::Relaxed);
+        if *err == SinkError::Disconnected {
+            self.disconnected[index].store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn connected_sinks(&self) -> impl Iterator<Item = &S> {
+        self.sinks
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| !self.is_disconnected(index))
+            .map(|(_, sink)| sink)
+    }
+}
+
+impl<S: PacketSink> PacketSink for FanOutSink<S> {
+    // Tries the sink first so that a disconnected one hands the batch back, and only waits on
+    // one that's full.
+    fn send_batch(&self, mut batch: PacketBatch) -> Result<(), SinkError> {
+        while let Some(index) = self.next_index() {
+            let sink = &self.sinks[index];
+            let result = match sink.try_send_batch(batch) {
+                Err((SinkError::Disconnected, Some(returned))) => {
+                    self.record_error(index, &SinkError::Disconnected);
+                    batch = returned;
+                    continue;
+                }
+                Err((SinkError::Full, Some(returned))) => sink.send_batch(returned),
+                result => result.map_err(|(err, _)| err),
+            };
+            if let Err(err) = &result {
+                self.record_error(index, err);
+            }
+            return result;
+        }
+        Err(SinkError::Disconnected)
+    }
+
+    // A retried batch goes to the next sink in turn.
+    fn try_send_batch(
+        &self,
+        mut batch: PacketBatch,
+    ) -> Result<(), (SinkError, Option<PacketBatch>)> {
+        while let Some(index) = self.next_index() {
+            match self.sinks[index].try_send_batch(batch) {
+                Ok(()) => return Ok(()),
+                Err((err, returned)) => {
+                    self.record_error(index, &err);
+                    match (err, returned) {
+                        (SinkError::Disconnected, Some(returned)) => batch = returned,
+                        error => return Err(error),
+                    }
+                }
+            }
+        }
+        Err((SinkError::Disconnected, Some(batch)))
+    }
+
+    // The total over the connected sinks that report one.
+    fn depth(&self) -> Option<usize> {
+        self.connected_sinks()
+            .filter_map(PacketSink::depth)
+            .reduce(|a, b| a + b)
+    }
+
+    // The total, if every connected sink reports one.
+    fn capacity(&self) -> Option<usize> {
+        self.connected_sinks().map(PacketSink::capacity).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use crossbeam_channel::unbounded;
+
+    use super::*;
+
+    #[test]
+    fn test_crossbeam_sender_sink() {
+        let (sender, receiver) = unbounded();
+        assert_eq!(sender.send_batch(PacketBatch::with_capacity(1)), Ok(()));
+        assert_eq!(receiver.try_iter().count(), 1);
+        drop(receiver);
+        assert_eq!(
+            sender.send_batch(PacketBatch::with_capacity(1)),
+            Err(SinkError::Disconnected)
+        );
+
+        let (sender, receiver) = bounded(1);
+        assert_eq!(sender.send_batch(PacketBatch::with_capacity(1)), Ok(()));
+        assert_matches!(
+            sender.try_send_batch(PacketBatch::with_capacity(1)),
+            Err((SinkError::Full, Some(_)))
+        );
+        assert_eq!(sender.depth(), Some(1));
+        assert_eq!(sender.capacity(), Some(1));
+        // A full bounded channel holds up the send until the consumer makes room.
+        let consumer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            receiver.recv().unwrap();
+            receiver
+        });
+        let start = std::time::Instant::now();
+        assert_eq!(sender.send_batch(PacketBatch::with_capacity(1)), Ok(()));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(consumer.join().unwrap().len(), 1);
+        assert_eq!(unbounded::<PacketBatch>().0.capacity(), None);
+    }
+
+    #[test]
+    fn test_recommended_packet_channel_capacity() {
+        assert_eq!(
+            recommended_packet_channel_capacity(1, Duration::from_millis(100)),
+            MIN_PACKET_CHANNEL_CAPACITY
+        );
+        // 40 windows of 5ms, each flushing 32 full batches for 2000 staked peers.
+        assert_eq!(
+            recommended_packet_channel_capacity(2000, Duration::from_millis(5)),
+            1280
+        );
+        assert!(recommended_packet_channel_capacity(2000, Duration::ZERO) > 1280);
+
+        let (sender, receiver) = bounded_packet_channel(2);
+        assert_eq!(sender.capacity(), Some(2));
+        drop(receiver);
+    }
+
+    #[test]
+    fn test_fan_out_sink() {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..4).map(|_| unbounded()).unzip();
+        let sink = FanOutSink::new(senders);
+        for _ in 0..400 {
+            sink.send_batch(PacketBatch::with_capacity(1)).unwrap();
+        }
+        for receiver in &receivers {
+            assert_eq!(receiver.try_iter().count(), 100);
+        }
+
+        // A disconnected sink hands its batch on to the next one, and is skipped from then on.
+        let mut receivers = receivers;
+        drop(receivers.remove(1));
+        for _ in 0..7 {
+            sink.send_batch(PacketBatch::with_capacity(1)).unwrap();
+        }
+        assert_eq!(sink.send_errors(0), 0);
+        assert_eq!(sink.send_errors(1), 1);
+        assert!(sink.is_disconnected(1));
+        assert_eq!(sink.depth(), Some(7));
+        assert_eq!(
+            receivers
+                .iter()
+                .map(|receiver| receiver.try_iter().count())
+                .sum::<usize>(),
+            7
+        );
+        assert_eq!(sink.capacity(), None);
+
+        // Once every sink disconnected, the fan-out does too.
+        drop(receivers);
+        for _ in 0..3 {
+            assert_eq!(
+                sink.send_batch(PacketBatch::with_capacity(1)),
+                Err(SinkError::Disconnected)
+            );
+        }
+        assert!((0..4).all(|index| sink.is_disconnected(index)));
+        assert_matches!(
+            sink.try_send_batch(PacketBatch::with_capacity(1)),
+            Err((SinkError::Disconnected, Some(_)))
+        );
+        let sink = FanOutSink::new(vec![bounded(2).0, bounded(3).0]);
+        assert_eq!(sink.capacity(), Some(5));
+    }
+
+    #[test]
+    fn test_fan_out_sink_try_send_disconnected() {
+        let (senders, mut receivers): (Vec<_>, Vec<_>) = (0..2).map(|_| bounded(8)).unzip();
+        let sink = FanOutSink::new(senders);
+        drop(receivers.remove(0));
+        for _ in 0..4 {
+            sink.try_send_batch(PacketBatch::with_capacity(1)).unwrap();
+        }
+        // Every batch went to the connected sink, none was lost.
+        assert_eq!(receivers[0].try_iter().count(), 4);
+        assert!(sink.is_disconnected(0));
+        assert_eq!(sink.send_errors(0), 1);
+        assert_eq!(sink.send_errors(1), 0);
+
+        // Full is still reported as such, with the batch handed back.
+        for _ in 0..8 {
+            sink.try_send_batch(PacketBatch::with_capacity(1)).unwrap();
+        }
+        assert_matches!(
+            sink.try_send_batch(PacketBatch::with_capacity(1)),
+            Err((SinkError::Full, Some(_)))
+        );
+    }
+}
@@ -1,26 +1,60 @@
-use std::net::{IpAddr, UdpSocket};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
-use std::thread;
-use std::time::{Duration, SystemTime};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fmt, thread};
 
+use arc_swap::ArcSwap;
+use bytes::Bytes;
 use crossbeam_channel::Sender;
+use ipnet::IpNet;
 use pem::Pem;
-use quinn::{Endpoint, IdleTimeout, ServerConfig};
-use rustls::server::ClientCertVerified;
-use rustls::{Certificate, DistinguishedName};
-use solana_perf::packet::PacketBatch;
+use quinn::{congestion, crypto, Endpoint, IdleTimeout, RecvStream, SendStream, ServerConfig};
+use quinn_proto::transport_parameters::TransportParameters;
+use quinn_proto::{ConnectionId as QuinnConnectionId, Side, TransportError, TransportErrorCode};
+use rcgen::RcgenError;
+use rustls::server::{
+    ClientCertVerified, ClientCertVerifier, ClientHello, ResolvesServerCert, WantsServerCert,
+};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    AlertDescription, Certificate, ConfigBuilder, DistinguishedName, SupportedCipherSuite,
+    SupportedKxGroup,
+};
+use serde::Serialize;
 use solana_sdk::packet::PACKET_DATA_SIZE;
-use solana_sdk::quic::{NotifyKeyUpdate, QUIC_MAX_TIMEOUT, QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::quic::{
+    NotifyKeyUpdate, QUIC_CONNECTION_HANDSHAKE_TIMEOUT, QUIC_MAX_TIMEOUT,
+    QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS,
+};
 use solana_sdk::signature::Keypair;
-use tokio::runtime::Runtime;
+use tokio::runtime::{Handle, Runtime};
+use tokio::task::AbortHandle;
 
-use crate::nonblocking::quic::ALPN_TPU_PROTOCOL_ID;
+use crate::atomic_histogram::{AtomicHistogram, HistogramSnapshot, RateHistogram};
+use crate::nonblocking::ban_list::BannedPeer;
+use crate::nonblocking::connection_rate_limiter::ConnectionRateLimit;
+use crate::nonblocking::peer_stats::{PeerStatsSnapshot, PeerStatsTable};
+use crate::nonblocking::quic::{
+    ConnectionControl, ConnectionId, ConnectionLimits, PacketBatchConsumer, StakedNodesSource,
+    ALPN_TPU_PROTOCOL_ID, DEFAULT_MAX_STREAMS_PER_MS, DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+    MAX_STREAMS_PER_MS_LIMIT,
+};
+use crate::nonblocking::socket_rebind::SocketRebindConfig;
+use crate::nonblocking::stake_tiers::{StakeTier, StakeTiers};
+use crate::packet_sink::PacketSink;
 use crate::streamer::StakedNodes;
-use crate::tls_certificates::new_self_signed_tls_certificate;
+use crate::tls_certificates::{
+    new_self_signed_tls_certificate_with_options, SelfSignedCertificateOptions,
+};
 
 pub const MAX_STAKED_CONNECTIONS: usize = 2000;
 pub const MAX_UNSTAKED_CONNECTIONS: usize = 500;
+pub const DEFAULT_MAX_CONNECTIONS_PER_PEER: usize = 8;
+pub const DEFAULT_TPU_COALESCE: Duration = Duration::from_millis(5);
 
 pub struct SkipClientVerification;
 
@@ -30,13 +64,194 @@ impl SkipClientVerification {
     }
 }
 
+/// Name of the server thread unless set with [`QuicServerBuilder::thread_name`].
+pub const DEFAULT_SERVER_THREAD_NAME: &str = "solQuicServer";
+
 pub struct SpawnServerResult {
-    pub endpoint: Endpoint,
+    pub endpoints: Vec<Endpoint>,
+    /// Address the endpoints are bound to, including the port assigned by the OS for sockets
+    /// bound to port 0.
+    pub local_addr: SocketAddr,
     pub thread: thread::JoinHandle<()>,
     pub key_updater: Arc<EndpointKeyUpdater>,
+    /// Set when [`QuicServerConfig::enable_peer_stats`] is on.
+    pub peer_stats_table: Option<Arc<PeerStatsTable>>,
+    /// Live counters shared with the server. The server still reports and resets them
+    /// periodically, use [`StreamStats::snapshot`] to read them without interfering.
+    pub stats: Arc<StreamStats>,
+    /// PEM encoded certificate chain the server presented when spawned, e.g. to pin it in
+    /// clients. `None` for an externally constructed TLS configuration. Later rotations by the
+    /// `key_updater` are reported to [`QuicServerConfig::key_rotation_handler`].
+    pub cert_chain_pem: Option<String>,
+    exit: Arc<AtomicBool>,
+    server_task: AbortHandle,
+    max_streams_per_ms: Arc<AtomicU64>,
+    connection_limits: Arc<ConnectionLimits>,
+    connection_control: Arc<ConnectionControl>,
+}
+
+/// Returned by [`SpawnServerResult::shutdown`].
+#[derive(Clone, Debug)]
+pub struct ShutdownSummary {
+    /// Number of streams that were still active when the timeout elapsed.
+    pub outstanding_streams: usize,
+    /// Counters captured after the server thread exited. The server resets its counters every
+    /// time it reports them, so these cover the time since the last periodic report.
+    pub stats: StreamStatsSnapshot,
+}
+
+/// Returned by [`SpawnServerResult::join_timeout`] when the server thread is still running.
+#[derive(thiserror::Error, Debug)]
+#[error("server thread did not exit within {0:?}")]
+pub struct JoinTimeout(pub Duration);
+
+impl SpawnServerResult {
+    /// Waits for the server thread to exit, once `exit` is set, and returns the final counters,
+    /// see [`ShutdownSummary::stats`].
+    pub fn join(self) -> thread::Result<StreamStatsSnapshot> {
+        self.thread.join()?;
+        Ok(self.stats.snapshot())
+    }
+
+    /// Waits up to `timeout` for the server thread to exit, once `exit` is set. When it doesn't,
+    /// `exit` is set and the server task is cancelled, see [`Self::abort`], and the thread is
+    /// left detached.
+    /// Panics of the server thread are resumed on the caller's.
+    pub fn join_timeout(self, timeout: Duration) -> Result<(), JoinTimeout> {
+        const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+        let start = Instant::now();
+        while !self.thread.is_finished() {
+            if start.elapsed() >= timeout {
+                // Also stops the tasks outside of the server task, like `shutdown` does.
+                self.exit.store(true, Ordering::Relaxed);
+                self.server_task.abort();
+                return Err(JoinTimeout(timeout));
+            }
+            thread::sleep(JOIN_POLL_INTERVAL);
+        }
+        self.thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        Ok(())
+    }
+
+    /// Stops accepting new connections, waits up to `timeout` for the active streams to finish
+    /// and flushes the packets received so far before closing the endpoints and joining the
+    /// server thread.
+    pub fn shutdown(self, timeout: Duration) -> thread::Result<ShutdownSummary> {
+        const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+        for endpoint in &self.endpoints {
+            endpoint.set_server_config(None);
+        }
+        let start = Instant::now();
+        while self.stats.active_streams() > 0 && start.elapsed() < timeout {
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+        let outstanding_streams = self.stats.active_streams();
+        for endpoint in &self.endpoints {
+            endpoint.close(0u32.into(), b"shutdown");
+        }
+        self.exit.store(true, Ordering::Relaxed);
+        let stats = self.join()?;
+        Ok(ShutdownSummary {
+            outstanding_streams,
+            stats,
+        })
+    }
+
+    /// Stops the server without waiting for anything: sets `exit`, closes the endpoints, which
+    /// resets the active streams and drops the packets not yet sent, and cancels the server
+    /// task. The server thread then exits at once, unless one of its tasks is stuck outside
+    /// of an await point, so follow up with [`Self::join_timeout`].
+    pub fn abort(&self) {
+        self.exit.store(true, Ordering::Relaxed);
+        for endpoint in &self.endpoints {
+            endpoint.close(0u32.into(), b"abort");
+        }
+        self.server_task.abort();
+    }
+
+    /// Returns the counters of the peer identified by `pubkey`. Always `None` unless
+    /// [`QuicServerConfig::enable_peer_stats`] is on.
+    pub fn peer_stats(&self, pubkey: &Pubkey) -> Option<PeerStatsSnapshot> {
+        self.peer_stats_table.as_ref()?.get(pubkey)
+    }
+
+    /// Whether the server is still accepting connections: `exit` isn't set, the server thread
+    /// is running and at least one endpoint is open. Doesn't tell whether the server keeps up
+    /// with the load, see [`Self::last_packet_received`] for that.
+    pub fn is_healthy(&self) -> bool {
+        !self.exit.load(Ordering::Relaxed)
+            && !self.thread.is_finished()
+            && self.stats.active_endpoints.get() > 0
+    }
+
+    /// When the last packet was received in full from a peer, `None` until the first one.
+    pub fn last_packet_received(&self) -> Option<Instant> {
+        self.stats.last_packet_received.get()
+    }
+
+    /// Whether the endpoints rebind their socket when it fails, see
+    /// [`QuicServerConfig::socket_rebind`].
+    pub fn socket_rebind_enabled(&self) -> bool {
+        self.key_updater.quic_server_config.socket_rebind.is_some()
+    }
+
+    pub fn max_streams_per_ms(&self) -> u64 {
+        self.max_streams_per_ms.load(Ordering::Relaxed)
+    }
+
+    /// Re-tunes the stream throttling without restarting the endpoints. The new limit applies
+    /// from the next throttle window on, zero is treated as one, and values above
+    /// [`MAX_STREAMS_PER_MS_LIMIT`] are capped at it.
+    pub fn set_max_streams_per_ms(&self, max_streams_per_ms: u64) {
+        self.max_streams_per_ms.store(
+            max_streams_per_ms.min(MAX_STREAMS_PER_MS_LIMIT),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn connection_limits(&self) -> &ConnectionLimits {
+        &self.connection_limits
+    }
+
+    /// Changes the cap on staked connections, see [`ConnectionLimits`] for how the server
+    /// applies it.
+    pub fn set_max_staked_connections(&self, max_staked_connections: usize) {
+        self.connection_limits
+            .set_max_staked_connections(max_staked_connections);
+    }
+
+    /// Changes the cap on unstaked connections, see [`ConnectionLimits`] for how the server
+    /// applies it.
+    pub fn set_max_unstaked_connections(&self, max_unstaked_connections: usize) {
+        self.connection_limits
+            .set_max_unstaked_connections(max_unstaked_connections);
+    }
+
+    /// Closes the connections identified by `peer`, returns whether any was found. Panics when
+    /// called from within an async context, use [`ConnectionControl::close_connection`] there.
+    pub fn close_connection(&self, peer: ConnectionId) -> bool {
+        self.connection_control.blocking_close_connection(peer)
+    }
+
+    /// Closes the connections of `peer` and rejects new ones for `duration`. Panics when called
+    /// from within an async context, use [`ConnectionControl::ban`] there.
+    pub fn ban(&self, peer: BannedPeer, duration: Duration) {
+        self.connection_control.blocking_ban(peer, duration);
+    }
+
+    /// Lifts the ban of `peer`, returns whether it was banned.
+    pub fn unban(&self, peer: BannedPeer) -> bool {
+        self.connection_control.unban(peer)
+    }
+
+    pub fn connection_control(&self) -> &Arc<ConnectionControl> {
+        &self.connection_control
+    }
 }
 
-impl rustls::server::ClientCertVerifier for SkipClientVerification {
+impl ClientCertVerifier for SkipClientVerification {
     fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
         &[]
     }
@@ -51,57 +266,1263 @@ impl rustls::server::ClientCertVerifier for SkipClientVerification {
     }
 }
 
+/// Settings of the QUIC server beyond the arguments of the spawn functions: transport
+/// parameters, TLS, admission, throttling, packet delivery and reporting.
+///
+/// Each field documents its default, and [`QuicServerConfig::default`] runs the server the way
+/// the spawn arguments alone describe. The spawn functions, and [`QuicServerBuilder`], reject
+/// inconsistent settings through [`QuicServerConfig::validate`].
+#[derive(Clone)]
+pub struct QuicServerConfig {
+    /// Per-stream receive window in bytes. Defaults to `receive_window_base`.
+    pub stream_receive_window: Option<u32>,
+    /// Connection-wide receive window in bytes during the handshake. Defaults to
+    /// `receive_window_base`.
+    pub receive_window: Option<u32>,
+    /// Bytes the window sizing is based on: the defaults of the two windows above, and the
+    /// connection-wide windows of admitted peers, which are multiples of it scaled by their
+    /// stake. Defaults to `PACKET_DATA_SIZE`, which few networks would want to raise beyond
+    /// experiments with larger packets. Streams are still read as a single packet each and
+    /// dropped once beyond `PACKET_DATA_SIZE`, see `packets_rejected_oversize`.
+    pub receive_window_base: Option<u32>,
+    /// Time after which an idle connection is closed. Defaults to, and may not exceed,
+    /// `QUIC_MAX_TIMEOUT`.
+    pub max_idle_timeout: Option<Duration>,
+    /// Interval at which the server pings otherwise idle peers, so that dead connections are
+    /// detected, and their slots reclaimed, within an idle timeout after going quiet. Must be
+    /// below the idle timeout. Defaults to `None`, which sends no keep-alives.
+    pub keep_alive_interval: Option<Duration>,
+    /// Time a connection may take from being accepted to completing its handshake before it's
+    /// dropped, counted as `connection_setup_timeout`. Defaults to
+    /// `QUIC_CONNECTION_HANDSHAKE_TIMEOUT`.
+    ///
+    /// Must not be shorter than the idle timeout: a handshake whose packets are lost stalls for
+    /// as long as the connection would tolerate once established, so a shorter timeout would
+    /// refuse peers on lossy links the server otherwise keeps. Lower `max_idle_timeout` along
+    /// with it to tighten both on a fast network.
+    pub connection_setup_timeout: Duration,
+    /// Number of streams of a connection that may time out waiting for a chunk, see
+    /// `wait_for_chunk_timeout`, before the connection is closed. Defaults to `None`, which
+    /// only drops the streams and keeps the connection open.
+    pub max_stream_timeouts_before_close: Option<usize>,
+    /// Maximum time a stream may go without producing a chunk before it's dropped, counted as
+    /// `streams_reaped_idle`, to reclaim the resources of peers that open streams and stall.
+    /// The `wait_for_chunk_timeout` spawn argument already bounds every read of a stream, so
+    /// this only takes effect when shorter. Defaults to `None`.
+    ///
+    /// The server asks the peer to stop sending on a dropped stream, and the stream slot counted
+    /// against `max_concurrent_uni_streams` frees up once the peer resets it.
+    pub stream_idle_timeout: Option<Duration>,
+    /// Maximum number of chunks read from a single stream before it's dropped, counted as
+    /// `streams_reset_chunk_limit`. This bounds the work a peer causes by dribbling a packet in
+    /// tiny chunks, regardless of their size. Defaults to `None`, which leaves the number of
+    /// chunks unbounded. Must not be zero.
+    pub max_chunks_per_stream: Option<usize>,
+    /// Replaces `max_chunks_per_stream` for admitted staked peers, e.g. to give them more room.
+    /// Staked peers with too little stake to be treated as staked get the other limit.
+    pub max_staked_chunks_per_stream: Option<usize>,
+    /// Congestion control algorithm of the connections. Defaults to quinn's default, Cubic.
+    pub congestion_controller: CongestionController,
+    /// Maximum number of concurrent unidirectional streams every peer may open. By default,
+    /// peers may open twice `QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS` during the handshake, and
+    /// once the connection is admitted a limit that [`compute_max_allowed_uni_streams`] derives
+    /// from their stake. When set, this replaces both.
+    ///
+    /// [`compute_max_allowed_uni_streams`]: crate::nonblocking::quic::compute_max_allowed_uni_streams
+    pub max_concurrent_uni_streams: Option<u32>,
+    /// Maximum number of concurrent unidirectional streams of admitted staked peers, replacing
+    /// `max_concurrent_uni_streams` and the stake-derived limit for them. Staked peers with too
+    /// little stake to be treated as staked get the unstaked limit.
+    pub max_staked_concurrent_uni_streams: Option<u32>,
+    /// Maximum number of concurrent unidirectional streams of admitted unstaked peers, replacing
+    /// `max_concurrent_uni_streams` and `QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS` for them.
+    ///
+    /// Streams granted during the handshake aren't revoked, so either limit below the handshake
+    /// limit only takes effect as the peer closes streams.
+    pub max_unstaked_concurrent_uni_streams: Option<u32>,
+    /// Maximum rate, in bytes per second, at which the streams of each connection of an
+    /// admitted staked peer are read. Defaults to `None`, which doesn't cap them.
+    pub max_staked_connection_bandwidth: Option<u64>,
+    /// Like `max_staked_connection_bandwidth` for the connections of unstaked peers, including
+    /// staked peers with too little stake to be treated as staked.
+    ///
+    /// A connection may burst a second's worth of bytes before its cap applies. Streams held
+    /// back by the stream load throttling, see `stream_load_ema`, refill the allowance while
+    /// they wait, so the two throttles don't add up.
+    pub max_unstaked_connection_bandwidth: Option<u64>,
+    /// Maximum rate, in bytes per second, at which the streams of all connections are read
+    /// together. Defaults to `None`, which doesn't cap them.
+    ///
+    /// Like the per-connection caps, it allows a burst of a second's worth of bytes. A stream
+    /// that would have to wait longer than the chunk timeout for its next read is dropped and
+    /// counted as timed out.
+    pub max_bandwidth: Option<u64>,
+    /// Percentage of the `max_bandwidth` burst that unstaked peers leave to staked peers, whose
+    /// reads thus resume first when the bandwidth runs short. Defaults to 50.
+    pub staked_bandwidth_reserve_percent: u8,
+    /// Maximum number of chunks handled at once across all connections. Defaults to `None`,
+    /// which doesn't cap them.
+    ///
+    /// When set, a stream waits for a turn to handle each chunk it reads, and the turns go
+    /// round-robin to the connections waiting for one rather than to the streams that asked
+    /// first, so that a connection with many busy streams can't starve the others under
+    /// saturation. Staked connections take up to 4 turns per round, depending on how many
+    /// streams their stake allows.
+    pub max_concurrent_reads: Option<usize>,
+    /// Maximum number of concurrent bidirectional streams every peer may open, served by
+    /// `bidi_stream_handler`. Defaults to 0, which disables them as the TPU doesn't use them.
+    pub max_concurrent_bidi_streams: u32,
+    /// Serves the bidirectional streams of admitted connections, required exactly when
+    /// `max_concurrent_bidi_streams` is non-zero.
+    pub bidi_stream_handler: Option<Arc<dyn BidiStreamHandler>>,
+    /// Receive buffer for unreliable datagrams, in bytes. Defaults to `None`, which disables
+    /// datagrams. Must be set exactly when `datagram_sender` is.
+    pub datagram_receive_buffer_size: Option<usize>,
+    /// Receives the datagrams of admitted connections along with the peer's address, e.g. for
+    /// health probes that don't warrant a stream each. Datagrams that don't fit in the channel
+    /// are dropped and counted in `datagrams_dropped`.
+    pub datagram_sender: Option<Sender<(SocketAddr, Bytes)>>,
+    /// Protocols accepted through ALPN, in order of preference, e.g. to migrate clients to a
+    /// new protocol version. Peers offering none of them are rejected and counted in
+    /// `connection_rejected_alpn`. Defaults to `ALPN_TPU_PROTOCOL_ID` alone.
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// Identities presented instead of the server's own to peers requesting these server names
+    /// through SNI, each with a self-signed certificate like the server's. Names are matched
+    /// case-insensitively. Unused with an externally constructed TLS configuration.
+    pub sni_identities: HashMap<String, Arc<Keypair>>,
+    /// Identity presented to peers requesting no server name, or one missing from
+    /// `sni_identities`.
+    pub sni_fallback: SniFallback,
+    /// Options of the self-signed certificates derived from the identity keypairs. With a
+    /// `validity`, a server that never calls [`EndpointKeyUpdater::update_key`] fails every
+    /// handshake with clients checking it once that much time has passed since it was
+    /// spawned, while every update restarts the validity of the certificate it installs.
+    pub certificate_options: SelfSignedCertificateOptions,
+    /// Cipher suites offered to peers, e.g. to comply with a mandated set. At least one must
+    /// be a TLS 1.3 suite, which QUIC requires. Defaults to rustls' safe defaults. Unused with
+    /// an externally constructed TLS configuration.
+    pub tls_cipher_suites: Option<Vec<SupportedCipherSuite>>,
+    /// Key exchange groups offered to peers, like `tls_cipher_suites`.
+    pub tls_kx_groups: Option<Vec<&'static SupportedKxGroup>>,
+    /// Enables generic segmentation offload for outbound packets. Defaults to `false`, see
+    /// the comment in [`configure_server`] for why.
+    pub enable_segmentation_offload: bool,
+    /// Sends a retry packet to every new client to validate its address before any handshake
+    /// state is allocated. Defaults to `true`. Disabling it saves a round trip during connection
+    /// setup but removes stateless address validation, which leaves the server exposed to
+    /// amplification attacks from spoofed source addresses.
+    pub use_retry: bool,
+    /// How long a retry token stays valid after it was issued. Defaults to `None`, which keeps
+    /// quinn's 15 seconds. A longer lifetime tolerates clients that are slow to answer the
+    /// retry, e.g. over high-latency links. Tokens carry their issue time in whole seconds, so
+    /// they may expire up to a second early.
+    ///
+    /// Retry tokens only validate the address of the connection attempt they were issued for.
+    /// This version of quinn doesn't issue tokens for future connections, so reconnecting
+    /// peers are validated again whatever the lifetime.
+    pub retry_token_lifetime: Option<Duration>,
+    /// Verifier for the certificates presented by clients during the handshake. Defaults to
+    /// [`SkipClientVerification`], which accepts any certificate and leaves it to the stake
+    /// lookup to decide how much to trust the peer.
+    pub client_cert_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    /// Receives, for every batch delivered to the packet sender and in the same order as its
+    /// packets, the pubkey from each sender's certificate. Peers whose certificate can't be
+    /// parsed are reported as `None`.
+    pub identity_sender: Option<Sender<Vec<Option<Pubkey>>>>,
+    /// Receives, like `identity_sender`, the stake each packet's sender was admitted with, which
+    /// is 0 for the peers flagged as unstaked in the packet meta. Saves a second stake lookup
+    /// since the meta has no room for the stake itself.
+    pub stake_sender: Option<Sender<Vec<u64>>>,
+    /// Receives, like `identity_sender`, when the first chunk of each packet was read off its
+    /// stream. Comparing against the time the batch is processed measures the coalesce and
+    /// channel delay.
+    pub receive_time_sender: Option<Sender<Vec<Instant>>>,
+    /// Destination for the periodic stats reports. Defaults to [`default_metrics_sink`].
+    pub metrics_sink: Option<Arc<dyn MetricsSink>>,
+    /// Tracks streams, chunks, bytes and throttle events per peer identity, see
+    /// [`SpawnServerResult::peer_stats`]. Defaults to `false` since the table grows with the
+    /// number of connected peers. Staked peers are kept for
+    /// [`DEFAULT_STAKED_PEER_STATS_TTL`](crate::nonblocking::peer_stats::DEFAULT_STAKED_PEER_STATS_TTL)
+    /// after they disconnect.
+    pub enable_peer_stats: bool,
+    /// Notified when connections are added, evicted and removed.
+    pub connection_event_handler: Option<Arc<dyn ConnectionEventHandler>>,
+    /// Has the final say over the admission of every connection that completed its handshake
+    /// and isn't banned. Defaults to `None`, which admits them all.
+    pub admission_controller: Option<Arc<dyn AdmissionController>>,
+    /// Notified when [`EndpointKeyUpdater::update_key`] installs a new identity.
+    pub key_rotation_handler: Option<Arc<dyn KeyRotationHandler>>,
+    /// When set, only connections from these networks are accepted. The source address is
+    /// checked as soon as the connection attempt arrives, before the handshake completes.
+    pub allowed_ips: Option<Vec<IpNet>>,
+    /// Connections from these networks are rejected, even if they're also in `allowed_ips`.
+    pub denied_ips: Vec<IpNet>,
+    /// Load balancers in front of the endpoint that prepend a PROXY protocol v2 header to the
+    /// datagrams they relay, on every datagram or only on the first ones of a flow. The header
+    /// is stripped off the datagrams from these networks, and the client address it carries
+    /// stands in for the balancer's in the ip filters, bans, rate limits, connection tables,
+    /// connection events and packet meta. Datagrams from other sources are never parsed, so
+    /// clients can't claim another address. Replies still go out to the balancer, and stakes
+    /// are looked up by the certificate, unaffected by the address. Disabled by default.
+    pub proxy_protocol_trusted_proxies: Option<Vec<IpNet>>,
+    /// Limits how fast each source IP may open connections that end up unstaked. Staked peers
+    /// are exempt. Since the stake is only known from the certificate, the limit is applied
+    /// once the handshake completes.
+    pub unstaked_connection_rate_limit: Option<ConnectionRateLimit>,
+    /// When set, the stats are reported at this interval from a dedicated thread, which stops
+    /// once `exit` is set. Otherwise they're reported every 5 seconds from the accept loop,
+    /// which may be late by up to a second while no connections arrive.
+    pub report_interval: Option<Duration>,
+    /// Runtime created by the blocking spawn functions. Unused by the nonblocking ones and
+    /// when running on a caller-provided runtime.
+    pub runtime_config: RuntimeConfig,
+    /// When set, the packets of staked peers are batched separately and delivered here, while
+    /// the packet sender only receives the packets of unstaked peers. Peers are classified by
+    /// the same stake lookup that drives the staked and unstaked packet counters.
+    ///
+    /// Can't be combined with `identity_sender`, `stake_sender` or `receive_time_sender`. Their
+    /// vectors carry no reference to a batch and are paired with the batches by the order both
+    /// are sent in, which doesn't hold once the batches go to two sinks drained independently.
+    pub staked_packet_sink: Option<Arc<dyn PacketSink>>,
+    /// Flushes a batch once it holds this many bytes, even if the coalesce window hasn't
+    /// elapsed yet.
+    pub coalesce_max_bytes: Option<usize>,
+    /// Flushes a batch once it holds this many packets, even if the coalesce window hasn't
+    /// elapsed yet. Batches never hold more than `PACKETS_PER_BATCH` packets.
+    pub coalesce_max_packets: Option<usize>,
+    /// Tuning of the stream throttling of staked peers.
+    pub stream_load_ema: StreamLoadEmaConfig,
+    /// Splits the staked connection slots by the stake of the peers so that many low-stake
+    /// peers can't crowd out the high-stake ones. Each tier reserves its `max_connections` out of
+    /// `max_staked_connections`, the staked peers below every tier share what's left. A full tier
+    /// evicts its lowest-stake connection for a higher-stake peer of the same tier. Empty, the
+    /// default, puts all staked peers in a single pool.
+    pub stake_tiers: Vec<StakeTier>,
+    /// Picks the connection a full staked table evicts for a new staked peer. Not consulted
+    /// when `stake_tiers` are set.
+    pub eviction_policy: EvictionPolicy,
+    /// Whether staked peers the staked table has no room for may take, and evict, unstaked
+    /// connection slots.
+    pub staked_overflow: StakedOverflow,
+    /// Peers identified by their certificate that are always admitted, regardless of the
+    /// connection caps and the unstaked connection rate limit, and never evicted. Their
+    /// connections still count against the caps, so keep the set small. Bans and
+    /// `max_connections_per_peer` still apply.
+    pub protected_peers: HashSet<Pubkey>,
+    /// Maximum number of concurrent connections of each admitted staked peer, replacing the
+    /// `max_connections_per_peer` spawn argument for them, e.g. to let staked nodes open
+    /// several connections from the same host. Staked peers with too little stake to be
+    /// treated as staked get the unstaked limit.
+    pub max_connections_per_staked_peer: Option<usize>,
+    /// Like `max_connections_per_staked_peer` for unstaked peers, which are told apart by their
+    /// IP address.
+    pub max_connections_per_unstaked_peer: Option<usize>,
+    /// Handling of batches the packet sink, or `staked_packet_sink`, can't take right away.
+    pub full_channel_policy: FullChannelPolicy,
+    /// When set, an endpoint whose socket fails binds a fresh one to the same address instead
+    /// of stopping, keeping its connections, stats and staked nodes. Disabled by default.
+    pub socket_rebind: Option<SocketRebindConfig>,
+}
+
+impl Default for QuicServerConfig {
+    fn default() -> Self {
+        Self {
+            stream_receive_window: None,
+            receive_window: None,
+            receive_window_base: None,
+            max_idle_timeout: None,
+            keep_alive_interval: None,
+            connection_setup_timeout: QUIC_CONNECTION_HANDSHAKE_TIMEOUT,
+            max_stream_timeouts_before_close: None,
+            stream_idle_timeout: None,
+            max_chunks_per_stream: None,
+            max_staked_chunks_per_stream: None,
+            congestion_controller: CongestionController::default(),
+            max_concurrent_uni_streams: None,
+            max_staked_concurrent_uni_streams: None,
+            max_unstaked_concurrent_uni_streams: None,
+            max_staked_connection_bandwidth: None,
+            max_unstaked_connection_bandwidth: None,
+            max_bandwidth: None,
+            staked_bandwidth_reserve_percent: 50,
+            max_concurrent_reads: None,
+            max_concurrent_bidi_streams: 0,
+            bidi_stream_handler: None,
+            datagram_receive_buffer_size: None,
+            datagram_sender: None,
+            alpn_protocols: vec![ALPN_TPU_PROTOCOL_ID.to_vec()],
+            sni_identities: HashMap::default(),
+            sni_fallback: SniFallback::default(),
+            certificate_options: SelfSignedCertificateOptions::default(),
+            tls_cipher_suites: None,
+            tls_kx_groups: None,
+            enable_segmentation_offload: false,
+            use_retry: true,
+            retry_token_lifetime: None,
+            client_cert_verifier: None,
+            identity_sender: None,
+            stake_sender: None,
+            receive_time_sender: None,
+            metrics_sink: None,
+            enable_peer_stats: false,
+            connection_event_handler: None,
+            admission_controller: None,
+            key_rotation_handler: None,
+            allowed_ips: None,
+            denied_ips: Vec::new(),
+            proxy_protocol_trusted_proxies: None,
+            unstaked_connection_rate_limit: None,
+            report_interval: None,
+            runtime_config: RuntimeConfig::default(),
+            staked_packet_sink: None,
+            coalesce_max_bytes: None,
+            coalesce_max_packets: None,
+            stream_load_ema: StreamLoadEmaConfig::default(),
+            stake_tiers: Vec::default(),
+            eviction_policy: EvictionPolicy::default(),
+            staked_overflow: StakedOverflow::default(),
+            protected_peers: HashSet::default(),
+            max_connections_per_staked_peer: None,
+            max_connections_per_unstaked_peer: None,
+            full_channel_policy: FullChannelPolicy::default(),
+            socket_rebind: None,
+        }
+    }
+}
+
+// By hand for the handlers, which are trait objects.
+impl fmt::Debug for QuicServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicServerConfig")
+            .field("stream_receive_window", &self.stream_receive_window)
+            .field("receive_window", &self.receive_window)
+            .field("receive_window_base", &self.receive_window_base)
+            .field("max_idle_timeout", &self.max_idle_timeout)
+            .field("keep_alive_interval", &self.keep_alive_interval)
+            .field("connection_setup_timeout", &self.connection_setup_timeout)
+            .field(
+                "max_stream_timeouts_before_close",
+                &self.max_stream_timeouts_before_close,
+            )
+            .field("stream_idle_timeout", &self.stream_idle_timeout)
+            .field("max_chunks_per_stream", &self.max_chunks_per_stream)
+            .field(
+                "max_staked_chunks_per_stream",
+                &self.max_staked_chunks_per_stream,
+            )
+            .field("congestion_controller", &self.congestion_controller)
+            .field(
+                "max_concurrent_uni_streams",
+                &self.max_concurrent_uni_streams,
+            )
+            .field(
+                "max_staked_concurrent_uni_streams",
+                &self.max_staked_concurrent_uni_streams,
+            )
+            .field(
+                "max_unstaked_concurrent_uni_streams",
+                &self.max_unstaked_concurrent_uni_streams,
+            )
+            .field(
+                "max_staked_connection_bandwidth",
+                &self.max_staked_connection_bandwidth,
+            )
+            .field(
+                "max_unstaked_connection_bandwidth",
+                &self.max_unstaked_connection_bandwidth,
+            )
+            .field("max_bandwidth", &self.max_bandwidth)
+            .field(
+                "staked_bandwidth_reserve_percent",
+                &self.staked_bandwidth_reserve_percent,
+            )
+            .field("max_concurrent_reads", &self.max_concurrent_reads)
+            .field(
+                "max_concurrent_bidi_streams",
+                &self.max_concurrent_bidi_streams,
+            )
+            .field(
+                "bidi_stream_handler",
+                &self
+                    .bidi_stream_handler
+                    .as_ref()
+                    .map(|_| "dyn BidiStreamHandler"),
+            )
+            .field(
+                "datagram_receive_buffer_size",
+                &self.datagram_receive_buffer_size,
+            )
+            .field("datagram_sender", &self.datagram_sender)
+            .field("alpn_protocols", &self.alpn_protocols)
+            // The keypairs would print their secret keys.
+            .field("sni_identities", &self.sni_identities.keys())
+            .field("sni_fallback", &self.sni_fallback)
+            .field("certificate_options", &self.certificate_options)
+            .field("tls_cipher_suites", &self.tls_cipher_suites)
+            .field("tls_kx_groups", &self.tls_kx_groups)
+            .field(
+                "enable_segmentation_offload",
+                &self.enable_segmentation_offload,
+            )
+            .field("use_retry", &self.use_retry)
+            .field("retry_token_lifetime", &self.retry_token_lifetime)
+            .field(
+                "client_cert_verifier",
+                &self
+                    .client_cert_verifier
+                    .as_ref()
+                    .map(|_| "dyn ClientCertVerifier"),
+            )
+            .field("identity_sender", &self.identity_sender)
+            .field("stake_sender", &self.stake_sender)
+            .field("receive_time_sender", &self.receive_time_sender)
+            .field(
+                "metrics_sink",
+                &self.metrics_sink.as_ref().map(|_| "dyn MetricsSink"),
+            )
+            .field("enable_peer_stats", &self.enable_peer_stats)
+            .field(
+                "connection_event_handler",
+                &self
+                    .connection_event_handler
+                    .as_ref()
+                    .map(|_| "dyn ConnectionEventHandler"),
+            )
+            .field(
+                "admission_controller",
+                &self
+                    .admission_controller
+                    .as_ref()
+                    .map(|_| "dyn AdmissionController"),
+            )
+            .field(
+                "key_rotation_handler",
+                &self
+                    .key_rotation_handler
+                    .as_ref()
+                    .map(|_| "dyn KeyRotationHandler"),
+            )
+            .field("allowed_ips", &self.allowed_ips)
+            .field("denied_ips", &self.denied_ips)
+            .field(
+                "proxy_protocol_trusted_proxies",
+                &self.proxy_protocol_trusted_proxies,
+            )
+            .field(
+                "unstaked_connection_rate_limit",
+                &self.unstaked_connection_rate_limit,
+            )
+            .field("report_interval", &self.report_interval)
+            .field("runtime_config", &self.runtime_config)
+            .field(
+                "staked_packet_sink",
+                &self.staked_packet_sink.as_ref().map(|_| "dyn PacketSink"),
+            )
+            .field("coalesce_max_bytes", &self.coalesce_max_bytes)
+            .field("coalesce_max_packets", &self.coalesce_max_packets)
+            .field("stream_load_ema", &self.stream_load_ema)
+            .field("stake_tiers", &self.stake_tiers)
+            .field("eviction_policy", &self.eviction_policy)
+            .field("staked_overflow", &self.staked_overflow)
+            .field("protected_peers", &self.protected_peers)
+            .field(
+                "max_connections_per_staked_peer",
+                &self.max_connections_per_staked_peer,
+            )
+            .field(
+                "max_connections_per_unstaked_peer",
+                &self.max_connections_per_unstaked_peer,
+            )
+            .field("full_channel_policy", &self.full_channel_policy)
+            .field("socket_rebind", &self.socket_rebind)
+            .finish()
+    }
+}
+
+impl QuicServerConfig {
+    /// Returns whether connections from `ip` pass the `allowed_ips` and `denied_ips` filters.
+    pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+        // Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses.
+        let ip = ip.to_canonical();
+        if self.denied_ips.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        match &self.allowed_ips {
+            Some(allowed) => allowed.iter().any(|net| net.contains(&ip)),
+            None => true,
+        }
+    }
+
+    pub(crate) fn receive_window_base(&self) -> u32 {
+        self.receive_window_base.unwrap_or(PACKET_DATA_SIZE as u32)
+    }
+
+    pub fn validate(&self) -> Result<(), QuicServerError> {
+        let windows = [
+            ("stream_receive_window", self.stream_receive_window),
+            ("receive_window", self.receive_window),
+            ("receive_window_base", self.receive_window_base),
+        ];
+        for (field, window) in windows {
+            if let Some(window) = window.filter(|&window| (window as usize) < PACKET_DATA_SIZE) {
+                return Err(QuicServerError::InvalidConfig(format!(
+                    "{field} ({window}) must be at least PACKET_DATA_SIZE ({PACKET_DATA_SIZE})"
+                )));
+            }
+        }
+        if let Some(max_idle_timeout) = self
+            .max_idle_timeout
+            .filter(|&timeout| timeout > QUIC_MAX_TIMEOUT)
+        {
+            return Err(QuicServerError::InvalidConfig(format!(
+                "max_idle_timeout ({max_idle_timeout:?}) must not exceed QUIC_MAX_TIMEOUT \
+                 ({QUIC_MAX_TIMEOUT:?})"
+            )));
+        }
+        let max_idle_timeout = self.max_idle_timeout.unwrap_or(QUIC_MAX_TIMEOUT);
+        if let Some(keep_alive_interval) = self
+            .keep_alive_interval
+            .filter(|&interval| interval.is_zero() || interval >= max_idle_timeout)
+        {
+            return Err(QuicServerError::InvalidConfig(format!(
+                "keep_alive_interval ({keep_alive_interval:?}) must be non-zero and below the \
+                 idle timeout ({max_idle_timeout:?})"
+            )));
+        }
+        if self.connection_setup_timeout.is_zero()
+            || self.connection_setup_timeout < max_idle_timeout
+        {
+            return Err(QuicServerError::InvalidConfig(format!(
+                "connection_setup_timeout ({:?}) must be non-zero and not shorter than the idle \
+                 timeout ({max_idle_timeout:?})",
+                self.connection_setup_timeout
+            )));
+        }
+        let bandwidths = [
+            (
+                "max_staked_connection_bandwidth",
+                self.max_staked_connection_bandwidth,
+            ),
+            (
+                "max_unstaked_connection_bandwidth",
+                self.max_unstaked_connection_bandwidth,
+            ),
+            ("max_bandwidth", self.max_bandwidth),
+        ];
+        for (field, bandwidth) in bandwidths {
+            if bandwidth == Some(0) {
+                return Err(QuicServerError::InvalidConfig(format!(
+                    "{field} must not be zero"
+                )));
+            }
+        }
+        let limits = [
+            ("max_chunks_per_stream", self.max_chunks_per_stream),
+            (
+                "max_staked_chunks_per_stream",
+                self.max_staked_chunks_per_stream,
+            ),
+            (
+                "max_connections_per_staked_peer",
+                self.max_connections_per_staked_peer,
+            ),
+            (
+                "max_connections_per_unstaked_peer",
+                self.max_connections_per_unstaked_peer,
+            ),
+            ("max_concurrent_reads", self.max_concurrent_reads),
+        ];
+        for (field, limit) in limits {
+            if limit == Some(0) {
+                return Err(QuicServerError::InvalidConfig(format!(
+                    "{field} must not be zero"
+                )));
+            }
+        }
+        if self
+            .socket_rebind
+            .is_some_and(|socket_rebind| socket_rebind.max_attempts == 0)
+        {
+            return Err(QuicServerError::InvalidConfig(
+                "socket_rebind.max_attempts must not be zero".to_string(),
+            ));
+        }
+        if self.staked_bandwidth_reserve_percent > 100 {
+            return Err(QuicServerError::InvalidConfig(format!(
+                "staked_bandwidth_reserve_percent ({}) must not exceed 100",
+                self.staked_bandwidth_reserve_percent
+            )));
+        }
+        if self.retry_token_lifetime == Some(Duration::ZERO) {
+            return Err(QuicServerError::InvalidConfig(
+                "retry_token_lifetime must not be zero".to_string(),
+            ));
+        }
+        if self.stream_idle_timeout == Some(Duration::ZERO) {
+            return Err(QuicServerError::InvalidConfig(
+                "stream_idle_timeout must not be zero".to_string(),
+            ));
+        }
+        if self.max_stream_timeouts_before_close == Some(0) {
+            return Err(QuicServerError::InvalidConfig(
+                "max_stream_timeouts_before_close must not be zero".to_string(),
+            ));
+        }
+        if (self.max_concurrent_bidi_streams > 0) != self.bidi_stream_handler.is_some() {
+            return Err(QuicServerError::InvalidConfig(
+                "bidi_stream_handler must be set exactly when max_concurrent_bidi_streams is \
+                 non-zero"
+                    .to_string(),
+            ));
+        }
+        if self.datagram_receive_buffer_size.is_some() != self.datagram_sender.is_some() {
+            return Err(QuicServerError::InvalidConfig(
+                "datagram_sender must be set exactly when datagram_receive_buffer_size is"
+                    .to_string(),
+            ));
+        }
+        if self.datagram_receive_buffer_size == Some(0) {
+            return Err(QuicServerError::InvalidConfig(
+                "datagram_receive_buffer_size must not be zero".to_string(),
+            ));
+        }
+        if self.alpn_protocols.is_empty() || self.alpn_protocols.iter().any(Vec::is_empty) {
+            return Err(QuicServerError::InvalidConfig(
+                "alpn_protocols must be non-empty and hold non-empty protocol ids".to_string(),
+            ));
+        }
+        if self.certificate_options.validity == Some(Duration::ZERO) {
+            return Err(QuicServerError::InvalidConfig(
+                "certificate_options.validity must not be zero".to_string(),
+            ));
+        }
+        if self
+            .tls_cipher_suites
+            .as_ref()
+            .is_some_and(|cipher_suites| {
+                !cipher_suites
+                    .iter()
+                    .any(|suite| matches!(suite, SupportedCipherSuite::Tls13(_)))
+            })
+        {
+            return Err(QuicServerError::InvalidConfig(
+                "tls_cipher_suites must include a TLS 1.3 cipher suite".to_string(),
+            ));
+        }
+        if self
+            .tls_kx_groups
+            .as_ref()
+            .is_some_and(|kx_groups| kx_groups.is_empty())
+        {
+            return Err(QuicServerError::InvalidConfig(
+                "tls_kx_groups must not be empty".to_string(),
+            ));
+        }
+        if self.runtime_config.worker_threads == Some(0) {
+            return Err(QuicServerError::InvalidConfig(
+                "runtime_config.worker_threads must not be zero".to_string(),
+            ));
+        }
+        self.stream_load_ema.validate()?;
+        let thresholds = [
+            ("coalesce_max_bytes", self.coalesce_max_bytes),
+            ("coalesce_max_packets", self.coalesce_max_packets),
+        ];
+        for (field, threshold) in thresholds {
+            if threshold == Some(0) {
+                return Err(QuicServerError::InvalidConfig(format!(
+                    "{field} must not be zero"
+                )));
+            }
+        }
+        // Paired with the batches by order, see `staked_packet_sink`.
+        let side_channels = [
+            ("identity_sender", self.identity_sender.is_some()),
+            ("stake_sender", self.stake_sender.is_some()),
+            ("receive_time_sender", self.receive_time_sender.is_some()),
+        ];
+        if let Some((field, _)) = side_channels
+            .into_iter()
+            .find(|&(_, is_set)| is_set && self.staked_packet_sink.is_some())
+        {
+            return Err(QuicServerError::InvalidConfig(format!(
+                "{field} can't be combined with staked_packet_sink"
+            )));
+        }
+        let mut min_stakes: Vec<_> = self.stake_tiers.iter().map(|tier| tier.min_stake).collect();
+        min_stakes.sort_unstable();
+        if min_stakes.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(QuicServerError::InvalidConfig(
+                "stake_tiers must have distinct min_stake values".to_string(),
+            ));
+        }
+        if self.report_interval == Some(Duration::ZERO) {
+            return Err(QuicServerError::InvalidConfig(
+                "report_interval must not be zero".to_string(),
+            ));
+        }
+        // Either would refuse every unstaked connection.
+        if let Some(limit) = self.unstaked_connection_rate_limit.filter(|limit| {
+            limit.burst == 0
+                || !limit.connections_per_second.is_finite()
+                || limit.connections_per_second <= 0.0
+        }) {
+            return Err(QuicServerError::InvalidConfig(format!(
+                "unstaked_connection_rate_limit ({limit:?}) must have a non-zero burst and a \
+                 positive, finite connections_per_second"
+            )));
+        }
+        Ok(())
+    }
+}
+
 /// Returns default server configuration along with its PEM certificate chain.
-#[allow(clippy::field_reassign_with_default)] // https://github.com/rust-lang/rust-clippy/issues/6527
 pub(crate) fn configure_server(
     identity_keypair: &Keypair,
     gossip_host: IpAddr,
     max_concurrent_connections: usize,
+    quic_server_config: &QuicServerConfig,
+    stats: &Arc<StreamStats>,
 ) -> Result<(ServerConfig, String), QuicServerError> {
-    let (cert, priv_key) = new_self_signed_tls_certificate(identity_keypair, gossip_host)?;
+    quic_server_config.validate()?;
+    let default_identity = new_certified_key(
+        identity_keypair,
+        gossip_host,
+        &quic_server_config.certificate_options,
+    )?;
     let cert_chain_pem_parts = vec![Pem {
         tag: "CERTIFICATE".to_string(),
-        contents: cert.0.clone(),
+        contents: default_identity.cert[0].0.clone(),
     }];
     let cert_chain_pem = pem::encode_many(&cert_chain_pem_parts);
+    let sni_identities = quic_server_config
+        .sni_identities
+        .iter()
+        .map(|(server_name, keypair)| {
+            Ok((
+                server_name.to_ascii_lowercase(),
+                new_certified_key(
+                    keypair,
+                    gossip_host,
+                    &quic_server_config.certificate_options,
+                )?,
+            ))
+        })
+        .collect::<Result<_, QuicServerError>>()?;
+
+    let mut server_tls_config = server_tls_config_builder(quic_server_config)?.with_cert_resolver(
+        Arc::new(SniCertResolver {
+            default_identity,
+            sni_identities,
+            sni_fallback: quic_server_config.sni_fallback,
+        }),
+    );
+    server_tls_config.alpn_protocols = quic_server_config.alpn_protocols.clone();
+
+    let server_config = configure_server_with_tls(
+        Arc::new(server_tls_config),
+        max_concurrent_connections,
+        quic_server_config,
+        stats,
+    )?;
+    Ok((server_config, cert_chain_pem))
+}
+
+/// Returns a TLS configuration presenting the certificate chain in `cert_pem` and the private
+/// key in `key_pem`, e.g. provisioned by existing PKI tooling, instead of a self-signed
+/// certificate derived from the identity keypair. It's meant for
+/// [`QuicServerBuilder::new_with_tls`] and advertises the [`QuicServerConfig::alpn_protocols`].
+pub fn configure_server_from_pem(
+    cert_pem: &str,
+    key_pem: &str,
+    quic_server_config: &QuicServerConfig,
+) -> Result<Arc<rustls::ServerConfig>, QuicServerError> {
+    let cert_chain: Vec<_> = pem::parse_many(cert_pem)
+        .map_err(|_| RcgenError::CouldNotParseCertificate)?
+        .into_iter()
+        .filter(|pem| pem.tag == "CERTIFICATE")
+        .map(|pem| rustls::Certificate(pem.contents))
+        .collect();
+    if cert_chain.is_empty() {
+        return Err(RcgenError::CouldNotParseCertificate.into());
+    }
+    let priv_key = pem::parse_many(key_pem)
+        .map_err(|_| RcgenError::CouldNotParseKeyPair)?
+        .into_iter()
+        .find(|pem| {
+            matches!(
+                pem.tag.as_str(),
+                "PRIVATE KEY" | "RSA PRIVATE KEY" | "EC PRIVATE KEY"
+            )
+        })
+        .map(|pem| rustls::PrivateKey(pem.contents))
+        .filter(|priv_key| rustls::sign::any_supported_type(priv_key).is_ok())
+        .ok_or(RcgenError::CouldNotParseKeyPair)?;
+
+    let mut server_tls_config =
+        server_tls_config_builder(quic_server_config)?.with_single_cert(cert_chain, priv_key)?;
+    server_tls_config.alpn_protocols = quic_server_config.alpn_protocols.clone();
+    Ok(Arc::new(server_tls_config))
+}
+
+fn server_tls_config_builder(
+    quic_server_config: &QuicServerConfig,
+) -> Result<ConfigBuilder<rustls::ServerConfig, WantsServerCert>, QuicServerError> {
+    let builder = rustls::ServerConfig::builder();
+    let builder = match &quic_server_config.tls_cipher_suites {
+        Some(cipher_suites) => builder.with_cipher_suites(cipher_suites),
+        None => builder.with_safe_default_cipher_suites(),
+    };
+    let builder = match &quic_server_config.tls_kx_groups {
+        Some(kx_groups) => builder.with_kx_groups(kx_groups),
+        None => builder.with_safe_default_kx_groups(),
+    };
+    // QUIC requires TLS 1.3
+    Ok(builder
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(
+            quic_server_config
+                .client_cert_verifier
+                .clone()
+                .unwrap_or_else(|| SkipClientVerification::new()),
+        ))
+}
+
+fn new_certified_key(
+    keypair: &Keypair,
+    gossip_host: IpAddr,
+    certificate_options: &SelfSignedCertificateOptions,
+) -> Result<Arc<CertifiedKey>, QuicServerError> {
+    let (cert, priv_key) =
+        new_self_signed_tls_certificate_with_options(keypair, gossip_host, certificate_options)?;
+    let signing_key = rustls::sign::any_supported_type(&priv_key)
+        .map_err(|_| rustls::Error::General("unsupported identity key".to_string()))?;
+    Ok(Arc::new(CertifiedKey::new(vec![cert], signing_key)))
+}
+
+// Picks the identity by the server name the peer requested
+struct SniCertResolver {
+    default_identity: Arc<CertifiedKey>,
+    // Keyed by the lowercase server name
+    sni_identities: HashMap<String, Arc<CertifiedKey>>,
+    sni_fallback: SniFallback,
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let identity = client_hello
+            .server_name()
+            .and_then(|server_name| self.sni_identities.get(&server_name.to_ascii_lowercase()));
+        match (identity, self.sni_fallback) {
+            (Some(identity), _) => Some(identity.clone()),
+            (None, SniFallback::DefaultIdentity) => Some(self.default_identity.clone()),
+            (None, SniFallback::Reject) => None,
+        }
+    }
+}
+
+// The handshake of a peer not offering any of the accepted protocols may fail before quinn
+// hands the connection to the server, so the rejection is counted by the TLS session as rustls
+// refuses the ClientHello. A ClientHello answered by a HelloRetryRequest isn't refused, the
+// retried one is.
+struct AlpnCountingServerConfig {
+    inner: Arc<rustls::ServerConfig>,
+    stats: Arc<StreamStats>,
+}
+
+impl crypto::ServerConfig for AlpnCountingServerConfig {
+    fn initial_keys(
+        &self,
+        version: u32,
+        dst_cid: &QuinnConnectionId,
+        side: Side,
+    ) -> Result<crypto::Keys, crypto::UnsupportedVersion> {
+        self.inner.initial_keys(version, dst_cid, side)
+    }
+
+    fn retry_tag(&self, version: u32, orig_dst_cid: &QuinnConnectionId, packet: &[u8]) -> [u8; 16] {
+        self.inner.retry_tag(version, orig_dst_cid, packet)
+    }
+
+    fn start_session(
+        self: Arc<Self>,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Box<dyn crypto::Session> {
+        Box::new(AlpnCountingSession {
+            inner: self.inner.clone().start_session(version, params),
+            stats: self.stats.clone(),
+        })
+    }
+}
+
+struct AlpnCountingSession {
+    inner: Box<dyn crypto::Session>,
+    stats: Arc<StreamStats>,
+}
+
+impl crypto::Session for AlpnCountingSession {
+    fn initial_keys(&self, dst_cid: &QuinnConnectionId, side: Side) -> crypto::Keys {
+        self.inner.initial_keys(dst_cid, side)
+    }
+
+    fn handshake_data(&self) -> Option<Box<dyn Any>> {
+        self.inner.handshake_data()
+    }
+
+    fn peer_identity(&self) -> Option<Box<dyn Any>> {
+        self.inner.peer_identity()
+    }
+
+    fn early_crypto(&self) -> Option<(Box<dyn crypto::HeaderKey>, Box<dyn crypto::PacketKey>)> {
+        self.inner.early_crypto()
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        self.inner.early_data_accepted()
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.inner.is_handshaking()
+    }
+
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<bool, TransportError> {
+        // Not `inspect_err`, which is newer than the rust versions this crate supports.
+        let result = self.inner.read_handshake(buf);
+        if let Err(err) = &result {
+            if err.code
+                == TransportErrorCode::crypto(AlertDescription::NoApplicationProtocol.get_u8())
+            {
+                self.stats.connection_rejected_alpn.add(1);
+            }
+        }
+        result
+    }
 
-    let mut server_tls_config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_client_cert_verifier(SkipClientVerification::new())
-        .with_single_cert(vec![cert], priv_key)?;
-    server_tls_config.alpn_protocols = vec![ALPN_TPU_PROTOCOL_ID.to_vec()];
+    fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
+        self.inner.transport_parameters()
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<crypto::Keys> {
+        self.inner.write_handshake(buf)
+    }
+
+    fn next_1rtt_keys(&mut self) -> Option<crypto::KeyPair<Box<dyn crypto::PacketKey>>> {
+        self.inner.next_1rtt_keys()
+    }
+
+    fn is_valid_retry(
+        &self,
+        orig_dst_cid: &QuinnConnectionId,
+        header: &[u8],
+        payload: &[u8],
+    ) -> bool {
+        self.inner.is_valid_retry(orig_dst_cid, header, payload)
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), crypto::ExportKeyingMaterialError> {
+        self.inner.export_keying_material(output, label, context)
+    }
+}
+
+/// Returns the server configuration for an externally constructed TLS configuration.
+///
+/// The transport is tuned the same way as for the self-signed identity and the
+/// [`QuicServerConfig::alpn_protocols`] missing from the advertised protocols are appended.
+#[allow(clippy::field_reassign_with_default)] // https://github.com/rust-lang/rust-clippy/issues/6527
+pub(crate) fn configure_server_with_tls(
+    mut server_tls_config: Arc<rustls::ServerConfig>,
+    max_concurrent_connections: usize,
+    quic_server_config: &QuicServerConfig,
+    stats: &Arc<StreamStats>,
+) -> Result<ServerConfig, QuicServerError> {
+    quic_server_config.validate()?;
+    let server_tls_config_mut = Arc::make_mut(&mut server_tls_config);
+    for alpn in &quic_server_config.alpn_protocols {
+        if !server_tls_config_mut.alpn_protocols.contains(alpn) {
+            server_tls_config_mut.alpn_protocols.push(alpn.clone());
+        }
+    }
 
-    let mut server_config = ServerConfig::with_crypto(Arc::new(server_tls_config));
+    let mut server_config = ServerConfig::with_crypto(Arc::new(AlpnCountingServerConfig {
+        inner: server_tls_config,
+        stats: stats.clone(),
+    }));
     server_config.concurrent_connections(max_concurrent_connections as u32);
-    server_config.use_retry(true);
+    server_config.use_retry(quic_server_config.use_retry);
+    if let Some(retry_token_lifetime) = quic_server_config.retry_token_lifetime {
+        server_config.retry_token_lifetime(retry_token_lifetime);
+    }
     let config = Arc::get_mut(&mut server_config.transport).unwrap();
 
     // QUIC_MAX_CONCURRENT_STREAMS doubled, which was found to improve reliability
     const MAX_CONCURRENT_UNI_STREAMS: u32 =
         (QUIC_MAX_UNSTAKED_CONCURRENT_STREAMS.saturating_mul(2)) as u32;
-    config.max_concurrent_uni_streams(MAX_CONCURRENT_UNI_STREAMS.into());
-    config.stream_receive_window((PACKET_DATA_SIZE as u32).into());
-    config.receive_window((PACKET_DATA_SIZE as u32).into());
-    let timeout = IdleTimeout::try_from(QUIC_MAX_TIMEOUT).unwrap();
+    config.max_concurrent_uni_streams(
+        quic_server_config
+            .max_concurrent_uni_streams
+            .unwrap_or(MAX_CONCURRENT_UNI_STREAMS)
+            .into(),
+    );
+    let receive_window_base = quic_server_config.receive_window_base();
+    config.stream_receive_window(
+        quic_server_config
+            .stream_receive_window
+            .unwrap_or(receive_window_base)
+            .into(),
+    );
+    config.receive_window(
+        quic_server_config
+            .receive_window
+            .unwrap_or(receive_window_base)
+            .into(),
+    );
+    let max_idle_timeout = quic_server_config
+        .max_idle_timeout
+        .unwrap_or(QUIC_MAX_TIMEOUT);
+    let timeout = IdleTimeout::try_from(max_idle_timeout).map_err(|_| {
+        QuicServerError::InvalidConfig(format!(
+            "max_idle_timeout ({max_idle_timeout:?}) is out of range"
+        ))
+    })?;
     config.max_idle_timeout(Some(timeout));
+    config.keep_alive_interval(quic_server_config.keep_alive_interval);
+    match quic_server_config.congestion_controller {
+        CongestionController::Cubic => {
+            config.congestion_controller_factory(Arc::new(congestion::CubicConfig::default()))
+        }
+        CongestionController::NewReno => {
+            config.congestion_controller_factory(Arc::new(congestion::NewRenoConfig::default()))
+        }
+        CongestionController::Bbr => {
+            config.congestion_controller_factory(Arc::new(congestion::BbrConfig::default()))
+        }
+    };
 
-    // disable bidi & datagrams
-    const MAX_CONCURRENT_BIDI_STREAMS: u32 = 0;
-    config.max_concurrent_bidi_streams(MAX_CONCURRENT_BIDI_STREAMS.into());
-    config.datagram_receive_buffer_size(None);
+    // bidi streams and datagrams are opt-in
+    config.max_concurrent_bidi_streams(quic_server_config.max_concurrent_bidi_streams.into());
+    config.datagram_receive_buffer_size(quic_server_config.datagram_receive_buffer_size);
 
-    // Disable GSO. The server only accepts inbound unidirectional streams initiated by clients,
-    // which means that reply data never exceeds one MTU. By disabling GSO, we make
-    // quinn_proto::Connection::poll_transmit allocate only 1 MTU vs 10 * MTU for _each_ transmit.
-    // See https://github.com/anza-xyz/agave/pull/1647.
-    config.enable_segmentation_offload(false);
+    // GSO is disabled by default. The server only accepts inbound unidirectional streams
+    // initiated by clients, which means that reply data never exceeds one MTU. By disabling GSO,
+    // we make quinn_proto::Connection::poll_transmit allocate only 1 MTU vs 10 * MTU for _each_
+    // transmit. See https://github.com/anza-xyz/agave/pull/1647.
+    config.enable_segmentation_offload(quic_server_config.enable_segmentation_offload);
 
-    Ok((server_config, cert_chain_pem))
+    Ok(server_config)
+}
+
+/// How a full staked connection table picks the connection to evict for a new staked peer.
+///
+/// Peers with more stake than the new one are never evicted. If there is no candidate, the new
+/// connection is put in the unstaked table instead, see [`StakedOverflow`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts the lowest-stake of two randomly sampled peers, if its stake is below the new
+    /// peer's.
+    #[default]
+    LowestStake,
+    /// Evicts the peer that least recently opened a stream.
+    LeastRecentlyUsed,
+    /// Evicts the peer holding the connection that was established first.
+    OldestConnection,
+}
+
+/// What becomes of a staked peer the full staked connection table has no room for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StakedOverflow {
+    /// Puts it in the unstaked table, evicting the unstaked peer that least recently opened a
+    /// stream if it's full, see `StreamStatsSnapshot::evicted_unstaked_for_staked`. Staked peers
+    /// already in the unstaked table and protected peers aren't evicted.
+    #[default]
+    EvictUnstaked,
+    /// Rejects it, keeping the unstaked table to unstaked peers.
+    Strict,
+}
+
+/// Congestion control algorithm of the connections, see [`quinn::congestion`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CongestionController {
+    /// quinn's default.
+    #[default]
+    Cubic,
+    NewReno,
+    /// Tends to sustain a higher throughput on paths with a large bandwidth-delay product.
+    Bbr,
+}
+
+/// See [`QuicServerConfig::sni_fallback`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SniFallback {
+    /// Presents the server's own identity.
+    #[default]
+    DefaultIdentity,
+    /// Fails the handshake.
+    Reject,
+}
+
+/// What the batcher does with a batch the packet sink reports as full.
+///
+/// Only applies to [`PacketSink`] consumers. The tokio channel of
+/// [`crate::nonblocking::quic::spawn_server_with_async_sender`] always awaits capacity, until
+/// the server exits. Either way, a batch still waiting for room at the exit is dropped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FullChannelPolicy {
+    /// Drops the batch and counts it in `total_packet_batch_send_err`.
+    #[default]
+    Drop,
+    /// Retries the send for up to this long before dropping the batch.
+    ///
+    /// The batcher stops assembling new batches while it waits, but the stream tasks keep
+    /// reading: their packets queue up in front of the batcher, in memory and unbounded, for up
+    /// to this long. The stalls don't reach the peers, see `backpressure` of
+    /// [`StreamLoadEmaConfig`] to throttle them as the channel fills.
+    Block(Duration),
+}
+
+/// Parameters of the stream load EMA, which drives the throttling of staked streams.
+///
+/// The server accepts `max_streams_per_ms` new streams per millisecond in total. Of those,
+/// `max_unstaked_streams_percent` are set aside for unstaked peers and split evenly between the
+/// unstaked connections, the rest is the staked load capacity. The EMA of the staked load is
+/// updated every 5ms and, per 100ms throttling interval, each staked peer may open its stake's
+/// share of `capacity^2 / max(ema, capacity * min_load_percent / 100)` streams. A higher
+/// `alpha` lets throttling react faster to changes of the load.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamLoadEmaConfig {
+    /// Weight of the most recent 5ms interval in the EMA, in `(0, 1]`. Defaults to `2 / 11`,
+    /// i.e. an EMA over 10 intervals.
+    pub alpha: f64,
+    /// Percentage of `max_streams_per_ms` reserved for unstaked peers. Defaults to 20.
+    pub max_unstaked_streams_percent: u64,
+    /// Lower bound of the load used in the capacity calculation, in percent of the staked load
+    /// capacity. It caps the streams a staked peer gets when the server is mostly idle.
+    /// Defaults to 25.
+    pub min_load_percent: u64,
+    /// Tightens the throttling of all peers as the packet channel fills. Defaults to `None`.
+    pub backpressure: Option<BackpressureThrottleConfig>,
+}
+
+/// Scales `max_streams_per_ms` down as the packet channel fills, so that new streams are
+/// throttled before full channels drop batches. The scale, reported as
+/// `stream_throttle_scale_percent`, goes linearly from 100% at `start_percent` of the channel
+/// capacity to `min_scale_percent` when the channel is full, and back as it drains.
+///
+/// It takes effect with packet sinks that report their depth and capacity, see
+/// [`crate::packet_sink::PacketSink::capacity`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackpressureThrottleConfig {
+    /// Fill of the packet channel, in percent of its capacity, below which the stream limits
+    /// aren't scaled. Defaults to 50.
+    pub start_percent: u64,
+    /// Scale of the stream limits, in percent, once the packet channel is full. Defaults to 10.
+    pub min_scale_percent: u64,
+}
+
+impl Default for BackpressureThrottleConfig {
+    fn default() -> Self {
+        Self {
+            start_percent: 50,
+            min_scale_percent: 10,
+        }
+    }
+}
+
+impl BackpressureThrottleConfig {
+    /// Scale of the stream limits, in percent, for a packet channel holding `depth` batches.
+    pub(crate) fn scale_percent(&self, depth: usize, capacity: usize) -> u64 {
+        let fill_percent = (depth as u64)
+            .saturating_mul(100)
+            .checked_div(capacity as u64)
+            .unwrap_or(100)
+            .min(100);
+        if fill_percent <= self.start_percent {
+            return 100;
+        }
+        100 - (100 - self.min_scale_percent) * (fill_percent - self.start_percent)
+            / (100 - self.start_percent)
+    }
+}
+
+impl Default for StreamLoadEmaConfig {
+    fn default() -> Self {
+        Self {
+            alpha: 2.0 / 11.0,
+            max_unstaked_streams_percent: 20,
+            min_load_percent: 25,
+            backpressure: None,
+        }
+    }
+}
+
+impl StreamLoadEmaConfig {
+    fn validate(&self) -> Result<(), QuicServerError> {
+        if !(self.alpha > 0.0 && self.alpha <= 1.0) {
+            return Err(QuicServerError::InvalidConfig(format!(
+                "stream_load_ema.alpha ({}) must be in (0, 1]",
+                self.alpha
+            )));
+        }
+        if self.max_unstaked_streams_percent > 100 {
+            return Err(QuicServerError::InvalidConfig(format!(
+                "stream_load_ema.max_unstaked_streams_percent ({}) must not exceed 100",
+                self.max_unstaked_streams_percent
+            )));
+        }
+        if !(1..=100).contains(&self.min_load_percent) {
+            return Err(QuicServerError::InvalidConfig(format!(
+                "stream_load_ema.min_load_percent ({}) must be in [1, 100]",
+                self.min_load_percent
+            )));
+        }
+        if let Some(backpressure) = &self.backpressure {
+            if backpressure.start_percent >= 100 {
+                return Err(QuicServerError::InvalidConfig(format!(
+                    "stream_load_ema.backpressure.start_percent ({}) must be below 100",
+                    backpressure.start_percent
+                )));
+            }
+            if !(1..=100).contains(&backpressure.min_scale_percent) {
+                return Err(QuicServerError::InvalidConfig(format!(
+                    "stream_load_ema.backpressure.min_scale_percent ({}) must be in [1, 100]",
+                    backpressure.min_scale_percent
+                )));
+            }
+        }
+        Ok(())
+    }
 }
 
-fn rt() -> Runtime {
-    tokio::runtime::Builder::new_multi_thread()
-        .thread_name("quic-server")
+/// Settings of the runtime created by the blocking spawn functions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// Number of worker threads. Defaults to the number of cores.
+    pub worker_threads: Option<usize>,
+    /// Name of the worker threads. Defaults to `quic-server`.
+    pub thread_name: String,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            thread_name: "quic-server".to_string(),
+        }
+    }
+}
+
+fn rt(config: &RuntimeConfig) -> Runtime {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = config.worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    builder
+        .thread_name(config.thread_name.clone())
         .enable_all()
         .build()
         .unwrap()
@@ -109,379 +1530,1462 @@ fn rt() -> Runtime {
 
 #[derive(thiserror::Error, Debug)]
 pub enum QuicServerError {
+    /// Setting up an endpoint failed for a reason other than the ones below.
     #[error("Endpoint creation failed: {0}")]
     EndpointFailed(std::io::Error),
+    /// Another socket holds the address, so another port may be worth a try.
+    #[error("Address {addr} is already in use: {source}")]
+    AddrInUse {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+    /// The process may not use the address, e.g. a privileged port.
+    #[error("Permission denied for address {addr}: {source}")]
+    PermissionDenied {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
     #[error("Certificate error: {0}")]
     CertificateError(#[from] rcgen::RcgenError),
     #[error("TLS error: {0}")]
     TlsError(#[from] rustls::Error),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+}
+
+impl QuicServerError {
+    /// Classifies a failure to set up an endpoint on `addr`, which is `None` if it's unknown.
+    pub(crate) fn endpoint_failed(addr: Option<SocketAddr>, source: std::io::Error) -> Self {
+        match (addr, source.kind()) {
+            (Some(addr), std::io::ErrorKind::AddrInUse) => Self::AddrInUse { addr, source },
+            (Some(addr), std::io::ErrorKind::PermissionDenied) => {
+                Self::PermissionDenied { addr, source }
+            }
+            _ => Self::EndpointFailed(source),
+        }
+    }
 }
 
 pub struct EndpointKeyUpdater {
-    endpoint: Endpoint,
-    gossip_host: IpAddr,
+    endpoints: Vec<Endpoint>,
+    // None when the server was spawned with an externally constructed TLS configuration, in
+    // which case the identity is not derived from a keypair and can't be regenerated here.
+    gossip_host: Option<IpAddr>,
     max_concurrent_connections: usize,
+    quic_server_config: QuicServerConfig,
+    stats: Arc<StreamStats>,
 }
 
 impl NotifyKeyUpdate for EndpointKeyUpdater {
     fn update_key(&self, key: &Keypair) -> Result<(), Box<dyn std::error::Error>> {
-        let (config, _) = configure_server(key, self.gossip_host, self.max_concurrent_connections)?;
-        self.endpoint.set_server_config(Some(config));
+        let Some(gossip_host) = self.gossip_host else {
+            return Err(Box::new(QuicServerError::InvalidConfig(
+                "the server identity is an external TLS configuration".to_string(),
+            )));
+        };
+        let (config, cert_chain_pem) = configure_server(
+            key,
+            gossip_host,
+            self.max_concurrent_connections,
+            &self.quic_server_config,
+            &self.stats,
+        )?;
+        for endpoint in &self.endpoints {
+            endpoint.set_server_config(Some(config.clone()));
+        }
+        if let Some(handler) = &self.quic_server_config.key_rotation_handler {
+            handler.on_key_rotated(&cert_chain_pem, SystemTime::now());
+        }
         Ok(())
     }
 }
 
+/// Number of events since the last report, reset by [`StreamStats::report`] and
+/// [`StreamStats::reset`].
+#[derive(Debug, Default)]
+pub(crate) struct Counter(AtomicUsize);
+
+impl Counter {
+    pub(crate) fn add(&self, value: usize) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn collect(&self, reset: bool) -> u64 {
+        if reset {
+            self.0.swap(0, Ordering::Relaxed) as u64
+        } else {
+            self.get() as u64
+        }
+    }
+}
+
+/// Current value of some live state. Never reset, since e.g. a connection that closes after
+/// a reset would decrement `total_connections` below its true value.
+#[derive(Debug, Default)]
+pub(crate) struct Gauge(AtomicUsize);
+
+impl Gauge {
+    pub(crate) fn add(&self, value: usize) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sub(&self, value: usize) {
+        self.0.fetch_sub(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set(&self, value: usize) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn collect(&self) -> u64 {
+        self.get() as u64
+    }
+}
+
+/// Time of the latest occurrence of some event, never reset.
+#[derive(Debug)]
+pub(crate) struct Timestamp {
+    base: Instant,
+    // Microseconds since `base` plus one, zero until the first occurrence.
+    offset_us: AtomicU64,
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self {
+            base: Instant::now(),
+            offset_us: AtomicU64::default(),
+        }
+    }
+}
+
+impl Timestamp {
+    pub(crate) fn set_now(&self) {
+        let offset_us = self.base.elapsed().as_micros() as u64 + 1;
+        self.offset_us.fetch_max(offset_us, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> Option<Instant> {
+        let offset_us = self.offset_us.load(Ordering::Relaxed).checked_sub(1)?;
+        Some(self.base + Duration::from_micros(offset_us))
+    }
+}
+
+/// Counters and gauges of a QUIC server, see [`Counter`] and [`Gauge`] for which are reset
+/// by [`Self::report`] and [`Self::reset`].
 #[derive(Default)]
 pub struct StreamStats {
-    pub(crate) total_connections: AtomicUsize,
-    pub(crate) total_new_connections: AtomicUsize,
-    pub(crate) total_streams: AtomicUsize,
-    pub(crate) total_new_streams: AtomicUsize,
-    pub(crate) total_invalid_chunks: AtomicUsize,
-    pub(crate) total_invalid_chunk_size: AtomicUsize,
-    pub(crate) total_packets_allocated: AtomicUsize,
-    pub(crate) total_packet_batches_allocated: AtomicUsize,
-    pub(crate) total_chunks_received: AtomicUsize,
-    pub(crate) total_staked_chunks_received: AtomicUsize,
-    pub(crate) total_unstaked_chunks_received: AtomicUsize,
-    pub(crate) total_packet_batch_send_err: AtomicUsize,
-    pub(crate) total_handle_chunk_to_packet_batcher_send_err: AtomicUsize,
-    pub(crate) total_packet_batches_sent: AtomicUsize,
-    pub(crate) total_packet_batches_none: AtomicUsize,
-    pub(crate) total_packets_sent_for_batching: AtomicUsize,
-    pub(crate) total_bytes_sent_for_batching: AtomicUsize,
-    pub(crate) total_chunks_sent_for_batching: AtomicUsize,
-    pub(crate) total_packets_sent_to_consumer: AtomicUsize,
-    pub(crate) total_bytes_sent_to_consumer: AtomicUsize,
-    pub(crate) total_chunks_processed_by_batcher: AtomicUsize,
-    pub(crate) total_stream_read_errors: AtomicUsize,
-    pub(crate) total_stream_read_timeouts: AtomicUsize,
-    pub(crate) num_evictions: AtomicUsize,
-    pub(crate) connection_added_from_staked_peer: AtomicUsize,
-    pub(crate) connection_added_from_unstaked_peer: AtomicUsize,
-    pub(crate) connection_add_failed: AtomicUsize,
-    pub(crate) connection_add_failed_invalid_stream_count: AtomicUsize,
-    pub(crate) connection_add_failed_staked_node: AtomicUsize,
-    pub(crate) connection_add_failed_unstaked_node: AtomicUsize,
-    pub(crate) connection_add_failed_on_pruning: AtomicUsize,
-    pub(crate) connection_setup_timeout: AtomicUsize,
-    pub(crate) connection_setup_error: AtomicUsize,
-    pub(crate) connection_setup_error_closed: AtomicUsize,
-    pub(crate) connection_setup_error_timed_out: AtomicUsize,
-    pub(crate) connection_setup_error_transport: AtomicUsize,
-    pub(crate) connection_setup_error_app_closed: AtomicUsize,
-    pub(crate) connection_setup_error_reset: AtomicUsize,
-    pub(crate) connection_setup_error_locally_closed: AtomicUsize,
-    pub(crate) connection_removed: AtomicUsize,
-    pub(crate) connection_remove_failed: AtomicUsize,
-    pub(crate) throttled_streams: AtomicUsize,
-    pub(crate) stream_load_ema: AtomicUsize,
-    pub(crate) stream_load_ema_overflow: AtomicUsize,
-    pub(crate) stream_load_capacity_overflow: AtomicUsize,
-    pub(crate) total_staked_packets_sent_for_batching: AtomicUsize,
-    pub(crate) total_unstaked_packets_sent_for_batching: AtomicUsize,
-    pub(crate) throttled_staked_streams: AtomicUsize,
-    pub(crate) throttled_unstaked_streams: AtomicUsize,
+    pub(crate) total_connections: Gauge,
+    pub(crate) total_new_connections: Counter,
+    pub(crate) total_streams: Gauge,
+    pub(crate) total_new_streams: Counter,
+    pub(crate) total_invalid_chunks: Counter,
+    pub(crate) total_invalid_chunk_size: Counter,
+    pub(crate) total_packets_allocated: Counter,
+    pub(crate) total_packet_batches_allocated: Counter,
+    pub(crate) total_chunks_received: Counter,
+    pub(crate) total_staked_chunks_received: Counter,
+    pub(crate) total_unstaked_chunks_received: Counter,
+    pub(crate) total_packet_batch_send_err: Counter,
+    pub(crate) total_handle_chunk_to_packet_batcher_send_err: Counter,
+    pub(crate) total_packet_batches_sent: Counter,
+    pub(crate) total_packet_batches_none: Counter,
+    pub(crate) total_packets_sent_for_batching: Counter,
+    pub(crate) total_bytes_sent_for_batching: Counter,
+    pub(crate) total_chunks_sent_for_batching: Counter,
+    pub(crate) total_packets_sent_to_consumer: Counter,
+    pub(crate) total_bytes_sent_to_consumer: Counter,
+    pub(crate) total_chunks_processed_by_batcher: Counter,
+    pub(crate) total_stream_read_errors: Counter,
+    pub(crate) total_stream_read_timeouts: Counter,
+    pub(crate) num_evictions: Counter,
+    pub(crate) connection_added_from_staked_peer: Counter,
+    pub(crate) connection_added_from_unstaked_peer: Counter,
+    pub(crate) connection_add_failed: Counter,
+    pub(crate) connection_add_failed_invalid_stream_count: Counter,
+    pub(crate) connection_add_failed_staked_node: Counter,
+    pub(crate) connection_add_failed_unstaked_node: Counter,
+    pub(crate) connection_add_failed_on_pruning: Counter,
+    pub(crate) connection_setup_timeout: Counter,
+    pub(crate) connection_setup_error: Counter,
+    pub(crate) connection_setup_error_closed: Counter,
+    pub(crate) connection_setup_error_timed_out: Counter,
+    pub(crate) connection_setup_error_transport: Counter,
+    pub(crate) connection_setup_error_app_closed: Counter,
+    pub(crate) connection_setup_error_reset: Counter,
+    pub(crate) connection_setup_error_locally_closed: Counter,
+    pub(crate) connection_setup_error_version_mismatch: Counter,
+    pub(crate) connection_removed: Counter,
+    pub(crate) connection_remove_failed: Counter,
+    pub(crate) throttled_streams: Counter,
+    pub(crate) stream_load_ema: Gauge,
+    pub(crate) stream_load_ema_overflow: Counter,
+    pub(crate) stream_load_capacity_overflow: Counter,
+    pub(crate) total_staked_packets_sent_for_batching: Counter,
+    pub(crate) total_unstaked_packets_sent_for_batching: Counter,
+    pub(crate) throttled_staked_streams: Counter,
+    pub(crate) throttled_unstaked_streams: Counter,
+    pub(crate) connection_rejected_by_filter: Counter,
+    pub(crate) connection_rate_limited: Counter,
+    pub(crate) connection_rejected_banned: Counter,
+    pub(crate) connection_added_protected: Counter,
+    pub(crate) packet_channel_depth: Gauge,
+    pub(crate) invalid_chunk_oversize: Counter,
+    pub(crate) invalid_chunk_count: Counter,
+    pub(crate) invalid_chunk_empty: Counter,
+    pub(crate) stream_bytes: AtomicHistogram,
+    pub(crate) batch_packets: AtomicHistogram,
+    pub(crate) batch_bytes: AtomicHistogram,
+    pub(crate) connection_setup_latency_us: AtomicHistogram,
+    pub(crate) new_connections_per_second: RateHistogram,
+    pub(crate) sampled_connections: Gauge,
+    pub(crate) connection_rtt_min_us: Gauge,
+    pub(crate) connection_rtt_avg_us: Gauge,
+    pub(crate) connection_rtt_max_us: Gauge,
+    pub(crate) connection_lost_packets: Gauge,
+    pub(crate) staked_uni_streams_limit_min: Gauge,
+    pub(crate) staked_uni_streams_limit_max: Gauge,
+    pub(crate) unstaked_uni_streams_limit_min: Gauge,
+    pub(crate) unstaked_uni_streams_limit_max: Gauge,
+    pub(crate) total_bidi_streams: Counter,
+    pub(crate) total_datagrams: Counter,
+    pub(crate) datagrams_dropped: Counter,
+    pub(crate) connection_rejected_alpn: Counter,
+    pub(crate) bandwidth_throttled_streams: Counter,
+    pub(crate) global_bandwidth_throttled: Counter,
+    pub(crate) connection_closed_stream_timeouts: Counter,
+    pub(crate) streams_reaped_idle: Counter,
+    pub(crate) active_staked_connections: Gauge,
+    pub(crate) active_unstaked_connections: Gauge,
+    pub(crate) stream_throttle_scale_percent: Gauge,
+    pub(crate) proxy_protocol_invalid_headers: Counter,
+    pub(crate) streams_reset_chunk_limit: Counter,
+    pub(crate) packets_rejected_oversize: Counter,
+    pub(crate) endpoint_datagrams_received: Counter,
+    pub(crate) endpoint_initial_packets_received: Counter,
+    pub(crate) endpoint_retry_packets_sent: Counter,
+    pub(crate) endpoint_version_negotiation_sent: Counter,
+    pub(crate) endpoint_connections_refused: Counter,
+    pub(crate) connection_add_failed_staked_peer_limit: Counter,
+    pub(crate) connection_add_failed_unstaked_peer_limit: Counter,
+    pub(crate) endpoint_rebinds: Counter,
+    pub(crate) active_endpoints: Gauge,
+    pub(crate) last_packet_received: Timestamp,
+    pub(crate) total_bytes_received: Counter,
+    pub(crate) connection_rejected_unstaked_disabled: Counter,
+    pub(crate) evicted_unstaked_for_staked: Counter,
+    pub(crate) connection_packets_per_second_max: Gauge,
+    pub(crate) connection_packets_per_second_min: Gauge,
+    pub(crate) read_turns_waited: Counter,
+    pub(crate) connection_rejected_by_controller: Counter,
+    pub(crate) proxy_protocol_dropped_addresses: Counter,
+    pub(crate) stake_tiers: OnceLock<Arc<StakeTiers>>,
+}
+
+/// Point-in-time copy of every [`StreamStats`] counter.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct StreamStatsSnapshot {
+    pub total_connections: u64,
+    pub total_new_connections: u64,
+    pub total_streams: u64,
+    pub total_new_streams: u64,
+    pub total_invalid_chunks: u64,
+    pub total_invalid_chunk_size: u64,
+    pub total_packets_allocated: u64,
+    pub total_packet_batches_allocated: u64,
+    pub total_chunks_received: u64,
+    pub total_staked_chunks_received: u64,
+    pub total_unstaked_chunks_received: u64,
+    pub total_packet_batch_send_err: u64,
+    pub total_handle_chunk_to_packet_batcher_send_err: u64,
+    pub total_packet_batches_sent: u64,
+    pub total_packet_batches_none: u64,
+    pub total_packets_sent_for_batching: u64,
+    pub total_bytes_sent_for_batching: u64,
+    pub total_chunks_sent_for_batching: u64,
+    pub total_packets_sent_to_consumer: u64,
+    pub total_bytes_sent_to_consumer: u64,
+    pub total_chunks_processed_by_batcher: u64,
+    pub total_stream_read_errors: u64,
+    pub total_stream_read_timeouts: u64,
+    pub num_evictions: u64,
+    pub connection_added_from_staked_peer: u64,
+    pub connection_added_from_unstaked_peer: u64,
+    pub connection_add_failed: u64,
+    pub connection_add_failed_invalid_stream_count: u64,
+    pub connection_add_failed_staked_node: u64,
+    pub connection_add_failed_unstaked_node: u64,
+    pub connection_add_failed_on_pruning: u64,
+    pub connection_setup_timeout: u64,
+    pub connection_setup_error: u64,
+    pub connection_setup_error_closed: u64,
+    pub connection_setup_error_timed_out: u64,
+    pub connection_setup_error_transport: u64,
+    pub connection_setup_error_app_closed: u64,
+    pub connection_setup_error_reset: u64,
+    pub connection_setup_error_locally_closed: u64,
+    pub connection_setup_error_version_mismatch: u64,
+    pub connection_removed: u64,
+    pub connection_remove_failed: u64,
+    pub throttled_streams: u64,
+    pub stream_load_ema: u64,
+    pub stream_load_ema_overflow: u64,
+    pub stream_load_capacity_overflow: u64,
+    pub total_staked_packets_sent_for_batching: u64,
+    pub total_unstaked_packets_sent_for_batching: u64,
+    pub throttled_staked_streams: u64,
+    pub throttled_unstaked_streams: u64,
+    pub connection_rejected_by_filter: u64,
+    pub connection_rate_limited: u64,
+    pub connection_rejected_banned: u64,
+    pub connection_added_protected: u64,
+    pub packet_channel_depth: u64,
+    /// Chunks reaching past `PACKET_DATA_SIZE`, counted in `total_invalid_chunks` as well, and
+    /// in `total_invalid_chunk_size` when only their end does.
+    pub invalid_chunk_oversize: u64,
+    /// Chunks not starting where the previous chunk of their stream ended.
+    pub invalid_chunk_count: u64,
+    /// Chunks without any bytes, which are skipped.
+    pub invalid_chunk_empty: u64,
+    /// Bytes received per completed stream.
+    pub stream_bytes: HistogramSnapshot,
+    /// Packets per batch sent to the consumer. Batches mostly holding a single packet suggest
+    /// a coalesce window too short to be worth its latency.
+    pub batch_packets: HistogramSnapshot,
+    /// Bytes per batch sent to the consumer.
+    pub batch_bytes: HistogramSnapshot,
+    /// Microseconds from accepting a connection to completing its handshake, for the
+    /// handshakes that succeeded.
+    pub connection_setup_latency_us: HistogramSnapshot,
+    /// Connections established in each second completed since the last report, idle seconds
+    /// included, which tells a flood from steady churn.
+    pub new_connections_per_second: HistogramSnapshot,
+    /// Moving average of the connections established per second, weighing mostly the last
+    /// ten seconds. It's carried over across reports.
+    pub new_connections_per_second_ema: u64,
+    /// Live connections whose quinn stats were folded into the `connection_rtt_*_us` and
+    /// `connection_lost_packets` gauges at the last sample.
+    pub sampled_connections: u64,
+    pub connection_rtt_min_us: u64,
+    pub connection_rtt_avg_us: u64,
+    pub connection_rtt_max_us: u64,
+    /// Packets lost over the lifetime of the sampled connections.
+    pub connection_lost_packets: u64,
+    /// Lowest and highest stream limits among the staked connections at the last sample, zero
+    /// without any.
+    pub staked_uni_streams_limit_min: u64,
+    pub staked_uni_streams_limit_max: u64,
+    /// Same as `staked_uni_streams_limit_min` and `staked_uni_streams_limit_max`, for the
+    /// unstaked connections.
+    pub unstaked_uni_streams_limit_min: u64,
+    pub unstaked_uni_streams_limit_max: u64,
+    pub total_bidi_streams: u64,
+    pub total_datagrams: u64,
+    pub datagrams_dropped: u64,
+    pub connection_rejected_alpn: u64,
+    pub bandwidth_throttled_streams: u64,
+    pub global_bandwidth_throttled: u64,
+    pub connection_closed_stream_timeouts: u64,
+    pub streams_reaped_idle: u64,
+    pub active_staked_connections: u64,
+    pub active_unstaked_connections: u64,
+    pub stream_throttle_scale_percent: u64,
+    pub proxy_protocol_invalid_headers: u64,
+    /// Streams dropped for exceeding `max_chunks_per_stream`.
+    pub streams_reset_chunk_limit: u64,
+    /// Packets dropped for reaching past `PACKET_DATA_SIZE`, before they reach the batcher.
+    pub packets_rejected_oversize: u64,
+    /// Datagrams the endpoints received, before quinn handles them. Together with the other
+    /// `endpoint_*` counters, they account for the handshakes quinn refuses on its own, which
+    /// never reach `total_new_connections`.
+    pub endpoint_datagrams_received: u64,
+    /// Initial packets received, including the ones repeated after a retry or lost reply.
+    pub endpoint_initial_packets_received: u64,
+    /// Retry packets sent to validate the address of new peers, see
+    /// `QuicServerConfig::use_retry`.
+    pub endpoint_retry_packets_sent: u64,
+    /// Replies to peers offering only QUIC versions the server doesn't support.
+    pub endpoint_version_negotiation_sent: u64,
+    /// Handshakes quinn closed right away, e.g. for an invalid retry token, a failed TLS
+    /// handshake or too many connections. Told apart by the short Initial packets closing
+    /// them, so it may include the rare Initial packet sent only to acknowledge.
+    pub endpoint_connections_refused: u64,
+    /// Part of `connection_add_failed` refused to staked peers already holding
+    /// `QuicServerConfig::max_connections_per_staked_peer` connections.
+    pub connection_add_failed_staked_peer_limit: u64,
+    /// Same as `connection_add_failed_staked_peer_limit` for unstaked peers.
+    pub connection_add_failed_unstaked_peer_limit: u64,
+    /// Fresh sockets bound after the socket of an endpoint failed, see
+    /// `QuicServerConfig::socket_rebind`.
+    pub endpoint_rebinds: u64,
+    /// Endpoints still accepting connections, fewer than spawned once one stopped, e.g. after
+    /// its socket failed.
+    pub active_endpoints: u64,
+    /// Bytes of every chunk read off the unidirectional streams, valid or not. The difference
+    /// with `total_bytes_sent_for_batching` is what rejected chunks and dropped streams cost.
+    pub total_bytes_received: u64,
+    /// Unstaked connections refused since the unstaked connection cap is zero, once their
+    /// handshake completed unless no peer has stake, see [`ConnectionLimits`].
+    pub connection_rejected_unstaked_disabled: u64,
+    /// Part of `num_evictions` evicting unstaked peers for staked peers the staked table had
+    /// no room for, see [`StakedOverflow::EvictUnstaked`].
+    pub evicted_unstaked_for_staked: u64,
+    /// Highest and lowest rate, in packets per second, at which a connection had packets sent
+    /// for batching over the last sampling interval, among the connections that had any. Far
+    /// apart when some connections get most of the reads, see
+    /// [`QuicServerConfig::max_concurrent_reads`].
+    pub connection_packets_per_second_max: u64,
+    pub connection_packets_per_second_min: u64,
+    /// Chunks whose stream had to wait for a turn to handle them, see
+    /// [`QuicServerConfig::max_concurrent_reads`].
+    pub read_turns_waited: u64,
+    /// Connections the [`AdmissionController`] rejected once their handshake completed.
+    pub connection_rejected_by_controller: u64,
+    /// Client addresses of PROXY protocol headers that weren't recorded because the table of
+    /// them was full, whose connections are attributed to the balancer instead.
+    pub proxy_protocol_dropped_addresses: u64,
+    /// Staked connections per stake tier, the peers below every configured tier first. Empty
+    /// unless [`QuicServerConfig::stake_tiers`] is set.
+    pub staked_connections_per_tier: Vec<u64>,
 }
 
 impl StreamStats {
+    /// Number of connections currently open.
+    pub fn active_connections(&self) -> usize {
+        self.total_connections.get()
+    }
+
+    /// Number of streams currently being read.
+    pub fn active_streams(&self) -> usize {
+        self.total_streams.get()
+    }
+
+    /// Connections established since the last report.
+    pub fn new_connections(&self) -> usize {
+        self.total_new_connections.get()
+    }
+
+    /// Streams opened since the last report.
+    pub fn new_streams(&self) -> usize {
+        self.total_new_streams.get()
+    }
+
+    /// Connections evicted since the last report.
+    pub fn evictions(&self) -> usize {
+        self.num_evictions.get()
+    }
+
+    /// Packets delivered to the consumer since the last report.
+    pub fn packets_sent_to_consumer(&self) -> usize {
+        self.total_packets_sent_to_consumer.get()
+    }
+
+    /// Bytes delivered to the consumer since the last report.
+    pub fn bytes_sent_to_consumer(&self) -> usize {
+        self.total_bytes_sent_to_consumer.get()
+    }
+
+    /// Packet batches delivered to the consumer since the last report.
+    pub fn packet_batches_sent(&self) -> usize {
+        self.total_packet_batches_sent.get()
+    }
+
+    /// Streams throttled since the last report.
+    pub fn throttled_streams(&self) -> usize {
+        self.throttled_streams.get()
+    }
+
+    /// Current stream load EMA of staked connections.
+    pub fn stream_load_ema(&self) -> usize {
+        self.stream_load_ema.get()
+    }
+
+    /// Returns the current value of every counter without resetting them, unlike
+    /// [`Self::report`].
+    pub fn snapshot(&self) -> StreamStatsSnapshot {
+        self.collect(false)
+    }
+
+    /// Resets every counter and histogram to zero without reporting them. Gauges are left alone
+    /// since they track live state.
+    pub fn reset(&self) {
+        self.collect(true);
+    }
+
+    fn collect(&self, reset: bool) -> StreamStatsSnapshot {
+        let (new_connections_per_second, new_connections_per_second_ema) =
+            self.new_connections_per_second.collect(reset);
+        StreamStatsSnapshot {
+            total_connections: self.total_connections.collect(),
+            total_new_connections: self.total_new_connections.collect(reset),
+            total_streams: self.total_streams.collect(),
+            total_new_streams: self.total_new_streams.collect(reset),
+            total_invalid_chunks: self.total_invalid_chunks.collect(reset),
+            total_invalid_chunk_size: self.total_invalid_chunk_size.collect(reset),
+            total_packets_allocated: self.total_packets_allocated.collect(reset),
+            total_packet_batches_allocated: self.total_packet_batches_allocated.collect(reset),
+            total_chunks_received: self.total_chunks_received.collect(reset),
+            total_staked_chunks_received: self.total_staked_chunks_received.collect(reset),
+            total_unstaked_chunks_received: self.total_unstaked_chunks_received.collect(reset),
+            total_packet_batch_send_err: self.total_packet_batch_send_err.collect(reset),
+            total_handle_chunk_to_packet_batcher_send_err: self
+                .total_handle_chunk_to_packet_batcher_send_err
+                .collect(reset),
+            total_packet_batches_sent: self.total_packet_batches_sent.collect(reset),
+            total_packet_batches_none: self.total_packet_batches_none.collect(reset),
+            total_packets_sent_for_batching: self.total_packets_sent_for_batching.collect(reset),
+            total_bytes_sent_for_batching: self.total_bytes_sent_for_batching.collect(reset),
+            total_chunks_sent_for_batching: self.total_chunks_sent_for_batching.collect(reset),
+            total_packets_sent_to_consumer: self.total_packets_sent_to_consumer.collect(reset),
+            total_bytes_sent_to_consumer: self.total_bytes_sent_to_consumer.collect(reset),
+            total_chunks_processed_by_batcher: self
+                .total_chunks_processed_by_batcher
+                .collect(reset),
+            total_stream_read_errors: self.total_stream_read_errors.collect(reset),
+            total_stream_read_timeouts: self.total_stream_read_timeouts.collect(reset),
+            num_evictions: self.num_evictions.collect(reset),
+            connection_added_from_staked_peer: self
+                .connection_added_from_staked_peer
+                .collect(reset),
+            connection_added_from_unstaked_peer: self
+                .connection_added_from_unstaked_peer
+                .collect(reset),
+            connection_add_failed: self.connection_add_failed.collect(reset),
+            connection_add_failed_invalid_stream_count: self
+                .connection_add_failed_invalid_stream_count
+                .collect(reset),
+            connection_add_failed_staked_node: self
+                .connection_add_failed_staked_node
+                .collect(reset),
+            connection_add_failed_unstaked_node: self
+                .connection_add_failed_unstaked_node
+                .collect(reset),
+            connection_add_failed_on_pruning: self.connection_add_failed_on_pruning.collect(reset),
+            connection_setup_timeout: self.connection_setup_timeout.collect(reset),
+            connection_setup_error: self.connection_setup_error.collect(reset),
+            connection_setup_error_closed: self.connection_setup_error_closed.collect(reset),
+            connection_setup_error_timed_out: self.connection_setup_error_timed_out.collect(reset),
+            connection_setup_error_transport: self.connection_setup_error_transport.collect(reset),
+            connection_setup_error_app_closed: self
+                .connection_setup_error_app_closed
+                .collect(reset),
+            connection_setup_error_reset: self.connection_setup_error_reset.collect(reset),
+            connection_setup_error_locally_closed: self
+                .connection_setup_error_locally_closed
+                .collect(reset),
+            connection_setup_error_version_mismatch: self
+                .connection_setup_error_version_mismatch
+                .collect(reset),
+            connection_removed: self.connection_removed.collect(reset),
+            connection_remove_failed: self.connection_remove_failed.collect(reset),
+            throttled_streams: self.throttled_streams.collect(reset),
+            stream_load_ema: self.stream_load_ema.collect(),
+            stream_load_ema_overflow: self.stream_load_ema_overflow.collect(reset),
+            stream_load_capacity_overflow: self.stream_load_capacity_overflow.collect(reset),
+            total_staked_packets_sent_for_batching: self
+                .total_staked_packets_sent_for_batching
+                .collect(reset),
+            total_unstaked_packets_sent_for_batching: self
+                .total_unstaked_packets_sent_for_batching
+                .collect(reset),
+            throttled_staked_streams: self.throttled_staked_streams.collect(reset),
+            throttled_unstaked_streams: self.throttled_unstaked_streams.collect(reset),
+            connection_rejected_by_filter: self.connection_rejected_by_filter.collect(reset),
+            connection_rate_limited: self.connection_rate_limited.collect(reset),
+            connection_rejected_banned: self.connection_rejected_banned.collect(reset),
+            connection_added_protected: self.connection_added_protected.collect(reset),
+            packet_channel_depth: self.packet_channel_depth.collect(),
+            invalid_chunk_oversize: self.invalid_chunk_oversize.collect(reset),
+            invalid_chunk_count: self.invalid_chunk_count.collect(reset),
+            invalid_chunk_empty: self.invalid_chunk_empty.collect(reset),
+            stream_bytes: self.stream_bytes.collect(reset),
+            batch_packets: self.batch_packets.collect(reset),
+            batch_bytes: self.batch_bytes.collect(reset),
+            connection_setup_latency_us: self.connection_setup_latency_us.collect(reset),
+            new_connections_per_second,
+            new_connections_per_second_ema,
+            sampled_connections: self.sampled_connections.collect(),
+            connection_rtt_min_us: self.connection_rtt_min_us.collect(),
+            connection_rtt_avg_us: self.connection_rtt_avg_us.collect(),
+            connection_rtt_max_us: self.connection_rtt_max_us.collect(),
+            connection_lost_packets: self.connection_lost_packets.collect(),
+            staked_uni_streams_limit_min: self.staked_uni_streams_limit_min.collect(),
+            staked_uni_streams_limit_max: self.staked_uni_streams_limit_max.collect(),
+            unstaked_uni_streams_limit_min: self.unstaked_uni_streams_limit_min.collect(),
+            unstaked_uni_streams_limit_max: self.unstaked_uni_streams_limit_max.collect(),
+            total_bidi_streams: self.total_bidi_streams.collect(reset),
+            total_datagrams: self.total_datagrams.collect(reset),
+            datagrams_dropped: self.datagrams_dropped.collect(reset),
+            connection_rejected_alpn: self.connection_rejected_alpn.collect(reset),
+            bandwidth_throttled_streams: self.bandwidth_throttled_streams.collect(reset),
+            global_bandwidth_throttled: self.global_bandwidth_throttled.collect(reset),
+            connection_closed_stream_timeouts: self
+                .connection_closed_stream_timeouts
+                .collect(reset),
+            streams_reaped_idle: self.streams_reaped_idle.collect(reset),
+            active_staked_connections: self.active_staked_connections.collect(),
+            active_unstaked_connections: self.active_unstaked_connections.collect(),
+            stream_throttle_scale_percent: self.stream_throttle_scale_percent.collect(),
+            proxy_protocol_invalid_headers: self.proxy_protocol_invalid_headers.collect(reset),
+            streams_reset_chunk_limit: self.streams_reset_chunk_limit.collect(reset),
+            packets_rejected_oversize: self.packets_rejected_oversize.collect(reset),
+            endpoint_datagrams_received: self.endpoint_datagrams_received.collect(reset),
+            endpoint_initial_packets_received: self
+                .endpoint_initial_packets_received
+                .collect(reset),
+            endpoint_retry_packets_sent: self.endpoint_retry_packets_sent.collect(reset),
+            endpoint_version_negotiation_sent: self
+                .endpoint_version_negotiation_sent
+                .collect(reset),
+            endpoint_connections_refused: self.endpoint_connections_refused.collect(reset),
+            connection_add_failed_staked_peer_limit: self
+                .connection_add_failed_staked_peer_limit
+                .collect(reset),
+            connection_add_failed_unstaked_peer_limit: self
+                .connection_add_failed_unstaked_peer_limit
+                .collect(reset),
+            endpoint_rebinds: self.endpoint_rebinds.collect(reset),
+            active_endpoints: self.active_endpoints.collect(),
+            total_bytes_received: self.total_bytes_received.collect(reset),
+            connection_rejected_unstaked_disabled: self
+                .connection_rejected_unstaked_disabled
+                .collect(reset),
+            evicted_unstaked_for_staked: self.evicted_unstaked_for_staked.collect(reset),
+            connection_packets_per_second_max: self.connection_packets_per_second_max.collect(),
+            connection_packets_per_second_min: self.connection_packets_per_second_min.collect(),
+            read_turns_waited: self.read_turns_waited.collect(reset),
+            connection_rejected_by_controller: self
+                .connection_rejected_by_controller
+                .collect(reset),
+            proxy_protocol_dropped_addresses: self.proxy_protocol_dropped_addresses.collect(reset),
+            staked_connections_per_tier: self
+                .stake_tiers
+                .get()
+                .map(|stake_tiers| stake_tiers.snapshot())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Reports the counters through the default [`MetricsSink`] and resets them. Without the
+    /// `metrics` feature the default sink discards the report, use [`Self::report_to`] or
+    /// [`Self::snapshot`] to read the counters then.
     pub fn report(&self, name: &'static str) {
-        datapoint_info!(
-            name,
-            (
-                "active_connections",
-                self.total_connections.load(Ordering::Relaxed),
-                i64
-            ),
-            (
-                "active_streams",
-                self.total_streams.load(Ordering::Relaxed),
-                i64
-            ),
-            (
-                "new_connections",
-                self.total_new_connections.swap(0, Ordering::Relaxed),
-                i64
-            ),
-            (
-                "new_streams",
-                self.total_new_streams.swap(0, Ordering::Relaxed),
-                i64
-            ),
-            (
-                "evictions",
-                self.num_evictions.swap(0, Ordering::Relaxed),
-                i64
-            ),
+        self.report_to(name, default_metrics_sink().as_ref());
+    }
+
+    /// Reports the counters through `sink` and resets them.
+    pub fn report_to(&self, name: &'static str, sink: &dyn MetricsSink) {
+        sink.record(name, &self.collect(true));
+    }
+}
+
+impl StreamStatsSnapshot {
+    /// Average number of packets of the batches sent to the consumer, 0 without any.
+    pub fn avg_packets_per_batch(&self) -> u64 {
+        self.total_packets_sent_to_consumer
+            .checked_div(self.total_packet_batches_sent)
+            .unwrap_or_default()
+    }
+
+    /// Average size in bytes of the batches sent to the consumer, 0 without any.
+    pub fn avg_batch_bytes(&self) -> u64 {
+        self.total_bytes_sent_to_consumer
+            .checked_div(self.total_packet_batches_sent)
+            .unwrap_or_default()
+    }
+}
+
+/// Destination for the counters published by [`StreamStats::report`].
+pub trait MetricsSink: Send + Sync {
+    fn record(&self, name: &'static str, stats: &StreamStatsSnapshot);
+}
+
+/// Returns the sink used when none is configured: [`DatapointMetricsSink`] when the `metrics`
+/// feature is enabled and [`NoopMetricsSink`] otherwise.
+pub fn default_metrics_sink() -> Arc<dyn MetricsSink> {
+    #[cfg(feature = "metrics")]
+    {
+        Arc::new(DatapointMetricsSink)
+    }
+    #[cfg(not(feature = "metrics"))]
+    {
+        Arc::new(NoopMetricsSink)
+    }
+}
+
+/// Discards every report.
+pub struct NoopMetricsSink;
+
+impl MetricsSink for NoopMetricsSink {
+    fn record(&self, _name: &'static str, _stats: &StreamStatsSnapshot) {}
+}
+
+/// Submits every report as a `datapoint_info!` to the Solana metrics backend.
+#[cfg(feature = "metrics")]
+pub struct DatapointMetricsSink;
+
+#[cfg(feature = "metrics")]
+impl MetricsSink for DatapointMetricsSink {
+    fn record(&self, name: &'static str, stats: &StreamStatsSnapshot) {
+        datapoint_info!(
+            name,
+            ("active_connections", stats.total_connections, i64),
+            ("active_streams", stats.total_streams, i64),
+            ("new_connections", stats.total_new_connections, i64),
+            ("new_streams", stats.total_new_streams, i64),
+            ("evictions", stats.num_evictions, i64),
             (
                 "connection_added_from_staked_peer",
-                self.connection_added_from_staked_peer
-                    .swap(0, Ordering::Relaxed),
+                stats.connection_added_from_staked_peer,
                 i64
             ),
             (
                 "connection_added_from_unstaked_peer",
-                self.connection_added_from_unstaked_peer
-                    .swap(0, Ordering::Relaxed),
-                i64
-            ),
-            (
-                "connection_add_failed",
-                self.connection_add_failed.swap(0, Ordering::Relaxed),
+                stats.connection_added_from_unstaked_peer,
                 i64
             ),
+            ("connection_add_failed", stats.connection_add_failed, i64),
             (
                 "connection_add_failed_invalid_stream_count",
-                self.connection_add_failed_invalid_stream_count
-                    .swap(0, Ordering::Relaxed),
+                stats.connection_add_failed_invalid_stream_count,
                 i64
             ),
             (
                 "connection_add_failed_staked_node",
-                self.connection_add_failed_staked_node
-                    .swap(0, Ordering::Relaxed),
+                stats.connection_add_failed_staked_node,
                 i64
             ),
             (
                 "connection_add_failed_unstaked_node",
-                self.connection_add_failed_unstaked_node
-                    .swap(0, Ordering::Relaxed),
+                stats.connection_add_failed_unstaked_node,
                 i64
             ),
             (
                 "connection_add_failed_on_pruning",
-                self.connection_add_failed_on_pruning
-                    .swap(0, Ordering::Relaxed),
-                i64
-            ),
-            (
-                "connection_removed",
-                self.connection_removed.swap(0, Ordering::Relaxed),
+                stats.connection_add_failed_on_pruning,
                 i64
             ),
+            ("connection_removed", stats.connection_removed, i64),
             (
                 "connection_remove_failed",
-                self.connection_remove_failed.swap(0, Ordering::Relaxed),
+                stats.connection_remove_failed,
                 i64
             ),
             (
                 "connection_setup_timeout",
-                self.connection_setup_timeout.swap(0, Ordering::Relaxed),
-                i64
-            ),
-            (
-                "connection_setup_error",
-                self.connection_setup_error.swap(0, Ordering::Relaxed),
+                stats.connection_setup_timeout,
                 i64
             ),
+            ("connection_setup_error", stats.connection_setup_error, i64),
             (
                 "connection_setup_error_timed_out",
-                self.connection_setup_error_timed_out
-                    .swap(0, Ordering::Relaxed),
+                stats.connection_setup_error_timed_out,
                 i64
             ),
             (
                 "connection_setup_error_closed",
-                self.connection_setup_error_closed
-                    .swap(0, Ordering::Relaxed),
+                stats.connection_setup_error_closed,
                 i64
             ),
             (
                 "connection_setup_error_transport",
-                self.connection_setup_error_transport
-                    .swap(0, Ordering::Relaxed),
+                stats.connection_setup_error_transport,
                 i64
             ),
             (
                 "connection_setup_error_app_closed",
-                self.connection_setup_error_app_closed
-                    .swap(0, Ordering::Relaxed),
+                stats.connection_setup_error_app_closed,
                 i64
             ),
             (
                 "connection_setup_error_reset",
-                self.connection_setup_error_reset.swap(0, Ordering::Relaxed),
+                stats.connection_setup_error_reset,
                 i64
             ),
             (
                 "connection_setup_error_locally_closed",
-                self.connection_setup_error_locally_closed
-                    .swap(0, Ordering::Relaxed),
+                stats.connection_setup_error_locally_closed,
                 i64
             ),
             (
-                "invalid_chunk",
-                self.total_invalid_chunks.swap(0, Ordering::Relaxed),
-                i64
-            ),
-            (
-                "invalid_chunk_size",
-                self.total_invalid_chunk_size.swap(0, Ordering::Relaxed),
-                i64
-            ),
-            (
-                "packets_allocated",
-                self.total_packets_allocated.swap(0, Ordering::Relaxed),
+                "connection_setup_error_version_mismatch",
+                stats.connection_setup_error_version_mismatch,
                 i64
             ),
+            ("invalid_chunk", stats.total_invalid_chunks, i64),
+            ("invalid_chunk_size", stats.total_invalid_chunk_size, i64),
+            ("packets_allocated", stats.total_packets_allocated, i64),
             (
                 "packet_batches_allocated",
-                self.total_packet_batches_allocated
-                    .swap(0, Ordering::Relaxed),
+                stats.total_packet_batches_allocated,
                 i64
             ),
             (
                 "packets_sent_for_batching",
-                self.total_packets_sent_for_batching
-                    .swap(0, Ordering::Relaxed),
+                stats.total_packets_sent_for_batching,
                 i64
             ),
             (
                 "staked_packets_sent_for_batching",
-                self.total_staked_packets_sent_for_batching
-                    .swap(0, Ordering::Relaxed),
+                stats.total_staked_packets_sent_for_batching,
                 i64
             ),
             (
                 "unstaked_packets_sent_for_batching",
-                self.total_unstaked_packets_sent_for_batching
-                    .swap(0, Ordering::Relaxed),
+                stats.total_unstaked_packets_sent_for_batching,
                 i64
             ),
             (
                 "bytes_sent_for_batching",
-                self.total_bytes_sent_for_batching
-                    .swap(0, Ordering::Relaxed),
+                stats.total_bytes_sent_for_batching,
                 i64
             ),
             (
                 "chunks_sent_for_batching",
-                self.total_chunks_sent_for_batching
-                    .swap(0, Ordering::Relaxed),
+                stats.total_chunks_sent_for_batching,
                 i64
             ),
             (
                 "packets_sent_to_consumer",
-                self.total_packets_sent_to_consumer
-                    .swap(0, Ordering::Relaxed),
+                stats.total_packets_sent_to_consumer,
                 i64
             ),
             (
                 "bytes_sent_to_consumer",
-                self.total_bytes_sent_to_consumer.swap(0, Ordering::Relaxed),
+                stats.total_bytes_sent_to_consumer,
                 i64
             ),
             (
                 "chunks_processed_by_batcher",
-                self.total_chunks_processed_by_batcher
-                    .swap(0, Ordering::Relaxed),
-                i64
-            ),
-            (
-                "chunks_received",
-                self.total_chunks_received.swap(0, Ordering::Relaxed),
+                stats.total_chunks_processed_by_batcher,
                 i64
             ),
+            ("chunks_received", stats.total_chunks_received, i64),
             (
                 "staked_chunks_received",
-                self.total_staked_chunks_received.swap(0, Ordering::Relaxed),
+                stats.total_staked_chunks_received,
                 i64
             ),
             (
                 "unstaked_chunks_received",
-                self.total_unstaked_chunks_received
-                    .swap(0, Ordering::Relaxed),
+                stats.total_unstaked_chunks_received,
                 i64
             ),
             (
                 "packet_batch_send_error",
-                self.total_packet_batch_send_err.swap(0, Ordering::Relaxed),
+                stats.total_packet_batch_send_err,
                 i64
             ),
             (
                 "handle_chunk_to_packet_batcher_send_error",
-                self.total_handle_chunk_to_packet_batcher_send_err
-                    .swap(0, Ordering::Relaxed),
+                stats.total_handle_chunk_to_packet_batcher_send_err,
                 i64
             ),
+            ("packet_batches_sent", stats.total_packet_batches_sent, i64),
+            ("packet_batch_empty", stats.total_packet_batches_none, i64),
+            ("stream_read_errors", stats.total_stream_read_errors, i64),
             (
-                "packet_batches_sent",
-                self.total_packet_batches_sent.swap(0, Ordering::Relaxed),
+                "stream_read_timeouts",
+                stats.total_stream_read_timeouts,
                 i64
             ),
+            ("throttled_streams", stats.throttled_streams, i64),
+            ("stream_load_ema", stats.stream_load_ema, i64),
             (
-                "packet_batch_empty",
-                self.total_packet_batches_none.swap(0, Ordering::Relaxed),
+                "stream_load_ema_overflow",
+                stats.stream_load_ema_overflow,
                 i64
             ),
             (
-                "stream_read_errors",
-                self.total_stream_read_errors.swap(0, Ordering::Relaxed),
+                "stream_load_capacity_overflow",
+                stats.stream_load_capacity_overflow,
                 i64
             ),
             (
-                "stream_read_timeouts",
-                self.total_stream_read_timeouts.swap(0, Ordering::Relaxed),
+                "throttled_unstaked_streams",
+                stats.throttled_unstaked_streams,
                 i64
             ),
             (
-                "throttled_streams",
-                self.throttled_streams.swap(0, Ordering::Relaxed),
+                "throttled_staked_streams",
+                stats.throttled_staked_streams,
                 i64
             ),
             (
-                "stream_load_ema",
-                self.stream_load_ema.load(Ordering::Relaxed),
+                "connection_rejected_by_filter",
+                stats.connection_rejected_by_filter,
                 i64
             ),
             (
-                "stream_load_ema_overflow",
-                self.stream_load_ema_overflow.load(Ordering::Relaxed),
+                "connection_rate_limited",
+                stats.connection_rate_limited,
                 i64
             ),
             (
-                "stream_load_capacity_overflow",
-                self.stream_load_capacity_overflow.load(Ordering::Relaxed),
+                "connection_rejected_banned",
+                stats.connection_rejected_banned,
                 i64
             ),
             (
-                "throttled_unstaked_streams",
-                self.throttled_unstaked_streams.swap(0, Ordering::Relaxed),
+                "connection_added_protected",
+                stats.connection_added_protected,
                 i64
             ),
+            ("packet_channel_depth", stats.packet_channel_depth, i64),
+            ("invalid_chunk_oversize", stats.invalid_chunk_oversize, i64),
+            ("invalid_chunk_count", stats.invalid_chunk_count, i64),
+            ("invalid_chunk_empty", stats.invalid_chunk_empty, i64),
+            ("stream_bytes_count", stats.stream_bytes.count, i64),
+            ("stream_bytes_p50", stats.stream_bytes.p50, i64),
+            ("stream_bytes_p90", stats.stream_bytes.p90, i64),
+            ("stream_bytes_p99", stats.stream_bytes.p99, i64),
+            ("stream_bytes_max", stats.stream_bytes.max, i64),
+            ("batch_packets_count", stats.batch_packets.count, i64),
+            ("batch_packets_p50", stats.batch_packets.p50, i64),
+            ("batch_packets_p90", stats.batch_packets.p90, i64),
+            ("batch_packets_p99", stats.batch_packets.p99, i64),
+            ("batch_packets_max", stats.batch_packets.max, i64),
+            ("batch_bytes_p50", stats.batch_bytes.p50, i64),
+            ("batch_bytes_p90", stats.batch_bytes.p90, i64),
+            ("batch_bytes_p99", stats.batch_bytes.p99, i64),
+            ("batch_bytes_max", stats.batch_bytes.max, i64),
+            ("avg_packets_per_batch", stats.avg_packets_per_batch(), i64),
+            ("avg_batch_bytes", stats.avg_batch_bytes(), i64),
             (
-                "throttled_staked_streams",
-                self.throttled_staked_streams.swap(0, Ordering::Relaxed),
+                "connection_setup_latency_us_count",
+                stats.connection_setup_latency_us.count,
+                i64
+            ),
+            (
+                "connection_setup_latency_us_p50",
+                stats.connection_setup_latency_us.p50,
+                i64
+            ),
+            (
+                "connection_setup_latency_us_p90",
+                stats.connection_setup_latency_us.p90,
+                i64
+            ),
+            (
+                "connection_setup_latency_us_p99",
+                stats.connection_setup_latency_us.p99,
+                i64
+            ),
+            (
+                "connection_setup_latency_us_max",
+                stats.connection_setup_latency_us.max,
+                i64
+            ),
+            (
+                "new_connections_per_second_count",
+                stats.new_connections_per_second.count,
+                i64
+            ),
+            (
+                "new_connections_per_second_p50",
+                stats.new_connections_per_second.p50,
+                i64
+            ),
+            (
+                "new_connections_per_second_p90",
+                stats.new_connections_per_second.p90,
+                i64
+            ),
+            (
+                "new_connections_per_second_p99",
+                stats.new_connections_per_second.p99,
+                i64
+            ),
+            (
+                "new_connections_per_second_max",
+                stats.new_connections_per_second.max,
+                i64
+            ),
+            (
+                "new_connections_per_second_ema",
+                stats.new_connections_per_second_ema,
+                i64
+            ),
+            ("sampled_connections", stats.sampled_connections, i64),
+            ("connection_rtt_min_us", stats.connection_rtt_min_us, i64),
+            ("connection_rtt_avg_us", stats.connection_rtt_avg_us, i64),
+            ("connection_rtt_max_us", stats.connection_rtt_max_us, i64),
+            (
+                "connection_lost_packets",
+                stats.connection_lost_packets,
+                i64
+            ),
+            (
+                "staked_uni_streams_limit_min",
+                stats.staked_uni_streams_limit_min,
+                i64
+            ),
+            (
+                "staked_uni_streams_limit_max",
+                stats.staked_uni_streams_limit_max,
+                i64
+            ),
+            (
+                "unstaked_uni_streams_limit_min",
+                stats.unstaked_uni_streams_limit_min,
+                i64
+            ),
+            (
+                "unstaked_uni_streams_limit_max",
+                stats.unstaked_uni_streams_limit_max,
+                i64
+            ),
+            ("total_bidi_streams", stats.total_bidi_streams, i64),
+            ("total_datagrams", stats.total_datagrams, i64),
+            ("datagrams_dropped", stats.datagrams_dropped, i64),
+            (
+                "connection_rejected_alpn",
+                stats.connection_rejected_alpn,
+                i64
+            ),
+            (
+                "bandwidth_throttled_streams",
+                stats.bandwidth_throttled_streams,
+                i64
+            ),
+            (
+                "global_bandwidth_throttled",
+                stats.global_bandwidth_throttled,
+                i64
+            ),
+            (
+                "connection_closed_stream_timeouts",
+                stats.connection_closed_stream_timeouts,
+                i64
+            ),
+            ("streams_reaped_idle", stats.streams_reaped_idle, i64),
+            (
+                "active_staked_connections",
+                stats.active_staked_connections,
+                i64
+            ),
+            (
+                "active_unstaked_connections",
+                stats.active_unstaked_connections,
+                i64
+            ),
+            (
+                "stream_throttle_scale_percent",
+                stats.stream_throttle_scale_percent,
+                i64
+            ),
+            (
+                "proxy_protocol_invalid_headers",
+                stats.proxy_protocol_invalid_headers,
+                i64
+            ),
+            (
+                "streams_reset_chunk_limit",
+                stats.streams_reset_chunk_limit,
+                i64
+            ),
+            (
+                "packets_rejected_oversize",
+                stats.packets_rejected_oversize,
+                i64
+            ),
+            (
+                "endpoint_datagrams_received",
+                stats.endpoint_datagrams_received,
+                i64
+            ),
+            (
+                "endpoint_initial_packets_received",
+                stats.endpoint_initial_packets_received,
+                i64
+            ),
+            (
+                "endpoint_retry_packets_sent",
+                stats.endpoint_retry_packets_sent,
+                i64
+            ),
+            (
+                "endpoint_version_negotiation_sent",
+                stats.endpoint_version_negotiation_sent,
+                i64
+            ),
+            (
+                "endpoint_connections_refused",
+                stats.endpoint_connections_refused,
+                i64
+            ),
+            (
+                "connection_add_failed_staked_peer_limit",
+                stats.connection_add_failed_staked_peer_limit,
+                i64
+            ),
+            (
+                "connection_add_failed_unstaked_peer_limit",
+                stats.connection_add_failed_unstaked_peer_limit,
+                i64
+            ),
+            ("endpoint_rebinds", stats.endpoint_rebinds, i64),
+            ("active_endpoints", stats.active_endpoints, i64),
+            ("total_bytes_received", stats.total_bytes_received, i64),
+            (
+                "connection_rejected_unstaked_disabled",
+                stats.connection_rejected_unstaked_disabled,
+                i64
+            ),
+            (
+                "evicted_unstaked_for_staked",
+                stats.evicted_unstaked_for_staked,
+                i64
+            ),
+            (
+                "connection_packets_per_second_max",
+                stats.connection_packets_per_second_max,
+                i64
+            ),
+            (
+                "connection_packets_per_second_min",
+                stats.connection_packets_per_second_min,
+                i64
+            ),
+            ("read_turns_waited", stats.read_turns_waited, i64),
+            (
+                "connection_rejected_by_controller",
+                stats.connection_rejected_by_controller,
+                i64
+            ),
+            (
+                "proxy_protocol_dropped_addresses",
+                stats.proxy_protocol_dropped_addresses,
                 i64
             ),
         );
+        for (tier, &connections) in stats.staked_connections_per_tier.iter().enumerate() {
+            datapoint_info!(
+                "quic_stake_tier_connections",
+                ("server", name, String),
+                ("tier", tier, i64),
+                ("connections", connections, i64),
+            );
+        }
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn spawn_server(
+/// Notified as connections enter and leave the connection tables.
+///
+/// Calls are made from the connection tasks, so implementations should return quickly and must
+/// not block. `pubkey` is the one from the peer's certificate and `stake` is 0 for peers treated
+/// as unstaked.
+pub trait ConnectionEventHandler: Send + Sync {
+    /// A connection was added to the staked or unstaked connection table.
+    fn on_connect(&self, _remote_addr: SocketAddr, _pubkey: Option<Pubkey>, _stake: u64) {}
+    /// Called right before `on_connect` with the protocol negotiated through ALPN, see
+    /// [`QuicServerConfig::alpn_protocols`].
+    fn on_protocol_negotiated(
+        &self,
+        _remote_addr: SocketAddr,
+        _pubkey: Option<Pubkey>,
+        _protocol: &[u8],
+    ) {
+    }
+    /// A connection was pruned to make room for a new one. Called once the connection table
+    /// is unlocked again, so it may be notified after the new connection's `on_connect`.
+    fn on_evict(&self, _remote_addr: SocketAddr, _pubkey: Option<Pubkey>, _stake: u64) {}
+    /// A connection was closed and removed from its connection table, `summary` totals what it
+    /// did while it was admitted.
+    fn on_disconnect(
+        &self,
+        _remote_addr: SocketAddr,
+        _pubkey: Option<Pubkey>,
+        _stake: u64,
+        _summary: &ConnectionSummary,
+    ) {
+    }
+}
+
+/// Decides whether a connection that completed its handshake is admitted, see
+/// [`QuicServerConfig::admission_controller`].
+///
+/// Called from the connection setup tasks before the connection is rate limited, see
+/// `unstaked_connection_rate_limit`, or counted against the connection caps, so
+/// implementations should return quickly and must not block.
+pub trait AdmissionController: Send + Sync {
+    fn admit(&self, context: &ConnectionContext) -> AdmissionDecision;
+}
+
+/// What the server knows about a connection when it's passed to [`AdmissionController::admit`].
+#[derive(Clone, Debug)]
+pub struct ConnectionContext<'a> {
+    /// The address of the client, behind the proxy if the connection is relayed by one.
+    pub remote_addr: SocketAddr,
+    /// The pubkey from the peer's certificate, `None` if it can't be parsed.
+    pub pubkey: Option<Pubkey>,
+    /// Stake of the pubkey, 0 if it has none.
+    pub stake: u64,
+    /// Whether the peer is treated as staked, which it isn't with too little stake.
+    pub staked: bool,
+    /// Whether the peer is in `QuicServerConfig::protected_peers`.
+    pub protected: bool,
+    /// Protocol negotiated through ALPN, see [`QuicServerConfig::alpn_protocols`].
+    pub alpn_protocol: Option<&'a [u8]>,
+    pub occupancy: PoolOccupancy,
+}
+
+/// Connections in the staked and unstaked connection tables, and their caps, see
+/// [`ConnectionLimits`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolOccupancy {
+    pub staked_connections: usize,
+    pub max_staked_connections: usize,
+    pub unstaked_connections: usize,
+    pub max_unstaked_connections: usize,
+}
+
+/// Verdict of an [`AdmissionController`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdmissionDecision {
+    /// Goes on admitting the connection as the server would without a controller.
+    Accept,
+    /// Closes the connection, sending the reason to the peer. Keep it short, the reason has to
+    /// fit in a single packet.
+    Reject(String),
+    /// Admits the connection as though its peer had no stake, keyed by its address in the
+    /// unstaked connection table. A protected peer is admitted as an unprotected one, subject to
+    /// the unstaked connection caps and `unstaked_connection_rate_limit`.
+    AcceptAsUnstaked,
+}
+
+/// Totals of an admitted connection, passed to [`ConnectionEventHandler::on_disconnect`].
+///
+/// Streams still being read as the connection goes away may not be accounted for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionSummary {
+    /// Unidirectional streams accepted.
+    pub streams: u64,
+    pub chunks: u64,
+    pub bytes: u64,
+    /// Packets read to completion and handed to the batcher.
+    pub packets: u64,
+    /// Streams held back by the stream load throttling.
+    pub throttled_streams: u64,
+    /// Time from the admission of the connection to its removal.
+    pub duration: Duration,
+}
+
+/// Notified after [`EndpointKeyUpdater::update_key`] installed a new identity on the endpoints,
+/// e.g. to log rotations or detect unexpected key changes. `cert_chain_pem` is the PEM encoded
+/// certificate chain now presented to new connections.
+pub trait KeyRotationHandler: Send + Sync {
+    fn on_key_rotated(&self, cert_chain_pem: &str, rotated_at: SystemTime);
+}
+
+/// Serves the bidirectional streams enabled by [`QuicServerConfig::max_concurrent_bidi_streams`],
+/// e.g. for a request/response channel. They bypass the stream throttling and never reach the
+/// packet sender.
+///
+/// Calls are made from the connection tasks of admitted connections, so implementations should
+/// hand the streams off to a task of their own rather than serve them inline. `pubkey` and
+/// `stake` are as for [`ConnectionEventHandler`].
+pub trait BidiStreamHandler: Send + Sync {
+    fn on_stream(
+        &self,
+        remote_addr: SocketAddr,
+        pubkey: Option<Pubkey>,
+        stake: u64,
+        send_stream: SendStream,
+        recv_stream: RecvStream,
+    );
+}
+
+/// TLS identity presented by the server.
+enum ServerIdentity<'a> {
+    /// Self-signed certificate derived from the identity keypair.
+    Keypair {
+        keypair: &'a Keypair,
+        gossip_host: IpAddr,
+    },
+    /// Externally constructed TLS configuration.
+    Tls(Arc<rustls::ServerConfig>),
+}
+
+/// Builds and spawns a QUIC server on its own runtime and thread.
+///
+/// Only the arguments every server needs are taken by [`QuicServerBuilder::new`], the
+/// remaining ones default to the values used by the TPU and can be overridden with the
+/// chained setters.
+pub struct QuicServerBuilder<'a> {
     name: &'static str,
-    sock: UdpSocket,
-    keypair: &Keypair,
-    gossip_host: IpAddr,
-    packet_sender: Sender<PacketBatch>,
+    sockets: Vec<UdpSocket>,
+    identity: ServerIdentity<'a>,
+    packet_sender: Arc<dyn PacketSink>,
     exit: Arc<AtomicBool>,
     max_connections_per_peer: usize,
-    staked_nodes: Arc<RwLock<StakedNodes>>,
+    staked_nodes: StakedNodesSource,
     max_staked_connections: usize,
     max_unstaked_connections: usize,
     max_streams_per_ms: u64,
     wait_for_chunk_timeout: Duration,
     coalesce: Duration,
-) -> Result<SpawnServerResult, QuicServerError> {
-    let runtime = rt();
-    let result = {
-        let _guard = runtime.enter();
-        crate::nonblocking::quic::spawn_server(
+    quic_server_config: QuicServerConfig,
+    runtime: Option<Handle>,
+    thread_name: String,
+}
+
+impl<'a> QuicServerBuilder<'a> {
+    pub fn new<S: PacketSink>(
+        name: &'static str,
+        sock: UdpSocket,
+        keypair: &'a Keypair,
+        gossip_host: IpAddr,
+        packet_sender: S,
+        exit: Arc<AtomicBool>,
+    ) -> Self {
+        Self::new_multi(name, vec![sock], keypair, gossip_host, packet_sender, exit)
+    }
+
+    /// Creates a builder for a server with one endpoint per socket, see
+    /// [`crate::nonblocking::quic::spawn_server_multi`].
+    pub fn new_multi<S: PacketSink>(
+        name: &'static str,
+        sockets: Vec<UdpSocket>,
+        keypair: &'a Keypair,
+        gossip_host: IpAddr,
+        packet_sender: S,
+        exit: Arc<AtomicBool>,
+    ) -> Self {
+        Self::with_identity(
             name,
-            sock,
-            keypair,
-            gossip_host,
+            sockets,
+            ServerIdentity::Keypair {
+                keypair,
+                gossip_host,
+            },
+            Arc::new(packet_sender),
+            exit,
+        )
+    }
+
+    /// Creates a builder for a server presenting an externally constructed TLS configuration
+    /// instead of a self-signed certificate derived from the identity keypair.
+    pub fn new_with_tls<S: PacketSink>(
+        name: &'static str,
+        sockets: Vec<UdpSocket>,
+        tls_config: Arc<rustls::ServerConfig>,
+        packet_sender: S,
+        exit: Arc<AtomicBool>,
+    ) -> Self {
+        Self::with_identity(
+            name,
+            sockets,
+            ServerIdentity::Tls(tls_config),
+            Arc::new(packet_sender),
+            exit,
+        )
+    }
+
+    fn with_identity(
+        name: &'static str,
+        sockets: Vec<UdpSocket>,
+        identity: ServerIdentity<'a>,
+        packet_sender: Arc<dyn PacketSink>,
+        exit: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            name,
+            sockets,
+            identity,
+            packet_sender,
+            exit,
+            max_connections_per_peer: DEFAULT_MAX_CONNECTIONS_PER_PEER,
+            staked_nodes: StakedNodesSource::Locked(Arc::default()),
+            max_staked_connections: MAX_STAKED_CONNECTIONS,
+            max_unstaked_connections: MAX_UNSTAKED_CONNECTIONS,
+            max_streams_per_ms: DEFAULT_MAX_STREAMS_PER_MS,
+            wait_for_chunk_timeout: DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+            coalesce: DEFAULT_TPU_COALESCE,
+            quic_server_config: QuicServerConfig::default(),
+            runtime: None,
+            thread_name: DEFAULT_SERVER_THREAD_NAME.to_string(),
+        }
+    }
+
+    pub fn max_connections_per_peer(mut self, max_connections_per_peer: usize) -> Self {
+        self.max_connections_per_peer = max_connections_per_peer;
+        self
+    }
+
+    pub fn staked_nodes(mut self, staked_nodes: Arc<RwLock<StakedNodes>>) -> Self {
+        self.staked_nodes = staked_nodes.into();
+        self
+    }
+
+    /// Looks up stakes in `staked_nodes` instead, new snapshots are published with
+    /// `ArcSwap::store` without blocking the connection handlers.
+    pub fn swappable_staked_nodes(mut self, staked_nodes: Arc<ArcSwap<StakedNodes>>) -> Self {
+        self.staked_nodes = staked_nodes.into();
+        self
+    }
+
+    pub fn max_staked_connections(mut self, max_staked_connections: usize) -> Self {
+        self.max_staked_connections = max_staked_connections;
+        self
+    }
+
+    pub fn max_unstaked_connections(mut self, max_unstaked_connections: usize) -> Self {
+        self.max_unstaked_connections = max_unstaked_connections;
+        self
+    }
+
+    pub fn max_streams_per_ms(mut self, max_streams_per_ms: u64) -> Self {
+        self.max_streams_per_ms = max_streams_per_ms;
+        self
+    }
+
+    pub fn wait_for_chunk_timeout(mut self, wait_for_chunk_timeout: Duration) -> Self {
+        self.wait_for_chunk_timeout = wait_for_chunk_timeout;
+        self
+    }
+
+    /// Time a batch waits for more packets after its first one. `Duration::ZERO` disables
+    /// coalescing, each packet is sent as soon as it's read, batched only with the packets
+    /// that queued up meanwhile.
+    pub fn coalesce(mut self, coalesce: Duration) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    pub fn quic_server_config(mut self, quic_server_config: QuicServerConfig) -> Self {
+        self.quic_server_config = quic_server_config;
+        self
+    }
+
+    /// Runs the server on the runtime behind `runtime` instead of creating a dedicated one. This
+    /// lets several servers in the same process share a single runtime.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Name of the thread driving the server, [`DEFAULT_SERVER_THREAD_NAME`] by default. Give
+    /// each server of a process its own, e.g. `solQuicTpuFwd`, to tell them apart in `top` and
+    /// profilers. Linux truncates thread names to 15 bytes.
+    pub fn thread_name(mut self, thread_name: impl Into<String>) -> Self {
+        self.thread_name = thread_name.into();
+        self
+    }
+
+    pub fn build_and_spawn(self) -> Result<SpawnServerResult, QuicServerError> {
+        let Self {
+            name,
+            sockets,
+            identity,
             packet_sender,
             exit,
             max_connections_per_peer,
@@ -491,26 +2995,1222 @@ pub fn spawn_server(
             max_streams_per_ms,
             wait_for_chunk_timeout,
             coalesce,
-        )
-    }?;
-    let handle = thread::Builder::new()
-        .name("solQuicServer".into())
-        .spawn(move || {
-            if let Err(e) = runtime.block_on(result.thread) {
-                warn!("error from runtime.block_on: {:?}", e);
+            quic_server_config,
+            runtime,
+            thread_name,
+        } = self;
+        if thread_name.contains('\0') {
+            return Err(QuicServerError::InvalidConfig(
+                "thread_name must not contain NUL bytes".to_string(),
+            ));
+        }
+        // The runtime created here is moved into the server thread and dropped when it exits.
+        let (runtime, runtime_handle) = match runtime {
+            Some(handle) => (None, handle),
+            None => {
+                quic_server_config.validate()?;
+                let runtime = rt(&quic_server_config.runtime_config);
+                let handle = runtime.handle().clone();
+                (Some(runtime), handle)
+            }
+        };
+        let gossip_host = match &identity {
+            ServerIdentity::Keypair { gossip_host, .. } => Some(*gossip_host),
+            ServerIdentity::Tls(_) => None,
+        };
+        let result = {
+            let _guard = runtime_handle.enter();
+            match identity {
+                ServerIdentity::Keypair {
+                    keypair,
+                    gossip_host,
+                } => crate::nonblocking::quic::spawn_server_with_keypair(
+                    name,
+                    sockets,
+                    keypair,
+                    gossip_host,
+                    PacketBatchConsumer::Sink(packet_sender),
+                    exit.clone(),
+                    max_connections_per_peer,
+                    staked_nodes,
+                    max_staked_connections,
+                    max_unstaked_connections,
+                    max_streams_per_ms,
+                    wait_for_chunk_timeout,
+                    coalesce,
+                    quic_server_config.clone(),
+                ),
+                ServerIdentity::Tls(tls_config) => {
+                    crate::nonblocking::quic::spawn_server_with_tls_config(
+                        name,
+                        sockets,
+                        tls_config,
+                        PacketBatchConsumer::Sink(packet_sender),
+                        exit.clone(),
+                        max_connections_per_peer,
+                        staked_nodes,
+                        max_staked_connections,
+                        max_unstaked_connections,
+                        max_streams_per_ms,
+                        wait_for_chunk_timeout,
+                        coalesce,
+                        quic_server_config.clone(),
+                    )
+                }
             }
+        }?;
+        let local_addr = result.endpoints[0]
+            .local_addr()
+            .map_err(|err| QuicServerError::endpoint_failed(None, err))?;
+        let server_task = result.thread.abort_handle();
+        let handle = thread::Builder::new()
+            .name(thread_name)
+            .spawn(move || {
+                match runtime_handle.block_on(result.thread) {
+                    Err(e) if !e.is_cancelled() => {
+                        warn!("error from runtime.block_on: {:?}", e);
+                    }
+                    _ => (),
+                }
+                drop(runtime);
+            })
+            .unwrap();
+
+        let updater = EndpointKeyUpdater {
+            endpoints: result.endpoints.clone(),
+            gossip_host,
+            max_concurrent_connections: result.max_concurrent_connections,
+            quic_server_config,
+            stats: result.stats.clone(),
+        };
+
+        Ok(SpawnServerResult {
+            endpoints: result.endpoints,
+            local_addr,
+            thread: handle,
+            key_updater: Arc::new(updater),
+            peer_stats_table: result.peer_stats_table,
+            stats: result.stats,
+            cert_chain_pem: result.cert_chain_pem,
+            exit,
+            server_task,
+            max_streams_per_ms: result.max_streams_per_ms,
+            connection_limits: result.connection_limits,
+            connection_control: result.connection_control,
         })
-        .unwrap();
+    }
+}
 
-    let updater = EndpointKeyUpdater {
-        endpoint: result.endpoint.clone(),
-        gossip_host,
-        max_concurrent_connections: result.max_concurrent_connections,
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_server<S: PacketSink>(
+    name: &'static str,
+    sock: UdpSocket,
+    keypair: &Keypair,
+    gossip_host: IpAddr,
+    packet_sender: S,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: Arc<RwLock<StakedNodes>>,
+    max_staked_connections: usize,
+    max_unstaked_connections: usize,
+    max_streams_per_ms: u64,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnServerResult, QuicServerError> {
+    QuicServerBuilder::new(name, sock, keypair, gossip_host, packet_sender, exit)
+        .max_connections_per_peer(max_connections_per_peer)
+        .staked_nodes(staked_nodes)
+        .max_staked_connections(max_staked_connections)
+        .max_unstaked_connections(max_unstaked_connections)
+        .max_streams_per_ms(max_streams_per_ms)
+        .wait_for_chunk_timeout(wait_for_chunk_timeout)
+        .coalesce(coalesce)
+        .quic_server_config(quic_server_config)
+        .build_and_spawn()
+}
+
+/// Same as [`spawn_server`], but runs the server on the runtime behind `runtime` instead of
+/// creating a dedicated one.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_server_on_runtime<S: PacketSink>(
+    runtime: Handle,
+    name: &'static str,
+    sock: UdpSocket,
+    keypair: &Keypair,
+    gossip_host: IpAddr,
+    packet_sender: S,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: Arc<RwLock<StakedNodes>>,
+    max_staked_connections: usize,
+    max_unstaked_connections: usize,
+    max_streams_per_ms: u64,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnServerResult, QuicServerError> {
+    QuicServerBuilder::new(name, sock, keypair, gossip_host, packet_sender, exit)
+        .max_connections_per_peer(max_connections_per_peer)
+        .staked_nodes(staked_nodes)
+        .max_staked_connections(max_staked_connections)
+        .max_unstaked_connections(max_unstaked_connections)
+        .max_streams_per_ms(max_streams_per_ms)
+        .wait_for_chunk_timeout(wait_for_chunk_timeout)
+        .coalesce(coalesce)
+        .quic_server_config(quic_server_config)
+        .runtime(runtime)
+        .build_and_spawn()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_server_multi<S: PacketSink>(
+    name: &'static str,
+    sockets: Vec<UdpSocket>,
+    keypair: &Keypair,
+    gossip_host: IpAddr,
+    packet_sender: S,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: Arc<RwLock<StakedNodes>>,
+    max_staked_connections: usize,
+    max_unstaked_connections: usize,
+    max_streams_per_ms: u64,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnServerResult, QuicServerError> {
+    QuicServerBuilder::new_multi(name, sockets, keypair, gossip_host, packet_sender, exit)
+        .max_connections_per_peer(max_connections_per_peer)
+        .staked_nodes(staked_nodes)
+        .max_staked_connections(max_staked_connections)
+        .max_unstaked_connections(max_unstaked_connections)
+        .max_streams_per_ms(max_streams_per_ms)
+        .wait_for_chunk_timeout(wait_for_chunk_timeout)
+        .coalesce(coalesce)
+        .quic_server_config(quic_server_config)
+        .build_and_spawn()
+}
+
+/// Spawns a server presenting `tls_config` instead of a self-signed certificate derived from a
+/// keypair. The transport is tuned the same way as for [`spawn_server`].
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_server_with_tls<S: PacketSink>(
+    name: &'static str,
+    sock: UdpSocket,
+    tls_config: Arc<rustls::ServerConfig>,
+    packet_sender: S,
+    exit: Arc<AtomicBool>,
+    max_connections_per_peer: usize,
+    staked_nodes: Arc<RwLock<StakedNodes>>,
+    max_staked_connections: usize,
+    max_unstaked_connections: usize,
+    max_streams_per_ms: u64,
+    wait_for_chunk_timeout: Duration,
+    coalesce: Duration,
+    quic_server_config: QuicServerConfig,
+) -> Result<SpawnServerResult, QuicServerError> {
+    QuicServerBuilder::new_with_tls(name, vec![sock], tls_config, packet_sender, exit)
+        .max_connections_per_peer(max_connections_per_peer)
+        .staked_nodes(staked_nodes)
+        .max_staked_connections(max_staked_connections)
+        .max_unstaked_connections(max_unstaked_connections)
+        .max_streams_per_ms(max_streams_per_ms)
+        .wait_for_chunk_timeout(wait_for_chunk_timeout)
+        .coalesce(coalesce)
+        .quic_server_config(quic_server_config)
+        .build_and_spawn()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+
+    use assert_matches::assert_matches;
+    use crossbeam_channel::unbounded;
+    use solana_sdk::signer::Signer;
+
+    use super::*;
+    use crate::nonblocking::quic::get_remote_pubkey;
+    use crate::nonblocking::quic::test::make_client_connection;
+    use crate::packet::PacketBatch;
+    use crate::packet_sink::SinkError;
+    use crate::test_utils::spawn_test_server;
+    use crate::tls_certificates::{
+        get_pubkey_from_tls_certificate, new_self_signed_tls_certificate,
     };
 
-    Ok(SpawnServerResult {
-        endpoint: result.endpoint,
-        thread: handle,
-        key_updater: Arc::new(updater),
-    })
+    #[derive(Default)]
+    struct RecordingMetricsSink {
+        reports: Mutex<Vec<StreamStatsSnapshot>>,
+    }
+
+    impl MetricsSink for RecordingMetricsSink {
+        fn record(&self, _name: &'static str, stats: &StreamStatsSnapshot) {
+            self.reports.lock().unwrap().push(stats.clone());
+        }
+    }
+
+    #[test]
+    fn test_report_to_resets_counters_only() {
+        let stats = StreamStats::default();
+        stats.total_connections.set(3);
+        stats.total_new_connections.add(5);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total_connections, 3);
+        assert_eq!(snapshot.total_new_connections, 5);
+        assert_eq!(stats.new_connections(), 5);
+
+        let sink = RecordingMetricsSink::default();
+        stats.report_to("test", &sink);
+        stats.report_to("test", &sink);
+        let reports = sink.reports.lock().unwrap();
+        assert_eq!(reports[0], snapshot);
+        assert_eq!(reports[1].total_connections, 3);
+        assert_eq!(reports[1].total_new_connections, 0);
+        assert_eq!(stats.active_connections(), 3);
+        assert_eq!(stats.new_connections(), 0);
+    }
+
+    #[test]
+    fn test_reset() {
+        let stats = StreamStats::default();
+        stats.total_connections.set(3);
+        stats.total_new_connections.add(5);
+        stats.stream_load_ema.set(7);
+        stats.stream_load_ema_overflow.add(1);
+        stats.stream_bytes.record(1232);
+
+        stats.reset();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total_connections, 3);
+        assert_eq!(snapshot.stream_load_ema, 7);
+        assert_eq!(snapshot.total_new_connections, 0);
+        assert_eq!(snapshot.stream_load_ema_overflow, 0);
+        assert_eq!(snapshot.stream_bytes, HistogramSnapshot::default());
+    }
+
+    #[test]
+    fn test_validate_receive_windows() {
+        let config = |receive_window_base, stream_receive_window| QuicServerConfig {
+            receive_window_base,
+            stream_receive_window,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(Some(4_096), None).validate(), Ok(()));
+        assert_matches!(
+            config(Some(4_096), Some(PACKET_DATA_SIZE as u32)).validate(),
+            Ok(())
+        );
+        assert_matches!(
+            config(Some(PACKET_DATA_SIZE as u32 - 1), None).validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+        assert_eq!(
+            config(None, None).receive_window_base(),
+            PACKET_DATA_SIZE as u32
+        );
+    }
+
+    #[test]
+    fn test_validate_stream_load_ema() {
+        assert!(QuicServerConfig::default().validate().is_ok());
+        for stream_load_ema in [
+            StreamLoadEmaConfig {
+                alpha: 0.0,
+                ..StreamLoadEmaConfig::default()
+            },
+            StreamLoadEmaConfig {
+                alpha: f64::NAN,
+                ..StreamLoadEmaConfig::default()
+            },
+            StreamLoadEmaConfig {
+                max_unstaked_streams_percent: 101,
+                ..StreamLoadEmaConfig::default()
+            },
+            StreamLoadEmaConfig {
+                min_load_percent: 0,
+                ..StreamLoadEmaConfig::default()
+            },
+            StreamLoadEmaConfig {
+                backpressure: Some(BackpressureThrottleConfig {
+                    start_percent: 100,
+                    ..BackpressureThrottleConfig::default()
+                }),
+                ..StreamLoadEmaConfig::default()
+            },
+            StreamLoadEmaConfig {
+                backpressure: Some(BackpressureThrottleConfig {
+                    min_scale_percent: 0,
+                    ..BackpressureThrottleConfig::default()
+                }),
+                ..StreamLoadEmaConfig::default()
+            },
+        ] {
+            let config = QuicServerConfig {
+                stream_load_ema,
+                ..QuicServerConfig::default()
+            };
+            assert_matches!(config.validate(), Err(QuicServerError::InvalidConfig(_)));
+        }
+    }
+
+    #[test]
+    fn test_validate_keep_alive_interval() {
+        let config = |keep_alive_interval, max_idle_timeout| QuicServerConfig {
+            keep_alive_interval,
+            max_idle_timeout,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(None, None).validate(), Ok(()));
+        assert_matches!(
+            config(Some(Duration::from_secs(1)), None).validate(),
+            Ok(())
+        );
+        for (keep_alive_interval, max_idle_timeout) in [
+            (Duration::ZERO, None),
+            (QUIC_MAX_TIMEOUT, None),
+            (Duration::from_secs(1), Some(Duration::from_millis(500))),
+        ] {
+            assert_matches!(
+                config(Some(keep_alive_interval), max_idle_timeout).validate(),
+                Err(QuicServerError::InvalidConfig(_))
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_connection_setup_timeout() {
+        let config = |connection_setup_timeout, max_idle_timeout| QuicServerConfig {
+            connection_setup_timeout,
+            max_idle_timeout,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(QUIC_MAX_TIMEOUT, None).validate(), Ok(()));
+        assert_matches!(
+            config(Duration::from_secs(5), Some(Duration::from_secs(1))).validate(),
+            Ok(())
+        );
+        // Tightened along with the idle timeout.
+        assert_matches!(
+            config(Duration::from_millis(500), Some(Duration::from_millis(500))).validate(),
+            Ok(())
+        );
+        for (connection_setup_timeout, max_idle_timeout) in [
+            (Duration::ZERO, None),
+            (Duration::from_secs(1), None),
+            (Duration::from_millis(500), Some(Duration::from_secs(1))),
+        ] {
+            assert_matches!(
+                config(connection_setup_timeout, max_idle_timeout).validate(),
+                Err(QuicServerError::InvalidConfig(_))
+            );
+        }
+    }
+
+    struct NoopBidiStreamHandler;
+
+    impl BidiStreamHandler for NoopBidiStreamHandler {
+        fn on_stream(
+            &self,
+            _remote_addr: SocketAddr,
+            _pubkey: Option<Pubkey>,
+            _stake: u64,
+            _send_stream: SendStream,
+            _recv_stream: RecvStream,
+        ) {
+        }
+    }
+
+    #[test]
+    fn test_validate_bidi_streams() {
+        let config = |max_concurrent_bidi_streams, handler: bool| QuicServerConfig {
+            max_concurrent_bidi_streams,
+            bidi_stream_handler: handler
+                .then(|| Arc::new(NoopBidiStreamHandler) as Arc<dyn BidiStreamHandler>),
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(0, false).validate(), Ok(()));
+        assert_matches!(config(4, true).validate(), Ok(()));
+        assert_matches!(
+            config(4, false).validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+        assert_matches!(
+            config(0, true).validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+    }
+
+    #[test]
+    fn test_quic_server_config_debug() {
+        let config = QuicServerConfig {
+            bidi_stream_handler: Some(Arc::new(NoopBidiStreamHandler)),
+            sni_identities: HashMap::from([("tpu.example".to_string(), Arc::new(Keypair::new()))]),
+            ..QuicServerConfig::default()
+        };
+        let debug = format!("{config:?}");
+        assert!(debug.contains(r#"bidi_stream_handler: Some("dyn BidiStreamHandler")"#));
+        assert!(debug.contains(r#"sni_identities: ["tpu.example"]"#));
+        assert!(debug.contains("metrics_sink: None"));
+    }
+
+    #[test]
+    fn test_validate_connection_bandwidth() {
+        let config = |staked, unstaked| QuicServerConfig {
+            max_staked_connection_bandwidth: staked,
+            max_unstaked_connection_bandwidth: unstaked,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(None, Some(1_000)).validate(), Ok(()));
+        assert_matches!(
+            config(Some(0), None).validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+        assert_matches!(
+            config(None, Some(0)).validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+        assert_matches!(
+            QuicServerConfig {
+                max_bandwidth: Some(0),
+                ..QuicServerConfig::default()
+            }
+            .validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+        assert_matches!(
+            QuicServerConfig {
+                staked_bandwidth_reserve_percent: 101,
+                ..QuicServerConfig::default()
+            }
+            .validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+    }
+
+    #[test]
+    fn test_validate_max_chunks_per_stream() {
+        let config = |unstaked, staked| QuicServerConfig {
+            max_chunks_per_stream: unstaked,
+            max_staked_chunks_per_stream: staked,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(Some(1), Some(100)).validate(), Ok(()));
+        assert_matches!(
+            config(Some(0), None).validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+        assert_matches!(
+            config(None, Some(0)).validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+    }
+
+    #[test]
+    fn test_validate_max_stream_timeouts_before_close() {
+        let config = |max_stream_timeouts_before_close| QuicServerConfig {
+            max_stream_timeouts_before_close,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(Some(1)).validate(), Ok(()));
+        assert_matches!(
+            config(Some(0)).validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+        assert_matches!(
+            QuicServerConfig {
+                stream_idle_timeout: Some(Duration::ZERO),
+                ..QuicServerConfig::default()
+            }
+            .validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+    }
+
+    #[test]
+    fn test_validate_retry_token_lifetime() {
+        let config = |retry_token_lifetime| QuicServerConfig {
+            retry_token_lifetime,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(Some(Duration::from_secs(60))).validate(), Ok(()));
+        assert_matches!(
+            config(Some(Duration::ZERO)).validate(),
+            Err(QuicServerError::InvalidConfig(_))
+        );
+    }
+
+    #[test]
+    fn test_validate_unstaked_connection_rate_limit() {
+        let config = |connections_per_second, burst| QuicServerConfig {
+            unstaked_connection_rate_limit: Some(ConnectionRateLimit {
+                connections_per_second,
+                burst,
+            }),
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(0.5, 1).validate(), Ok(()));
+        for (connections_per_second, burst) in [
+            (8.0, 0),
+            (0.0, 8),
+            (-1.0, 8),
+            (f64::NAN, 8),
+            (f64::INFINITY, 8),
+        ] {
+            assert_matches!(
+                config(connections_per_second, burst).validate(),
+                Err(QuicServerError::InvalidConfig(_))
+            );
+        }
+    }
+
+    #[test]
+    fn test_socket_rebind() {
+        solana_logger::setup();
+        let config = |max_attempts| QuicServerConfig {
+            socket_rebind: Some(SocketRebindConfig {
+                max_attempts,
+                ..SocketRebindConfig::default()
+            }),
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(0).validate(), Err(QuicServerError::InvalidConfig(_)));
+
+        let (server, receiver) = spawn_test_server(Arc::default(), config(1));
+        assert!(server.socket_rebind_enabled());
+        let client_runtime = rt(&RuntimeConfig::default());
+        client_runtime.block_on(async {
+            let connection = make_client_connection(&server.local_addr, None).await;
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        });
+        let packet_batch = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(packet_batch[0].meta().size, 10);
+        server.abort();
+        server.join_timeout(Duration::from_secs(5)).unwrap();
+
+        let (server, _receiver) = spawn_test_server(Arc::default(), QuicServerConfig::default());
+        assert!(!server.socket_rebind_enabled());
+        server.abort();
+    }
+
+    #[test]
+    fn test_validate_alpn_protocols() {
+        let config = |alpn_protocols| QuicServerConfig {
+            alpn_protocols,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(QuicServerConfig::default().validate(), Ok(()));
+        assert_matches!(
+            config(vec![b"v2".to_vec(), ALPN_TPU_PROTOCOL_ID.to_vec()]).validate(),
+            Ok(())
+        );
+        for alpn_protocols in [vec![], vec![Vec::new()]] {
+            assert_matches!(
+                config(alpn_protocols).validate(),
+                Err(QuicServerError::InvalidConfig(_))
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_tls_cipher_suites() {
+        let config = |tls_cipher_suites, tls_kx_groups| QuicServerConfig {
+            tls_cipher_suites,
+            tls_kx_groups,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(None, None).validate(), Ok(()));
+        assert_matches!(
+            config(
+                Some(vec![
+                    rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+                    rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                ]),
+                Some(vec![&rustls::kx_group::SECP384R1]),
+            )
+            .validate(),
+            Ok(())
+        );
+        for (tls_cipher_suites, tls_kx_groups) in [
+            (
+                Some(vec![
+                    rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                ]),
+                None,
+            ),
+            (Some(vec![]), None),
+            (None, Some(vec![])),
+        ] {
+            assert_matches!(
+                config(tls_cipher_suites, tls_kx_groups).validate(),
+                Err(QuicServerError::InvalidConfig(_))
+            );
+        }
+    }
+
+    #[test]
+    fn test_endpoint_failed() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8009);
+        let error = |kind| std::io::Error::new(kind, "test");
+        assert_matches!(
+            QuicServerError::endpoint_failed(Some(addr), error(std::io::ErrorKind::AddrInUse)),
+            QuicServerError::AddrInUse { addr: a, source } if a == addr
+                && source.kind() == std::io::ErrorKind::AddrInUse
+        );
+        assert_matches!(
+            QuicServerError::endpoint_failed(
+                Some(addr),
+                error(std::io::ErrorKind::PermissionDenied)
+            ),
+            QuicServerError::PermissionDenied { addr: a, .. } if a == addr
+        );
+        assert_matches!(
+            QuicServerError::endpoint_failed(Some(addr), error(std::io::ErrorKind::Other)),
+            QuicServerError::EndpointFailed(_)
+        );
+        assert_matches!(
+            QuicServerError::endpoint_failed(None, error(std::io::ErrorKind::AddrInUse)),
+            QuicServerError::EndpointFailed(_)
+        );
+    }
+
+    #[test]
+    fn test_validate_datagrams() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let config = |datagram_receive_buffer_size, datagram_sender| QuicServerConfig {
+            datagram_receive_buffer_size,
+            datagram_sender,
+            ..QuicServerConfig::default()
+        };
+        assert_matches!(config(None, None).validate(), Ok(()));
+        assert_matches!(config(Some(1024), Some(sender.clone())).validate(), Ok(()));
+        for (datagram_receive_buffer_size, datagram_sender) in [
+            (Some(1024), None),
+            (None, Some(sender.clone())),
+            (Some(0), Some(sender)),
+        ] {
+            assert_matches!(
+                config(datagram_receive_buffer_size, datagram_sender).validate(),
+                Err(QuicServerError::InvalidConfig(_))
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_ip_allowed() {
+        let config = QuicServerConfig::default();
+        assert!(config.is_ip_allowed(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+
+        let config = QuicServerConfig {
+            allowed_ips: Some(vec!["10.0.0.0/8".parse().unwrap()]),
+            denied_ips: vec!["10.1.0.0/16".parse().unwrap()],
+            ..QuicServerConfig::default()
+        };
+        assert!(config.is_ip_allowed(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert!(config.is_ip_allowed("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!config.is_ip_allowed(IpAddr::V4(Ipv4Addr::new(10, 1, 0, 1))));
+        assert!(!config.is_ip_allowed(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1))));
+
+        let config = QuicServerConfig {
+            denied_ips: vec!["192.168.0.0/24".parse().unwrap()],
+            ..QuicServerConfig::default()
+        };
+        assert!(config.is_ip_allowed(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert!(!config.is_ip_allowed(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 7))));
+    }
+
+    #[test]
+    fn test_spawn_server_local_addr() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let keypair = Keypair::new();
+        let server = QuicServerBuilder::new(
+            "quic_streamer_test",
+            sock,
+            &keypair,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+        )
+        .build_and_spawn()
+        .unwrap();
+        assert_eq!(server.local_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_ne!(server.local_addr.port(), 0);
+        exit.store(true, Ordering::Relaxed);
+        server.thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_spawn_servers_on_shared_runtime() {
+        solana_logger::setup();
+        let runtime = rt(&RuntimeConfig::default());
+        let exit = Arc::new(AtomicBool::new(false));
+        let keypair = Keypair::new();
+        let servers: Vec<_> = (0..2)
+            .map(|_| {
+                let (sender, _receiver) = unbounded();
+                spawn_server_on_runtime(
+                    runtime.handle().clone(),
+                    "quic_streamer_test",
+                    UdpSocket::bind("127.0.0.1:0").unwrap(),
+                    &keypair,
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    sender,
+                    exit.clone(),
+                    DEFAULT_MAX_CONNECTIONS_PER_PEER,
+                    Arc::default(),
+                    MAX_STAKED_CONNECTIONS,
+                    MAX_UNSTAKED_CONNECTIONS,
+                    DEFAULT_MAX_STREAMS_PER_MS,
+                    DEFAULT_WAIT_FOR_CHUNK_TIMEOUT,
+                    DEFAULT_TPU_COALESCE,
+                    QuicServerConfig::default(),
+                )
+                .unwrap()
+            })
+            .collect();
+        assert_ne!(servers[0].local_addr, servers[1].local_addr);
+
+        let connection = runtime.block_on(make_client_connection(&servers[1].local_addr, None));
+        drop(connection);
+
+        exit.store(true, Ordering::Relaxed);
+        for server in servers {
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_runtime_config() {
+        solana_logger::setup();
+        let keypair = Keypair::new();
+        let spawn = |runtime_config| {
+            let (sender, _receiver) = unbounded();
+            QuicServerBuilder::new(
+                "quic_streamer_test",
+                UdpSocket::bind("127.0.0.1:0").unwrap(),
+                &keypair,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                sender,
+                Arc::new(AtomicBool::new(true)),
+            )
+            .quic_server_config(QuicServerConfig {
+                runtime_config,
+                ..QuicServerConfig::default()
+            })
+            .build_and_spawn()
+        };
+
+        let server = spawn(RuntimeConfig {
+            worker_threads: Some(1),
+            thread_name: "solQuicTest".to_string(),
+        })
+        .unwrap();
+        server.join().unwrap();
+
+        let result = spawn(RuntimeConfig {
+            worker_threads: Some(0),
+            ..RuntimeConfig::default()
+        });
+        assert!(matches!(result, Err(QuicServerError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_report_interval() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let keypair = Keypair::new();
+        let sink = Arc::new(RecordingMetricsSink::default());
+        let server = QuicServerBuilder::new(
+            "quic_streamer_test",
+            sock,
+            &keypair,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+        )
+        .quic_server_config(QuicServerConfig {
+            report_interval: Some(Duration::from_millis(50)),
+            metrics_sink: Some(sink.clone()),
+            ..QuicServerConfig::default()
+        })
+        .build_and_spawn()
+        .unwrap();
+
+        thread::sleep(Duration::from_millis(500));
+        exit.store(true, Ordering::Relaxed);
+        server.join().unwrap();
+        assert!(sink.reports.lock().unwrap().len() >= 2);
+    }
+
+    #[derive(Default)]
+    struct CountingSink {
+        packets: AtomicUsize,
+    }
+
+    impl PacketSink for CountingSink {
+        fn send_batch(&self, batch: PacketBatch) -> Result<(), SinkError> {
+            self.packets.fetch_add(batch.len(), Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_packet_sink() {
+        solana_logger::setup();
+        let exit = Arc::new(AtomicBool::new(false));
+        let keypair = Keypair::new();
+        let sink = Arc::new(CountingSink::default());
+        let server = QuicServerBuilder::new(
+            "quic_streamer_test",
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            &keypair,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sink.clone(),
+            exit.clone(),
+        )
+        .build_and_spawn()
+        .unwrap();
+
+        let client_runtime = rt(&RuntimeConfig::default());
+        client_runtime.block_on(async {
+            let connection = make_client_connection(&server.local_addr, None).await;
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        });
+        let start = Instant::now();
+        while sink.packets.load(Ordering::Relaxed) == 0 && start.elapsed() < Duration::from_secs(5)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(sink.packets.load(Ordering::Relaxed), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_configure_server_from_pem() {
+        solana_logger::setup();
+        let keypair = Keypair::new();
+        let (cert, priv_key) =
+            new_self_signed_tls_certificate(&keypair, IpAddr::V4(Ipv4Addr::LOCALHOST)).unwrap();
+        let cert_pem = pem::encode(&Pem {
+            tag: "CERTIFICATE".to_string(),
+            contents: cert.0,
+        });
+        let key_pem = pem::encode(&Pem {
+            tag: "PRIVATE KEY".to_string(),
+            contents: priv_key.0,
+        });
+        let config = QuicServerConfig::default();
+        for (cert_pem, key_pem) in [("", key_pem.as_str()), ("garbage", key_pem.as_str())] {
+            assert_matches!(
+                configure_server_from_pem(cert_pem, key_pem, &config),
+                Err(QuicServerError::CertificateError(
+                    RcgenError::CouldNotParseCertificate
+                ))
+            );
+        }
+        let bad_key_pem = pem::encode(&Pem {
+            tag: "PRIVATE KEY".to_string(),
+            contents: vec![1, 2, 3],
+        });
+        for key_pem in ["", cert_pem.as_str(), bad_key_pem.as_str()] {
+            assert_matches!(
+                configure_server_from_pem(&cert_pem, key_pem, &config),
+                Err(QuicServerError::CertificateError(
+                    RcgenError::CouldNotParseKeyPair
+                ))
+            );
+        }
+
+        let tls_config = configure_server_from_pem(&cert_pem, &key_pem, &config).unwrap();
+        assert_eq!(
+            tls_config.alpn_protocols,
+            vec![ALPN_TPU_PROTOCOL_ID.to_vec()]
+        );
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, _receiver) = unbounded();
+        let server = QuicServerBuilder::new_with_tls(
+            "quic_streamer_test",
+            vec![UdpSocket::bind("127.0.0.1:0").unwrap()],
+            tls_config,
+            sender,
+            exit.clone(),
+        )
+        .build_and_spawn()
+        .unwrap();
+        assert_eq!(server.cert_chain_pem, None);
+        let client_runtime = rt(&RuntimeConfig::default());
+        let server_pubkey = client_runtime.block_on(async {
+            let connection = make_client_connection(&server.local_addr, None).await;
+            get_remote_pubkey(&connection)
+        });
+        assert_eq!(server_pubkey, Some(keypair.pubkey()));
+
+        exit.store(true, Ordering::Relaxed);
+        server.join().unwrap();
+    }
+
+    #[derive(Default)]
+    struct RecordingKeyRotationHandler {
+        rotations: Mutex<Vec<(String, SystemTime)>>,
+    }
+
+    impl KeyRotationHandler for RecordingKeyRotationHandler {
+        fn on_key_rotated(&self, cert_chain_pem: &str, rotated_at: SystemTime) {
+            self.rotations
+                .lock()
+                .unwrap()
+                .push((cert_chain_pem.to_string(), rotated_at));
+        }
+    }
+
+    #[test]
+    fn test_key_rotation_handler() {
+        solana_logger::setup();
+        let exit = Arc::new(AtomicBool::new(false));
+        let keypair = Keypair::new();
+        let handler = Arc::new(RecordingKeyRotationHandler::default());
+        let (sender, _receiver) = unbounded();
+        let server = QuicServerBuilder::new(
+            "quic_streamer_test",
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            &keypair,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+        )
+        .quic_server_config(QuicServerConfig {
+            key_rotation_handler: Some(handler.clone()),
+            ..QuicServerConfig::default()
+        })
+        .build_and_spawn()
+        .unwrap();
+        assert!(handler.rotations.lock().unwrap().is_empty());
+        let pem_pubkeys = |cert_chain_pem: &str| -> Vec<_> {
+            pem::parse_many(cert_chain_pem)
+                .unwrap()
+                .into_iter()
+                .map(|pem| get_pubkey_from_tls_certificate(&Certificate(pem.contents)))
+                .collect()
+        };
+        assert_eq!(
+            pem_pubkeys(server.cert_chain_pem.as_ref().unwrap()),
+            vec![Some(keypair.pubkey())]
+        );
+
+        let new_keypair = Keypair::new();
+        let before = SystemTime::now();
+        server.key_updater.update_key(&new_keypair).unwrap();
+        let rotations = handler.rotations.lock().unwrap().clone();
+        assert_eq!(rotations.len(), 1);
+        let (cert_chain_pem, rotated_at) = &rotations[0];
+        assert!(*rotated_at >= before);
+        assert_eq!(
+            pem_pubkeys(cert_chain_pem),
+            vec![Some(new_keypair.pubkey())]
+        );
+
+        let client_runtime = rt(&RuntimeConfig::default());
+        let server_pubkey = client_runtime.block_on(async {
+            let connection = make_client_connection(&server.local_addr, None).await;
+            get_remote_pubkey(&connection)
+        });
+        assert_eq!(server_pubkey, Some(new_keypair.pubkey()));
+
+        exit.store(true, Ordering::Relaxed);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_swappable_staked_nodes() {
+        solana_logger::setup();
+        let exit = Arc::new(AtomicBool::new(false));
+        let keypair = Keypair::new();
+        let client_keypair = Keypair::new();
+        let (sender, _receiver) = unbounded();
+        let staked_nodes = Arc::new(ArcSwap::from_pointee(StakedNodes::default()));
+        let server = QuicServerBuilder::new(
+            "quic_streamer_test",
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            &keypair,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit.clone(),
+        )
+        .max_connections_per_peer(2)
+        .swappable_staked_nodes(staked_nodes.clone())
+        .build_and_spawn()
+        .unwrap();
+
+        let wait_for = |counter: &Counter| {
+            let start = Instant::now();
+            while counter.get() == 0 && start.elapsed() < Duration::from_secs(5) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            counter.get()
+        };
+        let client_runtime = rt(&RuntimeConfig::default());
+        let _unstaked_connection = client_runtime.block_on(make_client_connection(
+            &server.local_addr,
+            Some(&client_keypair),
+        ));
+        assert_eq!(
+            wait_for(&server.stats.connection_added_from_unstaked_peer),
+            1
+        );
+
+        staked_nodes.store(Arc::new(StakedNodes::new(
+            Arc::new(HashMap::from([(client_keypair.pubkey(), 1_000)])),
+            HashMap::default(),
+        )));
+        let _staked_connection = client_runtime.block_on(make_client_connection(
+            &server.local_addr,
+            Some(&client_keypair),
+        ));
+        assert_eq!(wait_for(&server.stats.connection_added_from_staked_peer), 1);
+
+        exit.store(true, Ordering::Relaxed);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_drains_streams() {
+        solana_logger::setup();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = unbounded();
+        let keypair = Keypair::new();
+        let server = QuicServerBuilder::new(
+            "quic_streamer_test",
+            sock,
+            &keypair,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            sender,
+            exit,
+        )
+        .build_and_spawn()
+        .unwrap();
+
+        let client_runtime = rt(&RuntimeConfig::default());
+        let connection = client_runtime.block_on(make_client_connection(&server.local_addr, None));
+        let mut finished_stream = client_runtime.block_on(connection.open_uni()).unwrap();
+        let mut unfinished_stream = client_runtime.block_on(connection.open_uni()).unwrap();
+        client_runtime.block_on(async {
+            finished_stream.write_all(&[1u8; 10]).await.unwrap();
+            finished_stream.finish().await.unwrap();
+            unfinished_stream.write_all(&[2u8; 10]).await.unwrap();
+        });
+        let start = Instant::now();
+        while server.stats.active_streams() < 1 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let summary = server.shutdown(Duration::from_millis(200)).unwrap();
+        assert_eq!(summary.outstanding_streams, 1);
+        let packets: usize = receiver.try_iter().map(|batch| batch.len()).sum();
+        assert_eq!(packets, 1);
+        assert_eq!(summary.stats.total_packets_sent_to_consumer, 1);
+    }
+
+    #[test]
+    fn test_abort() {
+        solana_logger::setup();
+        let (server, _receiver) = spawn_test_server(Arc::default(), QuicServerConfig::default());
+        let client_runtime = rt(&RuntimeConfig::default());
+        let connection = client_runtime.block_on(make_client_connection(&server.local_addr, None));
+        let mut unfinished_stream = client_runtime.block_on(connection.open_uni()).unwrap();
+        client_runtime
+            .block_on(unfinished_stream.write_all(&[1u8; 10]))
+            .unwrap();
+
+        server.abort();
+        server.join_timeout(Duration::from_secs(5)).unwrap();
+        client_runtime.block_on(connection.closed());
+    }
+
+    #[test]
+    fn test_is_healthy() {
+        solana_logger::setup();
+        let (server, receiver) = spawn_test_server(Arc::default(), QuicServerConfig::default());
+        assert!(server.is_healthy());
+        assert_eq!(server.last_packet_received(), None);
+
+        let before_packet = Instant::now();
+        let client_runtime = rt(&RuntimeConfig::default());
+        client_runtime.block_on(async {
+            let connection = make_client_connection(&server.local_addr, None).await;
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        });
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        let last_packet_received = server.last_packet_received().unwrap();
+        assert!(last_packet_received >= before_packet);
+        assert!(last_packet_received <= Instant::now());
+        assert!(server.is_healthy());
+
+        server.exit.store(true, Ordering::Relaxed);
+        assert!(!server.is_healthy());
+        server.join_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_thread_name() {
+        let (server, _receiver) = spawn_test_server(Arc::default(), QuicServerConfig::default());
+        assert_eq!(
+            server.thread.thread().name(),
+            Some(DEFAULT_SERVER_THREAD_NAME)
+        );
+        server.abort();
+
+        let keypair = Keypair::new();
+        let builder = || {
+            QuicServerBuilder::new(
+                "quic_streamer_test",
+                UdpSocket::bind("127.0.0.1:0").unwrap(),
+                &keypair,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                unbounded().0,
+                Arc::default(),
+            )
+        };
+        let server = builder()
+            .thread_name("solQuicTpuFwd")
+            .build_and_spawn()
+            .unwrap();
+        assert_eq!(server.thread.thread().name(), Some("solQuicTpuFwd"));
+        server.abort();
+        assert_matches!(
+            builder().thread_name("sol\0Quic").build_and_spawn().err(),
+            Some(QuicServerError::InvalidConfig(_))
+        );
+    }
+
+    #[test]
+    fn test_join_timeout() {
+        solana_logger::setup();
+        let (server, _receiver) = spawn_test_server(Arc::default(), QuicServerConfig::default());
+        let exit = server.exit.clone();
+        let result = server.join_timeout(Duration::from_millis(100));
+        assert_matches!(result, Err(JoinTimeout(timeout)) if timeout == Duration::from_millis(100));
+        assert!(exit.load(Ordering::Relaxed));
+
+        let (server, _receiver) = spawn_test_server(Arc::default(), QuicServerConfig::default());
+        server.exit.store(true, Ordering::Relaxed);
+        server.join_timeout(Duration::from_secs(5)).unwrap();
+    }
 }
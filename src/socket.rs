@@ -1,4 +1,17 @@
-use std::net::{IpAddr, SocketAddr};
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::quic::QuicServerError;
+
+/// Receive buffer size recommended for TPU servers, matching the `net.core.rmem_max` that
+/// validators are advised to configure.
+pub const RECOMMENDED_SERVER_RECV_BUFFER_SIZE: usize = 128 * 1024 * 1024;
+
+/// Send buffer size recommended for TPU servers. QUIC servers mostly send acknowledgements and
+/// handshakes, so this is sized for the bursts of new connections rather than the traffic.
+pub const RECOMMENDED_SERVER_SEND_BUFFER_SIZE: usize = 16 * 1024 * 1024;
 
 #[derive(Clone, Copy)]
 pub enum SocketAddrSpace {
@@ -37,3 +50,179 @@ impl SocketAddrSpace {
         }
     }
 }
+
+/// Binds a UDP socket for a QUIC server to `addr` with the given buffer sizes, in bytes, e.g.
+/// [`RECOMMENDED_SERVER_RECV_BUFFER_SIZE`] and [`RECOMMENDED_SERVER_SEND_BUFFER_SIZE`].
+///
+/// The default buffers of most kernels are far too small for the packet rates of a TPU, and
+/// packets that arrive while the receive buffer is full are dropped by the kernel before
+/// quinn sees them. The kernel caps the sizes at its limits, on Linux `net.core.rmem_max` and
+/// `net.core.wmem_max`, which need to be raised to at least the recommended sizes.
+///
+/// IPv6 sockets are made dual-stack, so that binding to `[::]` accepts IPv4 peers too.
+///
+/// Errors are classified like those of the endpoints, e.g. [`QuicServerError::AddrInUse`] when
+/// another socket holds `addr`.
+pub fn bind_server_socket(
+    addr: SocketAddr,
+    recv_buf_size: usize,
+    send_buf_size: usize,
+) -> Result<UdpSocket, QuicServerError> {
+    bind_server_socket_with(
+        addr,
+        ServerSocketOptions {
+            recv_buf_size,
+            send_buf_size,
+            only_v6: false,
+            reuse_port: false,
+        },
+    )
+    .map_err(|err| QuicServerError::endpoint_failed(Some(addr), err))
+}
+
+/// Options of a server socket, read from a bound socket so that another one can be bound with
+/// the same, see [`bind_server_socket_with`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ServerSocketOptions {
+    // In bytes, as passed to `setsockopt`
+    pub(crate) recv_buf_size: usize,
+    pub(crate) send_buf_size: usize,
+    // Ignored for IPv4 sockets
+    pub(crate) only_v6: bool,
+    // SO_REUSEPORT, which the sockets of a multi-socket server share their port with. Ignored
+    // where it isn't supported.
+    pub(crate) reuse_port: bool,
+}
+
+impl ServerSocketOptions {
+    pub(crate) fn of(socket: &UdpSocket) -> io::Result<Self> {
+        let socket = SockRef::from(socket);
+        Ok(Self {
+            recv_buf_size: requested_buffer_size(socket.recv_buffer_size()?),
+            send_buf_size: requested_buffer_size(socket.send_buffer_size()?),
+            only_v6: socket.local_addr()?.is_ipv6() && socket.only_v6()?,
+            reuse_port: reuse_port(&socket)?,
+        })
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn reuse_port(socket: &Socket) -> io::Result<bool> {
+    socket.reuse_port()
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn reuse_port(_socket: &Socket) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket, reuse_port: bool) -> io::Result<()> {
+    socket.set_reuse_port(reuse_port)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket, _reuse_port: bool) -> io::Result<()> {
+    Ok(())
+}
+
+// Linux doubles the buffer sizes it's given to make room for its bookkeeping, and reports the
+// doubled sizes back.
+fn requested_buffer_size(reported: usize) -> usize {
+    if cfg!(target_os = "linux") {
+        reported / 2
+    } else {
+        reported
+    }
+}
+
+pub(crate) fn bind_server_socket_with(
+    addr: SocketAddr,
+    options: ServerSocketOptions,
+) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_recv_buffer_size(options.recv_buf_size)?;
+    socket.set_send_buffer_size(options.send_buf_size)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(options.only_v6)?;
+    }
+    set_reuse_port(&socket, options.reuse_port)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    #[test]
+    fn test_bind_server_socket() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let socket = bind_server_socket(addr, 1 << 20, 1 << 20).unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        assert_ne!(local_addr.port(), 0);
+        assert!(SockRef::from(&socket).recv_buffer_size().unwrap() > 0);
+
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+        let socket = bind_server_socket(addr, 1 << 20, 1 << 20).unwrap();
+        assert!(!SockRef::from(&socket).only_v6().unwrap());
+
+        assert_matches!(
+            bind_server_socket(local_addr, 1 << 20, 1 << 20),
+            Err(QuicServerError::AddrInUse { addr, source }) if addr == local_addr
+                && source.kind() == io::ErrorKind::AddrInUse
+        );
+    }
+
+    #[test]
+    fn test_server_socket_options() {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+        let socket = bind_server_socket(addr, 1 << 20, 1 << 19).unwrap();
+        let options = ServerSocketOptions::of(&socket).unwrap();
+        assert!(!options.only_v6);
+        assert!(!options.reuse_port);
+
+        // A socket bound with the options of another ends up with the same.
+        let addr = socket.local_addr().unwrap();
+        drop(socket);
+        let socket = bind_server_socket_with(addr, options).unwrap();
+        assert_eq!(ServerSocketOptions::of(&socket).unwrap(), options);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_server_socket_options_reuse_port() {
+        let options = ServerSocketOptions {
+            recv_buf_size: 1 << 20,
+            send_buf_size: 1 << 19,
+            only_v6: false,
+            reuse_port: true,
+        };
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let socket = bind_server_socket_with(addr, options).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let _other = bind_server_socket_with(addr, options).unwrap();
+        assert_eq!(ServerSocketOptions::of(&socket).unwrap(), options);
+
+        // Another socket of the port is rebound while the others hold on to it.
+        drop(socket);
+        let socket = bind_server_socket_with(addr, options).unwrap();
+        assert_eq!(ServerSocketOptions::of(&socket).unwrap(), options);
+        assert_eq!(
+            bind_server_socket_with(
+                addr,
+                ServerSocketOptions {
+                    reuse_port: false,
+                    ..options
+                }
+            )
+            .unwrap_err()
+            .kind(),
+            io::ErrorKind::AddrInUse
+        );
+    }
+}
@@ -97,6 +97,7 @@ impl StreamerReceiveStats {
 
 pub type Result<T> = std::result::Result<T, StreamerError>;
 
+#[allow(clippy::too_many_arguments)]
 fn recv_loop(
     socket: &UdpSocket,
     exit: &AtomicBool,
@@ -315,6 +316,15 @@ impl StakedNodes {
         }
     }
 
+    /// Stakes of `stakes` without overrides, available with the `test-utils` feature.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn new_for_test(stakes: &[(Pubkey, u64)]) -> Self {
+        Self::new(
+            Arc::new(stakes.iter().copied().collect()),
+            HashMap::default(),
+        )
+    }
+
     pub fn get_node_stake(&self, pubkey: &Pubkey) -> Option<u64> {
         self.overrides
             .get(pubkey)
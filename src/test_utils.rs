@@ -0,0 +1,199 @@
+//! Helpers to stand up a server and connect clients to it in end-to-end tests, available with
+//! the `test-utils` feature.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use crossbeam_channel::{unbounded, Receiver};
+use quinn::{
+    ClientConfig, Connection, Endpoint, EndpointConfig, IdleTimeout, TokioRuntime, TransportConfig,
+};
+use rustls::Certificate;
+use solana_perf::packet::PacketBatch;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::quic::{QUIC_KEEP_ALIVE, QUIC_MAX_TIMEOUT};
+use solana_sdk::signature::Keypair;
+
+use crate::nonblocking::quic::{
+    classify_peer, ConnectionPeerType, ALPN_TPU_PROTOCOL_ID, MAX_STREAMS_PER_MS_LIMIT,
+};
+use crate::quic::{QuicServerBuilder, QuicServerConfig, SpawnServerResult};
+use crate::streamer::StakedNodes;
+use crate::tls_certificates::new_self_signed_tls_certificate;
+
+/// Accepts any server certificate, the test servers present self-signed ones.
+pub struct SkipServerVerification;
+
+impl SkipServerVerification {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self)
+    }
+}
+
+impl rustls::client::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Client configuration presenting a certificate for `keypair` and negotiating the TPU
+/// protocol.
+pub fn get_client_config(keypair: &Keypair) -> ClientConfig {
+    get_client_config_with_alpn(keypair, vec![ALPN_TPU_PROTOCOL_ID.to_vec()])
+}
+
+/// Same as [`get_client_config`], offering `alpn_protocols` instead.
+pub fn get_client_config_with_alpn(
+    keypair: &Keypair,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> ClientConfig {
+    let ipaddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let (cert, key) = new_self_signed_tls_certificate(keypair, ipaddr)
+        .expect("Failed to generate client certificate");
+
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(SkipServerVerification::new())
+        .with_client_auth_cert(vec![cert], key)
+        .expect("Failed to use client certificate");
+
+    crypto.enable_early_data = true;
+    crypto.alpn_protocols = alpn_protocols;
+
+    let mut config = ClientConfig::new(Arc::new(crypto));
+
+    let mut transport_config = TransportConfig::default();
+    let timeout = IdleTimeout::try_from(QUIC_MAX_TIMEOUT).unwrap();
+    transport_config.max_idle_timeout(Some(timeout));
+    transport_config.keep_alive_interval(Some(QUIC_KEEP_ALIVE));
+    config.transport_config(Arc::new(transport_config));
+
+    config
+}
+
+/// Spawns a server on a loopback port picked by the OS, see [`SpawnServerResult::local_addr`],
+/// with the defaults of [`QuicServerBuilder`] otherwise. Stop it with
+/// [`SpawnServerResult::shutdown`].
+pub fn spawn_test_server(
+    staked_nodes: Arc<RwLock<StakedNodes>>,
+    quic_server_config: QuicServerConfig,
+) -> (SpawnServerResult, Receiver<PacketBatch>) {
+    let sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let (sender, receiver) = unbounded();
+    let server = QuicServerBuilder::new(
+        "quic_streamer_test",
+        sock,
+        &Keypair::new(),
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        sender,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .staked_nodes(staked_nodes)
+    .quic_server_config(quic_server_config)
+    .build_and_spawn()
+    .unwrap();
+    (server, receiver)
+}
+
+/// Connects to the server at `addr` as the peer identified by `keypair`. Must be called from
+/// within a tokio runtime, which then drives the connection.
+pub async fn connect_test_client(addr: SocketAddr, keypair: &Keypair) -> Connection {
+    let local_ip = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let mut endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        None,
+        UdpSocket::bind((local_ip, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    endpoint.set_default_client_config(get_client_config(keypair));
+    endpoint.connect(addr, "localhost").unwrap().await.unwrap()
+}
+
+/// How the server classifies connections from `pubkey` given `staked_nodes`, while it admits
+/// `max_streams_per_ms` streams, see [`SpawnServerResult::max_streams_per_ms`].
+pub fn peer_type(
+    staked_nodes: &StakedNodes,
+    pubkey: &Pubkey,
+    max_streams_per_ms: u64,
+) -> ConnectionPeerType {
+    staked_nodes
+        .get_node_stake(pubkey)
+        .map_or(ConnectionPeerType::Unstaked, |stake| {
+            classify_peer(
+                stake,
+                staked_nodes.total_stake(),
+                max_streams_per_ms.clamp(1, MAX_STREAMS_PER_MS_LIMIT),
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    #[test]
+    fn test_peer_type() {
+        let whale = Pubkey::new_unique();
+        let minnow = Pubkey::new_unique();
+        let staked_nodes =
+            StakedNodes::new_for_test(&[(whale, 10_000), (minnow, 1), (Pubkey::new_unique(), 0)]);
+        assert_eq!(staked_nodes.total_stake(), 10_001);
+        assert_eq!(staked_nodes.get_node_stake(&whale), Some(10_000));
+
+        assert_matches!(
+            peer_type(&staked_nodes, &whale, 100),
+            ConnectionPeerType::Staked(10_000)
+        );
+        // Too little stake for a stream per throttling interval, unless the server admits
+        // more streams.
+        assert_matches!(
+            peer_type(&staked_nodes, &minnow, 100),
+            ConnectionPeerType::Unstaked
+        );
+        assert_matches!(
+            peer_type(&staked_nodes, &minnow, 10_000),
+            ConnectionPeerType::Staked(1)
+        );
+        assert_matches!(
+            peer_type(&staked_nodes, &Pubkey::new_unique(), 100),
+            ConnectionPeerType::Unstaked
+        );
+    }
+
+    #[test]
+    fn test_spawn_test_server() {
+        let (server, receiver) = spawn_test_server(Arc::default(), QuicServerConfig::default());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let connection = runtime.block_on(connect_test_client(server.local_addr, &Keypair::new()));
+        runtime.block_on(async {
+            let mut send_stream = connection.open_uni().await.unwrap();
+            send_stream.write_all(&[1u8; 10]).await.unwrap();
+            send_stream.finish().await.unwrap();
+        });
+        let packet_batch = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(packet_batch.len(), 1);
+        assert_eq!(packet_batch[0].meta().size, 10);
+
+        drop(connection);
+        let summary = server.shutdown(Duration::from_secs(1)).unwrap();
+        assert_eq!(summary.outstanding_streams, 0);
+    }
+}
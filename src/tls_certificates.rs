@@ -1,5 +1,7 @@
 use std::net::IpAddr;
+use std::time::Duration;
 
+use ::time::OffsetDateTime;
 use pkcs8::der::Document;
 use pkcs8::{AlgorithmIdentifier, ObjectIdentifier};
 use rcgen::{CertificateParams, DistinguishedName, DnType, RcgenError, SanType};
@@ -8,9 +10,44 @@ use solana_sdk::signature::Keypair;
 use x509_parser::prelude::*;
 use x509_parser::public_key::PublicKey;
 
+/// Options of the certificates created by [`new_self_signed_tls_certificate_with_options`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelfSignedCertificateOptions {
+    /// How long the certificate is valid from its creation. It's valid from
+    /// [`CERTIFICATE_BACKDATE`] before then, so that peers whose clock is behind accept it.
+    /// Defaults to `None`, which makes it valid from 1975 through 4096, i.e. it never expires in
+    /// practice.
+    pub validity: Option<Duration>,
+    /// Names the certificate is issued for besides the IP address it's created with, e.g. the
+    /// DNS name of a proxy fronting the endpoint for clients checking them.
+    pub extra_subject_alt_names: Vec<SubjectAltName>,
+}
+
+/// How long before their creation the certificates with a
+/// [`SelfSignedCertificateOptions::validity`] are valid from.
+pub const CERTIFICATE_BACKDATE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Ip(IpAddr),
+}
+
 pub fn new_self_signed_tls_certificate(
     keypair: &Keypair,
     san: IpAddr,
+) -> Result<(rustls::Certificate, rustls::PrivateKey), RcgenError> {
+    new_self_signed_tls_certificate_with_options(
+        keypair,
+        san,
+        &SelfSignedCertificateOptions::default(),
+    )
+}
+
+pub fn new_self_signed_tls_certificate_with_options(
+    keypair: &Keypair,
+    san: IpAddr,
+    options: &SelfSignedCertificateOptions,
 ) -> Result<(rustls::Certificate, rustls::PrivateKey), RcgenError> {
     // TODO(terorie): Is it safe to sign the TLS cert with the identity private key?
 
@@ -43,12 +80,35 @@ pub fn new_self_signed_tls_certificate(
 
     let mut cert_params = CertificateParams::default();
     cert_params.subject_alt_names = vec![SanType::IpAddress(san)];
+    cert_params
+        .subject_alt_names
+        .extend(
+            options
+                .extra_subject_alt_names
+                .iter()
+                .map(|name| match name {
+                    SubjectAltName::Dns(name) => SanType::DnsName(name.clone()),
+                    SubjectAltName::Ip(ip) => SanType::IpAddress(*ip),
+                }),
+        );
     cert_params.alg = &rcgen::PKCS_ED25519;
     cert_params.key_pair = Some(rcgen_keypair);
     cert_params.distinguished_name = DistinguishedName::new();
     cert_params
         .distinguished_name
         .push(DnType::CommonName, "Solana node");
+    if let Some(validity) = options.validity {
+        let now = OffsetDateTime::now_utc();
+        cert_params.not_before = now - CERTIFICATE_BACKDATE;
+        // Validities beyond what the certificate can encode keep the default end.
+        if let Some(not_after) = ::time::Duration::try_from(validity)
+            .ok()
+            .and_then(|validity| now.checked_add(validity))
+            .filter(|&not_after| not_after < cert_params.not_after)
+        {
+            cert_params.not_after = not_after;
+        }
+    }
 
     let cert = rcgen::Certificate::from_params(cert_params)?;
     let cert_der = cert.serialize_der().unwrap();
@@ -67,7 +127,7 @@ pub fn get_pubkey_from_tls_certificate(der_cert: &rustls::Certificate) -> Option
 
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     use solana_sdk::signer::Signer;
 
@@ -89,4 +149,80 @@ mod tests {
             panic!("Failed to generate certificates");
         }
     }
+
+    #[test]
+    fn test_tls_certificate_validity() {
+        const YEAR: i64 = 365 * 24 * 60 * 60;
+        let keypair = Keypair::new();
+        let san = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let validity = |validity| {
+            let options = SelfSignedCertificateOptions {
+                validity,
+                ..SelfSignedCertificateOptions::default()
+            };
+            let (cert, _) =
+                new_self_signed_tls_certificate_with_options(&keypair, san, &options).unwrap();
+            let (_, cert) = X509Certificate::from_der(cert.as_ref()).unwrap();
+            let validity = cert.validity();
+            (
+                validity.not_before.timestamp(),
+                validity.not_after.timestamp(),
+            )
+        };
+
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let (not_before, default_not_after) = validity(None);
+        assert!(not_before < now - 50 * YEAR);
+        assert!(default_not_after > now + 1_000 * YEAR);
+
+        let (not_before, not_after) = validity(Some(Duration::from_secs(3 * YEAR as u64)));
+        let backdate = CERTIFICATE_BACKDATE.as_secs() as i64;
+        assert!((now - backdate - 1..=now - backdate + 1).contains(&not_before));
+        assert!((now + 3 * YEAR - 1..=now + 3 * YEAR + 1).contains(&not_after));
+
+        // Validities past the default end are capped at it.
+        let (_, not_after) = validity(Some(Duration::MAX));
+        assert_eq!(not_after, default_not_after);
+    }
+
+    #[test]
+    fn test_tls_certificate_subject_alt_names() {
+        let keypair = Keypair::new();
+        let san = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let subject_alt_names = |extra_subject_alt_names| {
+            let options = SelfSignedCertificateOptions {
+                extra_subject_alt_names,
+                ..SelfSignedCertificateOptions::default()
+            };
+            let (cert, _) =
+                new_self_signed_tls_certificate_with_options(&keypair, san, &options).unwrap();
+            let (_, cert) = X509Certificate::from_der(cert.as_ref()).unwrap();
+            let names = &cert.subject_alternative_name().unwrap().unwrap().value;
+            names
+                .general_names
+                .iter()
+                .map(|name| match name {
+                    GeneralName::DNSName(name) => SubjectAltName::Dns(name.to_string()),
+                    GeneralName::IPAddress(&[a, b, c, d]) => {
+                        SubjectAltName::Ip(IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+                    }
+                    GeneralName::IPAddress(octets) => SubjectAltName::Ip(IpAddr::V6(
+                        <[u8; 16]>::try_from(*octets).unwrap().into(),
+                    )),
+                    name => panic!("unexpected name {name:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(subject_alt_names(vec![]), vec![SubjectAltName::Ip(san)]);
+        let extra_subject_alt_names = vec![
+            SubjectAltName::Dns("tpu.example.com".to_string()),
+            SubjectAltName::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            SubjectAltName::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        ];
+        assert_eq!(
+            subject_alt_names(extra_subject_alt_names.clone()),
+            [vec![SubjectAltName::Ip(san)], extra_subject_alt_names].concat()
+        );
+    }
 }